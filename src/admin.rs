@@ -0,0 +1,143 @@
+//! Local admin socket for privileged node operations.
+//!
+//! The socket speaks one JSON object per line, kept apart from both the DHT
+//! protocol and the control socket so it can be locked down on its own. It
+//! is created readable and writable by its owner only.
+//!
+//! ```text
+//! $ echo '{"op":"ban_peer","addr":"10.0.0.7:8080"}' | socat - UNIX-CONNECT:admin.sock
+//! {"ok":true,"result":{"was_known":true}}
+//! ```
+//!
+//! Operations: `ban_peer` (`addr`, optional `duration` such as `"1h"`),
+//! `unban_peer` (`addr`), `banned_peers`, `drop_key` (`key`),
+//! `check_replication`, `rebalance` and `set_log_level` (`filter`, in the
+//! `RUST_LOG` syntax).
+
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use rust_p2p_node::dht::{DhtNode, tasks};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+
+use crate::{control::bind_private, logging::LogFilter};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+enum AdminRequest {
+    BanPeer {
+        addr: SocketAddr,
+        /// Permanent when missing
+        #[serde(default, with = "humantime_serde")]
+        duration: Option<Duration>,
+    },
+    UnbanPeer {
+        addr: SocketAddr,
+    },
+    BannedPeers,
+    DropKey {
+        key: String,
+    },
+    CheckReplication,
+    Rebalance,
+    SetLogLevel {
+        filter: String,
+    },
+}
+
+/// What the admin operations act on.
+#[derive(Clone)]
+pub struct Admin {
+    pub node: DhtNode,
+    pub log_filter: LogFilter,
+}
+
+/// Serves admin requests on the Unix socket at `path` until the returned
+/// task is aborted.
+pub async fn serve(path: &Path, admin: Admin) -> Result<JoinHandle<()>> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow!(
+                "An admin socket is already served on {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener = bind_private(path)
+        .with_context(|| format!("Failed to bind admin socket {}", path.display()))?;
+
+    Ok(tasks::spawn("admin-listener", async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let admin = admin.clone();
+                    tasks::spawn("admin-connection", async move {
+                        if let Err(e) = handle_client(stream, admin).await {
+                            debug!(error = %e, "admin connection failed");
+                        }
+                    });
+                }
+                Err(e) => warn!(error = %e, "failed to accept admin connection"),
+            }
+        }
+    }))
+}
+
+async fn handle_client(stream: UnixStream, admin: Admin) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let result = match serde_json::from_str::<AdminRequest>(&line) {
+            Ok(request) => admin.execute(request).await,
+            Err(e) => Err(anyhow!("Invalid request: {}", e)),
+        };
+        let response = match result {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+        };
+
+        let mut response = response.to_string();
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+impl Admin {
+    async fn execute(&self, request: AdminRequest) -> Result<Value> {
+        info!(?request, "admin request");
+
+        Ok(match request {
+            AdminRequest::BanPeer { addr, duration } => {
+                json!({ "was_known": self.node.ban_peer(addr, duration) })
+            }
+            AdminRequest::UnbanPeer { addr } => {
+                json!({ "was_banned": self.node.unban_peer(addr) })
+            }
+            AdminRequest::BannedPeers => json!(self.node.banned_peers()),
+            AdminRequest::DropKey { key } => {
+                json!({ "dropped": self.node.drop_key(key.as_bytes()) })
+            }
+            AdminRequest::CheckReplication => {
+                json!({ "repaired": self.node.check_replication().await })
+            }
+            AdminRequest::Rebalance => json!(self.node.rebalance().await),
+            AdminRequest::SetLogLevel { filter } => {
+                self.log_filter.set(&filter)?;
+                json!({ "filter": filter })
+            }
+        })
+    }
+}
@@ -1,109 +1,945 @@
-use std::net::SocketAddr;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::pin,
+    time::{Duration, Instant},
+};
 
-use tokio::sync::mpsc;
+use anyhow::{Context, Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
 
-use rust_p2p_node::dht::DhtNode;
+use futures::{Stream, StreamExt};
+use rust_p2p_node::dht::{
+    DhtNode,
+    events::{DhtEvent, KeyChange},
+    lookup::ValueAnswer,
+    rpc::WriteRequest,
+};
+
+use crate::{
+    bench::{self, BenchOptions},
+    dashboard::{BucketFill, DashboardSnapshot, PeerRow},
+    encoding::BytesFormat,
+    files,
+    logging::LogFilter,
+};
+
+/// Node events kept for the dashboard.
+const RECENT_EVENTS: usize = 50;
 
 pub struct DhtApp {
     pub node: DhtNode,
-    command_receiver: mpsc::Receiver<AppCommand>,
+    bootstrap_peers: Vec<SocketAddr>,
+    command_receiver: mpsc::Receiver<AppRequest>,
+    /// Latest node events, newest first, with when they happened
+    recent: VecDeque<(Instant, String)>,
+    log_filter: LogFilter,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AppCommand {
-    Store(String, String),
-    Get(String),
+    /// Store a value, replicated as the request says or as configured
+    Store(Vec<u8>, Vec<u8>, WriteRequest),
+    /// Get a value, printed in `format` or as text when it is UTF-8, or
+    /// written as is to `output`
+    Get {
+        key: Vec<u8>,
+        format: Option<BytesFormat>,
+        output: Option<PathBuf>,
+    },
+    Lookup(String),
+    /// Metadata of a key, both the lookup's winning copy and the local one
+    Info(String),
+    PutFile(String, PathBuf),
+    GetFile(String, PathBuf),
     ListPeers,
     GetStats,
+    ResetStats,
+    ListKeys {
+        prefix: Option<String>,
+        limit: Option<usize>,
+    },
+    Dump,
+    Bench(BenchOptions),
+    /// Everything the dashboard shows, always as JSON
+    DashboardSnapshot,
+    /// Ban a peer by address or id, for good without a duration
+    Ban {
+        target: String,
+        duration: Option<Duration>,
+    },
+    Unban(String),
+    /// Replace the log filter, or show it without one
+    LogLevel(Option<String>),
+    Rebalance,
+}
+
+/// Error of a command whose output describes the failure, such as the JSON
+/// answer of a failed store; it is printed as is.
+#[derive(Debug)]
+pub struct CommandFailed(pub String);
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// A command for the app task together with where to send its output.
+pub struct AppRequest {
+    command: AppCommand,
+    /// Render the output as JSON instead of text
+    json: bool,
+    reply: oneshot::Sender<Result<String>>,
+}
+
+/// Submits commands to a running [`DhtApp`].
+#[derive(Clone)]
+pub struct AppHandle {
+    sender: mpsc::Sender<AppRequest>,
+    node: DhtNode,
+}
+
+impl AppHandle {
+    /// Runs `command` and returns its output, as JSON when `json` is set.
+    pub async fn execute(&self, command: AppCommand, json: bool) -> Result<String> {
+        let (reply, output) = oneshot::channel();
+        self.sender
+            .send(AppRequest {
+                command,
+                json,
+                reply,
+            })
+            .await
+            .map_err(|_| anyhow!("The node has stopped"))?;
+
+        output.await.map_err(|_| anyhow!("The node has stopped"))?
+    }
+
+    /// Follows `key`, yielding one line of output per change to its local
+    /// copy.
+    pub fn watch(&self, key: String, json: bool) -> impl Stream<Item = String> + Send + 'static {
+        self.node
+            .subscribe_key(key.clone().into_bytes())
+            .map(move |change| format_change(&key, change, json))
+    }
 }
 
 impl DhtApp {
-    pub fn new(node: DhtNode, command_receiver: mpsc::Receiver<AppCommand>) -> Self {
-        Self {
+    pub fn new(
+        node: DhtNode,
+        bootstrap_peers: Vec<SocketAddr>,
+        log_filter: LogFilter,
+    ) -> (Self, AppHandle) {
+        let (sender, command_receiver) = mpsc::channel(32);
+        let handle = AppHandle {
+            sender,
+            node: node.clone(),
+        };
+        let app = Self {
             node,
+            bootstrap_peers,
             command_receiver,
-        }
+            recent: VecDeque::with_capacity(RECENT_EVENTS),
+            log_filter,
+        };
+        (app, handle)
     }
 
-    pub async fn run(mut self) {
-        println!("DHT node running at {}", self.node.addr);
+    /// Serves commands until `shutdown` resolves, then finishes the ones
+    /// already queued and returns.
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) {
+        info!(addr = %self.node.addr, "DHT node running");
+        let mut events = pin!(self.node.subscribe());
+        let mut shutdown = pin!(shutdown);
+        let mut stopping = false;
 
-        if let Some(peers) = self.get_initial_peers().await {
-            if let Err(e) = self.node.bootstrap(peers).await {
-                eprintln!("Bootstrap failed: {}", e);
-            }
+        if let Some(peers) = self.get_initial_peers().await
+            && let Err(e) = self.node.bootstrap(peers).await
+        {
+            warn!(error = %e, "bootstrap failed");
         }
 
-        while let Some(cmd) = self.command_receiver.recv().await {
-            match cmd {
-                AppCommand::Store(key, value) => {
-                    self.handle_store(key, value).await;
+        loop {
+            tokio::select! {
+                request = self.command_receiver.recv() => {
+                    let Some(request) = request else { break };
+                    let output = self.execute(request.command, request.json).await;
+                    let _ = request.reply.send(output);
+                }
+                Some(event) = events.next() => self.record(event),
+                _ = &mut shutdown, if !stopping => {
+                    stopping = true;
+                    self.command_receiver.close();
                 }
-                AppCommand::Get(key) => {
-                    self.handle_get(key).await;
+            }
+        }
+    }
+
+    /// Keeps a readable line about `event` for the dashboard.
+    fn record(&mut self, event: DhtEvent) {
+        let key = |key: &[u8]| String::from_utf8_lossy(key).into_owned();
+        let line = match event {
+            DhtEvent::Bootstrapped {
+                reached,
+                known_peers,
+            } => format!("bootstrapped from {} peers, {} known", reached, known_peers),
+            // Runs every maintenance interval and would crowd out the rest
+            DhtEvent::MaintenanceCompleted { .. } => return,
+            DhtEvent::PeerDiscovered(peer) => format!("discovered peer {}", peer.addr),
+            DhtEvent::PeerEvicted(peer) => format!("evicted peer {}", peer.addr),
+            DhtEvent::ValueStored {
+                key: stored,
+                version,
+                is_replica,
+            } => format!(
+                "stored {} (version {}{})",
+                key(&stored),
+                version,
+                if is_replica { ", replica" } else { "" }
+            ),
+            DhtEvent::ValueExpired { key: expired } => format!("expired {}", key(&expired)),
+            DhtEvent::ValueRemoved { key: removed } => format!("removed {}", key(&removed)),
+            DhtEvent::ValueEvicted { key: evicted } => format!("evicted {}", key(&evicted)),
+            DhtEvent::LookupCompleted {
+                key: looked_up,
+                source,
+                replicas,
+                elapsed,
+            } => match source {
+                Some(source) => format!(
+                    "found {} at {} ({} replicas) in {:.1?}",
+                    key(&looked_up),
+                    source,
+                    replicas,
+                    elapsed
+                ),
+                None => format!("missed {} in {:.1?}", key(&looked_up), elapsed),
+            },
+            DhtEvent::RebalanceProgress { report, total } => format!(
+                "rebalanced {} of {} keys, {} pushed, {} dropped",
+                report.checked, total, report.pushed, report.dropped
+            ),
+            DhtEvent::Lagged(missed) => format!("{} events not shown", missed),
+        };
+
+        self.recent.truncate(RECENT_EVENTS - 1);
+        self.recent.push_front((Instant::now(), line));
+    }
+
+    async fn execute(&self, command: AppCommand, json: bool) -> Result<String> {
+        match command {
+            AppCommand::Store(key, value, request) => {
+                self.handle_store(key, value, request, json).await
+            }
+            AppCommand::Get {
+                key,
+                output: Some(path),
+                ..
+            } => self.handle_get_to_file(key, path, json).await,
+            AppCommand::Get {
+                key,
+                format,
+                output: None,
+            } => Ok(self.handle_get(key, format, json).await),
+            AppCommand::Lookup(key) => Ok(self.handle_lookup(key, json).await),
+            AppCommand::Info(key) => Ok(self.handle_info(key, json).await),
+            AppCommand::PutFile(key, path) => self.handle_put_file(key, path, json).await,
+            AppCommand::GetFile(key, path) => self.handle_get_file(key, path, json).await,
+            AppCommand::ListPeers => Ok(self.handle_list_peers(json)),
+            AppCommand::GetStats => Ok(self.handle_get_stats(json)),
+            AppCommand::ResetStats => {
+                self.node.reset_metrics();
+                if json {
+                    Ok(json!({ "reset": true }).to_string())
+                } else {
+                    Ok("Statistics reset".to_string())
                 }
-                AppCommand::ListPeers => {
-                    self.handle_list_peers().await;
+            }
+            AppCommand::ListKeys { prefix, limit } => {
+                Ok(self.handle_list_keys(prefix, limit, json))
+            }
+            AppCommand::Dump => self.handle_dump(json).await,
+            AppCommand::LogLevel(filter) => {
+                if let Some(filter) = &filter {
+                    self.log_filter.set(filter)?;
+                    info!(filter, "log filter changed");
                 }
-                AppCommand::GetStats => {
-                    self.handle_get_stats().await;
+                let filter = self.log_filter.current()?;
+                Ok(if json {
+                    json!({ "filter": filter }).to_string()
+                } else {
+                    format!("Log filter: {}", filter)
+                })
+            }
+            AppCommand::Rebalance => {
+                let report = self.node.rebalance().await;
+                Ok(if json {
+                    json!(report).to_string()
+                } else {
+                    format!(
+                        "Rebalanced {} keys: {} copies pushed, {} replicas dropped",
+                        report.checked, report.pushed, report.dropped
+                    )
+                })
+            }
+            AppCommand::Bench(options) => self.handle_bench(options, json).await,
+            AppCommand::DashboardSnapshot => self.handle_dashboard_snapshot(),
+            AppCommand::Ban { target, duration } => {
+                let addr = self.resolve_peer(&target)?;
+                self.node.ban_peer(addr, duration);
+                Ok(self.format_bans(json))
+            }
+            AppCommand::Unban(target) => {
+                let addr = self.resolve_peer(&target)?;
+                if !self.node.unban_peer(addr) {
+                    bail!("{} is not banned", target);
                 }
+                Ok(self.format_bans(json))
             }
         }
     }
 
     async fn get_initial_peers(&self) -> Option<Vec<SocketAddr>> {
-        // In a real app, you might load these from config or CLI
-        None
+        if self.bootstrap_peers.is_empty() {
+            None
+        } else {
+            Some(self.bootstrap_peers.clone())
+        }
     }
 
-    async fn handle_store(&self, key: String, value: String) {
-        match self.node.store(key.into_bytes(), value.into_bytes()).await {
-            Ok(_) => println!("Value stored successfully"),
-            Err(e) => eprintln!("Failed to store value: {}", e),
+    async fn handle_store(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        request: WriteRequest,
+        json: bool,
+    ) -> Result<String> {
+        let result = self
+            .node
+            .store_coordinated(key.clone(), value, request)
+            .await;
+        let (key, _) = encode_bytes(&key);
+
+        if json {
+            return match result {
+                Ok(report) => Ok(json!({
+                    "key": key,
+                    "stored": true,
+                    "version": report.version,
+                    "replicas": report.replicas,
+                    "acknowledged": report.acknowledged,
+                })
+                .to_string()),
+                Err(e) => Err(CommandFailed(
+                    json!({ "key": key, "stored": false, "error": e.to_string() }).to_string(),
+                )
+                .into()),
+            };
         }
+
+        let report = result.context("Failed to store value")?;
+        Ok(format!(
+            "Value stored: version {}, acknowledged by {} of {} replicas",
+            report.version, report.acknowledged, report.replicas
+        ))
     }
 
-    async fn handle_get(&self, key: String) {
-        match self.node.find_value(key.into_bytes()).await {
+    async fn handle_get(&self, key: Vec<u8>, format: Option<BytesFormat>, json: bool) -> String {
+        let value = self.node.find_value(key.clone()).await;
+        let (key, _) = encode_bytes(&key);
+
+        if json {
+            return match value {
+                Some(value) => {
+                    let (value, encoding) = match format {
+                        Some(format) => (format.encode(&value), format.name()),
+                        None => {
+                            let (value, encoding) = encode_bytes(&value);
+                            (Some(value), encoding)
+                        }
+                    };
+                    json!({ "key": key, "found": true, "value": value, "encoding": encoding })
+                }
+                None => json!({ "key": key, "found": false }),
+            }
+            .to_string();
+        }
+
+        match value {
+            Some(value) if format.is_some() => match format.and_then(|f| f.encode(&value)) {
+                Some(value) => format!("Value: {}", value),
+                None => "Value is not valid UTF-8, use --value-format hex or base64".to_string(),
+            },
             Some(value) => {
-                if let Ok(str_value) = String::from_utf8(value.clone()) {
-                    println!("Value: {}", str_value);
+                if let Ok(str_value) = std::str::from_utf8(&value) {
+                    format!("Value: {}", str_value)
                 } else {
-                    println!("Value (binary): {:?}", value);
+                    format!("Value (binary): {:?}", value)
+                }
+            }
+            None => "Value not found".to_string(),
+        }
+    }
+
+    async fn handle_get_to_file(&self, key: Vec<u8>, path: PathBuf, json: bool) -> Result<String> {
+        let value = self.node.find_value(key.clone()).await;
+        let (key, _) = encode_bytes(&key);
+        let Some(value) = value else {
+            return Ok(if json {
+                json!({ "key": key, "found": false }).to_string()
+            } else {
+                "Value not found".to_string()
+            });
+        };
+
+        tokio::fs::write(&path, &value)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        Ok(if json {
+            json!({ "key": key, "found": true, "path": path, "bytes": value.len() }).to_string()
+        } else {
+            format!("Wrote {} bytes to {}", value.len(), path.display())
+        })
+    }
+
+    async fn handle_info(&self, key: String, json: bool) -> String {
+        let found = self
+            .node
+            .find_value_detailed(key.clone().into_bytes())
+            .await;
+        let local = self.node.local_value(key.as_bytes());
+
+        if json {
+            let found = found.map(|found| {
+                json!({
+                    "source": found.source,
+                    "version": found.version,
+                    "ttl_remaining": found.ttl_remaining,
+                    "replicas": found.replicas,
+                    "last_node": found.last_node,
+                    "original_nodes": found.original_nodes,
+                    "size": found.value.len(),
+                })
+            });
+            let local = local.map(|local| {
+                json!({
+                    "version": local.version,
+                    "expiration": local.expiration,
+                    "is_replica": local.is_replica,
+                    "hinted_for": local.hinted_for,
+                    "last_node": local.last_node,
+                    "original_nodes": local.original_nodes,
+                    "size": local.data.len(),
+                })
+            });
+            return json!({ "key": key, "found": found, "local": local }).to_string();
+        }
+
+        let Some(found) = found else {
+            return "Value not found".to_string();
+        };
+
+        let list = |addrs: &[SocketAddr]| match addrs {
+            [] => "none known".to_string(),
+            addrs => addrs
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        let mut output = format!(
+            "Key: {}\nVersion: {} (from {}, {} replicas seen)\nSize: {} bytes\nExpires: {}\nLast written by: {}\nOriginal nodes: {}",
+            key,
+            found.version,
+            found.source,
+            found.replicas,
+            found.value.len(),
+            match found.ttl_remaining {
+                Some(ttl) => format!("in {}s", ttl),
+                None => "never".to_string(),
+            },
+            found.last_node,
+            list(&found.original_nodes),
+        );
+
+        let _ = match local {
+            Some(local) => write!(
+                output,
+                "\nLocal copy: version {}, {}",
+                local.version,
+                match (local.is_replica, local.hinted_for) {
+                    (_, Some(target)) => format!("hinted for {}", target),
+                    (true, None) => "replica".to_string(),
+                    (false, None) => "original".to_string(),
                 }
+            ),
+            None => write!(output, "\nLocal copy: none"),
+        };
+        output
+    }
+
+    async fn handle_lookup(&self, key: String, json: bool) -> String {
+        let trace = self.node.trace_lookup(key.clone().into_bytes()).await;
+
+        if json {
+            let result = trace.result.as_ref().map(|found| {
+                let (value, encoding) = encode_bytes(&found.value);
+                json!({
+                    "value": value,
+                    "encoding": encoding,
+                    "source": found.source,
+                    "version": found.version,
+                    "replicas": found.replicas,
+                })
+            });
+            return json!({
+                "key": key,
+                "local_version": trace.local_version,
+                "hops": trace.hops,
+                "result": result,
+                "elapsed_ms": trace.elapsed.as_millis(),
+                "timed_out": trace.timed_out,
+            })
+            .to_string();
+        }
+
+        let mut output = match &trace.result {
+            Some(found) => format!(
+                "Lookup of {}: found at {} (version {}, {} replicas) in {:?}",
+                key, found.source, found.version, found.replicas, trace.elapsed
+            ),
+            None => format!("Lookup of {}: not found in {:?}", key, trace.elapsed),
+        };
+        let _ = match trace.local_version {
+            Some(version) => write!(output, "\nLocal copy: version {}", version),
+            None => write!(output, "\nLocal copy: none"),
+        };
+
+        for (hop, round) in trace.hops.iter().enumerate() {
+            let _ = write!(
+                output,
+                "\nHop {} ({} peers, {:?}):",
+                hop + 1,
+                round.peers.len(),
+                round.elapsed
+            );
+            for query in &round.peers {
+                let answer = match &query.value {
+                    ValueAnswer::Found { version } => format!("found version {}", version),
+                    ValueAnswer::NotFound => "not found".to_string(),
+                    ValueAnswer::Failed { error } => format!("failed ({})", error),
+                    ValueAnswer::Unanswered => "no answer before the lookup ended".to_string(),
+                };
+                let closer = match &query.closer {
+                    Some(peers) => format!("{} closer peers", peers.len()),
+                    None => "no closer peers".to_string(),
+                };
+                let _ = write!(
+                    output,
+                    "\n- {} (distance {}): {}, {}",
+                    query.addr, query.distance, answer, closer
+                );
             }
-            None => println!("Value not found"),
         }
+
+        if trace.hops.is_empty() {
+            output.push_str("\nNo peers to query");
+        }
+        if trace.timed_out {
+            output.push_str("\nThe lookup ran out of time");
+        }
+        output
+    }
+
+    async fn handle_put_file(&self, key: String, path: PathBuf, json: bool) -> Result<String> {
+        let result = files::put_file(&self.node, &key, &path).await;
+
+        if json {
+            return match result {
+                Ok(size) => Ok(json!({ "key": key, "path": path, "bytes": size }).to_string()),
+                Err(e) => Err(CommandFailed(
+                    json!({ "key": key, "path": path, "error": e.to_string() }).to_string(),
+                )
+                .into()),
+            };
+        }
+
+        let size = result.context("Failed to store file")?;
+        Ok(format!("Stored {} bytes under {}", size, key))
     }
 
-    async fn handle_list_peers(&self) {
+    async fn handle_get_file(&self, key: String, path: PathBuf, json: bool) -> Result<String> {
+        let result = files::get_file(&self.node, &key, &path).await;
+
+        if json {
+            return match result {
+                Ok(size) => Ok(json!({ "key": key, "path": path, "bytes": size }).to_string()),
+                Err(e) => Err(CommandFailed(
+                    json!({ "key": key, "path": path, "error": e.to_string() }).to_string(),
+                )
+                .into()),
+            };
+        }
+
+        let size = result.context("Failed to fetch file")?;
+        Ok(format!("Wrote {} bytes to {}", size, path.display()))
+    }
+
+    fn handle_list_peers(&self, json: bool) -> String {
         let mut peers = Vec::new();
+        self.node.for_each_peer(|peer| peers.push(peer.clone()));
 
-        for bucket in self.node.routing_table.iter() {
-            peers.extend(bucket.value().peers.iter().cloned());
+        let peer_stats = self.node.peer_stats();
+
+        if json {
+            let peers: Vec<_> = peers
+                .iter()
+                .map(|peer| {
+                    json!({
+                        "id": peer.id.to_string(),
+                        "addr": peer.addr,
+                        "last_seen": peer.last_seen,
+                        "stats": peer_stats.get(&peer.addr),
+                    })
+                })
+                .collect();
+            return json!(peers).to_string();
         }
 
         if peers.is_empty() {
-            println!("No known peers");
-            return;
+            return "No known peers".to_string();
         }
 
-        println!("Known peers ({}):", peers.len());
+        let mut output = format!("Known peers ({}):", peers.len());
         for peer in peers {
-            println!("- ID: {}, Addr: {}", peer.id, peer.addr);
+            let _ = match peer_stats.get(&peer.addr) {
+                Some(stats) => write!(
+                    output,
+                    "\n- ID: {}, Addr: {}, Requests: {}, Failures: {}, Sent: {}B, Received: {}B, RTT: {}",
+                    peer.id,
+                    peer.addr,
+                    stats.requests,
+                    stats.failures,
+                    stats.bytes_sent,
+                    stats.bytes_received,
+                    stats
+                        .last_rtt
+                        .map(|rtt| format!("{:?}", rtt))
+                        .unwrap_or_else(|| "-".to_string()),
+                ),
+                None => write!(output, "\n- ID: {}, Addr: {}", peer.id, peer.addr),
+            };
+        }
+        output
+    }
+
+    fn handle_list_keys(&self, prefix: Option<String>, limit: Option<usize>, json: bool) -> String {
+        let mut entries = self
+            .node
+            .local_entries(prefix.as_deref().unwrap_or_default().as_bytes());
+
+        if json {
+            entries.truncate(limit.unwrap_or(entries.len()));
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|entry| {
+                    let (key, encoding) = encode_bytes(&entry.key);
+                    json!({
+                        "key": key,
+                        "encoding": encoding,
+                        "size": entry.size,
+                        "version": entry.version,
+                        "ttl_remaining": entry.ttl_remaining,
+                        "is_replica": entry.is_replica,
+                        "hinted_for": entry.hinted_for,
+                    })
+                })
+                .collect();
+            return json!(entries).to_string();
+        }
+
+        if entries.is_empty() {
+            return "No local keys".to_string();
+        }
+
+        let shown = limit.unwrap_or(entries.len()).min(entries.len());
+        let mut output = format!("Local keys ({} of {}):", shown, entries.len());
+        for entry in &entries[..shown] {
+            let key = match encode_bytes(&entry.key) {
+                (key, "utf8") => key,
+                (key, _) => format!("0x{}", key),
+            };
+            let ttl = match entry.ttl_remaining {
+                Some(ttl) => format!("expires in {}s", ttl),
+                None => "never expires".to_string(),
+            };
+            let status = match (entry.is_replica, entry.hinted_for) {
+                (_, Some(target)) => format!("hinted for {}", target),
+                (true, None) => "replica".to_string(),
+                (false, None) => "original".to_string(),
+            };
+            let _ = write!(
+                output,
+                "\n- {} ({} bytes, {}, {})",
+                key, entry.size, ttl, status
+            );
+        }
+        output
+    }
+
+    async fn handle_dump(&self, json: bool) -> Result<String> {
+        let dump = self.node.debug_dump().await;
+        let rendered = if json {
+            serde_json::to_string(&dump)
+        } else {
+            serde_json::to_string_pretty(&dump)
+        };
+        rendered.context("Failed to serialize debug dump")
+    }
+
+    async fn handle_bench(&self, options: BenchOptions, json: bool) -> Result<String> {
+        let report = bench::run(&self.node, &options).await;
+        if json {
+            return serde_json::to_string(&report).context("Failed to serialize bench report");
+        }
+
+        let mut output = format!(
+            "Benchmark ({} byte values, concurrency {}):",
+            options.value_size, options.concurrency
+        );
+        for (name, ops) in [("Writes", &report.writes), ("Reads", &report.reads)] {
+            let _ = write!(
+                output,
+                "\n- {}: {} ops, {} failed, {:.1} ops/s, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+                name, ops.ops, ops.failures, ops.throughput, ops.p50, ops.p90, ops.p99, ops.max
+            );
+        }
+        Ok(output)
+    }
+
+    fn handle_dashboard_snapshot(&self) -> Result<String> {
+        let current_time = self.node.now();
+        let mut peers = Vec::new();
+        let mut buckets = Vec::new();
+        for bucket in self.node.routing_table.iter() {
+            if bucket.peers.is_empty() {
+                continue;
+            }
+            buckets.push(BucketFill {
+                index: *bucket.key(),
+                peers: bucket.peers.len(),
+                capacity: bucket.max_size,
+            });
+            peers.extend(bucket.peers.iter().cloned());
+        }
+        buckets.sort_by_key(|bucket| bucket.index);
+
+        let peer_stats = self.node.peer_stats();
+        let snapshot = DashboardSnapshot {
+            addr: self.node.addr,
+            stats: self.node.get_stats(),
+            peers: peers
+                .into_iter()
+                .map(|peer| PeerRow {
+                    id: peer.id.to_string(),
+                    idle: current_time.saturating_sub(peer.last_seen),
+                    stats: peer_stats.get(&peer.addr).cloned(),
+                    addr: peer.addr,
+                })
+                .collect(),
+            buckets,
+            recent: self
+                .recent
+                .iter()
+                .map(|(at, line)| format!("{:>4}s ago  {}", at.elapsed().as_secs(), line))
+                .collect(),
+        };
+
+        serde_json::to_string(&snapshot).context("Failed to serialize dashboard snapshot")
+    }
+
+    /// Finds the peer meant by `target`: an address, or a node id or unique
+    /// prefix of one among known and banned peers.
+    fn resolve_peer(&self, target: &str) -> Result<SocketAddr> {
+        if let Ok(addr) = target.parse() {
+            return Ok(addr);
+        }
+
+        let prefix = target.to_lowercase();
+        let mut matches = Vec::new();
+        self.node.for_each_peer(|peer| {
+            if peer.id.to_string().starts_with(&prefix) {
+                matches.push(peer.addr);
+            }
+        });
+        matches.extend(
+            self.node
+                .banned_peers()
+                .into_iter()
+                .filter_map(|ban| Some((ban.id?.to_string(), ban.addr)))
+                .filter(|(id, _)| id.starts_with(&prefix))
+                .map(|(_, addr)| addr),
+        );
+        matches.sort();
+        matches.dedup();
+
+        match matches.as_slice() {
+            [addr] => Ok(*addr),
+            [] => Err(anyhow!("No known peer with address or id {}", target)),
+            _ => Err(anyhow!("Id {} matches {} peers", target, matches.len())),
+        }
+    }
+
+    fn format_bans(&self, json: bool) -> String {
+        let bans = self.node.banned_peers();
+        if json {
+            return json!(bans).to_string();
         }
+
+        if bans.is_empty() {
+            return "No banned peers".to_string();
+        }
+
+        let current_time = self.node.now();
+        let mut output = format!("Banned peers ({}):", bans.len());
+        for ban in bans {
+            let until = match ban.until {
+                Some(until) => format!(
+                    "for {}",
+                    humantime::format_duration(Duration::from_secs(
+                        until.saturating_sub(current_time)
+                    ))
+                ),
+                None => "permanently".to_string(),
+            };
+            let id = match &ban.id {
+                Some(id) => id.to_string()[..12].to_string(),
+                None => "unknown id".to_string(),
+            };
+            let _ = write!(output, "\n- {} ({}), {}", ban.addr, id, until);
+        }
+        output
     }
 
-    async fn handle_get_stats(&self) {
+    fn handle_get_stats(&self, json: bool) -> String {
+        if json {
+            return self.node.stats_json();
+        }
+
         let stats = self.node.get_stats();
-        println!("DHT Statistics:");
-        println!("- Store operations: {}", stats.store_ops);
-        println!("- Successful stores: {}", stats.store_success);
-        println!("- Find operations: {}", stats.find_value_ops);
-        println!("- Successful finds: {}", stats.find_value_success);
-        println!("- RPC requests: {}", stats.rpc_requests);
-        println!("- RPC failures: {}", stats.rpc_failures);
-        println!("- Known peers: {}", stats.known_peers);
-        println!("- Storage size: {}", stats.storage_size);
+        [
+            "DHT Statistics:".to_string(),
+            format!("- Store operations: {}", stats.store_ops),
+            format!("- Successful stores: {}", stats.store_success),
+            format!("- Find operations: {}", stats.find_value_ops),
+            format!("- Successful finds: {}", stats.find_value_success),
+            format!("- Finds answered from cache: {}", stats.lookup_cache_hits),
+            format!("- RPC requests: {}", stats.rpc_requests),
+            format!("- RPC failures: {}", stats.rpc_failures),
+            format!("- RPCs shed: {}", stats.rpcs_shed),
+            format!("- Retries deduplicated: {}", stats.requests_deduplicated),
+            format!("- Hot key requests shed: {}", stats.hot_key_requests_shed),
+            format!("- Known peers: {}", stats.known_peers),
+            format!("- Peers evicted: {}", stats.peers_evicted),
+            format!(
+                "- Health checks: {} ({} failed)",
+                stats.health_checks, stats.health_check_failures
+            ),
+            format!(
+                "- Replications: {}/{} succeeded",
+                stats.replication_successes, stats.replication_attempts
+            ),
+            format!("- Expired entries: {}", stats.expired_entries),
+            format!("- Storage evictions: {}", stats.storage_evictions),
+            format!(
+                "- Storage size: {} keys, {} bytes",
+                stats.storage_size, stats.storage_bytes
+            ),
+            format!("- Buffered writes: {}", stats.outbox_size),
+            format!(
+                "- Stores/sec (1m/5m): {:.2}/{:.2}",
+                stats.rates.store_ops_1m, stats.rates.store_ops_5m
+            ),
+            format!(
+                "- Finds/sec (1m/5m): {:.2}/{:.2}",
+                stats.rates.find_value_ops_1m, stats.rates.find_value_ops_5m
+            ),
+            format!(
+                "- RPCs/sec (1m/5m): {:.2}/{:.2}",
+                stats.rates.rpc_requests_1m, stats.rates.rpc_requests_5m
+            ),
+        ]
+        .into_iter()
+        .chain(stats.hot_keys.iter().enumerate().map(|(i, hot)| {
+            let heading = if i == 0 { "- Hot keys: " } else { "  " };
+            format!(
+                "{}{} ({} requests)",
+                heading,
+                encode_bytes(&hot.key).0,
+                hot.requests
+            )
+        }))
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+/// Renders a change to a watched key.
+fn format_change(key: &str, change: KeyChange, json: bool) -> String {
+    match change {
+        KeyChange::Stored {
+            value,
+            version,
+            is_replica,
+        } => {
+            let (value, encoding) = encode_bytes(&value);
+            if json {
+                return json!({
+                    "key": key,
+                    "change": "stored",
+                    "version": version,
+                    "is_replica": is_replica,
+                    "value": value,
+                    "encoding": encoding,
+                })
+                .to_string();
+            }
+
+            let value = if encoding == "utf8" {
+                value
+            } else {
+                format!("0x{}", value)
+            };
+            let copy = if is_replica { "replica" } else { "original" };
+            format!("{}: {} (version {}, {})", key, value, version, copy)
+        }
+        KeyChange::Expired if json => json!({ "key": key, "change": "expired" }).to_string(),
+        KeyChange::Expired => format!("{}: expired", key),
+        KeyChange::Removed if json => json!({ "key": key, "change": "removed" }).to_string(),
+        KeyChange::Removed => format!("{}: removed", key),
+        KeyChange::Evicted if json => json!({ "key": key, "change": "evicted" }).to_string(),
+        KeyChange::Evicted => format!("{}: evicted", key),
+        KeyChange::Lagged(missed) if json => {
+            json!({ "key": key, "change": "lagged", "missed": missed }).to_string()
+        }
+        KeyChange::Lagged(missed) => format!("{}: fell behind, {} events missed", key, missed),
+    }
+}
+
+/// Renders bytes as text when they are UTF-8 and as hex otherwise, along with
+/// the encoding used.
+fn encode_bytes(bytes: &[u8]) -> (String, &'static str) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (text.to_string(), "utf8"),
+        Err(_) => (hex::encode(bytes), "hex"),
     }
 }
@@ -0,0 +1,125 @@
+//! Built-in load generator.
+//!
+//! [`run`] writes `writes` random values under `bench:<run>:<n>` keys, then
+//! reads `reads` keys back, cycling over the written ones, with at most
+//! `concurrency` operations in flight. Bench keys expire like any other
+//! value.
+
+use std::time::{Duration, Instant};
+
+use futures::{StreamExt, stream};
+use rand::{Rng, RngCore};
+use rust_p2p_node::dht::DhtNode;
+use serde::{Deserialize, Serialize};
+
+/// What to run, see `bench --help`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BenchOptions {
+    pub writes: usize,
+    pub reads: usize,
+    pub concurrency: usize,
+    pub value_size: usize,
+}
+
+/// Same as the defaults of the `bench` subcommand.
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            writes: 1000,
+            reads: 1000,
+            concurrency: 16,
+            value_size: 256,
+        }
+    }
+}
+
+/// Results of one kind of operation.
+#[derive(Debug, Serialize)]
+pub struct OpReport {
+    pub ops: usize,
+    /// Failed writes, or reads that found nothing
+    pub failures: usize,
+    #[serde(with = "humantime_serde")]
+    pub elapsed: Duration,
+    /// Operations per second
+    pub throughput: f64,
+    #[serde(with = "humantime_serde")]
+    pub p50: Duration,
+    #[serde(with = "humantime_serde")]
+    pub p90: Duration,
+    #[serde(with = "humantime_serde")]
+    pub p99: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BenchReport {
+    pub writes: OpReport,
+    pub reads: OpReport,
+}
+
+pub async fn run(node: &DhtNode, options: &BenchOptions) -> BenchReport {
+    let run_id: u32 = rand::thread_rng().r#gen();
+    let key = |index: usize| format!("bench:{:08x}:{}", run_id, index).into_bytes();
+    let concurrency = options.concurrency.max(1);
+
+    let writes = measure(options.writes, concurrency, |index| {
+        let mut value = vec![0u8; options.value_size];
+        rand::thread_rng().fill_bytes(&mut value);
+        let key = key(index);
+        async move { node.store(key, value).await.is_ok() }
+    })
+    .await;
+
+    let reads = measure(options.reads, concurrency, |index| {
+        let key = key(index % options.writes.max(1));
+        async move { node.find_value(key).await.is_some() }
+    })
+    .await;
+
+    BenchReport { writes, reads }
+}
+
+/// Runs `ops` operations, `concurrency` at a time. Each operation reports
+/// whether it succeeded.
+async fn measure<F, Fut>(ops: usize, concurrency: usize, op: F) -> OpReport
+where
+    F: Fn(usize) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let started = Instant::now();
+    let outcomes: Vec<(bool, Duration)> = stream::iter(0..ops)
+        .map(|index| {
+            let op = op(index);
+            async move {
+                let op_started = Instant::now();
+                (op.await, op_started.elapsed())
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let elapsed = started.elapsed();
+
+    let mut latencies: Vec<Duration> = outcomes.iter().map(|(_, latency)| *latency).collect();
+    latencies.sort();
+    let percentile = |p: f64| {
+        let rank = (p * latencies.len() as f64).ceil() as usize;
+        latencies
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    };
+
+    OpReport {
+        ops,
+        failures: outcomes.iter().filter(|(ok, _)| !ok).count(),
+        elapsed,
+        throughput: ops as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        p50: percentile(0.5),
+        p90: percentile(0.9),
+        p99: percentile(0.99),
+        max: latencies.last().copied().unwrap_or_default(),
+    }
+}
@@ -1,17 +1,75 @@
-use clap::{Parser, Subcommand};
-use std::net::SocketAddr;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use rust_p2p_node::dht::rpc::Consistency;
+
+use crate::{encoding::BytesFormat, logging::LogFormat};
 
 #[derive(Parser)]
 #[command(version, about)]
 pub struct Cli {
+    /// Load settings from this TOML file; flags override its values, which
+    /// override DHT_* environment variables (DHT_ADDR, DHT_PEERS, ...)
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+
     /// Address to bind this node to (e.g. 127.0.0.1:8080)
     #[arg(long, short)]
-    pub addr: SocketAddr,
+    pub addr: Option<SocketAddr>,
 
     /// Known peers to bootstrap the network (comma separated)
     #[arg(long, short)]
     pub peers: Option<String>,
 
+    /// Directory for the node's on-disk state
+    #[arg(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Control socket of the daemon (defaults to <DATA_DIR>/control.sock)
+    #[arg(long)]
+    pub control: Option<PathBuf>,
+
+    /// Serve privileged admin operations on this Unix socket
+    #[arg(long)]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Skip pushing local keys to other peers on shutdown
+    #[arg(long)]
+    pub no_hand_off: bool,
+
+    /// Serve liveness/readiness probes over HTTP on this address
+    #[arg(long)]
+    pub health_addr: Option<SocketAddr>,
+
+    /// Serve browser clients over WebSocket on this address
+    #[arg(long)]
+    pub gateway_addr: Option<SocketAddr>,
+
+    /// Push metrics to the StatsD daemon at this address
+    #[arg(long)]
+    pub statsd: Option<SocketAddr>,
+
+    /// Back storage up into this directory every `snapshots.interval`
+    #[arg(long)]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Print command results as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Log filter in RUST_LOG syntax (defaults to RUST_LOG, then "warn")
+    #[arg(long)]
+    pub log_filter: Option<String>,
+
+    /// Log output format
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Export tracing spans over OTLP (endpoint from OTEL_EXPORTER_OTLP_ENDPOINT)
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    pub otlp: bool,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -19,14 +77,165 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     /// Store a key-value pair in the DHT
-    Store { key: String, value: String },
+    Store {
+        key: String,
+        value: String,
+
+        /// How KEY is written
+        #[arg(long, value_enum, default_value_t)]
+        key_format: BytesFormat,
+
+        /// How VALUE is written
+        #[arg(long, value_enum, default_value_t)]
+        value_format: BytesFormat,
+
+        /// Seconds until the value expires, the configured default otherwise
+        #[arg(long)]
+        ttl: Option<u64>,
+
+        /// Number of peers to replicate the value on, the configured
+        /// replication factor otherwise
+        #[arg(long)]
+        replicas: Option<u32>,
+
+        /// Replicas that must acknowledge the write for it to succeed
+        #[arg(long, value_enum, default_value_t)]
+        consistency: WriteConsistency,
+    },
 
     /// Retrieve a value from the DHT
-    Get { key: String },
+    Get {
+        key: String,
+
+        /// How KEY is written
+        #[arg(long, value_enum, default_value_t)]
+        key_format: BytesFormat,
+
+        /// How to print the value; text if it is UTF-8, hex otherwise by default
+        #[arg(long, value_enum)]
+        value_format: Option<BytesFormat>,
+
+        /// Write the value's bytes to this file instead of printing it
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Write the value's bytes as is, to stdout without --output
+        #[arg(long, conflicts_with = "value_format")]
+        raw: bool,
+    },
+
+    /// Look up a key and print every hop of the lookup
+    Lookup { key: String },
+
+    /// Look up a key and print its version, expiration and replicas
+    Info { key: String },
 
     /// List all known peers in the routing table
     Peers,
 
     /// Show DHT statistics
     Stats,
+
+    /// Store the contents of a file under a key
+    PutFile { key: String, path: PathBuf },
+
+    /// Fetch the value of a key into a file
+    GetFile { key: String, path: PathBuf },
+
+    /// List the keys held by this node
+    Keys {
+        /// Only list keys starting with this prefix
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// List at most this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+
+    /// Print the node's internal state as JSON, for bug reports
+    Dump,
+
+    /// Show the log filter, or replace it (e.g. debug, rust_p2p_node=trace)
+    LogLevel { filter: Option<String> },
+
+    /// Move local values to the nodes now responsible for them, after the
+    /// replication factor or the set of nodes changed
+    Rebalance,
+
+    /// Generate store/get load and report throughput and latency
+    Bench {
+        /// Number of values to store
+        #[arg(long, default_value_t = 1000)]
+        writes: usize,
+
+        /// Number of lookups, cycling over the stored values
+        #[arg(long, default_value_t = 1000)]
+        reads: usize,
+
+        /// Operations in flight at once
+        #[arg(long, default_value_t = 16)]
+        concurrency: usize,
+
+        /// Size of each value in bytes
+        #[arg(long, default_value_t = 256)]
+        value_size: usize,
+    },
+
+    /// Ban a peer, by address or node id (prefix), and print the ban list
+    Ban {
+        target: String,
+
+        /// Lift the ban after this long (e.g. 1h, 30m); permanent otherwise
+        #[arg(long, value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+
+    /// Lift the ban on a peer and print the ban list
+    Unban { target: String },
+
+    /// Print each change to a key's local copy until interrupted
+    Watch { key: String },
+
+    /// Show a live dashboard of stats, peers, buckets and recent operations
+    Dashboard,
+
+    /// Run the node in the foreground and serve commands on the control socket
+    Daemon,
+
+    /// Upgrade the files in the data directory written by an older version,
+    /// without starting a node
+    Migrate,
+
+    /// Start NODES linked nodes in this process on consecutive ports, then
+    /// open the interactive shell on the first one
+    DevCluster {
+        #[arg(long, default_value_t = 3)]
+        nodes: usize,
+    },
+}
+
+/// How many replicas must acknowledge a write, see [`Consistency`]
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum WriteConsistency {
+    /// None; the write is kept for later when no peer is reachable
+    #[default]
+    Any,
+    /// At least one
+    One,
+    /// A majority of the replicas
+    Quorum,
+    /// Every replica
+    All,
+}
+
+impl From<WriteConsistency> for Consistency {
+    fn from(consistency: WriteConsistency) -> Self {
+        match consistency {
+            WriteConsistency::Any => Consistency::Any,
+            WriteConsistency::One => Consistency::One,
+            WriteConsistency::Quorum => Consistency::Quorum,
+            WriteConsistency::All => Consistency::All,
+        }
+    }
 }
@@ -0,0 +1,39 @@
+//! Throwaway in-process cluster for trying the node out.
+
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result, anyhow};
+use rust_p2p_node::dht::DhtNode;
+
+/// Address of the first node when none is configured.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7000";
+
+/// Starts `count - 1` nodes on the ports following `first`'s, with its
+/// configuration, and makes every node a peer of every other.
+pub async fn start(first: &DhtNode, count: usize) -> Result<Vec<DhtNode>> {
+    let mut nodes = vec![first.clone()];
+
+    for offset in 1..count {
+        let port = u16::try_from(offset)
+            .ok()
+            .and_then(|offset| first.addr.port().checked_add(offset))
+            .ok_or_else(|| anyhow!("Not enough ports after {} for {} nodes", first.addr, count))?;
+        let node = DhtNode::new(
+            SocketAddr::new(first.addr.ip(), port),
+            Some(first.config.clone()),
+        );
+        node.listen()
+            .await
+            .with_context(|| format!("Failed to start node {} on {}", offset, node.addr))?;
+        node.start_maintenance_service().await;
+        nodes.push(node);
+    }
+
+    for node in &nodes {
+        for peer in nodes.iter().filter(|peer| peer.addr != node.addr) {
+            node.add_peer(peer.peer_info());
+        }
+    }
+
+    Ok(nodes)
+}
@@ -0,0 +1,70 @@
+//! Running the node from executors other than tokio.
+//!
+//! The node is built on tokio's sockets, timers and tasks, which only work
+//! inside a tokio runtime. With the `compat` feature enabled, wrapping calls
+//! in [`OnTokio::on_tokio`] makes them usable from async-std, smol or any
+//! other executor: they are polled within a tokio runtime that the library
+//! starts on a background thread the first time it is needed, and which
+//! also runs the node's listener and maintenance tasks.
+//!
+//! Under tokio there is nothing to do; the wrapper then uses the runtime
+//! already running.
+
+use std::future::Future;
+
+use async_compat::Compat;
+
+/// Runs futures of the node within a tokio runtime, whichever executor
+/// polls them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_p2p_node::{compat::OnTokio, dht::DhtNode};
+///
+/// fn main() -> anyhow::Result<()> {
+///     futures::executor::block_on(async {
+///         let node = DhtNode::new("127.0.0.1:8080".parse()?, None);
+///         node.listen().on_tokio().await?;
+///         node.start_maintenance_service().on_tokio().await;
+///
+///         node.store(b"key".to_vec(), b"value".to_vec())
+///             .on_tokio()
+///             .await
+///     })
+/// }
+/// ```
+pub trait OnTokio: Future + Sized {
+    fn on_tokio(self) -> Compat<Self> {
+        Compat::new(self)
+    }
+}
+
+impl<F: Future> OnTokio for F {}
+
+#[cfg(test)]
+mod compat_tests {
+    use bytes::Bytes;
+
+    use super::OnTokio;
+    use crate::helpers::create_test_node;
+
+    #[test]
+    fn test_node_runs_outside_tokio() {
+        futures::executor::block_on(async {
+            let node = create_test_node(8206);
+            let server = node.listen().on_tokio().await.unwrap();
+
+            node.store(b"key".to_vec(), b"value".to_vec())
+                .on_tokio()
+                .await
+                .unwrap();
+            assert_eq!(
+                node.find_value(b"key".to_vec()).on_tokio().await,
+                Some(Bytes::from_static(b"value"))
+            );
+
+            server.abort();
+        });
+    }
+}
@@ -0,0 +1,241 @@
+//! Local control socket between a daemon and its command line clients.
+//!
+//! Clients connect to the daemon's Unix socket and exchange one JSON object
+//! per line: a [`ControlRequest`] from the client, answered by a
+//! [`ControlResponse`] from the daemon. A connection may carry any number of
+//! requests. The socket is created readable and writable by its owner only,
+//! see [`bind_private`].
+
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::pin,
+};
+
+use anyhow::{Context, Result, anyhow};
+use futures::{Stream, StreamExt, stream};
+use rust_p2p_node::dht::tasks;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        UnixListener, UnixStream,
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    task::JoinHandle,
+};
+use tracing::{debug, warn};
+
+use crate::app::{AppCommand, AppHandle, CommandFailed};
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ControlRequest {
+    /// Runs a command and answers with its output
+    Execute {
+        command: AppCommand,
+        /// Render the output as JSON instead of text
+        json: bool,
+    },
+    /// Answers with one output per change to `key`, for as long as the
+    /// client stays connected
+    Watch { key: String, json: bool },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ControlResponse {
+    Output(String),
+    Error(String),
+    /// Output of a command that failed, see [`CommandFailed`]
+    Failed(String),
+}
+
+/// Binds a Unix socket at `path` that only its owner can connect to.
+///
+/// Setting the permissions after binding would leave the socket open to
+/// anyone the umask allows in the meantime. It is bound in a fresh
+/// directory only the owner can enter instead, restricted there, then moved
+/// to `path`.
+pub fn bind_private(path: &Path) -> Result<UnixListener> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?;
+    let staging = parent.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    // Left over by a process that had the same id
+    let _ = fs::remove_dir_all(&staging);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .with_context(|| format!("Failed to create {}", staging.display()))?;
+
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+/// Serves commands for `app` on the Unix socket at `path` until the returned
+/// task is aborted.
+///
+/// A leftover socket file from a previous daemon is replaced, but one that
+/// still has a daemon listening on it is not.
+pub async fn serve(path: &Path, app: AppHandle) -> Result<JoinHandle<()>> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow!(
+                "A daemon is already listening on {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+
+    let listener = bind_private(path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+
+    Ok(tasks::spawn("control-listener", async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let app = app.clone();
+                    tasks::spawn("control-connection", async move {
+                        if let Err(e) = handle_client(stream, app).await {
+                            debug!(error = %e, "control connection failed");
+                        }
+                    });
+                }
+                Err(e) => warn!(error = %e, "failed to accept control connection"),
+            }
+        }
+    }))
+}
+
+async fn handle_client(stream: UnixStream, app: AppHandle) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(ControlRequest::Execute { command, json }) => {
+                match app.execute(command, json).await {
+                    Ok(output) => ControlResponse::Output(output),
+                    Err(e) => match e.downcast::<CommandFailed>() {
+                        Ok(CommandFailed(output)) => ControlResponse::Failed(output),
+                        Err(e) => ControlResponse::Error(format!("{:#}", e)),
+                    },
+                }
+            }
+            Ok(ControlRequest::Watch { key, json }) => {
+                let mut changes = pin!(app.watch(key, json));
+                loop {
+                    tokio::select! {
+                        Some(change) = changes.next() => {
+                            respond(&mut writer, ControlResponse::Output(change)).await?;
+                        }
+                        // The watch lasts until the client goes away
+                        _ = lines.next_line() => return Ok(()),
+                    }
+                }
+            }
+            Err(e) => ControlResponse::Error(format!("Invalid request: {}", e)),
+        };
+
+        respond(&mut writer, response).await?;
+    }
+
+    Ok(())
+}
+
+async fn respond(writer: &mut OwnedWriteHalf, response: ControlResponse) -> Result<()> {
+    let mut response = serde_json::to_string(&response)?;
+    response.push('\n');
+    writer.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Connection to a running daemon.
+pub struct ControlClient {
+    path: PathBuf,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl ControlClient {
+    /// Connects to the daemon listening on `path`.
+    pub async fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("No daemon listening on {}", path.display()))?;
+        let (reader, writer) = stream.into_split();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines: BufReader::new(reader).lines(),
+            writer,
+        })
+    }
+
+    /// Runs `command` on the daemon and returns its output.
+    pub async fn execute(&mut self, command: AppCommand, json: bool) -> Result<String> {
+        self.send(ControlRequest::Execute { command, json }).await?;
+        match self.receive().await? {
+            Some(output) => Ok(output),
+            None => Err(anyhow!(
+                "The daemon on {} closed the connection",
+                self.path.display()
+            )),
+        }
+    }
+
+    /// Follows `key` on the daemon, yielding one output per change until the
+    /// daemon goes away.
+    pub async fn watch(
+        mut self,
+        key: String,
+        json: bool,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        self.send(ControlRequest::Watch { key, json }).await?;
+        Ok(stream::unfold(self, |mut client| async move {
+            client
+                .receive()
+                .await
+                .transpose()
+                .map(|output| (output, client))
+        }))
+    }
+
+    async fn send(&mut self, request: ControlRequest) -> Result<()> {
+        let mut request = serde_json::to_string(&request)?;
+        request.push('\n');
+        self.writer.write_all(request.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Reads the next output, `None` once the daemon closed the connection.
+    async fn receive(&mut self) -> Result<Option<String>> {
+        let Some(line) = self.lines.next_line().await? else {
+            return Ok(None);
+        };
+
+        match serde_json::from_str(&line).context("Invalid response from the daemon")? {
+            ControlResponse::Output(output) => Ok(Some(output)),
+            ControlResponse::Error(error) => Err(anyhow!(error)),
+            ControlResponse::Failed(output) => Err(CommandFailed(output).into()),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Live terminal dashboard.
+//!
+//! [`run`] redraws a [`DashboardSnapshot`] of the node every [`REFRESH`]
+//! until `q`, Esc or Ctrl-C is pressed. Snapshots come from the app, either
+//! in process or through a daemon's control socket.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, Paragraph, Row, Table},
+};
+use rust_p2p_node::dht::metrics::{DhtStats, PeerStats};
+use serde::{Deserialize, Serialize};
+
+/// How often the dashboard fetches a new snapshot.
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Width of the bucket fill bars, in cells.
+const BAR_WIDTH: usize = 20;
+
+/// Everything the dashboard shows.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DashboardSnapshot {
+    pub addr: SocketAddr,
+    pub stats: DhtStats,
+    pub peers: Vec<PeerRow>,
+    /// Non-empty k-buckets, by index
+    pub buckets: Vec<BucketFill>,
+    /// Latest node events, newest first
+    pub recent: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerRow {
+    pub addr: SocketAddr,
+    pub id: String,
+    /// Seconds since the peer was last seen
+    pub idle: u64,
+    pub stats: Option<PeerStats>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BucketFill {
+    pub index: u8,
+    pub peers: usize,
+    pub capacity: usize,
+}
+
+/// Shows snapshots from `fetch` until the user quits.
+pub async fn run(mut fetch: impl AsyncFnMut() -> Result<DashboardSnapshot>) -> Result<()> {
+    let mut terminal = ratatui::init();
+
+    let result = async {
+        loop {
+            let snapshot = fetch().await?;
+            terminal.draw(|frame| draw(frame, &snapshot))?;
+
+            if tokio::task::block_in_place(|| quit_requested(REFRESH))? {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+
+    ratatui::restore();
+    result
+}
+
+/// Waits up to `timeout` for a key press asking to quit.
+fn quit_requested(timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        if !event::poll(left)? {
+            break;
+        }
+        if let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(true),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(true);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+fn draw(frame: &mut Frame, snapshot: &DashboardSnapshot) {
+    let [top, peers, recent, footer] = Layout::vertical([
+        Constraint::Length(10),
+        Constraint::Min(5),
+        Constraint::Length(12),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [stats, buckets] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+
+    frame.render_widget(stats_panel(snapshot), stats);
+    frame.render_widget(buckets_panel(snapshot), buckets);
+    frame.render_widget(peers_panel(snapshot), peers);
+    frame.render_widget(
+        List::new(snapshot.recent.iter().map(String::as_str))
+            .block(Block::bordered().title(" Recent operations ")),
+        recent,
+    );
+    frame.render_widget(
+        Line::from(format!(
+            " {} · refreshing every {:?} · q to quit",
+            snapshot.addr, REFRESH
+        ))
+        .dim(),
+        footer,
+    );
+}
+
+fn stats_panel(snapshot: &DashboardSnapshot) -> Paragraph<'_> {
+    let stats = &snapshot.stats;
+    let lines = vec![
+        Line::from(format!(
+            "Stores:   {} ({} ok), {:.2}/s",
+            stats.store_ops, stats.store_success, stats.rates.store_ops_1m
+        )),
+        Line::from(format!(
+            "Finds:    {} ({} ok, {} cached), {:.2}/s",
+            stats.find_value_ops,
+            stats.find_value_success,
+            stats.lookup_cache_hits,
+            stats.rates.find_value_ops_1m
+        )),
+        Line::from(format!(
+            "RPCs:     {} ({} failed, {} shed, {} retried), {:.2}/s",
+            stats.rpc_requests,
+            stats.rpc_failures,
+            stats.rpcs_shed,
+            stats.requests_deduplicated,
+            stats.rates.rpc_requests_1m
+        )),
+        Line::from(format!(
+            "Peers:    {} known, {} evicted, {}/{} checks failed",
+            stats.known_peers,
+            stats.peers_evicted,
+            stats.health_check_failures,
+            stats.health_checks
+        )),
+        Line::from(format!(
+            "Storage:  {} keys, {} bytes, {} buffered",
+            stats.storage_size, stats.storage_bytes, stats.outbox_size
+        )),
+        Line::from(format!(
+            "Replicas: {}/{} replications ok",
+            stats.replication_successes, stats.replication_attempts
+        )),
+        Line::from(format!(
+            "Dropped:  {} expired, {} evicted",
+            stats.expired_entries, stats.storage_evictions
+        )),
+        Line::from(format!(
+            "Hot keys: {} shed{}",
+            stats.hot_key_requests_shed,
+            stats
+                .hot_keys
+                .first()
+                .map(|hot| format!(
+                    ", top {} ({})",
+                    String::from_utf8_lossy(&hot.key),
+                    hot.requests
+                ))
+                .unwrap_or_default()
+        )),
+    ];
+
+    Paragraph::new(lines).block(Block::bordered().title(" Stats (rates over 1m) "))
+}
+
+fn buckets_panel(snapshot: &DashboardSnapshot) -> Paragraph<'_> {
+    let lines: Vec<Line> = snapshot
+        .buckets
+        .iter()
+        .map(|bucket| {
+            let filled = (bucket.peers * BAR_WIDTH)
+                .div_ceil(bucket.capacity.max(1))
+                .min(BAR_WIDTH);
+            Line::from(format!(
+                "{:>3} {}{} {}/{}",
+                bucket.index,
+                "█".repeat(filled),
+                "░".repeat(BAR_WIDTH - filled),
+                bucket.peers,
+                bucket.capacity
+            ))
+        })
+        .collect();
+
+    Paragraph::new(lines).block(Block::bordered().title(" Buckets "))
+}
+
+fn peers_panel(snapshot: &DashboardSnapshot) -> Table<'_> {
+    let rows = snapshot.peers.iter().map(|peer| {
+        let stats = peer.stats.clone().unwrap_or_default();
+        Row::new(vec![
+            peer.addr.to_string(),
+            peer.id.chars().take(12).collect(),
+            stats
+                .last_rtt
+                .map(|rtt| format!("{:.1?}", rtt))
+                .unwrap_or_else(|| "-".to_string()),
+            stats.requests.to_string(),
+            stats.failures.to_string(),
+            format!("{}s ago", peer.idle),
+        ])
+    });
+
+    Table::new(
+        rows,
+        [
+            Constraint::Length(22),
+            Constraint::Length(13),
+            Constraint::Length(10),
+            Constraint::Length(9),
+            Constraint::Length(9),
+            Constraint::Length(10),
+        ],
+    )
+    .header(
+        Row::new(["Address", "ID", "RTT", "Requests", "Failures", "Last seen"])
+            .style(Style::new().bold()),
+    )
+    .block(Block::bordered().title(format!(" Peers ({}) ", snapshot.peers.len())))
+}
@@ -0,0 +1,102 @@
+//! Privileged operations for node operators.
+//!
+//! These are never reachable over the DHT protocol; the binary exposes them
+//! on a local admin socket only.
+
+use std::{net::SocketAddr, time::Duration};
+
+use serde::Serialize;
+
+use crate::dht::{DhtNode, events::DhtEvent, node::NodeId};
+
+/// An entry of the ban list.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ban {
+    pub addr: SocketAddr,
+    /// Id of the node at `addr`, if it was in the routing table when banned
+    pub id: Option<NodeId>,
+    /// Unix time the ban ends, `None` if it is permanent
+    pub until: Option<u64>,
+}
+
+impl DhtNode {
+    /// Bans the peer at `addr` for `duration`, or for good without one: it
+    /// is dropped from the routing table, never re-added and never sent RPCs
+    /// again until the ban ends or [`DhtNode::unban_peer`] lifts it.
+    ///
+    /// Returns whether the peer was in the routing table.
+    pub fn ban_peer(&self, addr: SocketAddr, duration: Option<Duration>) -> bool {
+        let id = self
+            .routing_table
+            .iter()
+            .find_map(|bucket| {
+                bucket
+                    .peers
+                    .iter()
+                    .find(|peer| peer.addr == addr)
+                    .map(|peer| peer.id.clone())
+            })
+            .or_else(|| self.banned.get(&addr).and_then(|ban| ban.id.clone()));
+        let until = duration.map(|duration| self.now() + duration.as_secs());
+        self.banned.insert(addr, Ban { addr, id, until });
+
+        let mut removed = Vec::new();
+        for mut bucket in self.routing_table.iter_mut() {
+            bucket.value_mut().peers.retain(|peer| {
+                let banned = peer.addr == addr;
+                if banned {
+                    removed.push(peer.clone());
+                }
+                !banned
+            });
+        }
+
+        let known = !removed.is_empty();
+        for peer in removed {
+            self.emit(|| DhtEvent::PeerEvicted(peer));
+        }
+        self.connection_pool.forget_peer(addr);
+        known
+    }
+
+    /// Lifts a ban. Returns whether the peer was banned.
+    pub fn unban_peer(&self, addr: SocketAddr) -> bool {
+        self.banned.remove(&addr).is_some()
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        match self.banned.get(addr).map(|ban| ban.until) {
+            None => false,
+            Some(None) => true,
+            Some(Some(until)) if until > self.now() => true,
+            Some(Some(_)) => {
+                self.banned.remove_if(addr, |_, ban| {
+                    ban.until.is_some_and(|until| until <= self.now())
+                });
+                false
+            }
+        }
+    }
+
+    /// Current bans, in address order.
+    pub fn banned_peers(&self) -> Vec<Ban> {
+        let current_time = self.now();
+        self.banned
+            .retain(|_, ban| ban.until.is_none_or(|until| until > current_time));
+
+        let mut bans: Vec<Ban> = self.banned.iter().map(|ban| ban.clone()).collect();
+        bans.sort_by_key(|ban| ban.addr);
+        bans
+    }
+
+    /// Removes `key` from local storage and the outbox without telling its
+    /// replicas. Returns whether anything was removed.
+    pub fn drop_key(&self, key: &[u8]) -> bool {
+        let stored = self.storage.remove(key).is_some();
+        if stored {
+            self.emit(|| DhtEvent::ValueRemoved { key: key.to_vec() });
+        }
+        let buffered = self.outbox.remove(key).is_some();
+        stored || buffered
+    }
+}
@@ -0,0 +1,292 @@
+//! Authenticating the traffic of a private cluster.
+//!
+//! With `cluster_secret` set in [`DhtConfig`](crate::dht::config::DhtConfig),
+//! a node appends an HMAC-SHA256 tag to every RPC it sends and answers, and
+//! drops those whose tag does not match: a node that does not know the
+//! secret can neither join the cluster nor inject values into it.
+//!
+//! With an `allowlist` of node ids set as well, or instead, the cluster only
+//! admits known members: every frame is signed by its sender's
+//! [`Identity`], and a node drops frames whose signer's id is not on the
+//! list, as well as announcements of peers that are not.
+//!
+//! Frames are authenticated, not encrypted, and a captured frame can be
+//! replayed. Clients need the secret too, see
+//! [`DhtClient::with_secret`](crate::dht::client::DhtClient::with_secret),
+//! and an allowed identity, see
+//! [`DhtClient::with_identity`](crate::dht::client::DhtClient::with_identity);
+//! the WebSocket gateway, meant for browsers, stays open.
+
+use std::fmt;
+
+use anyhow::{Result, anyhow, bail};
+use ed25519_dalek::{SIGNATURE_LENGTH, Signature, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::debug;
+
+use crate::dht::{DhtNode, identity::Identity, peer::PeerInfo};
+
+/// Length of the tag appended to each frame.
+pub const TAG_LEN: usize = 32;
+
+/// Length of the signer's public key and signature appended to each frame
+/// in allowlist mode.
+pub const SIGNATURE_BLOCK_LEN: usize = 32 + SIGNATURE_LENGTH;
+
+/// Secret shared by the nodes of a private cluster.
+///
+/// Its `Debug` form does not reveal it.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct ClusterSecret(String);
+
+impl ClusterSecret {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::new_from_slice(self.0.as_bytes()).expect("HMAC accepts keys of any length")
+    }
+
+    /// Appends the tag of `payload`.
+    pub fn seal(&self, mut payload: Vec<u8>) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(&payload);
+        payload.extend_from_slice(&mac.finalize().into_bytes());
+        payload
+    }
+
+    /// Checks and strips the tag added by [`ClusterSecret::seal`].
+    pub fn open(&self, mut frame: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(payload_len) = frame.len().checked_sub(TAG_LEN) else {
+            return Err(anyhow!("Frame too short to be authenticated"));
+        };
+
+        let mut mac = self.mac();
+        mac.update(&frame[..payload_len]);
+        mac.verify_slice(&frame[payload_len..])
+            .map_err(|_| anyhow!("Frame failed authentication"))?;
+        frame.truncate(payload_len);
+        Ok(frame)
+    }
+}
+
+impl fmt::Debug for ClusterSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClusterSecret(..)")
+    }
+}
+
+fn frame_message(payload: &[u8]) -> Vec<u8> {
+    [b"frame/1".as_slice(), payload].concat()
+}
+
+/// Appends `identity`'s public key and signature of `payload`.
+pub fn sign_frame(identity: &Identity, mut payload: Vec<u8>) -> Vec<u8> {
+    let signature = identity.sign(&frame_message(&payload));
+    payload.extend_from_slice(identity.public_key().as_bytes());
+    payload.extend_from_slice(&signature.to_bytes());
+    payload
+}
+
+/// Checks and strips the signature added by [`sign_frame`], returning the
+/// payload and the key it was signed with.
+pub fn open_signed_frame(mut frame: Vec<u8>) -> Result<(Vec<u8>, VerifyingKey)> {
+    let Some(payload_len) = frame.len().checked_sub(SIGNATURE_BLOCK_LEN) else {
+        bail!("Frame too short to be signed");
+    };
+
+    let (key, signature) = frame[payload_len..].split_at(32);
+    let key = VerifyingKey::try_from(key).map_err(|_| anyhow!("Frame signed by an invalid key"))?;
+    let signature = Signature::from_slice(signature)?;
+    key.verify(&frame_message(&frame[..payload_len]), &signature)
+        .map_err(|_| anyhow!("Frame failed signature check"))?;
+    frame.truncate(payload_len);
+    Ok((frame, key))
+}
+
+impl DhtNode {
+    /// Signs and tags an outgoing frame, as the cluster's allowlist and
+    /// secret require.
+    pub(super) fn seal_frame(&self, payload: Vec<u8>) -> Vec<u8> {
+        let payload = match &self.config.allowlist {
+            Some(_) => sign_frame(&self.identity, payload),
+            None => payload,
+        };
+        match &self.config.cluster_secret {
+            Some(secret) => secret.seal(payload),
+            None => payload,
+        }
+    }
+
+    /// Checks and strips the tag and signature of an incoming frame, as
+    /// the cluster's secret and allowlist require.
+    pub(super) fn open_frame(&self, frame: Vec<u8>) -> Result<Vec<u8>> {
+        let frame = match &self.config.cluster_secret {
+            Some(secret) => secret.open(frame)?,
+            None => frame,
+        };
+        let Some(allowlist) = &self.config.allowlist else {
+            return Ok(frame);
+        };
+
+        let (payload, key) = open_signed_frame(frame)?;
+        let signer = Identity::id_of(&key, self.config.id_hash);
+        if !allowlist.contains(&signer) {
+            bail!("Frame signed by {}, which is not allowed", signer);
+        }
+        Ok(payload)
+    }
+
+    /// Whether `peer` may enter the routing table: with an allowlist set,
+    /// it must be on it and prove it holds the key of its id.
+    pub(super) fn is_allowed(&self, peer: &PeerInfo) -> bool {
+        let Some(allowlist) = &self.config.allowlist else {
+            return true;
+        };
+        let allowed = allowlist.contains(&peer.id) && peer.verify(self.config.id_hash);
+        if !allowed {
+            debug!(peer = %peer.addr, id = %peer.id, "ignoring peer off the allowlist");
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use bytes::Bytes;
+
+    use super::{ClusterSecret, open_signed_frame, sign_frame};
+    use crate::{
+        dht::{
+            DhtNode, PeerInfo, client::DhtClient, config::DhtConfig, identity::Identity,
+            rpc::DhtRpc,
+        },
+        helpers::test_config,
+    };
+
+    fn private_node(port: u16, secret: Option<&str>) -> DhtNode {
+        let config = DhtConfig {
+            cluster_secret: secret.map(ClusterSecret::new),
+            ..test_config()
+        };
+        DhtNode::new(format!("127.0.0.1:{}", port).parse().unwrap(), Some(config))
+    }
+
+    #[test]
+    fn test_sealed_frames_open_with_the_same_secret_only() {
+        let secret = ClusterSecret::new("secret");
+        let frame = secret.seal(b"payload".to_vec());
+
+        assert_eq!(secret.open(frame.clone()).unwrap(), b"payload");
+        assert!(ClusterSecret::new("other").open(frame.clone()).is_err());
+        assert!(secret.open(frame[1..].to_vec()).is_err());
+        assert!(secret.open(b"short".to_vec()).is_err());
+        assert_eq!(format!("{:?}", secret), "ClusterSecret(..)");
+    }
+
+    #[tokio::test]
+    async fn test_private_cluster_rejects_outsiders() {
+        let node = private_node(8215, Some("secret"));
+        let server = node.listen().await.unwrap();
+
+        let member = private_node(8216, Some("secret"));
+        assert!(matches!(
+            member.send_rpc(node.addr, DhtRpc::Ping).await,
+            Ok(DhtRpc::Pong)
+        ));
+
+        for outsider in [private_node(8217, None), private_node(8218, Some("guess"))] {
+            let store = DhtRpc::Store(b"key".to_vec(), Bytes::from_static(b"forged"));
+            assert!(outsider.send_rpc(node.addr, store).await.is_err());
+        }
+        assert!(node.local_value(b"key").is_none());
+
+        let client = DhtClient::new(node.addr);
+        assert!(client.ping().await.is_err());
+        client
+            .with_secret(ClusterSecret::new("secret"))
+            .ping()
+            .await
+            .unwrap();
+
+        server.abort();
+    }
+
+    #[test]
+    fn test_signed_frames_name_their_signer() {
+        let identity = Identity::from_secret_bytes(&[1; 32]);
+        let frame = sign_frame(&identity, b"payload".to_vec());
+
+        let (payload, key) = open_signed_frame(frame.clone()).unwrap();
+        assert_eq!(payload, b"payload");
+        assert_eq!(key, identity.public_key());
+
+        let mut tampered = frame.clone();
+        tampered[0] ^= 1;
+        assert!(open_signed_frame(tampered).is_err());
+        assert!(open_signed_frame(b"short".to_vec()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_keeps_strangers_out() {
+        let identities: Vec<_> = (1..=3)
+            .map(|i| Identity::from_secret_bytes(&[i; 32]))
+            .collect();
+        let config = DhtConfig {
+            allowlist: Some(
+                identities[..2]
+                    .iter()
+                    .map(|i| i.node_id(Default::default()))
+                    .collect(),
+            ),
+            ..test_config()
+        };
+        let nodes: Vec<_> = identities
+            .iter()
+            .zip(8226..)
+            .map(|(identity, port)| {
+                DhtNode::new(
+                    format!("127.0.0.1:{}", port).parse().unwrap(),
+                    Some(config.clone()),
+                )
+                .with_identity(identity.clone())
+            })
+            .collect();
+        let (node, member, stranger) = (&nodes[0], &nodes[1], &nodes[2]);
+        let server = node.listen().await.unwrap();
+
+        assert!(matches!(
+            member.send_rpc(node.addr, DhtRpc::Ping).await,
+            Ok(DhtRpc::Pong)
+        ));
+        assert!(stranger.send_rpc(node.addr, DhtRpc::Ping).await.is_err());
+
+        node.add_peer(stranger.peer_info());
+        node.add_peer(PeerInfo::new(member.id.clone(), member.addr));
+        assert_eq!(node.routing_table.iter().map(|b| b.len()).sum::<usize>(), 0);
+        node.add_peer(member.peer_info());
+        assert_eq!(node.routing_table.iter().map(|b| b.len()).sum::<usize>(), 1);
+
+        let client = DhtClient::new(node.addr);
+        assert!(client.ping().await.is_err());
+        assert!(
+            client
+                .clone()
+                .with_identity(identities[2].clone())
+                .ping()
+                .await
+                .is_err()
+        );
+        client
+            .with_identity(identities[1].clone())
+            .ping()
+            .await
+            .unwrap();
+
+        server.abort();
+    }
+}
@@ -0,0 +1,309 @@
+//! Authorizing writes.
+//!
+//! On a DHT used as shared infrastructure, a [`WriteAuthorizer`] decides
+//! which writes a node applies. Every stored value carries a capability
+//! token over its key, which the nodes it is replicated on check before
+//! storing it, and `Expire` notices carry the token of the expired value.
+//! Tokens travel with the value, so a replica can re-replicate it without
+//! being allowed to write anything itself.
+//!
+//! [`AllowAll`] is used unless another authorizer is set with
+//! [`DhtNode::with_write_authorizer`](crate::dht::DhtNode::with_write_authorizer).
+//! The node's own writes carry the token the authorizer
+//! [issues](WriteAuthorizer::issue), or one given to
+//! [`DhtNode::store_with_token`](crate::dht::DhtNode::store_with_token).
+//! Writes it coordinates for clients, `ClientStore` and `CoordinatedStore`
+//! requests, carry the client's token instead and never one the node issues,
+//! so a client can only write what its own token allows.
+
+use std::fmt;
+
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::dht::identity::Identity;
+
+/// Decides which writes a node applies.
+///
+/// # Examples
+///
+/// ```
+/// use rust_p2p_node::dht::authz::WriteAuthorizer;
+///
+/// /// Lets anyone write under `public/`, and nobody anywhere else.
+/// struct PublicOnly;
+///
+/// impl WriteAuthorizer for PublicOnly {
+///     fn authorize(&self, key: &[u8], _token: Option<&[u8]>) -> bool {
+///         key.starts_with(b"public/")
+///     }
+/// }
+/// ```
+pub trait WriteAuthorizer: Send + Sync {
+    /// Token for this node's own writes to `key`, if it may mint one.
+    fn issue(&self, _key: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Whether a write to `key` carrying `token` may be applied.
+    fn authorize(&self, key: &[u8], token: Option<&[u8]>) -> bool;
+}
+
+/// Applies every write, tokens or not.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AllowAll;
+
+impl WriteAuthorizer for AllowAll {
+    fn authorize(&self, _key: &[u8], _token: Option<&[u8]>) -> bool {
+        true
+    }
+}
+
+/// Tokens are HMAC-SHA256 tags of the key under a secret shared by the
+/// nodes, which can all mint them.
+///
+/// Its `Debug` form does not reveal the secret.
+#[derive(Clone)]
+pub struct HmacTokens {
+    secret: Vec<u8>,
+}
+
+impl HmacTokens {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    fn mac(&self, key: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(b"write/1");
+        mac.update(key);
+        mac
+    }
+
+    /// The token allowing writes to `key`.
+    pub fn token(&self, key: &[u8]) -> Vec<u8> {
+        self.mac(key).finalize().into_bytes().to_vec()
+    }
+}
+
+impl WriteAuthorizer for HmacTokens {
+    fn issue(&self, key: &[u8]) -> Option<Vec<u8>> {
+        Some(self.token(key))
+    }
+
+    fn authorize(&self, key: &[u8], token: Option<&[u8]>) -> bool {
+        token.is_some_and(|token| self.mac(key).verify_slice(token).is_ok())
+    }
+}
+
+impl fmt::Debug for HmacTokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HmacTokens").finish_non_exhaustive()
+    }
+}
+
+/// Tokens are signatures of the key by an issuer, so that nodes can check
+/// them without being able to mint any.
+///
+/// The issuer hands out tokens made with [`SignedTokens::token`]; a node
+/// running as the issuer, see [`SignedTokens::issuing`], also signs its own
+/// writes.
+#[derive(Debug, Clone)]
+pub struct SignedTokens {
+    issuer: VerifyingKey,
+    signer: Option<Identity>,
+}
+
+impl SignedTokens {
+    /// Accepts the tokens signed by `issuer`.
+    pub fn new(issuer: VerifyingKey) -> Self {
+        Self {
+            issuer,
+            signer: None,
+        }
+    }
+
+    /// Accepts the tokens signed by `identity`, and signs this node's
+    /// writes with it.
+    pub fn issuing(identity: Identity) -> Self {
+        Self {
+            issuer: identity.public_key(),
+            signer: Some(identity),
+        }
+    }
+
+    /// The token allowing writes to `key`, signed by `issuer`.
+    pub fn token(issuer: &Identity, key: &[u8]) -> Vec<u8> {
+        issuer.sign(&signed_bytes(key)).to_vec()
+    }
+}
+
+fn signed_bytes(key: &[u8]) -> Vec<u8> {
+    [b"write/1".as_slice(), key].concat()
+}
+
+impl WriteAuthorizer for SignedTokens {
+    fn issue(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.signer.as_ref().map(|signer| Self::token(signer, key))
+    }
+
+    fn authorize(&self, key: &[u8], token: Option<&[u8]>) -> bool {
+        token
+            .and_then(|token| Signature::from_slice(token).ok())
+            .is_some_and(|signature| self.issuer.verify(&signed_bytes(key), &signature).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod authz_tests {
+    use bytes::Bytes;
+
+    use crate::{
+        dht::{
+            authz::{HmacTokens, SignedTokens, WriteAuthorizer},
+            clock::ManualClock,
+            identity::Identity,
+            rpc::{DhtRpc, WriteRequest},
+            storage::{create_stored_value, serialize_value},
+        },
+        helpers::create_test_node,
+    };
+
+    #[test]
+    fn test_hmac_tokens_cover_one_key() {
+        let tokens = HmacTokens::new("secret");
+        let token = tokens.token(b"key");
+
+        assert!(tokens.authorize(b"key", Some(&token)));
+        assert!(!tokens.authorize(b"other", Some(&token)));
+        assert!(!tokens.authorize(b"key", None));
+        assert!(!HmacTokens::new("guess").authorize(b"key", Some(&token)));
+        assert_eq!(tokens.issue(b"key"), Some(token));
+    }
+
+    #[test]
+    fn test_signed_tokens_need_the_issuer() {
+        let issuer = Identity::from_secret_bytes(&[1; 32]);
+        let verifier = SignedTokens::new(issuer.public_key());
+        let token = SignedTokens::token(&issuer, b"key");
+
+        assert!(verifier.authorize(b"key", Some(&token)));
+        assert!(!verifier.authorize(b"other", Some(&token)));
+        assert!(!verifier.authorize(b"key", Some(b"not a signature")));
+        assert_eq!(verifier.issue(b"key"), None);
+
+        let forged = SignedTokens::token(&Identity::from_secret_bytes(&[2; 32]), b"key");
+        assert!(!verifier.authorize(b"key", Some(&forged)));
+        assert_eq!(SignedTokens::issuing(issuer).issue(b"key"), Some(token));
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_writes_are_rejected() {
+        let tokens = HmacTokens::new("secret");
+        let node = create_test_node(8080).with_write_authorizer(tokens.clone());
+        let key = b"key".to_vec();
+
+        let mut stored =
+            create_stored_value(b"forged".to_vec(), node.addr, false, None, node.now());
+        node.handle_rpc(DhtRpc::Store(
+            key.clone(),
+            serialize_value(&stored).unwrap(),
+        ))
+        .await;
+        assert!(node.storage.get(&key).is_none());
+
+        stored.data = Bytes::from_static(b"genuine");
+        stored.token = Some(tokens.token(&key));
+        node.handle_rpc(DhtRpc::Store(
+            key.clone(),
+            serialize_value(&stored).unwrap(),
+        ))
+        .await;
+        assert!(node.storage.get(&key).is_some());
+
+        node.handle_rpc(DhtRpc::Expire(key.clone(), u64::MAX, None, None))
+            .await;
+        assert!(node.storage.get(&key).is_some());
+        assert_eq!(node.get_stats().rpc_failures, 2);
+
+        node.handle_rpc(DhtRpc::Expire(
+            key.clone(),
+            u64::MAX,
+            Some(tokens.token(&key)),
+            None,
+        ))
+        .await;
+        assert!(node.storage.get(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_nodes_write_with_their_tokens() {
+        let issuer = Identity::from_secret_bytes(&[1; 32]);
+        let node = create_test_node(8080)
+            .with_clock(ManualClock::new(1_000))
+            .with_write_authorizer(SignedTokens::new(issuer.public_key()));
+
+        assert!(
+            node.store(b"key".to_vec(), b"value".to_vec())
+                .await
+                .is_err()
+        );
+        assert!(node.storage.get(b"key").is_none());
+
+        let token = SignedTokens::token(&issuer, b"key");
+        node.store_with_token(b"key".to_vec(), b"value".to_vec(), token)
+            .await
+            .unwrap();
+        assert_eq!(
+            node.find_value(b"key".to_vec()).await,
+            Some(Bytes::from_static(b"value"))
+        );
+
+        let issuing = create_test_node(8081).with_write_authorizer(SignedTokens::issuing(issuer));
+        issuing
+            .store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_writes_need_their_own_token() {
+        // The node may mint tokens, but not lend them to its clients
+        let tokens = HmacTokens::new("secret");
+        let node = create_test_node(8080).with_write_authorizer(tokens.clone());
+        let value = Bytes::from_static(b"value");
+
+        let store = DhtRpc::ClientStore(b"key".to_vec(), value.clone(), None);
+        assert!(matches!(
+            node.handle_rpc(store).await,
+            DhtRpc::ClientStoreResponse(Err(_))
+        ));
+        let coordinated =
+            DhtRpc::CoordinatedStore(b"key".to_vec(), value.clone(), WriteRequest::default());
+        assert!(matches!(
+            node.handle_rpc(coordinated).await,
+            DhtRpc::CoordinatedStoreResponse(Err(_))
+        ));
+        assert!(node.storage.get(b"key").is_none());
+
+        let store = DhtRpc::ClientStore(b"key".to_vec(), value.clone(), Some(tokens.token(b"key")));
+        assert!(matches!(
+            node.handle_rpc(store).await,
+            DhtRpc::ClientStoreResponse(Ok(_))
+        ));
+        let request = WriteRequest {
+            token: Some(tokens.token(b"other")),
+            ..WriteRequest::default()
+        };
+        let coordinated = DhtRpc::CoordinatedStore(b"other".to_vec(), value, request);
+        assert!(matches!(
+            node.handle_rpc(coordinated).await,
+            DhtRpc::CoordinatedStoreResponse(Ok(_))
+        ));
+        assert!(node.storage.get(b"key").is_some() && node.storage.get(b"other").is_some());
+    }
+}
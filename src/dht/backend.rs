@@ -0,0 +1,323 @@
+//! Pluggable storage for the values a node holds.
+//!
+//! A node keeps its values, encoded [`StoredValue`]s, in a [`Storage`].
+//! [`MemoryStorage`] is used unless another backend is injected with
+//! [`DhtNode::with_storage`](crate::dht::DhtNode::with_storage), such as an
+//! embedder's existing database or an instrumented store in tests.
+//!
+//! [`StoredValue`]: crate::dht::storage::StoredValue
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+/// Key-value store backing a node.
+///
+/// Values are opaque to the backend. Every method may be called from
+/// several tasks at once; a backend only needs to make each call atomic.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use bytes::Bytes;
+/// use rust_p2p_node::dht::backend::{MemoryStorage, Storage};
+///
+/// /// Counts the writes reaching an in-memory store.
+/// #[derive(Default)]
+/// struct CountingStorage {
+///     inner: MemoryStorage,
+///     writes: AtomicUsize,
+/// }
+///
+/// impl Storage for CountingStorage {
+///     fn get(&self, key: &[u8]) -> Option<Bytes> {
+///         self.inner.get(key)
+///     }
+///
+///     fn insert(&self, key: Vec<u8>, value: Bytes) {
+///         self.writes.fetch_add(1, Ordering::Relaxed);
+///         self.inner.insert(key, value);
+///     }
+///
+///     fn remove(&self, key: &[u8]) -> Option<Bytes> {
+///         self.inner.remove(key)
+///     }
+///
+///     fn remove_if(&self, key: &[u8], condition: &dyn Fn(&[u8]) -> bool) -> bool {
+///         self.inner.remove_if(key, condition)
+///     }
+///
+///     fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Bytes)> + '_> {
+///         self.inner.iter()
+///     }
+///
+///     fn len(&self) -> usize {
+///         self.inner.len()
+///     }
+///
+///     fn size_bytes(&self) -> usize {
+///         self.inner.size_bytes()
+///     }
+/// }
+/// ```
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &[u8]) -> Option<Bytes>;
+
+    /// Inserts or replaces the value of `key`.
+    fn insert(&self, key: Vec<u8>, value: Bytes);
+
+    /// Removes `key`, returning its value if there was one.
+    fn remove(&self, key: &[u8]) -> Option<Bytes>;
+
+    /// Removes `key` if its current value satisfies `condition`, checked
+    /// and removed atomically. Returns whether it was removed.
+    fn remove_if(&self, key: &[u8], condition: &dyn Fn(&[u8]) -> bool) -> bool;
+
+    /// Iterates over every entry, in no particular order.
+    ///
+    /// The node does not write to the storage until the iterator is dropped.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Bytes)> + '_>;
+
+    /// Number of entries.
+    fn len(&self) -> usize;
+
+    /// Total size of the keys and values, in bytes.
+    ///
+    /// The default walks every entry; a backend should keep a running total
+    /// instead, as the node checks it after every write.
+    fn size_bytes(&self) -> usize {
+        self.iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Removes every entry for which `keep` returns `false`.
+    ///
+    /// By default entries are collected first and then removed with
+    /// [`Storage::remove_if`], so an entry rewritten meanwhile is kept.
+    fn retain(&self, keep: &mut dyn FnMut(&[u8], &[u8]) -> bool) {
+        let dropped: Vec<_> = self
+            .iter()
+            .filter(|(key, value)| !keep(key, value))
+            .collect();
+        for (key, value) in dropped {
+            self.remove_if(&key, &|current| current == value);
+        }
+    }
+
+    /// Removes every entry.
+    fn clear(&self) {
+        self.retain(&mut |_, _| false);
+    }
+}
+
+/// In-memory storage, the default.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: DashMap<Vec<u8>, Bytes>,
+    /// Running total of [`Storage::size_bytes`]
+    bytes: AtomicUsize,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a storage with room for `capacity` entries before it
+    /// reallocates.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: DashMap::with_capacity(capacity),
+            bytes: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.entries.get(key).map(|value| value.clone())
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Bytes) {
+        let key_len = key.len();
+        self.bytes
+            .fetch_add(key_len + value.len(), Ordering::Relaxed);
+        if let Some(previous) = self.entries.insert(key, value) {
+            self.bytes
+                .fetch_sub(key_len + previous.len(), Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&self, key: &[u8]) -> Option<Bytes> {
+        let (key, value) = self.entries.remove(key)?;
+        self.bytes
+            .fetch_sub(key.len() + value.len(), Ordering::Relaxed);
+        Some(value)
+    }
+
+    fn remove_if(&self, key: &[u8], condition: &dyn Fn(&[u8]) -> bool) -> bool {
+        match self.entries.remove_if(key, |_, value| condition(value)) {
+            Some((key, value)) => {
+                self.bytes
+                    .fetch_sub(key.len() + value.len(), Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Bytes)> + '_> {
+        Box::new(
+            self.entries
+                .iter()
+                .map(|entry| (entry.key().clone(), entry.value().clone())),
+        )
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&[u8], &[u8]) -> bool) {
+        self.entries.retain(|key, value| {
+            let kept = keep(key, value);
+            if !kept {
+                self.bytes
+                    .fetch_sub(key.len() + value.len(), Ordering::Relaxed);
+            }
+            kept
+        });
+    }
+
+    fn clear(&self) {
+        self.retain(&mut |_, _| false);
+    }
+}
+
+#[cfg(test)]
+mod backend_tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use bytes::Bytes;
+
+    use super::{MemoryStorage, Storage};
+    use crate::helpers::create_test_node;
+
+    /// Relies on the default methods, like most custom backends.
+    #[derive(Default)]
+    struct CountingStorage {
+        entries: Mutex<BTreeMap<Vec<u8>, Bytes>>,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl Storage for CountingStorage {
+        fn get(&self, key: &[u8]) -> Option<Bytes> {
+            self.entries.lock().unwrap().get(key).cloned()
+        }
+
+        fn insert(&self, key: Vec<u8>, value: Bytes) {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.entries.lock().unwrap().insert(key, value);
+        }
+
+        fn remove(&self, key: &[u8]) -> Option<Bytes> {
+            self.entries.lock().unwrap().remove(key)
+        }
+
+        fn remove_if(&self, key: &[u8], condition: &dyn Fn(&[u8]) -> bool) -> bool {
+            let mut entries = self.entries.lock().unwrap();
+            let matches = entries.get(key).is_some_and(|value| condition(value));
+            if matches {
+                entries.remove(key);
+            }
+            matches
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (Vec<u8>, Bytes)> + '_> {
+            Box::new(self.entries.lock().unwrap().clone().into_iter())
+        }
+
+        fn len(&self) -> usize {
+            self.entries.lock().unwrap().len()
+        }
+    }
+
+    #[test]
+    fn test_memory_storage_counts_bytes() {
+        let storage = MemoryStorage::new();
+        storage.insert(b"key".to_vec(), Bytes::from_static(b"value"));
+        storage.insert(b"other".to_vec(), Bytes::from_static(b"value"));
+        assert_eq!(storage.size_bytes(), 8 + 10);
+
+        storage.insert(b"key".to_vec(), Bytes::from_static(b"longer value"));
+        assert_eq!(storage.size_bytes(), 15 + 10);
+        assert!(!storage.remove_if(b"key", &|_| false));
+        assert!(storage.remove_if(b"key", &|_| true));
+        assert_eq!(storage.size_bytes(), 10);
+
+        storage.insert(b"third".to_vec(), Bytes::from_static(b"value"));
+        storage.retain(&mut |key, _| key != b"other");
+        assert_eq!(storage.size_bytes(), 10);
+        assert_eq!(storage.remove(b"third").map(|value| value.len()), Some(5));
+        assert_eq!(storage.size_bytes(), 0);
+
+        storage.insert(b"key".to_vec(), Bytes::from_static(b"value"));
+        storage.clear();
+        assert_eq!(storage.size_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_node_runs_on_injected_storage() {
+        let storage = CountingStorage::default();
+        let writes = Arc::clone(&storage.writes);
+        let node = create_test_node(8211).with_storage(storage);
+
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        node.store(b"other".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            node.find_value(b"key".to_vec()).await,
+            Some(Bytes::from_static(b"value"))
+        );
+        assert_eq!(node.local_entries(b"").len(), 2);
+        assert_eq!(node.get_stats().storage_size, 2);
+        assert_eq!(
+            node.get_stats().storage_bytes,
+            node.storage
+                .iter()
+                .map(|(k, v)| k.len() + v.len())
+                .sum::<usize>() as u64
+        );
+
+        node.drop_key(b"key");
+        assert!(!node.storage.contains_key(b"key"));
+
+        node.storage.clear();
+        assert!(node.storage.is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+//! Refusing to store or serve keys, for abuse handling.
+//!
+//! Keys are blocked by operators in the `blocklist` section of
+//! [`DhtConfig`](crate::dht::config::DhtConfig), either verbatim or by a
+//! prefix of the hash logged for them (see
+//! [`key_hash`](crate::helpers::key_hash)), or by a callback set with
+//! [`DhtNode::with_key_filter`](crate::dht::DhtNode::with_key_filter). A
+//! node neither stores values of blocked keys, whether written locally or
+//! replicated from a peer, nor serves them to lookups.
+
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use serde::Deserialize;
+
+use crate::dht::{DhtNode, node::NodeId};
+
+/// Keys refused by the node.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    /// Keys blocked verbatim
+    pub keys: HashSet<String>,
+    /// Prefixes of the hex hashes of blocked keys, as logged
+    pub hash_prefixes: Vec<String>,
+}
+
+impl BlocklistConfig {
+    /// Whether `key` is blocked by this configuration.
+    pub fn blocks(&self, key: &[u8]) -> bool {
+        if std::str::from_utf8(key).is_ok_and(|key| self.keys.contains(key)) {
+            return true;
+        }
+        if self.hash_prefixes.is_empty() {
+            return false;
+        }
+        let hash = NodeId::new(key).to_string();
+        self.hash_prefixes
+            .iter()
+            .any(|prefix| hash.starts_with(&prefix.to_ascii_lowercase()))
+    }
+}
+
+/// Callback blocking the keys it returns `true` for, see
+/// [`DhtNode::with_key_filter`].
+#[derive(Clone)]
+pub(crate) struct KeyFilter(Arc<FilterFn>);
+
+type FilterFn = dyn Fn(&[u8]) -> bool + Send + Sync;
+
+impl fmt::Debug for KeyFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyFilter(..)")
+    }
+}
+
+impl DhtNode {
+    /// Also blocks the keys `filter` returns `true` for, on top of the
+    /// configured blocklist.
+    pub fn with_key_filter(
+        mut self,
+        filter: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.key_filter = Some(KeyFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Whether the node refuses to store and serve `key`.
+    pub fn is_key_blocked(&self, key: &[u8]) -> bool {
+        self.config.blocklist.blocks(key)
+            || self
+                .key_filter
+                .as_ref()
+                .is_some_and(|filter| (filter.0)(key))
+    }
+}
+
+#[cfg(test)]
+mod blocklist_tests {
+    use bytes::Bytes;
+
+    use crate::{
+        dht::{
+            DhtNode,
+            blocklist::BlocklistConfig,
+            clock::ManualClock,
+            config::DhtConfig,
+            rpc::DhtRpc,
+            storage::{create_stored_value, serialize_value},
+        },
+        helpers::{key_hash, test_config},
+    };
+
+    #[test]
+    fn test_keys_are_blocked_verbatim_or_by_hash() {
+        let config = BlocklistConfig {
+            keys: ["spam".to_string()].into(),
+            hash_prefixes: vec![key_hash(b"abuse")[..6].to_uppercase()],
+        };
+
+        assert!(config.blocks(b"spam"));
+        assert!(config.blocks(b"abuse"));
+        assert!(!config.blocks(b"spam2"));
+        assert!(!BlocklistConfig::default().blocks(b"spam"));
+    }
+
+    #[tokio::test]
+    async fn test_blocked_keys_are_neither_stored_nor_served() {
+        let config = DhtConfig {
+            blocklist: BlocklistConfig {
+                keys: ["spam".to_string()].into(),
+                hash_prefixes: Vec::new(),
+            },
+            ..test_config()
+        };
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config))
+            .with_clock(ManualClock::new(1_000))
+            .with_key_filter(|key| key.starts_with(b"illegal/"));
+
+        assert!(
+            node.store(b"spam".to_vec(), b"value".to_vec())
+                .await
+                .is_err()
+        );
+        assert!(
+            node.store(b"illegal/1".to_vec(), b"value".to_vec())
+                .await
+                .is_err()
+        );
+        node.store(b"fine".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        let stored = create_stored_value(b"value".to_vec(), node.addr, false, None, node.now());
+        node.handle_rpc(DhtRpc::Store(
+            b"spam".to_vec(),
+            serialize_value(&stored).unwrap(),
+        ))
+        .await;
+        assert!(node.storage.get(b"spam").is_none());
+
+        // Values stored before the key was blocked are not served either
+        node.storage
+            .insert(b"illegal/2".to_vec(), serialize_value(&stored).unwrap());
+        assert_eq!(node.find_value(b"illegal/2".to_vec()).await, None);
+        assert!(matches!(
+            node.handle_rpc(DhtRpc::FindValue(b"illegal/2".to_vec()))
+                .await,
+            DhtRpc::FindValueResponse(None)
+        ));
+        assert_eq!(
+            node.find_value(b"fine".to_vec()).await,
+            Some(Bytes::from_static(b"value"))
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Reusing recent lookup results on the node that made them.
+//!
+//! Applications reading the same keys over and over would otherwise pay a
+//! network lookup for every read. With `lookup.cache_capacity` set, the
+//! values [`DhtNode::find_value`] finds are kept, and reads of their keys
+//! answered from the cache, until one of:
+//!
+//! - the value expires, as its winning copy says;
+//! - `lookup.cache_max_age` passes, so writes made through other nodes show
+//!   up within that long;
+//! - the key is written again on this node, or replicated to it, or an
+//!   `Expire` notice covers the cached version.
+//!
+//! Keys not found are not cached. The least recently read keys are dropped
+//! past the capacity.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::dht::{DhtNode, lookup::LookupResult};
+
+/// Recent lookup results, least recently read first.
+#[derive(Debug)]
+pub(super) struct LookupCache {
+    capacity: usize,
+    max_age: Duration,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    results: HashMap<Vec<u8>, Entry>,
+    /// Keys by the tick they were last read at
+    recency: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    result: LookupResult,
+    found: Instant,
+    /// Unix timestamp the value expires at
+    expiration: Option<u64>,
+    used: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.results.get_mut(key) {
+            self.recency.remove(&entry.used);
+            entry.used = tick;
+            self.recency.insert(tick, key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.results.remove(key) {
+            self.recency.remove(&entry.used);
+        }
+    }
+}
+
+impl LookupCache {
+    pub(super) fn new(capacity: usize, max_age: Duration) -> Self {
+        Self {
+            capacity,
+            max_age,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The cached result for `key`, if still fresh at `now`.
+    fn get(&self, key: &[u8], now: u64) -> Option<LookupResult> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.results.get(key)?;
+        if entry.found.elapsed() >= self.max_age || entry.expiration.is_some_and(|e| e <= now) {
+            entries.remove(key);
+            return None;
+        }
+        let mut result = entry.result.clone();
+        result.ttl_remaining = entry.expiration.map(|e| e - now);
+        entries.touch(key);
+        Some(result)
+    }
+
+    fn insert(&self, key: Vec<u8>, result: LookupResult, now: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        let expiration = result.ttl_remaining.map(|ttl| now + ttl);
+        entries.results.insert(
+            key.clone(),
+            Entry {
+                result,
+                found: Instant::now(),
+                expiration,
+                used: 0,
+            },
+        );
+        entries.touch(&key);
+        while entries.results.len() > self.capacity {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            entries.results.remove(&oldest);
+        }
+    }
+
+    /// Drops the cached result for `key` unless it is newer than
+    /// `version`, which was just stored or expired here.
+    ///
+    /// Versions are timestamps, so a write in the same second as the cached
+    /// one may carry the same version; it still drops it.
+    pub(super) fn invalidate(&self, key: &[u8], version: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .results
+            .get(key)
+            .is_some_and(|entry| entry.result.version <= version)
+        {
+            entries.remove(key);
+        }
+    }
+}
+
+impl DhtNode {
+    /// Answers a lookup of `key` from the cache, when it holds a fresh
+    /// result.
+    pub(super) fn cached_lookup(&self, key: &[u8]) -> Option<LookupResult> {
+        let result = self.lookup_cache.get(key, self.now())?;
+        self.metrics.inc_lookup_cache_hits();
+        Some(result)
+    }
+
+    pub(super) fn cache_lookup(&self, key: Vec<u8>, result: &LookupResult) {
+        self.lookup_cache.insert(key, result.clone(), self.now());
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use std::time::Duration;
+
+    use super::LookupCache;
+    use crate::{
+        dht::{DhtNode, lookup::LookupResult},
+        helpers::test_config,
+    };
+
+    fn result(version: u64, ttl_remaining: Option<u64>) -> LookupResult {
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        LookupResult {
+            value: b"value".to_vec().into(),
+            source: addr,
+            version,
+            ttl_remaining,
+            replicas: 1,
+            last_node: addr,
+            original_nodes: vec![addr],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_entries_expire_and_are_invalidated() {
+        let cache = LookupCache::new(2, Duration::from_secs(30));
+        cache.insert(b"a".to_vec(), result(5, Some(10)), 100);
+        assert_eq!(cache.get(b"a", 104).unwrap().ttl_remaining, Some(6));
+        // The value's own expiration
+        assert!(cache.get(b"a", 110).is_none());
+
+        cache.insert(b"a".to_vec(), result(5, None), 100);
+        cache.invalidate(b"a", 4);
+        assert!(cache.get(b"a", 100).is_some());
+        cache.invalidate(b"a", 5);
+        assert!(cache.get(b"a", 100).is_none());
+
+        cache.insert(b"a".to_vec(), result(5, None), 100);
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(cache.get(b"a", 100).is_none());
+    }
+
+    #[test]
+    fn test_least_recently_read_keys_are_dropped() {
+        let cache = LookupCache::new(2, Duration::from_secs(30));
+        cache.insert(b"a".to_vec(), result(1, None), 0);
+        cache.insert(b"b".to_vec(), result(1, None), 0);
+        assert!(cache.get(b"a", 0).is_some());
+        cache.insert(b"c".to_vec(), result(1, None), 0);
+
+        assert!(cache.get(b"a", 0).is_some());
+        assert!(cache.get(b"b", 0).is_none());
+        assert!(cache.get(b"c", 0).is_some());
+
+        let disabled = LookupCache::new(0, Duration::from_secs(30));
+        disabled.insert(b"a".to_vec(), result(1, None), 0);
+        assert!(disabled.get(b"a", 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_answered_from_the_cache() {
+        let mut config = test_config();
+        config.storage.default_ttl = 60;
+        config.lookup.cache_capacity = 16;
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+
+        node.store(b"key".to_vec(), b"old".to_vec()).await.unwrap();
+        assert_eq!(node.find_value(b"key".to_vec()).await.unwrap(), &b"old"[..]);
+        assert_eq!(node.find_value(b"key".to_vec()).await.unwrap(), &b"old"[..]);
+        assert_eq!(node.get_stats().lookup_cache_hits, 1);
+
+        // A newer local write is never hidden by the cache
+        node.store(b"key".to_vec(), b"new".to_vec()).await.unwrap();
+        assert_eq!(node.find_value(b"key".to_vec()).await.unwrap(), &b"new"[..]);
+        assert_eq!(node.get_stats().lookup_cache_hits, 1);
+    }
+}
@@ -0,0 +1,192 @@
+//! Cancelling long-running operations.
+//!
+//! Bootstrapping, stores (with their replication) and lookups can take as
+//! long as their peers are slow to answer. The `*_cancellable` variants stop
+//! them as soon as a [`CancellationToken`] is cancelled, failing with
+//! [`Cancelled`].
+//!
+//! Cancelling is the same as dropping the operation's future, which is
+//! equally safe and lets callers use `select!` or timeouts instead:
+//!
+//! - A pooled connection dropped in the middle of an RPC is closed, never
+//!   returned to the pool with a half-written request or an unanswered
+//!   response. A dropped multiplexed request only forgets its own stream,
+//!   unless its frame was partially written, in which case the connection is
+//!   closed and the next request dials a new one.
+//! - Connection permits are released and no task is left running on behalf
+//!   of the operation.
+//! - A store cancelled while replicating keeps its local copy and is
+//!   buffered in the outbox, to be replicated by the maintenance service like
+//!   a write that reached no peer.
+//! - Peers discovered by a cancelled bootstrap or lookup stay in the routing
+//!   table.
+
+use std::{fmt, future::Future, net::SocketAddr};
+
+use anyhow::Result;
+use bytes::Bytes;
+pub use tokio_util::sync::CancellationToken;
+
+use crate::dht::DhtNode;
+
+/// An operation was stopped through its [`CancellationToken`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Operation cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+impl DhtNode {
+    /// Runs [`DhtNode::bootstrap`] until it finishes or `cancel` is
+    /// cancelled.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use rust_p2p_node::dht::{DhtNode, cancel::CancellationToken};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None);
+    ///     let cancel = CancellationToken::new();
+    ///
+    ///     let stop = cancel.clone();
+    ///     tokio::spawn(async move {
+    ///         tokio::time::sleep(Duration::from_secs(1)).await;
+    ///         stop.cancel();
+    ///     });
+    ///
+    ///     let peers = vec!["127.0.0.1:8081".parse().unwrap()];
+    ///     if let Err(e) = node.bootstrap_cancellable(peers, &cancel).await {
+    ///         eprintln!("{}", e);
+    ///     }
+    /// }
+    /// ```
+    pub async fn bootstrap_cancellable(
+        &self,
+        known_peers: Vec<SocketAddr>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        until_cancelled(cancel, self.bootstrap(known_peers)).await?
+    }
+
+    /// Runs [`DhtNode::store`] until it finishes or `cancel` is cancelled.
+    ///
+    /// A store cancelled during replication is kept locally and in the
+    /// outbox, so it still reaches its replicas later.
+    pub async fn store_cancellable(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        until_cancelled(cancel, self.store(key, value)).await?
+    }
+
+    /// Runs [`DhtNode::find_value`] until it finishes or `cancel` is
+    /// cancelled.
+    pub async fn find_value_cancellable(
+        &self,
+        key: Vec<u8>,
+        cancel: &CancellationToken,
+    ) -> Result<Option<Bytes>> {
+        until_cancelled(cancel, self.find_value(key)).await
+    }
+
+    /// Runs [`DhtNode::check_replication`] until it finishes or `cancel` is
+    /// cancelled.
+    pub async fn check_replication_cancellable(&self, cancel: &CancellationToken) -> Result<usize> {
+        until_cancelled(cancel, self.check_replication()).await
+    }
+}
+
+async fn until_cancelled<T>(
+    cancel: &CancellationToken,
+    operation: impl Future<Output = T>,
+) -> Result<T> {
+    cancel
+        .run_until_cancelled(operation)
+        .await
+        .ok_or_else(|| Cancelled.into())
+}
+
+#[cfg(test)]
+mod cancel_tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use super::{CancellationToken, Cancelled};
+    use crate::{
+        dht::{NodeId, PeerInfo},
+        helpers::create_test_node,
+    };
+
+    /// Accepts connections and reads from them without ever answering.
+    async fn silent_peer() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while socket.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+                });
+            }
+        });
+        addr
+    }
+
+    fn cancel_after(delay: Duration) -> CancellationToken {
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            token.cancel();
+        });
+        cancel
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_bootstrap_leaves_no_connection_behind() {
+        let node = create_test_node(8207);
+        let peer = silent_peer().await;
+
+        let error = node
+            .bootstrap_cancellable(vec![peer], &cancel_after(Duration::from_millis(50)))
+            .await
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<Cancelled>(), Some(&Cancelled));
+
+        tokio::task::yield_now().await;
+        let snapshot = node.connection_pool.snapshot().await;
+        let connections = snapshot.peers.get(&peer).map_or(0, |p| p.idle + p.in_use);
+        assert_eq!(connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_store_is_buffered() {
+        let node = create_test_node(8208);
+        let peer = silent_peer().await;
+        node.add_peer(PeerInfo::new(NodeId::new(b"silent"), peer));
+
+        let error = node
+            .store_cancellable(
+                b"key".to_vec(),
+                b"value".to_vec(),
+                &cancel_after(Duration::from_millis(50)),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<Cancelled>(), Some(&Cancelled));
+
+        assert!(node.local_value(b"key").is_some());
+        assert!(node.outbox.contains_key(b"key".as_slice()));
+    }
+}
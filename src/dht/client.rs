@@ -0,0 +1,350 @@
+//! Talking to a node without being one.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use tokio::{net::TcpStream, time::timeout};
+use tracing::debug;
+
+use crate::dht::{
+    auth::{ClusterSecret, open_signed_frame, sign_frame},
+    connection::{
+        mux::MuxConnection,
+        transport::{MAX_FRAME_LEN, read_frame, write_frame},
+    },
+    identity::Identity,
+    metrics::DhtStats,
+    rpc::{DhtRpc, Envelope, WriteReport, WriteRequest},
+};
+
+/// Client of a single node, which stores and looks up values on its
+/// behalf.
+///
+/// Unlike a [`DhtNode`](crate::dht::DhtNode) it joins no network, keeps no
+/// routing table and holds no data; every call opens a connection, sends one
+/// RPC and waits for the answer.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_p2p_node::dht::client::DhtClient;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let client = DhtClient::new("127.0.0.1:8080".parse()?);
+///     client.store(b"key".to_vec(), b"value".to_vec()).await?;
+///     assert_eq!(client.get(b"key".to_vec()).await?.as_deref(), Some(&b"value"[..]));
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct DhtClient {
+    addr: SocketAddr,
+    timeout: Duration,
+    multiplexing: bool,
+    secret: Option<ClusterSecret>,
+    identity: Option<Identity>,
+    retries: u32,
+}
+
+impl DhtClient {
+    /// Creates a client of the node at `addr`.
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            timeout: Duration::from_secs(5),
+            multiplexing: false,
+            secret: None,
+            identity: None,
+            retries: 0,
+        }
+    }
+
+    /// Gives up on calls that take longer than `timeout`, including the
+    /// lookups and replication the node runs for them. 5s by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Speaks the multiplexed framing, for nodes with
+    /// `connection_pool.multiplexing` enabled.
+    pub fn with_multiplexing(mut self, multiplexing: bool) -> Self {
+        self.multiplexing = multiplexing;
+        self
+    }
+
+    /// Authenticates calls with the `cluster_secret` of a private cluster.
+    pub fn with_secret(mut self, secret: ClusterSecret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Signs calls with `identity`, for clusters with an `allowlist`, which
+    /// must include its id.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Sends calls that got no answer again, up to `retries` times. 0 by
+    /// default.
+    ///
+    /// Writes then carry an id, the same in every attempt, so the node
+    /// applies them once even when only its answer was lost, see
+    /// [`dedup`](crate::dht::dedup).
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Checks that the node answers.
+    pub async fn ping(&self) -> Result<()> {
+        match self.call(DhtRpc::Ping).await? {
+            DhtRpc::Pong => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Stores a value through the node, which replicates it like its own
+    /// writes. Returns the version written.
+    pub async fn store(&self, key: Vec<u8>, value: impl Into<Bytes>) -> Result<u64> {
+        self.store_authorized(key, value.into(), None).await
+    }
+
+    /// Stores a value through the node with a `token` authorizing the write,
+    /// obtained from an issuer, see [`authz`](crate::dht::authz). Returns
+    /// the version written.
+    pub async fn store_with_token(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        token: Vec<u8>,
+    ) -> Result<u64> {
+        self.store_authorized(key, value.into(), Some(token)).await
+    }
+
+    async fn store_authorized(
+        &self,
+        key: Vec<u8>,
+        value: Bytes,
+        token: Option<Vec<u8>>,
+    ) -> Result<u64> {
+        match self.call(DhtRpc::ClientStore(key, value, token)).await? {
+            DhtRpc::ClientStoreResponse(result) => result.map_err(|e| anyhow!(e)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Stores a value through the node, which coordinates the write as
+    /// `request` says and reports the replicas that acknowledged it.
+    ///
+    /// Fails when fewer replicas than `request.consistency` requires
+    /// acknowledged the write, see
+    /// [`DhtNode::store_coordinated`](crate::dht::DhtNode::store_coordinated).
+    pub async fn store_coordinated(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        request: WriteRequest,
+    ) -> Result<WriteReport> {
+        let store = DhtRpc::CoordinatedStore(key, value.into(), request);
+        match self.call(store).await? {
+            DhtRpc::CoordinatedStoreResponse(result) => result.map_err(|e| anyhow!(e)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Looks a value up through the node.
+    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Bytes>> {
+        match self.call(DhtRpc::ClientGet(key)).await? {
+            DhtRpc::ClientGetResponse(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Fetches the node's statistics.
+    pub async fn stats(&self) -> Result<DhtStats> {
+        match self.call(DhtRpc::GetStats).await? {
+            DhtRpc::StatsResponse(stats) => {
+                serde_json::from_str(&stats).context("Invalid stats from the node")
+            }
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn call(&self, request: DhtRpc) -> Result<DhtRpc> {
+        let mut envelope = Envelope::anonymous(request);
+        if self.retries > 0
+            && matches!(
+                envelope.rpc,
+                DhtRpc::ClientStore(..) | DhtRpc::CoordinatedStore(..)
+            )
+        {
+            envelope.id = Some(rand::random());
+        }
+        let mut request = bincode::serialize(&envelope)?;
+        if let Some(identity) = &self.identity {
+            request = sign_frame(identity, request);
+        }
+        if let Some(secret) = &self.secret {
+            request = secret.seal(request);
+        }
+        let mut attempt = 0;
+        let mut response = loop {
+            let response = timeout(self.timeout, self.exchange(&request))
+                .await
+                .map_err(|_| anyhow!("No answer from {} within {:?}", self.addr, self.timeout))
+                .and_then(|response| {
+                    response.with_context(|| format!("Request to {} failed", self.addr))
+                });
+            match response {
+                Ok(response) => break response,
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    debug!(node = %self.addr, attempt, error = %e, "retrying call");
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        if let Some(secret) = &self.secret {
+            response = secret.open(response)?;
+        }
+        if self.identity.is_some() {
+            response = open_signed_frame(response)?.0;
+        }
+        Ok(bincode::deserialize(&response)?)
+    }
+
+    /// Writes one framed request and reads the framed response.
+    async fn exchange(&self, request: &[u8]) -> Result<Vec<u8>> {
+        if self.multiplexing {
+            let conn = MuxConnection::connect(self.addr, self.timeout).await?;
+            return conn.request(request).await;
+        }
+
+        let mut stream = TcpStream::connect(self.addr).await?;
+        write_frame(&mut stream, request).await?;
+        Ok(read_frame(&mut stream, MAX_FRAME_LEN).await?)
+    }
+}
+
+fn unexpected(response: DhtRpc) -> anyhow::Error {
+    anyhow!("Unexpected {} from the node", response.name())
+}
+
+#[cfg(test)]
+mod client_tests {
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    use super::DhtClient;
+    use crate::{
+        dht::{
+            connection::transport::{MAX_FRAME_LEN, read_frame, write_frame},
+            options::Consistency,
+            rpc::{DhtRpc, Envelope, WriteRequest},
+        },
+        helpers::create_test_node,
+    };
+
+    #[tokio::test]
+    async fn test_client_stores_and_gets_through_a_node() {
+        let node = create_test_node(8191);
+        let server = node.listen().await.unwrap();
+        let client = DhtClient::new(node.addr);
+
+        client.ping().await.unwrap();
+        let version = client
+            .store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(node.local_value(b"key").unwrap().version, version);
+        assert_eq!(
+            client.get(b"key".to_vec()).await.unwrap(),
+            Some(Bytes::from_static(b"value"))
+        );
+        assert_eq!(client.get(b"missing".to_vec()).await.unwrap(), None);
+        assert_eq!(client.stats().await.unwrap().store_ops, 1);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_any_node_coordinates_writes() {
+        let (mut coordinator, replica) = (create_test_node(8249), create_test_node(8250));
+        coordinator.config.storage.default_ttl = 60;
+        let _servers = (
+            coordinator.listen().await.unwrap(),
+            replica.listen().await.unwrap(),
+        );
+        coordinator.add_peer(replica.peer_info());
+        let client = DhtClient::new(coordinator.addr);
+
+        let request = WriteRequest {
+            replication: Some(1),
+            ttl: Some(120),
+            consistency: Consistency::All,
+            token: None,
+        };
+        let report = client
+            .store_coordinated(b"key".to_vec(), b"value".to_vec(), request.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            (report.replicas, report.acknowledged, report.required),
+            (1, 1, 1)
+        );
+        let stored = replica.local_value(b"key").unwrap();
+        assert_eq!(stored.version, report.version);
+        assert!(stored.expiration.unwrap() > coordinator.now() + 60);
+
+        // Only one replica known, so three cannot acknowledge
+        let request = WriteRequest {
+            replication: Some(3),
+            ..request
+        };
+        let error = client
+            .store_coordinated(b"key".to_vec(), b"value".to_vec(), request)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Only 1 of the 3 replicas"));
+    }
+
+    #[tokio::test]
+    async fn test_retried_writes_keep_their_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = DhtClient::new(listener.local_addr().unwrap()).with_retries(1);
+        // Drops the first request unanswered, as if the answer was lost
+        let node = tokio::spawn(async move {
+            let mut ids = Vec::new();
+            for answer in [false, true] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_frame(&mut socket, MAX_FRAME_LEN).await.unwrap();
+                ids.push(bincode::deserialize::<Envelope>(&request).unwrap().id);
+                if answer {
+                    let response = DhtRpc::ClientStoreResponse(Ok(1));
+                    let response = bincode::serialize(&response).unwrap();
+                    write_frame(&mut socket, &response).await.unwrap();
+                }
+            }
+            ids
+        });
+
+        assert_eq!(
+            client
+                .store(b"key".to_vec(), b"value".to_vec())
+                .await
+                .unwrap(),
+            1
+        );
+        let ids = node.await.unwrap();
+        assert!(ids[0].is_some());
+        assert_eq!(ids[0], ids[1]);
+    }
+}
@@ -0,0 +1,353 @@
+//! Time as seen by a node.
+//!
+//! Every time-dependent behaviour of a node asks its [`Clock`]: the Unix time
+//! behind versions, TTLs, peer staleness and bans, and the timers behind the
+//! maintenance, replication and StatsD intervals, RPC and lookup timeouts and
+//! injected delays. [`SystemClock`] is used unless another clock is injected
+//! with [`DhtNode::with_clock`].
+//!
+//! A [`ManualClock`] only moves when told to, so tests can expire values,
+//! run maintenance rounds or time out RPCs deterministically and without
+//! real sleeps. The connection pool's own timers (idle connections, dial
+//! backoff, connect timeouts) and the listeners' accept backoff stay on
+//! tokio's clock, and [`PeerInfo::new`](crate::dht::peer::PeerInfo::new)
+//! stamps peers with the system time, as it has no node to ask.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use crate::{dht::DhtNode, helpers};
+
+/// Source of time for a node.
+///
+/// # Examples
+///
+/// ```
+/// use std::{future::Future, pin::Pin, time::Duration};
+/// use rust_p2p_node::dht::clock::{Clock, SystemClock};
+///
+/// /// Runs a day ahead of the system clock.
+/// struct Tomorrow;
+///
+/// impl Clock for Tomorrow {
+///     fn now(&self) -> u64 {
+///         SystemClock.now() + 24 * 60 * 60
+///     }
+///
+///     fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+///         SystemClock.sleep(duration)
+///     }
+/// }
+/// ```
+pub trait Clock: Send + Sync {
+    /// Current Unix time in seconds.
+    fn now(&self) -> u64;
+
+    /// Resolves once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The system's time and tokio's timers, the default.
+///
+/// Follows tokio's clock, so it is virtual under a paused runtime, see
+/// [`helpers::now`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        helpers::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves through [`ManualClock::advance`].
+///
+/// Clones share the same time.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use rust_p2p_node::dht::{DhtNode, clock::ManualClock};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let clock = ManualClock::new(1_700_000_000);
+///     let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None).with_clock(clock.clone());
+///
+///     node.store_with_ttl(b"key".to_vec(), b"value".to_vec(), 60).await.unwrap();
+///     clock.advance(Duration::from_secs(61));
+///     assert!(node.local_value(b"key").is_none());
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+#[derive(Default)]
+struct ManualState {
+    /// Time since the Unix epoch
+    now: Duration,
+    /// Pending sleeps, with the time each one ends at
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl ManualClock {
+    /// Creates a clock standing at `unix_secs`.
+    pub fn new(unix_secs: u64) -> Self {
+        let clock = Self::default();
+        clock.state.lock().unwrap().now = Duration::from_secs(unix_secs);
+        clock
+    }
+
+    /// Creates a clock standing at the current system time.
+    pub fn starting_now() -> Self {
+        Self::new(helpers::now())
+    }
+
+    /// Moves the clock forward, ending the sleeps that are due.
+    ///
+    /// Tasks woken this way run once the caller yields to the runtime.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now += duration;
+
+        let now = state.now;
+        let (due, pending) = state
+            .sleepers
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleepers = pending;
+        drop(state);
+
+        for (_, wake) in due {
+            let _ = wake.send(());
+        }
+    }
+
+    /// Number of sleeps that have not ended yet.
+    pub fn pending_sleeps(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.sleepers.retain(|(_, wake)| !wake.is_closed());
+        state.sleepers.len()
+    }
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("ManualClock")
+            .field("now", &state.now)
+            .field("sleepers", &state.sleepers.len())
+            .finish()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.state.lock().unwrap().now.as_secs()
+    }
+
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        if duration.is_zero() {
+            return Box::pin(std::future::ready(()));
+        }
+
+        let (wake, woken) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        let deadline = state.now + duration;
+        state.sleepers.push((deadline, wake));
+        Box::pin(async move {
+            let _ = woken.await;
+        })
+    }
+}
+
+/// A [`timeout`] ran out before its future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Runs `future` until it completes or `duration` passes on `clock`.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let deadline = clock.sleep(duration);
+    tokio::select! {
+        output = future => Ok(output),
+        _ = deadline => Err(Elapsed),
+    }
+}
+
+/// Ticks every `period` on a clock; the first tick is immediate.
+pub struct Interval {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    started: bool,
+}
+
+impl Interval {
+    pub fn new(clock: Arc<dyn Clock>, period: Duration) -> Self {
+        Self {
+            clock,
+            period,
+            started: false,
+        }
+    }
+
+    /// Waits for the next tick.
+    pub async fn tick(&mut self) {
+        if self.started {
+            self.clock.sleep(self.period).await;
+        }
+        self.started = true;
+    }
+}
+
+impl DhtNode {
+    /// Runs the node on `clock` instead of the [`SystemClock`].
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Current Unix time in seconds, on the node's clock.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Runs `future` until it completes or `duration` passes on the node's
+    /// clock.
+    pub(super) fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> impl Future<Output = Result<F::Output, Elapsed>> + use<F> {
+        let clock = Arc::clone(&self.clock);
+        async move { timeout(clock.as_ref(), duration, future).await }
+    }
+
+    /// Waits `duration` on the node's clock, for injected delays.
+    #[cfg(any(test, feature = "testing"))]
+    pub(super) fn sleep(&self, duration: Duration) -> impl Future<Output = ()> + use<> {
+        self.clock.sleep(duration)
+    }
+
+    pub(super) fn interval(&self, period: Duration) -> Interval {
+        Interval::new(Arc::clone(&self.clock), period)
+    }
+}
+
+#[cfg(test)]
+mod clock_tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use super::{Clock, ManualClock};
+    use crate::{
+        dht::{NodeId, PeerInfo, events::DhtEvent},
+        helpers::create_test_node,
+    };
+
+    #[tokio::test]
+    async fn test_sleeps_end_when_the_clock_is_advanced() {
+        let clock = ManualClock::new(1000);
+        let sleep = tokio::spawn(clock.sleep(Duration::from_millis(1500)));
+
+        clock.advance(Duration::from_secs(1));
+        tokio::task::yield_now().await;
+        assert!(!sleep.is_finished());
+        assert_eq!(clock.now(), 1001);
+
+        clock.advance(Duration::from_millis(500));
+        sleep.await.unwrap();
+        assert_eq!(clock.pending_sleeps(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ttls_and_peer_staleness_follow_the_node_clock() {
+        let clock = ManualClock::starting_now();
+        let node = create_test_node(8212).with_clock(clock.clone());
+
+        node.store_with_ttl(b"key".to_vec(), b"value".to_vec(), 60)
+            .await
+            .unwrap();
+        let mut peer = PeerInfo::new(NodeId::new(b"peer"), "127.0.0.1:1".parse().unwrap());
+        peer.last_seen = node.now();
+        node.add_peer(peer);
+        let known_peers = || -> usize { node.routing_table.iter().map(|b| b.peers.len()).sum() };
+
+        clock.advance(Duration::from_secs(59));
+        assert!(node.local_value(b"key").is_some());
+        node.remove_inactive_peers(60);
+        assert_eq!(known_peers(), 1);
+
+        clock.advance(Duration::from_secs(2));
+        assert!(node.local_value(b"key").is_none());
+        node.remove_inactive_peers(60);
+        assert_eq!(known_peers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_runs_on_the_node_clock() {
+        let clock = ManualClock::starting_now();
+        let node = create_test_node(8213).with_clock(clock.clone());
+        node.store_with_ttl(b"key".to_vec(), b"value".to_vec(), 1)
+            .await
+            .unwrap();
+
+        let mut events = Box::pin(node.subscribe().filter(|event| {
+            std::future::ready(matches!(
+                event,
+                DhtEvent::MaintenanceCompleted { .. } | DhtEvent::ValueExpired { .. }
+            ))
+        }));
+        let mut next_event = async || {
+            tokio::time::timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        // The first round runs right away, before the value expires
+        node.start_maintenance_service().await;
+        assert!(matches!(
+            next_event().await,
+            DhtEvent::MaintenanceCompleted { .. }
+        ));
+
+        // The next one waits for the clock
+        while clock.pending_sleeps() < 2 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(node.config.maintenance_interval);
+        assert!(matches!(
+            next_event().await,
+            DhtEvent::ValueExpired { key } if key == b"key"
+        ));
+
+        node.shutdown().await;
+    }
+}
@@ -1,7 +1,16 @@
-use std::time::Duration;
+use std::{collections::HashSet, time::Duration};
+
+use serde::Deserialize;
+
+use crate::dht::{
+    auth::ClusterSecret,
+    blocklist::BlocklistConfig,
+    node::{IdHash, NodeId},
+};
 
 /// Configureation parameters for the DHT node
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DhtConfig {
     /// Replication factor (k in Kademlia)
     pub replication: ReplicationConfig,
@@ -12,81 +21,472 @@ pub struct DhtConfig {
     /// Storage settings
     pub storage: StorageConfig,
     /// Timeout for network operations
+    #[serde(with = "humantime_serde")]
     pub operation_timeout: Duration,
     /// Interval for maintenance tasks (health checks, replication etc.)
+    #[serde(with = "humantime_serde")]
     pub maintenance_interval: Duration,
     pub health_check: HealthCheckConfig,
+    /// Value lookup termination settings
+    pub lookup: LookupConfig,
+    /// Seed dialing settings
+    pub bootstrap: BootstrapConfig,
+    /// Events buffered per subscriber before a slow one starts missing them
+    pub event_capacity: usize,
+    /// Peers the node must know before it reports ready
+    pub min_ready_peers: usize,
+    /// Number of peers whose traffic is tracked for [`DhtNode::peer_stats`](crate::dht::DhtNode::peer_stats)
+    pub max_tracked_peers: usize,
+    /// StatsD push settings
+    pub statsd: StatsdConfig,
+    /// Hash turning addresses and keys into IDs, shared by the whole network
+    pub id_hash: IdHash,
+    /// Leading zero bits required of the hash of every node id (0 turns
+    /// the proof-of-work off), see [`identity`](crate::dht::identity)
+    pub id_difficulty: u8,
+    /// Shared secret authenticating every RPC, for private clusters (see
+    /// [`auth`](crate::dht::auth))
+    pub cluster_secret: Option<ClusterSecret>,
+    /// Ids of the only nodes accepted into the routing table or over the
+    /// listener, for fully private clusters (see [`auth`](crate::dht::auth))
+    pub allowlist: Option<HashSet<NodeId>>,
+    /// Keys the node refuses to store or serve
+    pub blocklist: BlocklistConfig,
+    /// Per-IP limits on inbound connections and requests
+    pub inbound_limits: InboundLimitsConfig,
+    /// Node-wide limit on the RPCs in flight
+    pub overload: OverloadConfig,
+    /// Topic broadcast settings
+    pub gossip: GossipConfig,
+    /// Writes whose response is kept to answer their retries, see
+    /// [`dedup`](crate::dht::dedup)
+    pub dedup_capacity: usize,
+    /// Writes whose response is kept for any one sender
+    pub dedup_per_sender: usize,
+    /// Tracking and shedding of the keys requested the most
+    pub hot_keys: HotKeyConfig,
+    /// Keep a copy of every value in the network rather than this node's
+    /// share, and advertise it so that peers send their writes here, see
+    /// [`mirror`](crate::dht::mirror)
+    pub mirror: bool,
+    /// Ids of the peers trusted as mirrors. Others advertising themselves as
+    /// mirrors are treated as any peer, see [`mirror`](crate::dht::mirror)
+    pub trusted_mirrors: HashSet<NodeId>,
+    /// Scheduled backups of storage
+    pub snapshots: SnapshotConfig,
 }
 
 /// Connection pool configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ConnectionPoolConfig {
     /// Max connections per peer
     pub max_connections_per_peer: usize,
+    /// Max open connections across all peers
+    pub max_total_connections: usize,
     /// Max idle time for connections
+    #[serde(with = "humantime_serde")]
     pub max_idle_time: Duration,
     /// Connection timeout
+    #[serde(with = "humantime_serde")]
     pub connect_timeout: Duration,
+    /// Initial delay before redialing an address that failed to connect
+    #[serde(with = "humantime_serde")]
+    pub dial_backoff_base: Duration,
+    /// Upper bound of the exponential dial backoff
+    #[serde(with = "humantime_serde")]
+    pub dial_backoff_max: Duration,
+    /// Head start given to each address before the next one of a
+    /// multi-address peer is dialed in parallel (Happy Eyeballs)
+    #[serde(with = "humantime_serde")]
+    pub happy_eyeballs_delay: Duration,
+    /// Carry all RPCs to a peer over one multiplexed connection. Both sides
+    /// must enable it, see [`DhtNode::serve_multiplexed`](crate::dht::DhtNode::serve_multiplexed).
+    pub multiplexing: bool,
+    /// Number of closest routing neighbors to keep a warm connection to
+    /// (0 disables pre-warming)
+    pub prewarm_peers: usize,
 }
 
 /// Storage configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct StorageConfig {
     /// Maximum number of key-value pairs to store
     pub max_entries: usize,
+    /// Maximum size of the keys and values stored, in bytes (0 for no
+    /// limit). Going over either limit evicts values, see
+    /// [`DhtNode::evict_to_fit`](crate::dht::DhtNode::evict_to_fit)
+    pub max_bytes: usize,
     /// Default time-to-live for stored values (in seconds)
     pub default_ttl: u64,
+    /// Share of a value's time-to-live, in percent, it may be shortened by
+    /// at random, so values written together do not all expire in the same
+    /// sweep (0 for exact expirations)
+    pub ttl_jitter_percent: u8,
     /// Interval for checking expired values (in seconds)
     pub expiration_check_interval: u64,
+    /// Maximum number of writes buffered while no peer is reachable; writes
+    /// beyond it are only kept locally
+    pub max_outbox_entries: usize,
+}
+
+/// Inbound traffic limits, see [`limits`](crate::dht::limits)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InboundLimitsConfig {
+    /// Maximum concurrent connections from one IP (0 means unlimited)
+    pub max_connections_per_ip: usize,
+    /// Maximum requests per second from one IP before it is greylisted
+    /// (0 means unlimited)
+    pub max_requests_per_second: u32,
+    /// How long a greylisted IP is refused
+    #[serde(with = "humantime_serde")]
+    pub greylist_duration: Duration,
+}
+
+/// In-flight RPC limit configuration, see [`overload`](crate::dht::overload)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverloadConfig {
+    /// Maximum number of RPCs sent and inbound requests answered at once
+    /// (0 lifts the limit)
+    pub max_in_flight: usize,
+    /// Share of `max_in_flight`, in percent, that maintenance RPCs may take
+    pub maintenance_percent: u8,
+}
+
+/// Topic broadcast configuration, see [`gossip`](crate::dht::gossip)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GossipConfig {
+    /// Number of times a message is relayed, counting its publication
+    pub max_hops: u8,
+    /// Number of recent message ids remembered to drop duplicates
+    pub seen_capacity: usize,
+    /// Messages buffered per subscriber before a slow one starts missing them
+    pub subscriber_capacity: usize,
+    /// Relays in flight at once. Messages arriving beyond it are still
+    /// delivered to local subscribers, but not relayed
+    pub max_relays: usize,
+}
+
+/// Hot key configuration, see [`hotkeys`](crate::dht::hotkeys)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HotKeyConfig {
+    /// Sliding window requests are counted over
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Number of distinct keys counted per window
+    pub max_tracked_keys: usize,
+    /// Number of keys reported in the stats
+    pub top: usize,
+    /// Requests within the window that make a key hot
+    pub threshold: u64,
+    /// How long `ClientGet` answers for hot keys are reused, not at all when
+    /// unset
+    #[serde(with = "humantime_serde")]
+    pub cache_ttl: Option<Duration>,
+    /// Requests for a hot key answered within the window, any number when
+    /// unset
+    pub max_requests: Option<u64>,
 }
 
 /// Health check configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct HealthCheckConfig {
     /// Interval between peer health checks
+    #[serde(with = "humantime_serde")]
     pub interval: Duration,
     /// Timeout for health check request
+    #[serde(with = "humantime_serde")]
     pub timeout: Duration,
     /// Number of failed attempts before marking peer as dead
-    pub max_failures: u8
+    pub max_failures: u8,
+}
+
+/// Lookup configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LookupConfig {
+    /// Maximum number of rounds of moving towards closer peers
+    pub max_hops: usize,
+    /// Maximum number of peers queried during a single lookup
+    pub max_peers_queried: usize,
+    /// Stop at the first value found instead of gathering every replica
+    pub stop_on_first_value: bool,
+    /// Wall-clock budget for a whole lookup
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Maximum number of lookups run at once by
+    /// [`DhtNode::get_many`](crate::dht::DhtNode::get_many)
+    pub max_concurrent: usize,
+    /// Number of lookup results kept to answer repeated reads, 0 to cache
+    /// none, see [`cache`](crate::dht::cache)
+    pub cache_capacity: usize,
+    /// How long a cached result is reused at most
+    #[serde(with = "humantime_serde")]
+    pub cache_max_age: Duration,
+}
+
+/// Bootstrap configuration, see
+/// [`DhtNode::bootstrap`](crate::dht::DhtNode::bootstrap)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapConfig {
+    /// Maximum number of seeds dialed at once
+    pub parallelism: usize,
+    /// Number of seeds that must answer before bootstrapping ends, without
+    /// waiting for the others; 0 waits for every seed
+    pub quorum: usize,
+}
+
+/// Backup configuration, see
+/// [`DhtNode::start_snapshots`](crate::dht::DhtNode::start_snapshots)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Interval between snapshots
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// Number of snapshots kept in the sink, 0 to keep every one
+    pub retain: usize,
+}
+
+/// StatsD exporter configuration, see
+/// [`DhtNode::start_statsd_exporter`](crate::dht::DhtNode::start_statsd_exporter)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatsdConfig {
+    /// Interval between pushes
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    /// Prefix of every metric name
+    pub prefix: String,
 }
 
 /// Replication configuration
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ReplicationConfig {
     pub factor: usize,
     /// Interval between replication checks
+    #[serde(with = "humantime_serde")]
     pub check_interval: Duration,
     /// Number of parallel replication requests
-    pub parallelism: usize
+    pub parallelism: usize,
+    /// Maximum number of locally held keys verified per replication check
+    pub check_sample_size: usize,
+    /// Maximum number of maintenance RPCs sent to a peer in one batch; 1
+    /// sends them one by one, for networks with nodes predating batches
+    pub batch_size: usize,
 }
 
 impl Default for DhtConfig {
     fn default() -> Self {
         Self {
-            replication: ReplicationConfig {
-                factor: 5,
-                check_interval: Duration::from_secs(60),
-                parallelism: 3,
-            },
+            replication: ReplicationConfig::default(),
             kbucket_size: 20,
-            connection_pool: ConnectionPoolConfig {
-                max_connections_per_peer: 3,
-                max_idle_time: Duration::from_secs(300),
-                connect_timeout: Duration::from_secs(3),
-            },
-            storage: StorageConfig {
-                max_entries: 10_000,
-                default_ttl: 3600,
-                expiration_check_interval: 60,
-            },
+            connection_pool: ConnectionPoolConfig::default(),
+            storage: StorageConfig::default(),
             operation_timeout: Duration::from_secs(3),
             maintenance_interval: Duration::from_secs(30),
-            health_check: HealthCheckConfig {
-                interval: Duration::from_secs(30),
-                timeout: Duration::from_secs(3),
-                max_failures: 2,
-            },
+            health_check: HealthCheckConfig::default(),
+            lookup: LookupConfig::default(),
+            bootstrap: BootstrapConfig::default(),
+            event_capacity: 1024,
+            min_ready_peers: 1,
+            max_tracked_peers: 1024,
+            statsd: StatsdConfig::default(),
+            id_hash: IdHash::default(),
+            id_difficulty: 0,
+            cluster_secret: None,
+            allowlist: None,
+            blocklist: BlocklistConfig::default(),
+            inbound_limits: InboundLimitsConfig::default(),
+            overload: OverloadConfig::default(),
+            gossip: GossipConfig::default(),
+            dedup_capacity: 4096,
+            dedup_per_sender: 256,
+            hot_keys: HotKeyConfig::default(),
+            mirror: false,
+            trusted_mirrors: HashSet::new(),
+            snapshots: SnapshotConfig::default(),
+        }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            factor: 5,
+            check_interval: Duration::from_secs(60),
+            parallelism: 3,
+            check_sample_size: 64,
+            batch_size: 64,
+        }
+    }
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self {
+            parallelism: 8,
+            quorum: 3,
+        }
+    }
+}
+
+impl Default for ConnectionPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_peer: 3,
+            max_total_connections: 256,
+            max_idle_time: Duration::from_secs(300),
+            connect_timeout: Duration::from_secs(3),
+            dial_backoff_base: Duration::from_secs(1),
+            dial_backoff_max: Duration::from_secs(60),
+            happy_eyeballs_delay: Duration::from_millis(250),
+            multiplexing: false,
+            prewarm_peers: 0,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_bytes: 256 * 1024 * 1024,
+            default_ttl: 3600,
+            ttl_jitter_percent: 0,
+            expiration_check_interval: 60,
+            max_outbox_entries: 1024,
+        }
+    }
+}
+
+impl Default for InboundLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: 0,
+            max_requests_per_second: 0,
+            greylist_duration: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            max_hops: 6,
+            seen_capacity: 4096,
+            subscriber_capacity: 256,
+            max_relays: 32,
+        }
+    }
+}
+
+impl Default for HotKeyConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(60),
+            max_tracked_keys: 10_000,
+            top: 10,
+            threshold: 1000,
+            cache_ttl: None,
+            max_requests: None,
+        }
+    }
+}
+
+impl Default for OverloadConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 1024,
+            maintenance_percent: 75,
+        }
+    }
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(3),
+            max_failures: 2,
+        }
+    }
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        Self {
+            max_hops: 3,
+            max_peers_queried: 20,
+            stop_on_first_value: false,
+            timeout: Duration::from_secs(10),
+            max_concurrent: 16,
+            cache_capacity: 0,
+            cache_max_age: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(3600),
+            retain: 24,
+        }
+    }
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            prefix: "dht".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use std::time::Duration;
+
+    use crate::dht::{config::DhtConfig, node::IdHash};
+
+    #[test]
+    fn test_partial_toml_keeps_defaults() {
+        let config: DhtConfig = toml::from_str(
+            r#"
+            kbucket_size = 16
+            operation_timeout = "500ms"
+            id_hash = "blake3"
+
+            [storage]
+            default_ttl = 60
+
+            [connection_pool]
+            max_idle_time = "2m"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.kbucket_size, 16);
+        assert_eq!(config.operation_timeout, Duration::from_millis(500));
+        assert_eq!(config.storage.default_ttl, 60);
+        assert_eq!(config.storage.max_entries, 10_000);
+        assert_eq!(
+            config.connection_pool.max_idle_time,
+            Duration::from_secs(120)
+        );
+        assert_eq!(config.replication.factor, 5);
+        assert_eq!(config.id_hash, IdHash::Blake3);
+
+        assert!(toml::from_str::<DhtConfig>("kbucket_sise = 16").is_err());
+    }
+}
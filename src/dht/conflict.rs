@@ -0,0 +1,84 @@
+//! Conflict resolution between divergent replicas.
+//!
+//! When a lookup gathers several copies of a value, or a replica receives a
+//! store for a key it already holds, a [`ConflictResolver`] decides which copy
+//! wins. [`LastWriteWins`] is used unless another resolver is injected with
+//! [`DhtNode::with_conflict_resolver`](crate::dht::DhtNode::with_conflict_resolver).
+
+use crate::dht::storage::StoredValue;
+
+/// Decides the winner among conflicting copies of a value.
+///
+/// # Examples
+///
+/// ```
+/// use rust_p2p_node::dht::{conflict::ConflictResolver, storage::StoredValue};
+///
+/// /// Keeps the first version ever written.
+/// struct FirstWriteWins;
+///
+/// impl ConflictResolver for FirstWriteWins {
+///     fn resolve(&self, candidates: &[StoredValue]) -> Option<usize> {
+///         candidates
+///             .iter()
+///             .enumerate()
+///             .min_by_key(|(_, v)| v.version)
+///             .map(|(i, _)| i)
+///     }
+/// }
+/// ```
+pub trait ConflictResolver: Send + Sync {
+    /// Returns the index of the winning candidate, or `None` if `candidates`
+    /// is empty.
+    fn resolve(&self, candidates: &[StoredValue]) -> Option<usize>;
+}
+
+/// Last-write-wins resolution: the copy with the highest version is kept.
+///
+/// Ties are broken in favour of the later candidate, so an incoming store
+/// with the same version replaces the local copy.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LastWriteWins;
+
+impl ConflictResolver for LastWriteWins {
+    fn resolve(&self, candidates: &[StoredValue]) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, v)| v.version)
+            .map(|(i, _)| i)
+    }
+}
+
+#[cfg(test)]
+mod conflict_tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use crate::dht::{
+        conflict::{ConflictResolver, LastWriteWins},
+        storage::StoredValue,
+    };
+
+    fn value(version: u64) -> StoredValue {
+        StoredValue {
+            data: version.to_be_bytes().to_vec().into(),
+            version,
+            last_node: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8090),
+            is_replica: false,
+            expiration: None,
+            original_nodes: vec![],
+            hinted_for: None,
+            record: None,
+            token: None,
+        }
+    }
+
+    #[test]
+    fn test_last_write_wins() {
+        let resolver = LastWriteWins;
+
+        assert_eq!(resolver.resolve(&[]), None);
+        assert_eq!(resolver.resolve(&[value(1), value(3), value(2)]), Some(1));
+        assert_eq!(resolver.resolve(&[value(2), value(2)]), Some(1));
+    }
+}
@@ -0,0 +1,102 @@
+//! Reusable buffers for network I/O.
+//!
+//! Every frame read and every message serialized needs a buffer. Rather than
+//! allocating one per message, the framing code takes them from
+//! [`FRAME_BUFFERS`] and whoever ends up owning a buffer gives it back once
+//! done with it. A buffer that is never given back is simply freed.
+
+use std::sync::Mutex;
+
+/// Idle buffers kept by [`FRAME_BUFFERS`]
+const MAX_IDLE_BUFFERS: usize = 64;
+/// Buffers grown past this capacity are freed instead of kept, so that one
+/// large value does not pin its memory for good
+const MAX_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// The pool shared by the framing code of every node in the process.
+pub static FRAME_BUFFERS: BufferPool = BufferPool::new(MAX_IDLE_BUFFERS, MAX_BUFFER_CAPACITY);
+
+/// A bounded pool of byte buffers.
+///
+/// # Examples
+///
+/// ```
+/// use rust_p2p_node::dht::connection::buffers::BufferPool;
+///
+/// let pool = BufferPool::new(8, 4096);
+/// let mut buf = pool.take();
+/// buf.extend_from_slice(b"frame");
+/// pool.give(buf);
+///
+/// // The same allocation, emptied
+/// let buf = pool.take();
+/// assert!(buf.is_empty() && buf.capacity() >= 5);
+/// ```
+#[derive(Debug)]
+pub struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    max_idle: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool keeping up to `max_idle` buffers of at most
+    /// `max_capacity` bytes each.
+    pub const fn new(max_idle: usize, max_capacity: usize) -> Self {
+        Self {
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+            max_capacity,
+        }
+    }
+
+    /// Returns an empty buffer, reusing an idle one if there is any.
+    pub fn take(&self) -> Vec<u8> {
+        self.idle.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Hands `buf` back for reuse. It is freed instead if the pool is full or
+    /// it grew too large.
+    pub fn give(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > self.max_capacity {
+            return;
+        }
+        buf.clear();
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_idle {
+            idle.push(buf);
+        }
+    }
+
+    /// Number of idle buffers.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod buffers_tests {
+    use super::BufferPool;
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let pool = BufferPool::new(2, 1024);
+
+        pool.give(Vec::new());
+        pool.give(Vec::with_capacity(4096));
+        assert_eq!(pool.idle(), 0);
+
+        for _ in 0..3 {
+            pool.give(vec![1; 16]);
+        }
+        assert_eq!(pool.idle(), 2);
+
+        let buf = pool.take();
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 16);
+        assert_eq!(pool.idle(), 1);
+
+        pool.take();
+        assert_eq!(pool.take().capacity(), 0);
+    }
+}
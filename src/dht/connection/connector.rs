@@ -0,0 +1,88 @@
+//! Dialing abstraction for the connection pool.
+//!
+//! The [`ConnectionPool`](super::ConnectionPool) never opens sockets itself; it
+//! asks a [`Connector`] for a [`PeerStream`]. Plain TCP is provided by
+//! [`TcpConnector`]; TLS, Noise or proxy transports can be layered in by
+//! implementing [`Connector`] without touching the pooling logic.
+//!
+//! A pool may hold several named transports in order of preference, each
+//! with its own [`TransportConfig`]; see
+//! [`ConnectionPool::with_transport`](super::ConnectionPool::with_transport).
+//! To also accept connections some other way than TCP, see
+//! [`Transport`](super::transport::Transport).
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// A bidirectional byte stream to a peer, as produced by a [`Connector`].
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin {
+    /// Cheap liveness probe used before an idle stream is reused.
+    ///
+    /// Wrappers around another stream should forward to it.
+    fn is_alive(&self) -> bool;
+}
+
+impl PeerStream for TcpStream {
+    /// An idle connection must have nothing to read: EOF means the peer closed
+    /// it, pending bytes mean a stale response, and any error means the socket
+    /// is broken. Only "would block" indicates a connection safe to reuse.
+    fn is_alive(&self) -> bool {
+        if !matches!(self.take_error(), Ok(None)) {
+            return false;
+        }
+
+        let mut buf = [0u8; 1];
+        matches!(self.try_read(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+/// Opens new streams to peers.
+///
+/// # Examples
+///
+/// ```
+/// use async_trait::async_trait;
+/// use rust_p2p_node::dht::connection::connector::{Connector, PeerStream, TcpConnector};
+/// use std::{io, net::SocketAddr};
+///
+/// /// Logs every dial before delegating to plain TCP.
+/// struct LoggingConnector(TcpConnector);
+///
+/// #[async_trait]
+/// impl Connector for LoggingConnector {
+///     async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+///         println!("dialing {}", addr);
+///         self.0.connect(addr).await
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Connector: Send + Sync {
+    /// Opens a new stream to `addr`. Timeouts are enforced by the caller.
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>>;
+}
+
+/// Dials plain TCP connections with `TCP_NODELAY` set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TcpConnector;
+
+#[async_trait]
+impl Connector for TcpConnector {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Per-transport settings of a [`ConnectionPool`](super::ConnectionPool).
+#[derive(Debug, Clone, Default)]
+pub struct TransportConfig {
+    /// Overrides the pool's connect timeout for this transport
+    pub connect_timeout: Option<Duration>,
+}
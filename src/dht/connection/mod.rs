@@ -3,28 +3,49 @@
 //! This module provides a [`ConnectionPool`] struct that manages reusable TCP connections
 //! to other nodes in the DHT network.
 
+pub mod buffers;
+pub mod connector;
+pub mod mux;
 pub mod pooled;
+mod slots;
+pub mod transport;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     net::SocketAddr,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
 };
 
 use anyhow::{Context, Result, anyhow};
+use dashmap::DashMap;
+use futures::{StreamExt, stream::FuturesUnordered};
+use serde::Serialize;
 use tokio::{
-    net::TcpStream,
-    sync::{Mutex, Semaphore},
-    time::timeout,
+    sync::{OnceCell, Semaphore},
+    task::JoinHandle,
+    time::{Instant, timeout},
 };
+use tracing::debug;
 
-use crate::dht::connection::pooled::PooledConnection;
+use crate::dht::connection::{
+    connector::{Connector, PeerStream, TcpConnector, TransportConfig},
+    mux::MuxConnection,
+    pooled::PooledConnection,
+    slots::PeerSlots,
+};
+use crate::dht::{overload::Priority, tasks};
 
 /// A pool of TCP connections to DHT nodes.
 ///
 /// Manages connections to other nodes, reusing them when possible and enforcing
-/// limits on the number of connection per peer.
+/// limits on the number of connection per peer. Each peer has its own limit, so
+/// a busy peer cannot starve connections to the others.
 ///
 /// # Examples
 ///
@@ -43,13 +64,71 @@ use crate::dht::connection::pooled::PooledConnection;
 /// ```
 #[derive(Clone)]
 pub struct ConnectionPool {
-    inner: Arc<Mutex<HashMap<SocketAddr, Vec<ConnectionEntry>>>>,
-    semaphore: Arc<Semaphore>,
+    /// Idle connections by peer, each list behind its own lock so that
+    /// checkouts to different peers do not wait on each other
+    inner: Arc<DashMap<SocketAddr, std::sync::Mutex<Vec<ConnectionEntry>>>>,
+    /// The multiplexed connection to each peer, set by the one caller that
+    /// dials it while the others wait
+    multiplexed: Arc<DashMap<SocketAddr, Arc<OnceCell<Arc<MuxConnection>>>>>,
+    slots: Arc<DashMap<SocketAddr, Arc<PeerSlots>>>,
+    max_connections_per_peer: usize,
+    max_total_connections: usize,
     max_idle_time: Duration,
+    connect_timeout: Duration,
+    request_timeout: Duration,
+    dial_failures: Arc<DashMap<(SocketAddr, usize), DialBackoff>>,
+    dial_backoff_base: Duration,
+    dial_backoff_max: Duration,
+    transports: Arc<Vec<PoolTransport>>,
+    alt_addrs: Arc<DashMap<SocketAddr, Vec<SocketAddr>>>,
+    happy_eyeballs_delay: Duration,
+    cleaner: Arc<std::sync::Mutex<Option<JoinHandle<()>>>>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Point-in-time state of a [`ConnectionPool`], see
+/// [`ConnectionPool::snapshot`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PoolSnapshot {
+    /// Whether the pool has been shut down
+    pub closed: bool,
+    /// Connection state of every peer the pool knows about
+    pub peers: BTreeMap<SocketAddr, PeerConnections>,
+}
+
+/// Connections to a single peer.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerConnections {
+    /// Connections parked in the pool
+    pub idle: usize,
+    /// Connections currently checked out
+    pub in_use: usize,
+    /// Whether a live multiplexed connection is open
+    pub multiplexed: bool,
+    /// Consecutive dial failures, summed over all transports
+    pub dial_failures: u32,
+    /// Transports currently backing off from dialing the peer
+    pub backing_off: Vec<&'static str>,
+}
+
+/// Recent dial failures to a single address.
+struct DialBackoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// A named way of reaching peers, see [`ConnectionPool::with_transport`].
+#[derive(Clone)]
+struct PoolTransport {
+    name: &'static str,
+    connector: Arc<dyn Connector>,
+    config: TransportConfig,
 }
 
 struct ConnectionEntry {
-    stream: TcpStream,
+    stream: Box<dyn PeerStream>,
+    /// Index into the pool's transports
+    transport: usize,
     last_used: Instant,
 }
 
@@ -62,12 +141,159 @@ impl ConnectionPool {
     /// * `max_idle_time` - How long to keep idle connections before discarding them
     pub fn new(max_connections_per_peer: usize, max_idle_time: Duration) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(HashMap::new())),
-            semaphore: Arc::new(Semaphore::new(max_connections_per_peer)),
+            inner: Arc::new(DashMap::new()),
+            multiplexed: Arc::new(DashMap::new()),
+            slots: Arc::new(DashMap::new()),
+            max_connections_per_peer,
+            max_total_connections: usize::MAX,
             max_idle_time,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            dial_failures: Arc::new(DashMap::new()),
+            dial_backoff_base: Duration::from_secs(1),
+            dial_backoff_max: Duration::from_secs(60),
+            transports: Arc::new(vec![PoolTransport {
+                name: "tcp",
+                connector: Arc::new(TcpConnector),
+                config: TransportConfig::default(),
+            }]),
+            alt_addrs: Arc::new(DashMap::new()),
+            happy_eyeballs_delay: Duration::from_millis(250),
+            cleaner: Arc::new(std::sync::Mutex::new(None)),
+            closed: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Dials new connections through `connector` instead of plain TCP.
+    ///
+    /// This replaces all transports registered so far.
+    pub fn with_connector(mut self, connector: impl Connector + 'static) -> Self {
+        self.transports = Arc::new(vec![PoolTransport {
+            name: "default",
+            connector: Arc::new(connector),
+            config: TransportConfig::default(),
+        }]);
+        self
+    }
+
+    /// Registers a further transport, less preferred than those registered
+    /// before it.
+    ///
+    /// Idle connections are kept per peer and transport, and checkouts reuse
+    /// the most preferred one available. New connections try the transports
+    /// in order of preference and fall back to the next one when dialing
+    /// fails; a transport the peer does not support is then skipped for the
+    /// dial backoff period. For example, to prefer QUIC and fall back to TCP:
+    ///
+    /// ```no_run
+    /// # use async_trait::async_trait;
+    /// # use rust_p2p_node::dht::connection::{
+    /// #     ConnectionPool,
+    /// #     connector::{Connector, PeerStream, TcpConnector, TransportConfig},
+    /// # };
+    /// # use std::{io, net::SocketAddr, time::Duration};
+    /// # struct QuicConnector;
+    /// # #[async_trait]
+    /// # impl Connector for QuicConnector {
+    /// #     async fn connect(&self, _: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+    /// #         unimplemented!()
+    /// #     }
+    /// # }
+    /// let pool = ConnectionPool::new(5, Duration::from_secs(30))
+    ///     .with_connector(QuicConnector)
+    ///     .with_transport("tcp", TcpConnector, TransportConfig::default());
+    /// ```
+    pub fn with_transport(
+        mut self,
+        name: &'static str,
+        connector: impl Connector + 'static,
+        config: TransportConfig,
+    ) -> Self {
+        Arc::make_mut(&mut self.transports).push(PoolTransport {
+            name,
+            connector: Arc::new(connector),
+            config,
+        });
+        self
+    }
+
+    /// Sets how long to wait for a new connection to be established.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets how long a request on a multiplexed connection waits for its
+    /// response, 30 seconds by default.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets the exponential backoff applied to addresses that failed to dial.
+    ///
+    /// After `n` consecutive failures the next dial is refused for
+    /// `base * 2^(n-1)`, capped at `max`. A successful dial resets it.
+    pub fn with_dial_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.dial_backoff_base = base;
+        self.dial_backoff_max = max;
+        self
+    }
+
+    /// Sets how long a dial gets before the next address of a multi-address
+    /// peer is tried in parallel.
+    pub fn with_happy_eyeballs_delay(mut self, delay: Duration) -> Self {
+        self.happy_eyeballs_delay = delay;
+        self
+    }
+
+    /// Caps the number of open connections across all peers.
+    ///
+    /// When the cap is exceeded the least recently used idle connections are
+    /// closed, whichever peer they belong to. Connections in use are never
+    /// closed, so the cap can be exceeded while all of them are busy.
+    /// Multiplexed connections count as in use while they are open.
+    pub fn with_max_total_connections(mut self, max_total_connections: usize) -> Self {
+        self.max_total_connections = max_total_connections;
+        self
+    }
+
+    /// Registers further addresses of the peer reachable at `addr`.
+    ///
+    /// New connections to `addr` then race all addresses Happy Eyeballs
+    /// style (RFC 8305): each one gets a head start of the configured delay
+    /// before the next is dialed, and the first to connect wins. Connections
+    /// are still pooled under `addr`.
+    pub fn set_alt_addrs(&self, addr: SocketAddr, alt_addrs: Vec<SocketAddr>) {
+        if alt_addrs.is_empty() {
+            self.alt_addrs.remove(&addr);
+        } else {
+            self.alt_addrs.insert(addr, alt_addrs);
+        }
+    }
+
+    /// Further addresses registered for the peer at `addr`, if any.
+    pub fn alt_addrs(&self, addr: SocketAddr) -> Option<Vec<SocketAddr>> {
+        self.alt_addrs.get(&addr).map(|a| a.clone())
+    }
+
+    /// Drops what the pool keeps about the peer at `addr` once it is no
+    /// longer known: its idle connections, further addresses, dial backoff
+    /// and, unless a connection is still checked out, its connection slots.
+    pub fn forget_peer(&self, addr: SocketAddr) {
+        self.inner.remove(&addr);
+        let alt_addrs = self
+            .alt_addrs
+            .remove(&addr)
+            .map(|(_, alt_addrs)| alt_addrs)
+            .unwrap_or_default();
+        self.dial_failures
+            .retain(|(failed, _), _| *failed != addr && !alt_addrs.contains(failed));
+        self.slots.remove_if(&addr, |_, slots| {
+            slots.is_unused(self.max_connections_per_peer)
+        });
+    }
+
     /// Gets a connection to the specified address, either reusing an existing one
     /// or establishing a new connection.
     ///
@@ -75,105 +301,516 @@ impl ConnectionPool {
     ///
     /// Returns an error if:
     /// - Connection limit is reached
-    /// - Connection attempt times out (5s)
+    /// - Connection attempt times out (`connect_timeout`, 5s by default)
+    /// - The address is backing off after recent dial failures
+    /// - The pool has been shut down
     /// - Underlying IO error occurs
     pub async fn get_connection(&self, addr: SocketAddr) -> Result<PooledConnection> {
-        if let Some(conn) = self.try_get_healthy_connection(addr).await? {
-            return Ok(conn);
+        self.get_connection_prioritized(addr, Priority::Normal)
+            .await
+    }
+
+    /// Gets a connection like [`ConnectionPool::get_connection`], waiting
+    /// for it behind every [`Priority::Normal`] caller when `priority` is
+    /// [`Priority::Maintenance`] and all connections to `addr` are taken.
+    pub async fn get_connection_prioritized(
+        &self,
+        addr: SocketAddr,
+        priority: Priority,
+    ) -> Result<PooledConnection> {
+        if self.is_closed() {
+            return Err(anyhow!("Connection pool is shut down"));
         }
 
         let permit = self
-            .semaphore
-            .clone()
-            .acquire_owned()
+            .peer_slots(addr)
+            .acquire(priority)
             .await
             .context("Failed to acquire semaphore permit")?;
 
-        let stream = match timeout(Duration::from_secs(5), TcpStream::connect(addr)).await {
-            Ok(Ok(stream)) => stream,
-            Ok(Err(e)) => return Err(e.into()),
-            Err(_) => return Err(anyhow!("Connection timeout to {}", addr)),
-        };
+        if let Some((stream, transport)) = self.try_get_healthy_connection(addr) {
+            return Ok(PooledConnection::new(
+                stream,
+                transport,
+                addr,
+                self.clone(),
+                permit,
+            ));
+        }
+
+        self.evict_over_capacity();
+
+        let (stream, transport) = self.dial_peer(addr).await?;
+
+        Ok(PooledConnection::new(
+            stream,
+            transport,
+            addr,
+            self.clone(),
+            permit,
+        ))
+    }
+
+    /// Gets the multiplexed connection to the specified address, establishing
+    /// it if there is none or the previous one was closed.
+    ///
+    /// A single multiplexed connection per peer carries any number of
+    /// concurrent requests, so it is not subject to the per-peer limit. It
+    /// counts against the total one, see
+    /// [`with_max_total_connections`](Self::with_max_total_connections).
+    /// Callers that find none at the same time wait for a single dial.
+    pub async fn get_multiplexed(&self, addr: SocketAddr) -> Result<Arc<MuxConnection>> {
+        if self.is_closed() {
+            return Err(anyhow!("Connection pool is shut down"));
+        }
 
-        stream.set_nodelay(true)?;
+        let cell = self.multiplexed_cell(addr);
+        let conn = cell.get_or_try_init(|| self.dial_multiplexed(addr)).await?;
+        if !conn.is_closed() {
+            return Ok(Arc::clone(conn));
+        }
 
-        Ok(PooledConnection::new(stream, addr, self.clone(), permit))
+        // Replaces the closed connection, unless another caller already did
+        self.multiplexed
+            .remove_if(&addr, |_, current| Arc::ptr_eq(current, &cell));
+        self.multiplexed_cell(addr)
+            .get_or_try_init(|| self.dial_multiplexed(addr))
+            .await
+            .map(Arc::clone)
+    }
+
+    /// Makes sure an idle connection to `addr` is ready for the next caller.
+    ///
+    /// An existing live connection is marked as freshly used so the cleaner
+    /// keeps it; otherwise a new one is dialed and parked in the pool, at
+    /// [`Priority::Maintenance`].
+    pub async fn warm(&self, addr: SocketAddr) -> Result<()> {
+        if let Some(peer) = self.inner.get(&addr) {
+            let mut connections = peer.lock().unwrap();
+            connections.retain(|entry| entry.stream.is_alive());
+            if let Some(entry) = connections.last_mut() {
+                entry.last_used = Instant::now();
+                return Ok(());
+            }
+        }
+
+        let conn = self
+            .get_connection_prioritized(addr, Priority::Maintenance)
+            .await?;
+        drop(conn);
+        Ok(())
     }
 
     /// Starts a background task that periodically cleans up stale connections.
     ///
-    /// The cleaner runs at the specified interval and removes connections that
-    /// have exceeded the max idle time.
+    /// The cleaner runs at the specified interval, see
+    /// [`clean_stale_connections`](Self::clean_stale_connections). Starting
+    /// it again replaces the previous cleaner.
     pub fn start_cleaner(&self, interval: Duration) {
         let pool = self.clone();
-        tokio::spawn(async move {
+        let handle = tasks::spawn("pool-cleaner", async move {
             let mut interval = tokio::time::interval(interval);
             loop {
                 interval.tick().await;
-                pool.clean_stale_connections().await
+                pool.clean_stale_connections();
             }
         });
+
+        if let Some(previous) = self.cleaner.lock().unwrap().replace(handle) {
+            previous.abort();
+        }
     }
 
-    async fn try_get_healthy_connection(
+    /// Shuts the pool down.
+    ///
+    /// Stops the cleaner, closes all idle and multiplexed connections and
+    /// refuses new checkouts. Connections still checked out are closed when
+    /// dropped instead of being returned; this waits up to `deadline` for
+    /// them and returns how many were still in use when it gave up.
+    pub async fn shutdown(&self, deadline: Duration) -> usize {
+        self.closed.store(true, Ordering::Release);
+
+        if let Some(cleaner) = self.cleaner.lock().unwrap().take() {
+            cleaner.abort();
+        }
+        self.multiplexed.clear();
+        self.inner.clear();
+
+        let semaphores: Vec<Arc<Semaphore>> = self
+            .slots
+            .iter()
+            .map(|s| Arc::clone(s.semaphore()))
+            .collect();
+        let drained = timeout(deadline, async {
+            for semaphore in &semaphores {
+                let _ = semaphore
+                    .acquire_many(self.max_connections_per_peer as u32)
+                    .await;
+            }
+        })
+        .await;
+
+        let in_use = match drained {
+            Ok(()) => 0,
+            Err(_) => semaphores
+                .iter()
+                .map(|s| self.max_connections_per_peer - s.available_permits())
+                .sum(),
+        };
+
+        for semaphore in &semaphores {
+            semaphore.close();
+        }
+        in_use
+    }
+
+    /// Reports the idle, checked out and multiplexed connections and the
+    /// dial backoff of every peer.
+    pub async fn snapshot(&self) -> PoolSnapshot {
+        let mut peers: BTreeMap<SocketAddr, PeerConnections> = BTreeMap::new();
+
+        for peer in self.inner.iter() {
+            let idle = peer.lock().unwrap().len();
+            if idle > 0 {
+                peers.entry(*peer.key()).or_default().idle = idle;
+            }
+        }
+        for slots in self.slots.iter() {
+            let in_use = self.max_connections_per_peer - slots.semaphore().available_permits();
+            if in_use > 0 {
+                peers.entry(*slots.key()).or_default().in_use = in_use;
+            }
+        }
+        for cell in self.multiplexed.iter() {
+            if let Some(conn) = cell.get()
+                && !conn.is_closed()
+            {
+                peers.entry(*cell.key()).or_default().multiplexed = true;
+            }
+        }
+
+        let now = Instant::now();
+        for failure in self.dial_failures.iter() {
+            let (addr, index) = *failure.key();
+            let peer = peers.entry(addr).or_default();
+            peer.dial_failures += failure.failures;
+            if failure.retry_at > now {
+                peer.backing_off.push(self.transports[index].name);
+            }
+        }
+
+        PoolSnapshot {
+            closed: self.is_closed(),
+            peers,
+        }
+    }
+
+    /// Returns `true` once [`shutdown`](Self::shutdown) has been called.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Opens a new connection to the peer at `addr`, racing its other
+    /// addresses if it has any.
+    async fn dial_peer(&self, addr: SocketAddr) -> Result<(Box<dyn PeerStream>, usize)> {
+        let Some(alt_addrs) = self.alt_addrs(addr) else {
+            return self.dial(addr).await;
+        };
+
+        let mut candidates = std::iter::once(addr)
+            .chain(alt_addrs.into_iter().filter(|a| *a != addr))
+            .peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+
+        loop {
+            // Move on right away when every running attempt has failed
+            if attempts.is_empty() {
+                match candidates.next() {
+                    Some(candidate) => attempts.push(self.dial(candidate)),
+                    None => break,
+                }
+            }
+
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(dialed) => return Ok(dialed),
+                    Err(e) => last_error = Some(e),
+                },
+                _ = tokio::time::sleep(self.happy_eyeballs_delay), if candidates.peek().is_some() => {
+                    if let Some(candidate) = candidates.next() {
+                        attempts.push(self.dial(candidate));
+                    }
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("No address to dial for {}", addr)))
+    }
+
+    /// Opens a new connection to `addr` over the most preferred transport
+    /// that connects, returning it with the transport's index.
+    async fn dial(&self, addr: SocketAddr) -> Result<(Box<dyn PeerStream>, usize)> {
+        let mut last_error = None;
+
+        for (index, transport) in self.transports.iter().enumerate() {
+            match self.dial_transport(addr, index, transport).await {
+                Ok(stream) => return Ok((stream, index)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("No transport to dial {}", addr)))
+    }
+
+    /// Opens a new connection through one transport, honoring its dial
+    /// backoff for `addr`.
+    async fn dial_transport(
         &self,
         addr: SocketAddr,
-    ) -> Result<Option<PooledConnection>> {
-        let mut pool = self.inner.lock().await;
+        index: usize,
+        transport: &PoolTransport,
+    ) -> Result<Box<dyn PeerStream>> {
+        if let Some(backoff) = self.dial_failures.get(&(addr, index)) {
+            let now = Instant::now();
+            if backoff.retry_at > now {
+                return Err(anyhow!(
+                    "Backing off dialing {} over {} for {:?}",
+                    addr,
+                    transport.name,
+                    backoff.retry_at - now
+                ));
+            }
+        }
 
-        if let Some(connections) = pool.get_mut(&addr) {
-            while let Some(entry) = connections.pop() {
-                if entry.last_used.elapsed() < self.max_idle_time
-                    && entry.stream.peer_addr().is_ok()
-                {
-                    entry.stream.set_nodelay(true)?;
-
-                    let permit = self
-                        .semaphore
-                        .clone()
-                        .acquire_owned()
-                        .await
-                        .context("Failed to acquire semaphore permit")?;
-
-                    return Ok(Some(PooledConnection::new(
-                        entry.stream,
-                        addr,
-                        self.clone(),
-                        permit,
-                    )));
+        let connect_timeout = transport
+            .config
+            .connect_timeout
+            .unwrap_or(self.connect_timeout);
+        let result = match timeout(connect_timeout, transport.connector.connect(addr)).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow!(
+                "Connection timeout to {} over {}",
+                addr,
+                transport.name
+            )),
+        };
+
+        match &result {
+            Ok(_) => {
+                self.dial_failures.remove(&(addr, index));
+            }
+            Err(e) => {
+                debug!(%addr, transport = transport.name, error = %e, "dial failed");
+                self.record_dial_failure((addr, index));
+            }
+        }
+
+        result
+    }
+
+    fn record_dial_failure(&self, key: (SocketAddr, usize)) {
+        let mut backoff = self.dial_failures.entry(key).or_insert(DialBackoff {
+            failures: 0,
+            retry_at: Instant::now(),
+        });
+
+        backoff.failures = backoff.failures.saturating_add(1);
+        let delay = self
+            .dial_backoff_base
+            .saturating_mul(2u32.saturating_pow(backoff.failures - 1))
+            .min(self.dial_backoff_max);
+        backoff.retry_at = Instant::now() + delay;
+    }
+
+    /// Returns where the multiplexed connection to `addr` is kept.
+    fn multiplexed_cell(&self, addr: SocketAddr) -> Arc<OnceCell<Arc<MuxConnection>>> {
+        Arc::clone(&self.multiplexed.entry(addr).or_default())
+    }
+
+    /// Opens a new multiplexed connection to `addr`.
+    async fn dial_multiplexed(&self, addr: SocketAddr) -> Result<Arc<MuxConnection>> {
+        self.evict_over_capacity();
+        let (stream, _) = self.dial_peer(addr).await?;
+        Ok(Arc::new(
+            MuxConnection::new(stream).with_request_timeout(self.request_timeout),
+        ))
+    }
+
+    /// Number of multiplexed connections open or being dialed.
+    fn multiplexed_count(&self) -> usize {
+        self.multiplexed
+            .iter()
+            .filter(|cell| is_open_or_dialing(cell.value()))
+            .count()
+    }
+
+    /// Returns the slots limiting connections to `addr`.
+    fn peer_slots(&self, addr: SocketAddr) -> Arc<PeerSlots> {
+        self.slots
+            .entry(addr)
+            .or_insert_with(|| Arc::new(PeerSlots::new(self.max_connections_per_peer)))
+            .clone()
+    }
+
+    /// Takes the most recently used live connection to `addr` over the most
+    /// preferred transport that has one.
+    fn try_get_healthy_connection(&self, addr: SocketAddr) -> Option<(Box<dyn PeerStream>, usize)> {
+        let peer = self.inner.get(&addr)?;
+        let mut connections = peer.lock().unwrap();
+
+        for transport in 0..self.transports.len() {
+            while let Some(index) = connections
+                .iter()
+                .rposition(|entry| entry.transport == transport)
+            {
+                let entry = connections.remove(index);
+                if entry.last_used.elapsed() < self.max_idle_time && entry.stream.is_alive() {
+                    return Some((entry.stream, transport));
                 }
             }
         }
-        Ok(None)
+        None
     }
 
-    async fn return_connection(&self, addr: SocketAddr, stream: TcpStream) {
-        let _ = stream.set_nodelay(false);
+    pub(super) fn return_connection(
+        &self,
+        addr: SocketAddr,
+        transport: usize,
+        stream: Box<dyn PeerStream>,
+    ) {
+        if self.is_closed() {
+            return;
+        }
 
-        let mut pool = self.inner.lock().await;
-        pool.entry(addr)
-            .or_insert_with(Vec::new)
+        self.inner
+            .entry(addr)
+            .or_default()
+            .get_mut()
+            .unwrap()
             .push(ConnectionEntry {
                 stream,
+                transport,
                 last_used: Instant::now(),
             });
+
+        // Shutdown may have cleared the pool since the check above
+        if self.is_closed() {
+            self.inner.remove(&addr);
+            return;
+        }
+
+        self.evict_over_capacity();
     }
 
-    async fn clean_stale_connections(&self) {
-        let mut pool = self.inner.lock().await;
-        for (_, connections) in pool.iter_mut() {
-            connections.retain(|entry| entry.last_used.elapsed() < self.max_idle_time);
+    /// Closes least recently used idle connections until the number of open
+    /// connections fits `max_total_connections`.
+    ///
+    /// This visits every peer, so it is skipped when there is no cap.
+    fn evict_over_capacity(&self) {
+        if self.max_total_connections == usize::MAX {
+            return;
+        }
+
+        let active: usize = self
+            .slots
+            .iter()
+            .map(|s| self.max_connections_per_peer - s.semaphore().available_permits())
+            .sum::<usize>()
+            + self.multiplexed_count();
+        let mut idle: usize = self
+            .inner
+            .iter()
+            .map(|peer| peer.lock().unwrap().len())
+            .sum();
+
+        while idle > 0 && active + idle > self.max_total_connections {
+            let oldest = self
+                .inner
+                .iter()
+                .filter_map(|peer| {
+                    let connections = peer.lock().unwrap();
+                    let last_used = connections.iter().map(|entry| entry.last_used).min()?;
+                    Some((*peer.key(), last_used))
+                })
+                .min_by_key(|(_, last_used)| *last_used);
+
+            let Some((addr, _)) = oldest else {
+                break;
+            };
+
+            if let Some(peer) = self.inner.get(&addr) {
+                let mut connections = peer.lock().unwrap();
+                if let Some(index) = connections
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(index, _)| index)
+                {
+                    connections.remove(index);
+                }
+            }
+            self.inner.remove_if(&addr, |_, connections| {
+                connections.lock().unwrap().is_empty()
+            });
+            idle -= 1;
         }
     }
+
+    /// Closes the connections that exceeded the max idle time, forgets the
+    /// closed multiplexed ones, drops the slots of peers that have no
+    /// connection checked out, and forgets the dial failures of addresses
+    /// not tried again for the longest backoff since they could be.
+    pub fn clean_stale_connections(&self) {
+        let now = Instant::now();
+        self.dial_failures
+            .retain(|_, backoff| backoff.retry_at + self.dial_backoff_max > now);
+
+        self.multiplexed.retain(|_, cell| is_open_or_dialing(cell));
+
+        self.inner.retain(|_, connections| {
+            let connections = connections.get_mut().unwrap();
+            connections.retain(|entry| entry.last_used.elapsed() < self.max_idle_time);
+            !connections.is_empty()
+        });
+
+        self.slots
+            .retain(|_, slots| !slots.is_unused(self.max_connections_per_peer));
+    }
+}
+
+/// Whether the multiplexed connection kept in `cell` is open, or being
+/// dialed by a caller holding the cell.
+fn is_open_or_dialing(cell: &Arc<OnceCell<Arc<MuxConnection>>>) -> bool {
+    match cell.get() {
+        Some(conn) => !conn.is_closed(),
+        None => Arc::strong_count(cell) > 1,
+    }
 }
 
 #[cfg(test)]
 mod connection_pool_tests {
-    use std::time::Duration;
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
 
-    use tokio::{net::TcpListener, time::timeout};
+    use async_trait::async_trait;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
 
-    use crate::dht::connection::ConnectionPool;
+    use crate::dht::connection::{
+        ConnectionPool,
+        connector::{Connector, PeerStream, TcpConnector, TransportConfig},
+    };
 
     #[tokio::test]
     async fn test_connection_reuse() {
@@ -194,8 +831,7 @@ mod connection_pool_tests {
         let conn2 = pool.get_connection(addr).await.unwrap();
         drop(conn2);
 
-        let pool_inner = pool.inner.lock().await;
-        assert_eq!(pool_inner.get(&addr).unwrap().len(), 1);
+        assert_eq!(pool.snapshot().await.peers[&addr].idle, 1);
     }
 
     #[tokio::test]
@@ -226,4 +862,405 @@ mod connection_pool_tests {
         drop(conn2);
         drop(conn3);
     }
+
+    #[tokio::test]
+    async fn test_connection_limit_is_per_peer() {
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                loop {
+                    let _ = listener.accept().await;
+                }
+            });
+        }
+
+        let pool = ConnectionPool::new(1, Duration::from_secs(30));
+
+        let _conn1 = pool.get_connection(addrs[0]).await.unwrap();
+
+        assert!(
+            timeout(Duration::from_millis(100), pool.get_connection(addrs[1]))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_checkouts_to_many_peers() {
+        let mut addrs = Vec::new();
+        for _ in 0..8 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                let mut sockets = Vec::new();
+                while let Ok((socket, _)) = listener.accept().await {
+                    sockets.push(socket);
+                }
+            });
+        }
+
+        let dials = Arc::new(AtomicUsize::new(0));
+        let pool = ConnectionPool::new(1, Duration::from_secs(30))
+            .with_connector(CountingConnector(Arc::clone(&dials)));
+
+        let tasks: Vec<_> = addrs
+            .iter()
+            .map(|&addr| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    for _ in 0..20 {
+                        drop(pool.get_connection(addr).await.unwrap());
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Returned as soon as dropped, so every checkout after the first
+        // reuses the peer's connection
+        assert_eq!(dials.load(Ordering::SeqCst), addrs.len());
+        let snapshot = pool.snapshot().await;
+        assert!(addrs.iter().all(|addr| snapshot.peers[addr].idle == 1));
+    }
+
+    #[tokio::test]
+    async fn test_liveness_probe_detects_closed_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (server_side, _) = listener.accept().await.unwrap();
+        assert!(stream.is_alive());
+
+        drop(server_side);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!stream.is_alive());
+    }
+
+    #[tokio::test]
+    async fn test_global_cap_evicts_least_recently_used() {
+        let mut addrs = Vec::new();
+        for _ in 0..3 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+
+            tokio::spawn(async move {
+                let mut sockets = Vec::new();
+                while let Ok((socket, _)) = listener.accept().await {
+                    sockets.push(socket);
+                }
+            });
+        }
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30)).with_max_total_connections(2);
+
+        for addr in &addrs {
+            drop(pool.get_connection(*addr).await.unwrap());
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert!(!pool.inner.contains_key(&addrs[0]));
+        let snapshot = pool.snapshot().await;
+        assert_eq!(
+            snapshot.peers.values().map(|peer| peer.idle).sum::<usize>(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_parks_an_idle_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30));
+
+        pool.warm(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pool.warm(addr).await.unwrap();
+
+        assert_eq!(pool.snapshot().await.peers[&addr].idle, 1);
+    }
+
+    #[tokio::test]
+    async fn test_dial_backoff_after_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30))
+            .with_dial_backoff(Duration::from_secs(10), Duration::from_secs(60));
+
+        let first = pool.get_connection(addr).await.err().unwrap();
+        assert!(!first.to_string().contains("Backing off"));
+
+        let second = pool.get_connection(addr).await.err().unwrap();
+        assert!(second.to_string().contains("Backing off"));
+    }
+
+    #[tokio::test]
+    async fn test_dial_failures_are_swept_and_forgotten() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30))
+            .with_dial_backoff(Duration::from_millis(10), Duration::from_millis(10));
+
+        assert!(pool.get_connection(addr).await.is_err());
+        pool.clean_stale_connections();
+        assert_eq!(pool.dial_failures.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        pool.clean_stale_connections();
+        assert!(pool.dial_failures.is_empty());
+
+        assert!(pool.get_connection(addr).await.is_err());
+        pool.forget_peer(addr);
+        assert!(pool.dial_failures.is_empty());
+    }
+
+    struct CountingConnector(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Connector for CountingConnector {
+        async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            TcpConnector.connect(addr).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dials_through_custom_connector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let dials = Arc::new(AtomicUsize::new(0));
+        let pool = ConnectionPool::new(5, Duration::from_secs(30))
+            .with_connector(CountingConnector(Arc::clone(&dials)));
+
+        drop(pool.get_connection(addr).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(pool.get_connection(addr).await.unwrap());
+
+        assert_eq!(dials.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_checkouts_and_refuses_new_ones() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30));
+        pool.start_cleaner(Duration::from_secs(60));
+
+        drop(pool.get_connection(addr).await.unwrap());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let conn = pool.get_connection(addr).await.unwrap();
+
+        // Still checked out when the deadline passes
+        assert_eq!(pool.shutdown(Duration::from_millis(50)).await, 1);
+        drop(conn);
+
+        assert!(pool.inner.is_empty());
+        assert!(pool.get_connection(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_returned_checkouts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30));
+        let conn = pool.get_connection(addr).await.unwrap();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(conn);
+        });
+
+        assert_eq!(pool.shutdown(Duration::from_secs(1)).await, 0);
+        assert!(pool.inner.is_empty());
+    }
+
+    /// Never completes dials to `stalled`, like a broken address family.
+    struct StallingConnector {
+        stalled: SocketAddr,
+    }
+
+    #[async_trait]
+    impl Connector for StallingConnector {
+        async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+            if addr == self.stalled {
+                std::future::pending::<()>().await;
+            }
+            TcpConnector.connect(addr).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_races_alt_addrs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let stalled: SocketAddr = "[::1]:9".parse().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30))
+            .with_connector(StallingConnector { stalled })
+            .with_connect_timeout(Duration::from_secs(10))
+            .with_happy_eyeballs_delay(Duration::from_millis(20));
+        pool.set_alt_addrs(stalled, vec![reachable]);
+
+        let conn = timeout(Duration::from_secs(1), pool.get_connection(stalled))
+            .await
+            .expect("dial should not wait out the stalled address")
+            .unwrap();
+        drop(conn);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Pooled under the address it was requested for
+        assert_eq!(pool.snapshot().await.peers[&stalled].idle, 1);
+    }
+
+    /// Refuses every dial, like a transport the peer does not speak.
+    struct RefusingConnector(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Connector for RefusingConnector {
+        async fn connect(&self, _addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(io::ErrorKind::ConnectionRefused.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_less_preferred_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let refused = Arc::new(AtomicUsize::new(0));
+        let pool = ConnectionPool::new(5, Duration::from_secs(30))
+            .with_dial_backoff(Duration::from_secs(10), Duration::from_secs(60))
+            .with_connector(RefusingConnector(Arc::clone(&refused)))
+            .with_transport("tcp", TcpConnector, TransportConfig::default());
+
+        let first = pool.get_connection(addr).await.unwrap();
+        let second = pool.get_connection(addr).await.unwrap();
+        drop((first, second));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // The preferred transport is skipped while backing off
+        assert_eq!(refused.load(Ordering::SeqCst), 1);
+
+        let peer = pool.inner.get(&addr).unwrap();
+        let connections = peer.lock().unwrap();
+        assert_eq!(connections.len(), 2);
+        assert!(connections.iter().all(|entry| entry.transport == 1));
+    }
+
+    #[tokio::test]
+    async fn test_slots_of_idle_and_forgotten_peers_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let pool = ConnectionPool::new(5, Duration::from_secs(30));
+
+        let conn = pool.get_connection(addr).await.unwrap();
+        pool.clean_stale_connections();
+        pool.forget_peer(addr);
+        assert!(pool.slots.contains_key(&addr));
+
+        drop(conn);
+        pool.clean_stale_connections();
+        assert!(!pool.slots.contains_key(&addr));
+        assert_eq!(pool.snapshot().await.peers[&addr].idle, 1);
+
+        drop(pool.get_connection(addr).await.unwrap());
+        pool.forget_peer(addr);
+        assert!(!pool.slots.contains_key(&addr));
+        assert!(!pool.inner.contains_key(&addr));
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_connections_are_dialed_once_and_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let dials = Arc::new(AtomicUsize::new(0));
+        let pool = ConnectionPool::new(5, Duration::from_secs(30))
+            .with_connector(CountingConnector(Arc::clone(&dials)))
+            .with_max_total_connections(1);
+
+        drop(pool.get_connection(addr).await.unwrap());
+        assert_eq!(pool.snapshot().await.peers[&addr].idle, 1);
+
+        let (a, b) = tokio::join!(pool.get_multiplexed(addr), pool.get_multiplexed(addr));
+        assert!(Arc::ptr_eq(&a.unwrap(), &b.unwrap()));
+        assert_eq!(dials.load(Ordering::SeqCst), 2);
+
+        // The multiplexed connection took the idle one's place under the cap
+        let snapshot = pool.snapshot().await;
+        assert_eq!(snapshot.peers[&addr].idle, 0);
+        assert!(snapshot.peers[&addr].multiplexed);
+    }
 }
@@ -0,0 +1,383 @@
+//! Stream multiplexing over a single TCP connection.
+//!
+//! A [`MuxConnection`] carries many concurrent request/response streams over
+//! one socket. Every frame is tagged with a stream id so responses can arrive
+//! in any order:
+//!
+//! ```text
+//! +----------------+----------------+-----------------+
+//! | length (u32 BE)| stream id (u32)| payload         |
+//! +----------------+----------------+-----------------+
+//! ```
+//!
+//! `length` covers the stream id and the payload, which is at most
+//! [`MAX_FRAME_LEN`] bytes. The serving side is implemented by [`serve`].
+
+use std::{
+    future::Future,
+    io::IoSlice,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use dashmap::DashMap;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, WriteHalf},
+    net::TcpStream,
+    sync::{Mutex, oneshot},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::dht::{
+    connection::{
+        buffers::FRAME_BUFFERS,
+        connector::PeerStream,
+        transport::{MAX_FRAME_LEN, write_all_vectored},
+    },
+    tasks,
+};
+
+type PendingStreams = Arc<DashMap<u32, oneshot::Sender<Vec<u8>>>>;
+
+/// How long a request waits for its response unless set otherwise
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client-side multiplexed connection to a single peer.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_p2p_node::dht::connection::mux::MuxConnection;
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let addr = "127.0.0.1:8080".parse().unwrap();
+///     let conn = MuxConnection::connect(addr, Duration::from_secs(3)).await.unwrap();
+///
+///     // Requests may run concurrently over the same socket
+///     let (a, b) = tokio::join!(conn.request(b"first"), conn.request(b"second"));
+/// }
+/// ```
+pub struct MuxConnection {
+    writer: Mutex<WriteHalf<Box<dyn PeerStream>>>,
+    pending: PendingStreams,
+    next_stream: AtomicU32,
+    closed: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+    request_timeout: Duration,
+}
+
+impl MuxConnection {
+    /// Connects to `addr` and starts demultiplexing its responses.
+    ///
+    /// `timeout_after` bounds both the connection attempt and every request
+    /// made on it.
+    pub async fn connect(addr: SocketAddr, timeout_after: Duration) -> Result<Arc<Self>> {
+        let stream = match timeout(timeout_after, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(anyhow!("Connection timeout to {}", addr)),
+        };
+        stream.set_nodelay(true)?;
+
+        Ok(Arc::new(
+            Self::new(Box::new(stream)).with_request_timeout(timeout_after),
+        ))
+    }
+
+    /// Wraps an established stream. Requests wait 30 seconds for their
+    /// response, see [`MuxConnection::with_request_timeout`].
+    pub fn new(stream: Box<dyn PeerStream>) -> Self {
+        let (mut read_half, write_half) = io::split(stream);
+        let pending: PendingStreams = Arc::new(DashMap::new());
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let reader = {
+            let pending = Arc::clone(&pending);
+            let closed = Arc::clone(&closed);
+            tasks::spawn("mux-reader", async move {
+                while let Ok((stream_id, payload)) = read_frame(&mut read_half).await {
+                    if let Some((_, sender)) = pending.remove(&stream_id) {
+                        let _ = sender.send(payload);
+                    }
+                }
+
+                closed.store(true, Ordering::Release);
+                pending.clear();
+            })
+        };
+
+        Self {
+            writer: Mutex::new(write_half),
+            pending,
+            next_stream: AtomicU32::new(0),
+            closed,
+            reader,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a request waits for its response before failing.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sends `payload` on a new stream and waits for its response.
+    pub async fn request(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        if self.is_closed() {
+            return Err(anyhow!("Multiplexed connection is closed"));
+        }
+
+        let stream_id = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(stream_id, sender);
+        let mut guard = StreamGuard {
+            conn: self,
+            stream_id,
+            writing: false,
+        };
+
+        {
+            let mut writer = self.writer.lock().await;
+            guard.writing = true;
+            write_frame(&mut *writer, stream_id, payload).await?;
+            guard.writing = false;
+        }
+
+        // The guard forgets the stream if no response arrives in time
+        match timeout(self.request_timeout, receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!("Multiplexed connection closed before response")),
+            Err(_) => Err(anyhow!(
+                "No response on stream {} within {:?}",
+                stream_id,
+                self.request_timeout
+            )),
+        }
+    }
+
+    /// Returns `true` once the underlying socket has failed or been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Forgets a stream once its request completes, fails or is dropped.
+struct StreamGuard<'a> {
+    conn: &'a MuxConnection,
+    stream_id: u32,
+    /// Set while the request frame may be partially written
+    writing: bool,
+}
+
+impl Drop for StreamGuard<'_> {
+    fn drop(&mut self) {
+        self.conn.pending.remove(&self.stream_id);
+        // A partial frame desynchronizes the stream for every other request
+        if self.writing {
+            self.conn.closed.store(true, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for MuxConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Serves multiplexed requests arriving on `stream` until it is closed.
+///
+/// Every request is handled on its own task, so a slow request does not hold
+/// up the others on the same connection.
+pub async fn serve<S, F, Fut>(stream: S, handler: F)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    F: Fn(Vec<u8>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Vec<u8>> + Send + 'static,
+{
+    let (mut read_half, write_half) = io::split(stream);
+    let writer = Arc::new(Mutex::new(write_half));
+
+    while let Ok((stream_id, payload)) = read_frame(&mut read_half).await {
+        let response = handler(payload);
+        let writer = Arc::clone(&writer);
+        tasks::spawn("mux-response", async move {
+            let response = response.await;
+            let mut writer = writer.lock().await;
+            let _ = write_frame(&mut *writer, stream_id, &response).await;
+            FRAME_BUFFERS.give(response);
+        });
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u32, Vec<u8>)> {
+    let mut len_buf = [0u8; 4];
+    reader
+        .read_exact(&mut len_buf)
+        .await
+        .context("Failed to read frame length")?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len < 4 {
+        return Err(anyhow!("Frame too short"));
+    }
+    if len - 4 > MAX_FRAME_LEN {
+        return Err(anyhow!(
+            "Frame of {} bytes exceeds the limit of {} bytes",
+            len - 4,
+            MAX_FRAME_LEN
+        ));
+    }
+
+    let mut id_buf = [0u8; 4];
+    reader
+        .read_exact(&mut id_buf)
+        .await
+        .context("Failed to read stream id")?;
+
+    let mut payload = FRAME_BUFFERS.take();
+    payload.resize(len - 4, 0);
+    reader
+        .read_exact(&mut payload)
+        .await
+        .context("Failed to read frame payload")?;
+
+    Ok((u32::from_be_bytes(id_buf), payload))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    stream_id: u32,
+    payload: &[u8],
+) -> Result<()> {
+    let len = (payload.len() as u32 + 4).to_be_bytes();
+    let stream_id = stream_id.to_be_bytes();
+
+    write_all_vectored(
+        writer,
+        &mut [
+            IoSlice::new(&len),
+            IoSlice::new(&stream_id),
+            IoSlice::new(payload),
+        ],
+    )
+    .await
+    .context("Failed to send frame")
+}
+
+#[cfg(test)]
+mod mux_tests {
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use tokio::net::TcpListener;
+
+    use crate::dht::connection::{
+        mux::{MuxConnection, read_frame, serve},
+        transport::MAX_FRAME_LEN,
+    };
+
+    #[tokio::test]
+    async fn test_concurrent_streams_share_one_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            serve(socket, |payload| async move {
+                // Answer later requests first to exercise out-of-order replies
+                tokio::time::sleep(Duration::from_millis(50 - payload[0] as u64 * 5)).await;
+                payload
+            })
+            .await;
+        });
+
+        let conn = MuxConnection::connect(addr, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let responses = join_all((0..10u8).map(|i| {
+            let conn = &conn;
+            async move { conn.request(&[i]).await.unwrap() }
+        }))
+        .await;
+
+        for (i, response) in responses.into_iter().enumerate() {
+            assert_eq!(response, vec![i as u8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dropped_request_forgets_its_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            serve(socket, |payload| async move {
+                if payload == b"slow" {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                payload
+            })
+            .await;
+        });
+
+        let conn = MuxConnection::connect(addr, Duration::from_secs(1))
+            .await
+            .unwrap();
+
+        let dropped = tokio::time::timeout(Duration::from_millis(50), conn.request(b"slow")).await;
+        assert!(dropped.is_err());
+        assert!(conn.pending.is_empty());
+
+        // The connection stays usable for other streams
+        assert_eq!(conn.request(b"fast").await.unwrap(), b"fast");
+    }
+
+    #[tokio::test]
+    async fn test_requests_time_out_and_forget_their_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            serve(socket, |payload| async move {
+                if payload == b"lost" {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                payload
+            })
+            .await;
+        });
+
+        let conn = MuxConnection::connect(addr, Duration::from_millis(100))
+            .await
+            .unwrap();
+        let error = conn.request(b"lost").await.unwrap_err();
+        assert!(error.to_string().contains("No response"));
+        assert!(conn.pending.is_empty());
+        assert_eq!(conn.request(b"found").await.unwrap(), b"found");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames_are_refused() {
+        let mut header = ((MAX_FRAME_LEN + 5) as u32).to_be_bytes().to_vec();
+        header.extend_from_slice(&0u32.to_be_bytes());
+        let error = read_frame(&mut header.as_slice()).await.unwrap_err();
+        assert!(error.to_string().contains("exceeds the limit"));
+
+        let mut header = (u32::MAX).to_be_bytes().to_vec();
+        header.extend_from_slice(&0u32.to_be_bytes());
+        assert!(read_frame(&mut header.as_slice()).await.is_err());
+    }
+}
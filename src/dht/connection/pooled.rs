@@ -1,7 +1,8 @@
 //! A module for managing pooled TCP connections in a DHT network.
 //!
-//! The [`PooledConnection`] struct provides a wrapper around [`TcpStream`] that
-//! automatically returns the connection to the pool when dropped.
+//! The [`PooledConnection`] struct provides a wrapper around a [`PeerStream`] that
+//! automatically returns the connection to the pool when dropped, unless it
+//! is dropped in the middle of an exchange.
 
 use std::{
     net::SocketAddr,
@@ -10,15 +11,14 @@ use std::{
 
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpStream,
     sync::OwnedSemaphorePermit,
 };
 
-use crate::dht::connection::ConnectionPool;
+use crate::dht::connection::{ConnectionPool, connector::PeerStream};
 
-/// A TCP connection that returns itself to the [`ConnectionPool`] when dropped.
+/// A connection that returns itself to the [`ConnectionPool`] when dropped.
 ///
-/// This wrapper provides transparent access to the underlying [`TcpStream`]
+/// This wrapper provides transparent access to the underlying [`PeerStream`]
 /// through [`Deref`] and [`DerefMut`] implementations, while ensuring proper
 /// connection pooling behavior.
 ///
@@ -37,7 +37,7 @@ use crate::dht::connection::ConnectionPool;
 ///     // Get a connection from the pool
 ///     let mut conn = pool.get_connection(addr).await.unwrap();
 ///     
-///     // Use the connection as a regular TcpStream
+///     // Use the connection as a regular stream
 ///     conn.write_all(b"hello").await.unwrap();
 ///     
 ///     // Connection automatically returns to pool when dropped
@@ -45,11 +45,13 @@ use crate::dht::connection::ConnectionPool;
 /// ```
 pub struct PooledConnection {
     inner: Option<PooledConnectionInner>,
+    in_exchange: bool,
 }
 
 /// Internal representation of a pooled connection
 struct PooledConnectionInner {
-    stream: TcpStream,
+    stream: Box<dyn PeerStream>,
+    transport: usize,
     addr: SocketAddr,
     pool: ConnectionPool,
     _permit: OwnedSemaphorePermit,
@@ -60,7 +62,8 @@ impl PooledConnection {
     ///
     /// # Arguments
     ///
-    /// * `stream` - The TCP stream to wrap
+    /// * `stream` - The stream to wrap
+    /// * `transport` - Index of the pool transport the stream was dialed over
     /// * `addr` - The remote address of the connection
     /// * `pool` - The connection pool to return to
     /// * `permit` - Semaphore permit tracking pool capacity
@@ -70,7 +73,8 @@ impl PooledConnection {
     /// This is typically called internally by [`ConnectionPool`]. Most users
     /// should use [`ConnectionPool::get_connection`] instead.
     pub fn new(
-        stream: TcpStream,
+        stream: Box<dyn PeerStream>,
+        transport: usize,
         addr: SocketAddr,
         pool: ConnectionPool,
         permit: OwnedSemaphorePermit,
@@ -78,13 +82,32 @@ impl PooledConnection {
         Self {
             inner: Some(PooledConnectionInner {
                 stream,
+                transport,
                 addr,
                 pool,
                 _permit: permit,
             }),
+            in_exchange: false,
         }
     }
 
+    /// Marks the start of a request/response exchange.
+    ///
+    /// Until [`end_exchange`](Self::end_exchange) is called, dropping the
+    /// connection closes it instead of returning it to the pool, so a
+    /// cancelled or failed exchange never leaves a half-written request or
+    /// an unread response for the next user.
+    pub fn begin_exchange(&mut self) {
+        self.in_exchange = true;
+    }
+
+    /// Marks the end of the exchange started by
+    /// [`begin_exchange`](Self::begin_exchange); the connection can be
+    /// reused again.
+    pub fn end_exchange(&mut self) {
+        self.in_exchange = false;
+    }
+
     // /// Explicitly close the connection without returning it to the pool.
     // ///
     // /// This is useful when you know the connection is in a bad state and
@@ -101,13 +124,23 @@ impl PooledConnection {
 impl Drop for PooledConnection {
     fn drop(&mut self) {
         if let Some(inner) = self.inner.take() {
-            let pool = inner.pool.clone();
-            let addr = inner.addr;
-            let stream = inner.stream;
+            // A shut down pool takes nothing back, nor is a stream left in an
+            // unknown state reused; the stream is closed here.
+            if inner.pool.is_closed() || self.in_exchange {
+                return;
+            }
 
-            tokio::spawn(async move {
-                pool.return_connection(addr, stream).await;
-            });
+            let PooledConnectionInner {
+                stream,
+                transport,
+                addr,
+                pool,
+                _permit,
+            } = inner;
+            // Released first, so the returned stream is not counted twice
+            // against the pool's total
+            drop(_permit);
+            pool.return_connection(addr, transport, stream);
         }
     }
 }
@@ -143,6 +176,26 @@ impl AsyncWrite for PooledConnection {
         }
     }
 
+    fn poll_write_vectored(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        match &mut self.inner {
+            Some(inner) => std::pin::Pin::new(&mut inner.stream).poll_write_vectored(cx, bufs),
+            None => std::task::Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Connection already returned to pool",
+            ))),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_some_and(|inner| inner.stream.is_write_vectored())
+    }
+
     fn poll_flush(
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
@@ -171,10 +224,10 @@ impl AsyncWrite for PooledConnection {
 }
 
 impl Deref for PooledConnection {
-    type Target = TcpStream;
+    type Target = dyn PeerStream;
 
     fn deref(&self) -> &Self::Target {
-        &self
+        &*self
             .inner
             .as_ref()
             .expect("PooledConnection is empty")
@@ -184,7 +237,7 @@ impl Deref for PooledConnection {
 
 impl DerefMut for PooledConnection {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self
+        &mut *self
             .inner
             .as_mut()
             .expect("PooledConnection is empty")
@@ -0,0 +1,137 @@
+//! Per-peer connection slots, handed out by priority.
+//!
+//! A peer's slots are a semaphore, which serves its waiters in arrival order.
+//! [`Priority::Maintenance`] callers stay out of that queue while a
+//! [`Priority::Normal`] caller is in it, and leave it when one arrives, so
+//! user operations are not stuck behind background work once every
+//! connection to a peer is taken.
+
+use std::{
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use tokio::sync::{AcquireError, Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::dht::overload::Priority;
+
+/// The connection slots to one peer.
+#[derive(Debug)]
+pub(super) struct PeerSlots {
+    semaphore: Arc<Semaphore>,
+    /// [`Priority::Normal`] callers waiting for a slot
+    normal_waiting: AtomicUsize,
+    /// Notified when `normal_waiting` goes up or down
+    changed: Notify,
+}
+
+/// Counts a [`Priority::Normal`] caller as waiting until dropped.
+struct Waiting<'a>(&'a PeerSlots);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.normal_waiting.fetch_sub(1, Ordering::AcqRel);
+        self.0.changed.notify_waiters();
+    }
+}
+
+impl PeerSlots {
+    pub(super) fn new(slots: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(slots)),
+            normal_waiting: AtomicUsize::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    pub(super) fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.semaphore
+    }
+
+    /// Whether no caller holds or waits for one of the `slots`, so that
+    /// dropping them loses nothing. Callers wait on a clone of `self`.
+    pub(super) fn is_unused(self: &Arc<Self>, slots: usize) -> bool {
+        Arc::strong_count(self) == 1 && self.semaphore.available_permits() == slots
+    }
+
+    /// Waits for a slot, behind every [`Priority::Normal`] caller when
+    /// `priority` is [`Priority::Maintenance`].
+    pub(super) async fn acquire(
+        &self,
+        priority: Priority,
+    ) -> Result<OwnedSemaphorePermit, AcquireError> {
+        match priority {
+            Priority::Normal => {
+                self.normal_waiting.fetch_add(1, Ordering::AcqRel);
+                self.changed.notify_waiters();
+                let _waiting = Waiting(self);
+                Arc::clone(&self.semaphore).acquire_owned().await
+            }
+            Priority::Maintenance => loop {
+                let mut changed = pin!(self.changed.notified());
+                changed.as_mut().enable();
+                if self.normal_waiting.load(Ordering::Acquire) > 0 {
+                    changed.await;
+                    continue;
+                }
+                // A caller who arrived meanwhile goes first, even if a slot
+                // was already handed to this one: leaving the queue passes
+                // it on
+                tokio::select! {
+                    biased;
+                    _ = changed => {}
+                    permit = Arc::clone(&self.semaphore).acquire_owned() => return permit,
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod slots_tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::PeerSlots;
+    use crate::dht::overload::Priority;
+
+    #[tokio::test]
+    async fn test_normal_callers_go_first() {
+        let slots = PeerSlots::new(1);
+        let held = slots.acquire(Priority::Normal).await.unwrap();
+
+        let mut maintenance = Box::pin(slots.acquire(Priority::Maintenance));
+        assert!(
+            timeout(Duration::from_millis(20), maintenance.as_mut())
+                .await
+                .is_err()
+        );
+        let mut normal = Box::pin(slots.acquire(Priority::Normal));
+        assert!(
+            timeout(Duration::from_millis(20), normal.as_mut())
+                .await
+                .is_err()
+        );
+
+        // Queued later, but served first
+        drop(held);
+        assert!(
+            timeout(Duration::from_millis(20), maintenance.as_mut())
+                .await
+                .is_err()
+        );
+        let normal = normal.await.unwrap();
+        assert!(
+            timeout(Duration::from_millis(20), maintenance.as_mut())
+                .await
+                .is_err()
+        );
+
+        drop(normal);
+        assert!(maintenance.await.is_ok());
+    }
+}
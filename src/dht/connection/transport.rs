@@ -0,0 +1,394 @@
+//! Running nodes over something other than TCP.
+//!
+//! A [`Transport`] both dials peers, as a [`Connector`], and accepts their
+//! connections through a [`Listener`]. [`DhtNode::with_network`] runs a node
+//! entirely over one, so the DHT can be embedded in an overlay network or an
+//! application's own channels. Whatever the transport, RPCs travel as
+//! length-prefixed frames, see [`write_frame`] and [`read_frame`], and are
+//! answered by [`DhtNode::handle_rpc`].
+//!
+//! [`TcpConnector`] is the default transport; [`MemoryTransport`] connects
+//! nodes within one process, which is handy for tests and simulations.
+//!
+//! [`DhtNode::with_network`]: crate::dht::DhtNode::with_network
+//! [`DhtNode::handle_rpc`]: crate::dht::DhtNode::handle_rpc
+
+use std::{
+    io::{self, IoSlice},
+    net::SocketAddr,
+    sync::{Arc, Weak},
+};
+
+use async_trait::async_trait;
+use dashmap::{DashMap, mapref::entry::Entry};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+use crate::dht::connection::{
+    buffers::FRAME_BUFFERS,
+    connector::{Connector, PeerStream, TcpConnector},
+};
+
+/// Largest frame accepted by [`read_frame`] from a peer.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Accepts connections from peers, as produced by [`Transport::bind`].
+#[async_trait]
+pub trait Listener: Send {
+    /// Waits for the next incoming stream, with the peer's address when the
+    /// transport knows it.
+    async fn accept(&mut self) -> io::Result<(Box<dyn PeerStream>, Option<SocketAddr>)>;
+}
+
+/// A way for nodes to reach each other: dials peers and listens for them.
+///
+/// # Examples
+///
+/// ```
+/// use async_trait::async_trait;
+/// use rust_p2p_node::dht::connection::{
+///     connector::{Connector, PeerStream, TcpConnector},
+///     transport::{Listener, Transport},
+/// };
+/// use std::{io, net::SocketAddr};
+///
+/// /// Plain TCP, announcing every listener it opens.
+/// struct LoudTcp;
+///
+/// #[async_trait]
+/// impl Connector for LoudTcp {
+///     async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+///         TcpConnector.connect(addr).await
+///     }
+/// }
+///
+/// #[async_trait]
+/// impl Transport for LoudTcp {
+///     async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+///         println!("listening on {}", addr);
+///         TcpConnector.bind(addr).await
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Transport: Connector {
+    /// Starts accepting connections addressed to `addr`.
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>>;
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&mut self) -> io::Result<(Box<dyn PeerStream>, Option<SocketAddr>)> {
+        let (stream, peer) = TcpListener::accept(self).await?;
+        stream.set_nodelay(true)?;
+        Ok((Box::new(stream), Some(peer)))
+    }
+}
+
+#[async_trait]
+impl Transport for TcpConnector {
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        Ok(Box::new(TcpListener::bind(addr).await?))
+    }
+}
+
+/// Writes `payload` as one frame: its length as a big-endian `u32`, then
+/// the payload itself.
+///
+/// Both go out in a single vectored write when the writer supports it.
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Frame too large"))?;
+    write_all_vectored(
+        writer,
+        &mut [IoSlice::new(&len.to_be_bytes()), IoSlice::new(payload)],
+    )
+    .await
+}
+
+/// Writes every buffer in `bufs`, in order, resuming after partial writes.
+///
+/// Writers without vectored writes get one buffer per call.
+pub(crate) async fn write_all_vectored<W>(
+    writer: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    // Drops leading empty buffers, which would read as a failed write
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs).await? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written => IoSlice::advance_slices(&mut bufs, written),
+        }
+    }
+    Ok(())
+}
+
+/// Reads one frame written by [`write_frame`], into a buffer of
+/// [`FRAME_BUFFERS`].
+///
+/// Fails with [`io::ErrorKind::InvalidData`] for frames longer than
+/// `max_len`, and with [`io::ErrorKind::UnexpectedEof`] if the stream ends
+/// first.
+pub async fn read_frame<R>(reader: &mut R, max_len: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the limit", len),
+        ));
+    }
+
+    let mut payload = FRAME_BUFFERS.take();
+    payload.resize(len, 0);
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+type MemoryListeners = DashMap<SocketAddr, mpsc::UnboundedSender<DuplexStream>>;
+
+/// In-process transport connecting the nodes that share it.
+///
+/// Addresses are only names here; nothing is bound on the host.
+///
+/// # Examples
+///
+/// ```
+/// use rust_p2p_node::dht::{DhtNode, connection::transport::MemoryTransport};
+///
+/// let network = MemoryTransport::new();
+/// let a = DhtNode::new("10.0.0.1:1".parse().unwrap(), None).with_network(network.clone());
+/// let b = DhtNode::new("10.0.0.2:1".parse().unwrap(), None).with_network(network);
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<MemoryListeners>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Bytes buffered in each direction of an in-memory connection.
+const MEMORY_BUFFER: usize = 64 * 1024;
+
+#[async_trait]
+impl Connector for MemoryTransport {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, addr.to_string());
+        let listener = self.listeners.get(&addr).ok_or_else(refused)?;
+
+        let (local, remote) = tokio::io::duplex(MEMORY_BUFFER);
+        listener.send(remote).map_err(|_| refused())?;
+        Ok(Box::new(local))
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        match self.listeners.entry(addr) {
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, addr.to_string()));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+            }
+        }
+
+        Ok(Box::new(MemoryListener {
+            addr,
+            incoming: receiver,
+            listeners: Arc::downgrade(&self.listeners),
+        }))
+    }
+}
+
+/// The in-memory streams are not probed: a stream whose peer went away is
+/// only noticed when it is used.
+impl PeerStream for DuplexStream {
+    fn is_alive(&self) -> bool {
+        true
+    }
+}
+
+struct MemoryListener {
+    addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<DuplexStream>,
+    listeners: Weak<MemoryListeners>,
+}
+
+#[async_trait]
+impl Listener for MemoryListener {
+    async fn accept(&mut self) -> io::Result<(Box<dyn PeerStream>, Option<SocketAddr>)> {
+        match self.incoming.recv().await {
+            Some(stream) => Ok((Box::new(stream), None)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Listener was unbound",
+            )),
+        }
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        if let Some(listeners) = self.listeners.upgrade() {
+            listeners.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use std::{
+        io::{self, IoSlice},
+        net::SocketAddr,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use bytes::Bytes;
+    use tokio::io::AsyncWrite;
+
+    use crate::dht::{
+        DhtNode,
+        config::DhtConfig,
+        connection::{connector::Connector, transport::Transport},
+    };
+
+    use super::{MemoryTransport, read_frame, write_frame};
+
+    /// Records every write call, taking at most `limit` bytes from each.
+    struct RecordingWriter {
+        written: Vec<u8>,
+        calls: usize,
+        limit: usize,
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            self.calls += 1;
+            let mut taken = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.limit - taken);
+                self.written.extend_from_slice(&buf[..take]);
+                taken += take;
+            }
+            Poll::Ready(Ok(taken))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_written_in_one_call() {
+        for (limit, calls) in [(usize::MAX, 1), (3, 4)] {
+            let mut writer = RecordingWriter {
+                written: Vec::new(),
+                calls: 0,
+                limit,
+            };
+            write_frame(&mut writer, b"payload").await.unwrap();
+            assert_eq!(writer.calls, calls);
+
+            let frame = read_frame(&mut &writer.written[..], 1024).await.unwrap();
+            assert_eq!(frame, b"payload");
+        }
+
+        // An empty payload needs no second write either
+        let mut writer = RecordingWriter {
+            written: Vec::new(),
+            calls: 0,
+            limit: usize::MAX,
+        };
+        write_frame(&mut writer, b"").await.unwrap();
+        assert_eq!((writer.calls, writer.written.len()), (1, 4));
+    }
+
+    fn memory_node(network: &MemoryTransport, addr: &str, multiplexing: bool) -> DhtNode {
+        let mut config = DhtConfig::default();
+        config.connection_pool.multiplexing = multiplexing;
+        DhtNode::new(addr.parse().unwrap(), Some(config)).with_network(network.clone())
+    }
+
+    fn link(a: &DhtNode, b: &DhtNode) {
+        a.add_peer(b.peer_info());
+        b.add_peer(a.peer_info());
+    }
+
+    #[tokio::test]
+    async fn test_nodes_talk_over_memory_transport() {
+        for multiplexing in [false, true] {
+            let network = MemoryTransport::new();
+            let a = memory_node(&network, "10.0.0.1:1", multiplexing);
+            let b = memory_node(&network, "10.0.0.2:1", multiplexing);
+            let _servers = (a.listen().await.unwrap(), b.listen().await.unwrap());
+            link(&a, &b);
+
+            a.store(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+            assert!(b.local_value(b"key").is_some());
+
+            b.drop_key(b"key");
+            assert_eq!(
+                b.find_value(b"key".to_vec()).await,
+                Some(Bytes::from_static(b"value"))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_addresses_are_exclusive() {
+        let network = MemoryTransport::new();
+        let addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+
+        let listener = network.bind(addr).await.unwrap();
+        let error = network.bind(addr).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        drop(listener);
+        let error = network.connect(addr).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(network.bind(addr).await.is_ok());
+    }
+}
@@ -0,0 +1,372 @@
+//! Socket I/O through io_uring, on Linux.
+//!
+//! [`UringTransport`] is a [`Transport`] whose sockets are driven by
+//! [tokio-uring] on a dedicated thread: reads, writes, connects and accepts
+//! complete through io_uring instead of epoll readiness and a syscall each.
+//! The rest of the node keeps running on tokio as usual. tokio-uring sockets
+//! cannot leave the thread that opened them, while pooled connections move
+//! between the node's tasks, so the node gets each socket as a stream that
+//! passes buffers to and from that thread: the buffers read are handed over
+//! as they are, and each write, a whole frame when vectored, goes out as one
+//! buffer, without being copied on the way.
+//!
+//! ```no_run
+//! use rust_p2p_node::dht::{DhtNode, connection::uring::UringTransport};
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None)
+//!     .with_network(UringTransport::new()?);
+//! node.listen().await.unwrap();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Needs a kernel with io_uring (5.10 or later) that does not forbid it;
+//! [`UringTransport::new`] fails otherwise.
+//!
+//! [tokio-uring]: https://docs.rs/tokio-uring
+
+use std::{
+    io::{self, IoSlice},
+    net::SocketAddr,
+    os::fd::{AsRawFd, BorrowedFd},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, ready},
+    thread,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+use tokio_uring::net::{TcpListener, TcpStream};
+use tokio_util::sync::PollSender;
+use tracing::debug;
+
+use crate::dht::connection::{
+    connector::{Connector, PeerStream},
+    transport::{Listener, Transport},
+};
+
+/// Buffers queued each way between a socket and the stream handed to the
+/// node
+const QUEUED_BUFFERS: usize = 4;
+/// Size of the buffers each socket is read into
+const IO_BUFFER: usize = 16 * 1024;
+/// Accepted connections waiting for [`Listener::accept`]
+const ACCEPT_BACKLOG: usize = 128;
+
+type Incoming = mpsc::Receiver<(UringStream, SocketAddr)>;
+
+/// Work for the io_uring thread.
+enum Command {
+    Connect(SocketAddr, oneshot::Sender<io::Result<UringStream>>),
+    Bind(SocketAddr, oneshot::Sender<io::Result<Incoming>>),
+}
+
+/// A transport running its sockets on an io_uring thread.
+///
+/// Clones share the thread, which stops once every clone is dropped, closing
+/// the listeners and connections it still drives.
+#[derive(Clone)]
+pub struct UringTransport {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl UringTransport {
+    /// Starts the io_uring thread.
+    ///
+    /// Fails if the kernel does not offer io_uring.
+    pub fn new() -> io::Result<Self> {
+        let (commands, mut receiver) = mpsc::unbounded_channel();
+        let (started, start) = std::sync::mpsc::sync_channel(1);
+
+        // The runtime is not Send, so it is created on its own thread, which
+        // reports whether that worked
+        thread::Builder::new()
+            .name("dht-uring".to_string())
+            .spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => {
+                        let _ = started.send(Ok(()));
+                        runtime
+                    }
+                    Err(e) => {
+                        let _ = started.send(Err(e));
+                        return;
+                    }
+                };
+                runtime.block_on(async move {
+                    while let Some(command) = receiver.recv().await {
+                        match command {
+                            Command::Connect(addr, reply) => {
+                                tokio_uring::spawn(async move {
+                                    let _ = reply.send(connect(addr).await);
+                                });
+                            }
+                            Command::Bind(addr, reply) => {
+                                let _ = reply.send(bind(addr));
+                            }
+                        }
+                    }
+                })
+            })?;
+
+        start
+            .recv()
+            .map_err(|_| io::Error::other("The io_uring thread failed to start"))??;
+        Ok(Self { commands })
+    }
+
+    async fn run<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<io::Result<T>>) -> Command,
+    ) -> io::Result<T> {
+        let (reply, response) = oneshot::channel();
+        let stopped = || io::Error::other("The io_uring thread stopped");
+        self.commands.send(command(reply)).map_err(|_| stopped())?;
+        response.await.map_err(|_| stopped())?
+    }
+}
+
+#[async_trait]
+impl Connector for UringTransport {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+        let stream = self.run(|reply| Command::Connect(addr, reply)).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[async_trait]
+impl Transport for UringTransport {
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let incoming = self.run(|reply| Command::Bind(addr, reply)).await?;
+        Ok(Box::new(UringListener { incoming }))
+    }
+}
+
+struct UringListener {
+    incoming: Incoming,
+}
+
+#[async_trait]
+impl Listener for UringListener {
+    async fn accept(&mut self) -> io::Result<(Box<dyn PeerStream>, Option<SocketAddr>)> {
+        match self.incoming.recv().await {
+            Some((stream, peer)) => Ok((Box::new(stream), Some(peer))),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "The io_uring thread stopped",
+            )),
+        }
+    }
+}
+
+async fn connect(addr: SocketAddr) -> io::Result<UringStream> {
+    let socket = TcpStream::connect(addr).await?;
+    set_nodelay(&socket)?;
+    Ok(UringStream::drive(socket))
+}
+
+/// Binds `addr` and accepts on it until the listener is dropped.
+fn bind(addr: SocketAddr) -> io::Result<Incoming> {
+    let listener = TcpListener::bind(addr)?;
+    let (sender, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+
+    tokio_uring::spawn(async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = sender.closed() => break,
+            };
+            match accepted {
+                Ok((socket, peer)) => {
+                    if let Err(e) = set_nodelay(&socket) {
+                        debug!(%peer, error = %e, "dropping accepted connection");
+                        continue;
+                    }
+                    if sender
+                        .send((UringStream::drive(socket), peer))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(e) => debug!(%addr, error = %e, "failed to accept connection"),
+            }
+        }
+    });
+    Ok(incoming)
+}
+
+/// Turns Nagle's algorithm off, as [`TcpConnector`] does: a frame larger
+/// than a buffer goes out in several writes, which must not wait for an ACK.
+///
+/// [`TcpConnector`]: crate::dht::connection::connector::TcpConnector
+fn set_nodelay(socket: &TcpStream) -> io::Result<()> {
+    // SAFETY: the descriptor belongs to `socket`, which outlives the borrow;
+    // it is duplicated before being handed to std
+    let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+    std::net::TcpStream::from(fd.try_clone_to_owned()?).set_nodelay(true)
+}
+
+/// A socket driven by the io_uring thread, as the node sees it.
+struct UringStream {
+    /// Buffers read from the socket; closed at its end or on an error
+    reads: mpsc::Receiver<Bytes>,
+    /// What is left of the last buffer read
+    unread: Bytes,
+    /// Buffers to write to the socket; closed once it fails
+    writes: PollSender<Vec<u8>>,
+}
+
+impl UringStream {
+    /// Reads and writes `socket` on the io_uring thread, on behalf of the
+    /// returned stream, until either side closes.
+    fn drive(socket: TcpStream) -> Self {
+        let (read_sender, reads) = mpsc::channel(QUEUED_BUFFERS);
+        let (writes, mut write_receiver) = mpsc::channel::<Vec<u8>>(QUEUED_BUFFERS);
+        let socket = Rc::new(socket);
+
+        let reader = Rc::clone(&socket);
+        tokio_uring::spawn(async move {
+            loop {
+                let (read, mut buf) = reader.read(vec![0u8; IO_BUFFER]).await;
+                match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        if read_sender.send(Bytes::from(buf)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        tokio_uring::spawn(async move {
+            while let Some(buf) = write_receiver.recv().await {
+                let (written, _) = socket.write_all(buf).await;
+                if written.is_err() {
+                    break;
+                }
+            }
+            let _ = socket.shutdown(std::net::Shutdown::Write);
+        });
+
+        Self {
+            reads,
+            unread: Bytes::new(),
+            writes: PollSender::new(writes),
+        }
+    }
+}
+
+impl PeerStream for UringStream {
+    /// As with a [`TcpStream`](tokio::net::TcpStream), an idle connection
+    /// must have nothing to read, and both of its directions still open.
+    fn is_alive(&self) -> bool {
+        self.unread.is_empty()
+            && self.reads.is_empty()
+            && !self.reads.is_closed()
+            && !self.writes.is_closed()
+    }
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.unread.is_empty() {
+            match ready!(self.reads.poll_recv(cx)) {
+                Some(read) => self.unread = read,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.unread.len().min(buf.remaining());
+        buf.put_slice(&self.unread.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    /// Hands the buffers to the io_uring thread, gathered into one.
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let closed = |_| io::Error::new(io::ErrorKind::BrokenPipe, "The socket was closed");
+        ready!(self.writes.poll_reserve(cx)).map_err(closed)?;
+        let mut buf = Vec::with_capacity(bufs.iter().map(|slice| slice.len()).sum());
+        for slice in bufs {
+            buf.extend_from_slice(slice);
+        }
+        let written = buf.len();
+        self.writes.send_item(buf).map_err(closed)?;
+        Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writes.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod uring_tests {
+    use bytes::Bytes;
+    use tracing::warn;
+
+    use crate::dht::{DhtNode, config::DhtConfig, connection::uring::UringTransport};
+
+    #[tokio::test]
+    async fn test_nodes_talk_over_io_uring() {
+        let Ok(transport) = UringTransport::new() else {
+            warn!("io_uring is not available, skipping");
+            return;
+        };
+        let node = |port: u16| {
+            DhtNode::new(
+                format!("127.0.0.1:{}", port).parse().unwrap(),
+                Some(DhtConfig::default()),
+            )
+            .with_network(transport.clone())
+        };
+        let (a, b) = (node(8231), node(8232));
+        let _servers = (a.listen().await.unwrap(), b.listen().await.unwrap());
+        a.add_peer(b.peer_info());
+        b.add_peer(a.peer_info());
+
+        a.store(b"key".to_vec(), vec![7; 100_000]).await.unwrap();
+        assert_eq!(b.local_value(b"key").unwrap().data.len(), 100_000);
+
+        b.drop_key(b"key");
+        assert_eq!(
+            b.find_value(b"key".to_vec()).await,
+            Some(Bytes::from(vec![7; 100_000]))
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! Counters that any node can update without coordination.
+//!
+//! A [`DhtCounter`] is a PN-counter: each node keeps its own totals of
+//! increments and decrements, and the value is their sum over every node.
+//! A node's totals are stored under a key of their own, the counter's key
+//! followed by the node's id. No other node writes that key, so updates from
+//! different nodes never replace each other, however close together they
+//! come. The counter's key itself lists the nodes that contributed; reads
+//! merge that list from every replica, then add up each node's totals.
+//!
+//! Nodes list themselves on their first update and check that they made it.
+//! A node listing itself while another one rewrites the list may still be
+//! dropped from it, until its next update. Neither the totals nor the list
+//! expire, so that the contributions of nodes that stopped updating the
+//! counter keep counting.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    dht::{DhtNode, NodeId, storage::create_stored_value},
+    helpers::key_hash,
+};
+
+/// Times a node writes the list of contributors before giving up on being
+/// listed until its next update
+const LISTING_ATTEMPTS: usize = 3;
+
+/// One node's contribution to a counter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Totals {
+    increments: u64,
+    decrements: u64,
+}
+
+impl Totals {
+    /// Keeps the highest totals of `self` and `other`, the latest ones as
+    /// totals only grow.
+    fn merge(&mut self, other: Totals) {
+        self.increments = self.increments.max(other.increments);
+        self.decrements = self.decrements.max(other.decrements);
+    }
+
+    fn add(&mut self, delta: i64) {
+        if delta >= 0 {
+            self.increments = self.increments.saturating_add(delta.unsigned_abs());
+        } else {
+            self.decrements = self.decrements.saturating_add(delta.unsigned_abs());
+        }
+    }
+}
+
+/// Sum of the contributions of every node.
+fn value(totals: &[Totals]) -> i64 {
+    let (increments, decrements) = totals.iter().fold((0i128, 0i128), |sum, t| {
+        (sum.0 + t.increments as i128, sum.1 + t.decrements as i128)
+    });
+    (increments - decrements).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// Key of the totals of node `id` for the counter at `key`.
+fn totals_key(key: &[u8], id: &NodeId) -> Vec<u8> {
+    let mut totals_key = Vec::with_capacity(key.len() + 1 + id.as_bytes().len());
+    totals_key.extend_from_slice(key);
+    totals_key.push(b'/');
+    totals_key.extend_from_slice(id.as_bytes());
+    totals_key
+}
+
+/// A handle for the counters stored in the DHT through a [`DhtNode`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_p2p_node::dht::DhtNode;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let node = DhtNode::new("127.0.0.1:8080".parse()?, None);
+///     let counters = node.counter();
+///
+///     counters.increment(b"visits".to_vec(), 1).await?;
+///     println!("{} visits", counters.read(b"visits".to_vec()).await?);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct DhtCounter {
+    node: DhtNode,
+}
+
+impl DhtCounter {
+    pub fn new(node: DhtNode) -> Self {
+        Self { node }
+    }
+
+    /// Adds `delta`, which may be negative, to the counter at `key` and
+    /// returns its new value as seen by this node.
+    ///
+    /// The counter is stored like [`DhtNode::store`] does, but never
+    /// expires. Updates from one node are expected one at a time.
+    pub async fn increment(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let id = &self.node.id;
+        let own_key = totals_key(&key, id);
+        let mut totals = self.totals(own_key.clone()).await?;
+        totals.add(delta);
+        self.store_lasting(own_key, bincode::serialize(&totals)?)
+            .await?;
+
+        let mut contributors = self.contributors(&key).await?;
+        self.list_self(&key, &mut contributors).await?;
+        contributors.remove(id);
+        let mut all = self.totals_of(&key, &contributors).await?;
+        all.push(totals);
+        Ok(value(&all))
+    }
+
+    /// Returns the value of the counter at `key`, 0 if it was never written.
+    ///
+    /// Fails if `key` holds something else than a counter.
+    pub async fn read(&self, key: Vec<u8>) -> Result<i64> {
+        let contributors = self.contributors(&key).await?;
+        Ok(value(&self.totals_of(&key, &contributors).await?))
+    }
+
+    /// Adds this node to the contributors of `key` if it is missing.
+    async fn list_self(&self, key: &[u8], contributors: &mut HashSet<NodeId>) -> Result<()> {
+        let id = &self.node.id;
+        for _ in 0..LISTING_ATTEMPTS {
+            if contributors.contains(id) {
+                return Ok(());
+            }
+            contributors.insert(id.clone());
+            self.store_lasting(key.to_vec(), bincode::serialize(&contributors)?)
+                .await?;
+            // Another node may have written the list at the same time
+            *contributors = self.contributors(key).await?;
+        }
+        if contributors.contains(id) {
+            return Ok(());
+        }
+        warn!(key = %key_hash(key), "failed to list this node among the counter's contributors");
+        Ok(())
+    }
+
+    /// Merges the lists of contributors to `key` held by its replicas.
+    async fn contributors(&self, key: &[u8]) -> Result<HashSet<NodeId>> {
+        let mut contributors = HashSet::new();
+        for (_, stored) in self.node.gather(key).await {
+            let nodes: HashSet<NodeId> =
+                bincode::deserialize(&stored.data).context("Value was not stored as a counter")?;
+            contributors.extend(nodes);
+        }
+        Ok(contributors)
+    }
+
+    /// Totals of each of `nodes` for the counter at `key`.
+    async fn totals_of(&self, key: &[u8], nodes: &HashSet<NodeId>) -> Result<Vec<Totals>> {
+        try_join_all(nodes.iter().map(|id| self.totals(totals_key(key, id)))).await
+    }
+
+    /// Merges the copies of the totals at `totals_key` held by its replicas.
+    async fn totals(&self, totals_key: Vec<u8>) -> Result<Totals> {
+        let mut totals = Totals::default();
+        for (_, stored) in self.node.gather(&totals_key).await {
+            let copy =
+                bincode::deserialize(&stored.data).context("Value was not stored as a counter")?;
+            totals.merge(copy);
+        }
+        Ok(totals)
+    }
+
+    /// Stores `data` at `key` like [`DhtNode::store`], without expiry.
+    async fn store_lasting(&self, key: Vec<u8>, data: Vec<u8>) -> Result<()> {
+        let node = &self.node;
+        let mut stored = create_stored_value(data, node.addr, false, None, node.now());
+        stored.token = node.own_token(&key, None);
+        node.store_value(key, stored).await.map(|_| ())
+    }
+}
+
+impl DhtNode {
+    /// Returns a [`DhtCounter`] over this node.
+    pub fn counter(&self) -> DhtCounter {
+        DhtCounter::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod counter_tests {
+    use std::time::Duration;
+
+    use super::{Totals, value};
+    use crate::{
+        dht::clock::ManualClock,
+        helpers::{create_test_node, test_config},
+        testing::TestCluster,
+    };
+
+    #[test]
+    fn test_merge_keeps_the_highest_totals() {
+        let mut ours = Totals::default();
+        ours.add(5);
+        let mut theirs = Totals::default();
+        theirs.add(3);
+        theirs.add(-4);
+
+        ours.merge(theirs);
+        assert_eq!(value(&[ours]), 5 - 4);
+        // Merging is idempotent
+        ours.merge(theirs);
+        assert_eq!(value(&[ours, theirs]), 1 + 3 - 4);
+    }
+
+    #[tokio::test]
+    async fn test_increments_of_every_node_are_merged_on_read() {
+        let (mut a, mut b) = (create_test_node(8242), create_test_node(8243));
+        a.config.storage.default_ttl = 60;
+        b.config.storage.default_ttl = 60;
+        let _servers = (a.listen().await.unwrap(), b.listen().await.unwrap());
+        a.add_peer(b.peer_info());
+        b.add_peer(a.peer_info());
+
+        let key = b"visits".to_vec();
+        assert_eq!(a.counter().read(key.clone()).await.unwrap(), 0);
+        assert_eq!(a.counter().increment(key.clone(), 2).await.unwrap(), 2);
+        assert_eq!(b.counter().increment(key.clone(), 3).await.unwrap(), 5);
+        assert_eq!(a.counter().increment(key.clone(), -1).await.unwrap(), 4);
+
+        assert_eq!(b.counter().read(key.clone()).await.unwrap(), 4);
+
+        a.store(b"raw".to_vec(), b"bytes".to_vec()).await.unwrap();
+        assert!(a.counter().read(b"raw".to_vec()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_increments_are_all_counted() {
+        let mut config = test_config();
+        config.storage.default_ttl = 60;
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        let cluster = TestCluster::with_config(3, config).await;
+
+        let key = b"visits".to_vec();
+        for round in 1..=3 {
+            let counters: Vec<_> = cluster.nodes().iter().map(|node| node.counter()).collect();
+            let increments = counters
+                .iter()
+                .map(|counter| counter.increment(key.clone(), 1));
+            futures::future::try_join_all(increments).await.unwrap();
+            for node in cluster.nodes() {
+                assert_eq!(node.counter().read(key.clone()).await.unwrap(), 3 * round);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_counts_outlive_the_ttl_of_quiet_nodes() {
+        let clock = ManualClock::new(1_000);
+        let mut a = create_test_node(8244).with_clock(clock.clone());
+        let mut b = create_test_node(8245).with_clock(clock.clone());
+        a.config.storage.default_ttl = 60;
+        b.config.storage.default_ttl = 60;
+        let _servers = (a.listen().await.unwrap(), b.listen().await.unwrap());
+        a.add_peer(b.peer_info());
+        b.add_peer(a.peer_info());
+
+        let key = b"visits".to_vec();
+        a.counter().increment(key.clone(), 2).await.unwrap();
+        b.counter().increment(key.clone(), 3).await.unwrap();
+
+        // Neither node writes again for longer than the TTL
+        clock.advance(Duration::from_secs(10 * 60));
+        a.clean_expired().await;
+        b.clean_expired().await;
+        assert_eq!(a.counter().read(key.clone()).await.unwrap(), 5);
+        assert_eq!(b.counter().increment(key.clone(), 1).await.unwrap(), 6);
+    }
+}
@@ -0,0 +1,131 @@
+//! Structured state dumps for bug reports.
+//!
+//! [`DhtNode::debug_dump`] collects the routing table, connection pool,
+//! storage and background work of a node into a single [`DebugDump`] that
+//! serializes to JSON.
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use serde::Serialize;
+
+use crate::dht::{DhtNode, connection::PoolSnapshot, node::NodeId, storage::deserialize_value};
+
+/// Everything [`DhtNode::debug_dump`] reports about a node.
+#[derive(Debug, Clone, Serialize)]
+pub struct DebugDump {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    /// Non-empty k-buckets, by index
+    pub routing_table: Vec<BucketDump>,
+    pub connection_pool: PoolSnapshot,
+    pub storage: StorageDump,
+    pub tasks: TasksDump,
+}
+
+/// Contents of one k-bucket.
+#[derive(Debug, Clone, Serialize)]
+pub struct BucketDump {
+    pub index: u8,
+    pub peers: Vec<PeerDump>,
+}
+
+/// A routing table entry.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerDump {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub alt_addrs: Vec<SocketAddr>,
+    pub last_seen: u64,
+}
+
+/// Summary of the local storage.
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageDump {
+    pub keys: usize,
+    /// Key count per namespace: the part of a UTF-8 key before its first
+    /// `:`, empty for keys without one and `<binary>` for non UTF-8 keys
+    pub namespaces: BTreeMap<String, usize>,
+    /// Copies held for this node's own writes rather than as replicas
+    pub originals: usize,
+    /// Transient replicas waiting to be handed off to their intended node
+    pub hinted: usize,
+}
+
+/// Background tasks and the work queued for them.
+#[derive(Debug, Clone, Serialize)]
+pub struct TasksDump {
+    /// Maintenance tasks still running
+    pub running: usize,
+    /// Maintenance tasks that have stopped
+    pub finished: usize,
+    /// Writes buffered until a peer becomes reachable
+    pub outbox: usize,
+}
+
+impl DhtNode {
+    /// Returns a snapshot of the node's internal state, meant to be attached
+    /// to bug reports.
+    pub async fn debug_dump(&self) -> DebugDump {
+        let mut routing_table: Vec<BucketDump> = self
+            .routing_table
+            .iter()
+            .filter(|bucket| !bucket.peers.is_empty())
+            .map(|bucket| BucketDump {
+                index: *bucket.key(),
+                peers: bucket
+                    .peers
+                    .iter()
+                    .map(|peer| PeerDump {
+                        id: peer.id.clone(),
+                        addr: peer.addr,
+                        alt_addrs: peer.alt_addrs.clone(),
+                        last_seen: peer.last_seen,
+                    })
+                    .collect(),
+            })
+            .collect();
+        routing_table.sort_by_key(|bucket| bucket.index);
+
+        let mut storage = StorageDump {
+            keys: 0,
+            namespaces: BTreeMap::new(),
+            originals: 0,
+            hinted: 0,
+        };
+        for (key, value) in self.storage.iter() {
+            storage.keys += 1;
+            *storage.namespaces.entry(namespace(&key)).or_default() += 1;
+
+            if let Ok(stored) = deserialize_value(&value) {
+                storage.originals += usize::from(!stored.is_replica);
+                storage.hinted += usize::from(stored.hinted_for.is_some());
+            }
+        }
+
+        let (running, finished) = {
+            let tasks = self.health.tasks.lock().unwrap();
+            let finished = tasks.iter().filter(|task| task.is_finished()).count();
+            (tasks.len() - finished, finished)
+        };
+
+        DebugDump {
+            id: self.id.clone(),
+            addr: self.addr,
+            routing_table,
+            connection_pool: self.connection_pool.snapshot().await,
+            storage,
+            tasks: TasksDump {
+                running,
+                finished,
+                outbox: self.outbox.len(),
+            },
+        }
+    }
+}
+
+fn namespace(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(key) => key.split_once(':').map_or("", |(ns, _)| ns).to_string(),
+        Err(_) => "<binary>".to_string(),
+    }
+}
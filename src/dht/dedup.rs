@@ -0,0 +1,287 @@
+//! Answering retried requests without applying them twice.
+//!
+//! A caller whose request got no answer cannot tell whether it was lost on
+//! the way or only its response was. Writes that carry an
+//! [`Envelope::id`](crate::dht::rpc::Envelope::id) are remembered, per
+//! sender, with the outcome of their response: a retry with the same id gets
+//! that response back instead of being handled again, and one arriving while
+//! the original is still being handled waits for it. [`DhtClient`] tags its
+//! writes this way when it retries calls, so a `ClientStore` is not written
+//! as two versions. Reads are safe to handle again and never remembered.
+//!
+//! Only the last `dedup_capacity` writes are remembered, and the last
+//! `dedup_per_sender` of each sender, so that one sender cannot push out
+//! the others'. Writes shed as [`DhtRpc::Busy`] are forgotten right away,
+//! as they were not applied.
+//!
+//! [`DhtClient`]: crate::dht::client::DhtClient
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OnceCell;
+
+use crate::dht::{
+    DhtNode,
+    rpc::{DhtRpc, WriteReport},
+};
+
+/// A request as told apart from the others: the sender's address, when
+/// known, and the id it gave the request.
+type RequestKey = (Option<IpAddr>, u64);
+
+/// Whether handling `rpc` again could apply it twice. A batch is only
+/// remembered when it holds nothing but writes, as the outcomes kept would
+/// lose its reads' values.
+pub(super) fn is_write(rpc: &DhtRpc) -> bool {
+    match rpc {
+        DhtRpc::Store(..)
+        | DhtRpc::StoreIf(..)
+        | DhtRpc::Expire(..)
+        | DhtRpc::ClientStore(..)
+        | DhtRpc::CoordinatedStore(..) => true,
+        DhtRpc::Batch(requests) => requests.iter().all(is_write),
+        _ => false,
+    }
+}
+
+/// What is kept of the response to a write.
+#[derive(Debug, Clone)]
+enum Ack {
+    Pong,
+    Busy,
+    Accepted(bool),
+    Stored(Result<u64, String>),
+    Coordinated(Result<WriteReport, String>),
+    Batch(Vec<Ack>),
+}
+
+impl From<&DhtRpc> for Ack {
+    fn from(response: &DhtRpc) -> Self {
+        match response {
+            DhtRpc::Busy => Self::Busy,
+            DhtRpc::StoreResponse(accepted) => Self::Accepted(*accepted),
+            DhtRpc::ClientStoreResponse(result) => Self::Stored(result.clone()),
+            DhtRpc::CoordinatedStoreResponse(result) => Self::Coordinated(result.clone()),
+            DhtRpc::BatchResponse(responses) => {
+                Self::Batch(responses.iter().map(Self::from).collect())
+            }
+            // Other writes are answered with a bare acknowledgement
+            _ => Self::Pong,
+        }
+    }
+}
+
+impl From<&Ack> for DhtRpc {
+    fn from(ack: &Ack) -> Self {
+        match ack {
+            Ack::Pong => Self::Pong,
+            Ack::Busy => Self::Busy,
+            Ack::Accepted(accepted) => Self::StoreResponse(*accepted),
+            Ack::Stored(result) => Self::ClientStoreResponse(result.clone()),
+            Ack::Coordinated(result) => Self::CoordinatedStoreResponse(result.clone()),
+            Ack::Batch(acks) => Self::BatchResponse(acks.iter().map(Self::from).collect()),
+        }
+    }
+}
+
+/// The most recent writes that carried an id, oldest first.
+#[derive(Debug)]
+pub(super) struct RecentRequests {
+    requests: Mutex<Requests>,
+}
+
+#[derive(Debug)]
+struct Requests {
+    responses: HashMap<RequestKey, Arc<OnceCell<Ack>>>,
+    order: VecDeque<RequestKey>,
+    /// Number of writes remembered for each sender
+    per_sender: HashMap<Option<IpAddr>, usize>,
+    capacity: usize,
+    sender_capacity: usize,
+}
+
+impl Requests {
+    fn remove(&mut self, key: &RequestKey) {
+        if self.responses.remove(key).is_none() {
+            return;
+        }
+        if let Some(count) = self.per_sender.get_mut(&key.0) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_sender.remove(&key.0);
+            }
+        }
+    }
+}
+
+impl RecentRequests {
+    pub(super) fn new(capacity: usize, sender_capacity: usize) -> Self {
+        Self {
+            requests: Mutex::new(Requests {
+                responses: HashMap::new(),
+                order: VecDeque::new(),
+                per_sender: HashMap::new(),
+                capacity: capacity.max(1),
+                sender_capacity: sender_capacity.max(1),
+            }),
+        }
+    }
+
+    /// Returns where the outcome of `key` is, or will be, kept.
+    fn response(&self, key: RequestKey) -> Arc<OnceCell<Ack>> {
+        let mut requests = self.requests.lock().unwrap();
+        if let Some(response) = requests.responses.get(&key) {
+            return Arc::clone(response);
+        }
+
+        // Make room among the sender's writes, then among everyone's
+        if requests.per_sender.get(&key.0).copied().unwrap_or(0) >= requests.sender_capacity
+            && let Some(position) = requests.order.iter().position(|k| k.0 == key.0)
+            && let Some(oldest) = requests.order.remove(position)
+        {
+            requests.remove(&oldest);
+        }
+        while requests.order.len() >= requests.capacity {
+            if let Some(oldest) = requests.order.pop_front() {
+                requests.remove(&oldest);
+            }
+        }
+
+        let response = Arc::new(OnceCell::new());
+        requests.responses.insert(key, Arc::clone(&response));
+        requests.order.push_back(key);
+        *requests.per_sender.entry(key.0).or_default() += 1;
+        response
+    }
+
+    fn forget(&self, key: &RequestKey) {
+        let mut requests = self.requests.lock().unwrap();
+        requests.remove(key);
+        requests.order.retain(|k| k != key);
+    }
+}
+
+impl DhtNode {
+    /// Handles the write `id` from `ip` once, answering its retries with
+    /// the same response.
+    pub(super) async fn handle_once(&self, id: u64, ip: Option<IpAddr>, rpc: DhtRpc) -> DhtRpc {
+        let key = (ip, id);
+        let cell = self.recent_requests.response(key);
+        let mut handled = false;
+        let ack = cell
+            .get_or_init(|| async {
+                handled = true;
+                Ack::from(&self.handle_rpc_admitted(rpc).await)
+            })
+            .await;
+
+        if !handled {
+            self.metrics.inc_requests_deduplicated();
+        } else if matches!(ack, Ack::Busy) {
+            self.recent_requests.forget(&key);
+        }
+        DhtRpc::from(ack)
+    }
+}
+
+#[cfg(test)]
+mod dedup_tests {
+    use bytes::Bytes;
+
+    use super::{Ack, RecentRequests};
+    use crate::{
+        dht::rpc::{DhtRpc, Envelope},
+        helpers::create_test_node,
+    };
+
+    #[test]
+    fn test_recent_requests_are_bounded() {
+        let recent = RecentRequests::new(2, 2);
+        let first = recent.response((None, 1));
+        assert!(first.set(Ack::Pong).is_ok());
+        recent.response((None, 2));
+        assert!(recent.response((None, 1)).initialized());
+
+        recent.response((None, 3));
+        assert!(!recent.response((None, 1)).initialized());
+        assert_eq!(recent.requests.lock().unwrap().responses.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retried_writes_are_applied_once() {
+        let mut node = create_test_node(8244);
+        node.config.storage.default_ttl = 60;
+        let ip = Some("127.0.0.1".parse().unwrap());
+        let store = |value: &'static [u8]| Envelope {
+            sender: None,
+            id: Some(7),
+            rpc: DhtRpc::ClientStore(b"key".to_vec(), Bytes::from_static(value), None),
+        };
+
+        let first = node.handle_request(store(b"first"), ip).await;
+        // Same id, so the first response even though the value differs
+        let retry = node.handle_request(store(b"second"), ip).await;
+        assert_eq!(format!("{:?}", first), format!("{:?}", retry));
+        assert_eq!(node.local_value(b"key").unwrap().data, &b"first"[..]);
+        assert_eq!(node.get_stats().requests_deduplicated, 1);
+
+        // Another sender may use the same id
+        let other = Some("127.0.0.2".parse().unwrap());
+        node.handle_request(store(b"other"), other).await;
+        assert_eq!(node.local_value(b"key").unwrap().data, &b"other"[..]);
+    }
+
+    #[test]
+    fn test_each_sender_is_bounded() {
+        let recent = RecentRequests::new(4, 2);
+        let (a, b) = (
+            Some("127.0.0.1".parse().unwrap()),
+            Some("127.0.0.2".parse().unwrap()),
+        );
+        recent.response((b, 1));
+        for id in 1..=3 {
+            recent.response((a, id));
+        }
+
+        // a's oldest made way for its third, b's is kept
+        let requests = recent.requests.lock().unwrap();
+        assert!(!requests.responses.contains_key(&(a, 1)));
+        assert!(requests.responses.contains_key(&(b, 1)));
+        assert_eq!(requests.responses.len(), 3);
+        assert_eq!(requests.per_sender[&a], 2);
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_not_remembered() {
+        let node = create_test_node(8080);
+        let ip = Some("127.0.0.1".parse().unwrap());
+        let get = Envelope {
+            sender: None,
+            id: Some(7),
+            rpc: DhtRpc::FindValue(b"key".to_vec()),
+        };
+
+        node.handle_request(get.clone(), ip).await;
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        // Same id, yet answered afresh
+        assert!(matches!(
+            node.handle_request(get, ip).await,
+            DhtRpc::FindValueResponse(Some(_))
+        ));
+        assert_eq!(node.get_stats().requests_deduplicated, 0);
+        assert!(
+            node.recent_requests
+                .requests
+                .lock()
+                .unwrap()
+                .responses
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Notifications about what happens inside a node.
+//!
+//! [`DhtNode::subscribe`] hands out a stream of [`DhtEvent`]s so embedding
+//! applications can react to bootstrapping, routing and storage changes and
+//! maintenance rounds without polling.
+//! Events are broadcast to every subscriber; one that falls too far behind
+//! receives [`DhtEvent::Lagged`] instead of the events it missed.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt, future, stream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::dht::{DhtNode, peer::PeerInfo, rebalance::RebalanceReport, storage::deserialize_value};
+
+/// Something that happened on a node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DhtEvent {
+    /// [`DhtNode::bootstrap`] reached at least one known peer
+    Bootstrapped {
+        /// Known peers that answered
+        reached: usize,
+        /// Peers in the routing table afterwards
+        known_peers: usize,
+    },
+    /// A peer was added to the routing table
+    PeerDiscovered(PeerInfo),
+    /// A peer was removed from the routing table as unresponsive or inactive
+    PeerEvicted(PeerInfo),
+    /// A value was written to local storage, by this node or as a replica
+    ValueStored {
+        key: Vec<u8>,
+        version: u64,
+        is_replica: bool,
+    },
+    /// A value was dropped from local storage after expiring
+    ValueExpired { key: Vec<u8> },
+    /// A value was removed from local storage by an operator, see
+    /// [`DhtNode::drop_key`], or handed over by [`DhtNode::rebalance`]
+    ValueRemoved { key: Vec<u8> },
+    /// A value was dropped from local storage to make room, see
+    /// [`StorageConfig`](crate::dht::config::StorageConfig)
+    ValueEvicted { key: Vec<u8> },
+    /// A value lookup finished
+    LookupCompleted {
+        key: Vec<u8>,
+        /// Node that returned the winning copy, `None` if nothing was found
+        source: Option<SocketAddr>,
+        /// Number of valid copies seen
+        replicas: usize,
+        elapsed: Duration,
+    },
+    /// A round of the maintenance service finished: peer health checks,
+    /// expiry, hinted hand-off and outbox flush
+    MaintenanceCompleted { elapsed: Duration },
+    /// A round of [`DhtNode::rebalance`] finished
+    RebalanceProgress {
+        /// Totals so far
+        report: RebalanceReport,
+        /// Values to check in all
+        total: usize,
+    },
+    /// The subscriber fell behind and this many events were dropped
+    Lagged(u64),
+}
+
+/// A change to a subscribed key, see [`DhtNode::subscribe_key`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyChange {
+    /// A new copy was written
+    Stored {
+        value: Bytes,
+        version: u64,
+        is_replica: bool,
+    },
+    /// The copy was dropped after expiring
+    Expired,
+    /// The copy was removed by an operator
+    Removed,
+    /// The copy was dropped to make room for other values
+    Evicted,
+    /// Events were dropped and changes may have been missed
+    Lagged(u64),
+}
+
+impl DhtNode {
+    /// Subscribes to the events of this node.
+    ///
+    /// The stream only yields events that happen after the call and ends
+    /// when every handle to the node is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use rust_p2p_node::dht::{DhtNode, events::DhtEvent};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None);
+    ///     let mut events = Box::pin(node.subscribe());
+    ///
+    ///     while let Some(event) = events.next().await {
+    ///         if let DhtEvent::PeerEvicted(peer) = event {
+    ///             println!("lost {}", peer.addr);
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe(&self) -> impl Stream<Item = DhtEvent> + Send + 'static {
+        stream::unfold(self.events.subscribe(), |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(event) => Some((event, receiver)),
+                Err(RecvError::Lagged(missed)) => Some((DhtEvent::Lagged(missed), receiver)),
+                Err(RecvError::Closed) => None,
+            }
+        })
+    }
+
+    /// Follows the local copy of `key`, as seen through [`DhtNode::subscribe`].
+    ///
+    /// Only changes to this node's own storage are reported, so a key shows
+    /// up once it is written here or replicated to this node, whether by a
+    /// local store or an incoming `Store` RPC, and goes away when it expires,
+    /// an `Expire` RPC arrives or it is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use rust_p2p_node::dht::{DhtNode, events::KeyChange};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None);
+    ///     let mut changes = Box::pin(node.subscribe_key(b"config".to_vec()));
+    ///
+    ///     while let Some(change) = changes.next().await {
+    ///         if let KeyChange::Stored { value, .. } = change {
+    ///             println!("config is now {}", String::from_utf8_lossy(&value));
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe_key(&self, key: Vec<u8>) -> impl Stream<Item = KeyChange> + Send + 'static {
+        let storage = Arc::clone(&self.storage);
+
+        self.subscribe().filter_map(move |event| {
+            let change = match event {
+                DhtEvent::ValueStored {
+                    key: stored,
+                    version,
+                    is_replica,
+                } if stored == key => storage
+                    .get(&key)
+                    .and_then(|value| deserialize_value(&value).ok())
+                    .map(|stored| KeyChange::Stored {
+                        value: stored.data,
+                        version,
+                        is_replica,
+                    }),
+                DhtEvent::ValueExpired { key: expired } if expired == key => {
+                    Some(KeyChange::Expired)
+                }
+                DhtEvent::ValueRemoved { key: removed } if removed == key => {
+                    Some(KeyChange::Removed)
+                }
+                DhtEvent::ValueEvicted { key: evicted } if evicted == key => {
+                    Some(KeyChange::Evicted)
+                }
+                DhtEvent::Lagged(missed) => Some(KeyChange::Lagged(missed)),
+                _ => None,
+            };
+            future::ready(change)
+        })
+    }
+
+    /// Broadcasts an event; it is only built when someone is subscribed.
+    pub(super) fn emit(&self, event: impl FnOnce() -> DhtEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event());
+        }
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use futures::{StreamExt, future};
+
+    use crate::{
+        dht::{
+            NodeId, PeerInfo,
+            events::{DhtEvent, KeyChange},
+            rpc::DhtRpc,
+            storage::{create_stored_value, serialize_value},
+        },
+        helpers::create_test_node,
+    };
+
+    #[tokio::test]
+    async fn test_subscriber_sees_peers_stores_and_lookups() {
+        let node = create_test_node(8200);
+        let mut events = Box::pin(node.subscribe());
+
+        let peer_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let peer = PeerInfo::new(NodeId::new(b"peer"), peer_addr);
+        node.add_peer(peer.clone());
+        // Refreshing a known peer is not a discovery
+        node.add_peer(peer.clone());
+
+        assert_eq!(events.next().await, Some(DhtEvent::PeerDiscovered(peer)));
+
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(
+            events.next().await,
+            Some(DhtEvent::ValueStored { key, is_replica: false, .. }) if key == b"key"
+        ));
+
+        node.find_value(b"key".to_vec()).await;
+        assert!(matches!(
+            events.next().await,
+            Some(DhtEvent::LookupCompleted { key, source: Some(source), .. })
+                if key == b"key" && source == node.addr
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_key_follows_one_key() {
+        let node = create_test_node(8201);
+        let mut changes = Box::pin(node.subscribe_key(b"watched".to_vec()));
+
+        node.store(b"other".to_vec(), b"ignored".to_vec())
+            .await
+            .unwrap();
+        let version = node
+            .store_versioned(
+                b"watched".to_vec(),
+                b"first".to_vec(),
+                node.config.storage.default_ttl,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            changes.next().await,
+            Some(KeyChange::Stored {
+                value: Bytes::from_static(b"first"),
+                version,
+                is_replica: false,
+            })
+        );
+
+        node.handle_rpc(DhtRpc::Expire(b"watched".to_vec(), version, None, None))
+            .await;
+        assert_eq!(changes.next().await, Some(KeyChange::Expired));
+
+        // Replicas arrive through the Store RPC
+        node.handle_rpc(DhtRpc::Store(
+            b"watched".to_vec(),
+            serialize_value(&create_stored_value(
+                b"second".to_vec(),
+                node.addr,
+                false,
+                Some(60),
+                node.now(),
+            ))
+            .unwrap(),
+        ))
+        .await;
+        assert!(matches!(
+            changes.next().await,
+            Some(KeyChange::Stored { value, is_replica: true, .. }) if value == b"second"[..]
+        ));
+
+        assert!(node.drop_key(b"watched"));
+        assert_eq!(changes.next().await, Some(KeyChange::Removed));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_and_maintenance_are_reported() {
+        let seed = create_test_node(8202);
+        let server = seed.listen().await.unwrap();
+        let node = create_test_node(8203);
+        let mut events = Box::pin(node.subscribe());
+
+        node.bootstrap(vec![seed.addr]).await.unwrap();
+        let bootstrapped = events
+            .by_ref()
+            .filter(|event| future::ready(matches!(event, DhtEvent::Bootstrapped { .. })))
+            .next()
+            .await;
+        assert_eq!(
+            bootstrapped,
+            Some(DhtEvent::Bootstrapped {
+                reached: 1,
+                known_peers: 0,
+            })
+        );
+
+        node.start_maintenance_service().await;
+        assert!(matches!(
+            events.next().await,
+            Some(DhtEvent::MaintenanceCompleted { .. })
+        ));
+
+        node.shutdown().await;
+        server.abort();
+    }
+}
@@ -0,0 +1,351 @@
+//! Injecting failures into RPCs, for tests.
+//!
+//! Only built for the crate's own tests and with the `testing` feature, so
+//! that nodes built otherwise carry none of it.
+//!
+//! A [`FaultInjector`] shared by the nodes of a test, see
+//! [`DhtNode::with_fault_injector`], drops, delays, duplicates or corrupts
+//! the RPCs that match its rules. This exercises how the node copes with
+//! lossy, slow or misbehaving peers — retries, quorums, read repair — without
+//! a real network misbehaving.
+//!
+//! Faults apply at three points of an RPC:
+//!
+//! - [`Phase::Request`]: in [`DhtNode::send_rpc`], before the request leaves
+//!   the caller
+//! - [`Phase::Handling`]: in [`DhtNode::handle_rpc`], on the node answering
+//! - [`Phase::Response`]: in [`DhtNode::send_rpc`], once the response came
+//!   back, after the peer acted on the request
+
+use std::{
+    borrow::Cow,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, anyhow};
+
+use crate::dht::{DhtNode, overload::Priority, rpc::DhtRpc};
+
+/// What happens to a matching RPC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The request or response is lost; a request dropped while being
+    /// handled is never answered
+    Drop,
+    /// The request or response is held back, or the handling slowed down
+    Delay(Duration),
+    /// The request is sent, or handled, twice; responses are not duplicated
+    Duplicate,
+    /// The request or response is mangled so it fails to decode; handling is
+    /// not affected
+    Corrupt,
+}
+
+/// Where in an RPC a [`FaultRule`] applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Request,
+    Handling,
+    Response,
+}
+
+/// Which RPCs suffer a fault, built with [`FaultRule::new`].
+///
+/// Rules match requests of any type between any nodes unless narrowed down.
+/// The sender of an RPC is unknown while it is handled, so rules for
+/// [`Phase::Handling`] ignore [`from`](Self::from).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    fault: Fault,
+    phase: Phase,
+    rpc: Option<&'static str>,
+    from: Option<SocketAddr>,
+    to: Option<SocketAddr>,
+    remaining: Option<u64>,
+}
+
+impl FaultRule {
+    /// Applies `fault` to requests.
+    pub fn new(fault: Fault) -> Self {
+        Self {
+            fault,
+            phase: Phase::Request,
+            rpc: None,
+            from: None,
+            to: None,
+            remaining: None,
+        }
+    }
+
+    /// Applies the fault at `phase` instead.
+    pub fn at(mut self, phase: Phase) -> Self {
+        self.phase = phase;
+        self
+    }
+
+    /// Only affects requests named `rpc`, as in [`DhtRpc::name`].
+    ///
+    /// [`DhtRpc::name`]: crate::dht::rpc::DhtRpc::name
+    pub fn on(mut self, rpc: &'static str) -> Self {
+        self.rpc = Some(rpc);
+        self
+    }
+
+    /// Only affects RPCs sent by the node at `addr`.
+    pub fn from(mut self, addr: SocketAddr) -> Self {
+        self.from = Some(addr);
+        self
+    }
+
+    /// Only affects RPCs sent to the node at `addr`.
+    pub fn to(mut self, addr: SocketAddr) -> Self {
+        self.to = Some(addr);
+        self
+    }
+
+    /// Stops applying after `count` RPCs.
+    pub fn times(mut self, count: u64) -> Self {
+        self.remaining = Some(count);
+        self
+    }
+
+    fn matches(&self, phase: Phase, rpc: &str, from: Option<SocketAddr>, to: SocketAddr) -> bool {
+        self.phase == phase
+            && self.rpc.is_none_or(|name| name == rpc)
+            && (phase == Phase::Handling || self.from.is_none() || self.from == from)
+            && self.to.is_none_or(|addr| addr == to)
+    }
+}
+
+/// Rules deciding which RPCs fail and how, see the [module docs](self).
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use rust_p2p_node::dht::{
+///     DhtNode,
+///     faults::{Fault, FaultInjector, FaultRule, Phase},
+/// };
+///
+/// let faults = Arc::new(FaultInjector::new());
+/// let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None)
+///     .with_fault_injector(Arc::clone(&faults));
+///
+/// // The next two replicas are written, but their acknowledgements are lost
+/// faults.inject(FaultRule::new(Fault::Drop).at(Phase::Response).on("Store").times(2));
+/// ```
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    rules: Mutex<Vec<FaultRule>>,
+    injected: AtomicU64,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule. When several match an RPC, the earliest added applies.
+    pub fn inject(&self, rule: FaultRule) {
+        self.rules.lock().unwrap().push(rule);
+    }
+
+    /// Removes every rule.
+    pub fn clear(&self) {
+        self.rules.lock().unwrap().clear();
+    }
+
+    /// Number of faults applied so far.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the fault for an RPC at `phase`, if any, consuming one use of
+    /// the rule that applies.
+    pub(super) fn fault(
+        &self,
+        phase: Phase,
+        rpc: &str,
+        from: Option<SocketAddr>,
+        to: SocketAddr,
+    ) -> Option<Fault> {
+        let mut rules = self.rules.lock().unwrap();
+        let index = rules
+            .iter()
+            .position(|rule| rule.matches(phase, rpc, from, to))?;
+
+        let rule = &mut rules[index];
+        let fault = rule.fault;
+        if let Some(remaining) = &mut rule.remaining {
+            *remaining -= 1;
+            if *remaining == 0 {
+                rules.remove(index);
+            }
+        }
+
+        self.injected.fetch_add(1, Ordering::Relaxed);
+        Some(fault)
+    }
+}
+
+/// Mangles an encoded RPC so that it no longer decodes: its variant tag
+/// becomes out of range.
+pub(super) fn corrupt(message: &mut [u8]) {
+    if let Some(tag) = message.first_mut() {
+        *tag ^= 0xff;
+    }
+}
+
+impl DhtNode {
+    /// Makes the node's RPCs suffer the faults of `injector`. Meant for
+    /// tests, see the [`faults`](self) module.
+    pub fn with_fault_injector(mut self, injector: std::sync::Arc<FaultInjector>) -> Self {
+        self.faults = Some(injector);
+        self
+    }
+
+    /// Exchanges an RPC with `peer` like [`DhtNode::send_rpc`], suffering
+    /// the faults of the request and response phases.
+    pub(super) async fn exchange_with_faults(
+        &self,
+        peer: SocketAddr,
+        rpc: &str,
+        priority: Priority,
+        request: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut request = Cow::Borrowed(request);
+        match self.injected_fault(Phase::Request, rpc, peer) {
+            Some(Fault::Drop) => return Err(anyhow!("{} request to {} was dropped", rpc, peer)),
+            Some(Fault::Delay(delay)) => self.sleep(delay).await,
+            Some(Fault::Duplicate) => {
+                let _ = self.exchange_rpc(peer, priority, &request).await;
+            }
+            Some(Fault::Corrupt) => corrupt(request.to_mut()),
+            None => {}
+        }
+
+        let mut response = self.exchange_rpc(peer, priority, &request).await?;
+        match self.injected_fault(Phase::Response, rpc, peer) {
+            Some(Fault::Drop) => return Err(anyhow!("{} response from {} was dropped", rpc, peer)),
+            Some(Fault::Delay(delay)) => self.sleep(delay).await,
+            Some(Fault::Corrupt) => corrupt(&mut response),
+            Some(Fault::Duplicate) | None => {}
+        }
+        Ok(response)
+    }
+
+    /// Suffers the fault, if any, of handling `rpc` on this node, before
+    /// [`DhtNode::handle_rpc`] answers it. A dropped request never
+    /// returns.
+    pub(super) async fn suffer_handling_fault(&self, rpc: &DhtRpc) {
+        match self.injected_fault(Phase::Handling, rpc.name(), self.addr) {
+            Some(Fault::Drop) => std::future::pending().await,
+            Some(Fault::Delay(delay)) => self.sleep(delay).await,
+            Some(Fault::Duplicate) => {
+                self.answer_rpc(rpc.clone()).await;
+            }
+            Some(Fault::Corrupt) | None => {}
+        }
+    }
+
+    /// Looks up the fault for an RPC this node takes part in.
+    pub(super) fn injected_fault(
+        &self,
+        phase: Phase,
+        rpc: &str,
+        peer: SocketAddr,
+    ) -> Option<Fault> {
+        let faults = self.faults.as_ref()?;
+        match phase {
+            Phase::Handling => faults.fault(phase, rpc, None, self.addr),
+            _ => faults.fault(phase, rpc, Some(self.addr), peer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod faults_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::{Fault, FaultInjector, FaultRule, Phase};
+    use crate::{dht::rpc::DhtRpc, testing::TestCluster};
+
+    #[tokio::test]
+    async fn test_dropped_and_corrupted_requests_fail() {
+        let cluster = TestCluster::new(3).await;
+        let faults = cluster.faults();
+        let [a, b, c] = [0, 1, 2].map(|i| cluster.node(i));
+
+        faults.inject(FaultRule::new(Fault::Drop).on("Ping").to(b.addr).times(1));
+        faults.inject(FaultRule::new(Fault::Corrupt).from(c.addr));
+
+        assert!(a.send_rpc(b.addr, DhtRpc::Ping).await.is_err());
+        assert!(a.send_rpc(b.addr, DhtRpc::Ping).await.is_ok());
+        assert!(c.send_rpc(b.addr, DhtRpc::Ping).await.is_err());
+        assert!(b.send_rpc(c.addr, DhtRpc::Ping).await.is_ok());
+        assert_eq!(faults.injected(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_lost_acknowledgements_still_store() {
+        let cluster = TestCluster::new(2).await;
+        let [a, b] = [0, 1].map(|i| cluster.node(i));
+        cluster
+            .faults()
+            .inject(FaultRule::new(Fault::Drop).at(Phase::Response).on("Store"));
+
+        // No replica acknowledged the write, so it waits in the outbox
+        a.store(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+        assert!(a.outbox.contains_key(b"key".as_slice()));
+        assert!(b.local_value(b"key").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_duplicated_and_slow_handling() {
+        let cluster = TestCluster::new(2).await;
+        let [a, b] = [0, 1].map(|i| cluster.node(i));
+        let faults = cluster.faults();
+
+        faults.inject(
+            FaultRule::new(Fault::Duplicate)
+                .at(Phase::Handling)
+                .on("Ping"),
+        );
+        a.send_rpc(b.addr, DhtRpc::Ping).await.unwrap();
+        assert_eq!(b.get_stats().rpc_requests, 2);
+
+        faults.clear();
+        faults.inject(
+            FaultRule::new(Fault::Delay(Duration::from_millis(50)))
+                .at(Phase::Handling)
+                .to(b.addr),
+        );
+        let started = Instant::now();
+        a.send_rpc(b.addr, DhtRpc::Ping).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_rules_apply_in_order_and_expire() {
+        let faults = Arc::new(FaultInjector::new());
+        let addr = "127.0.0.1:1".parse().unwrap();
+        faults.inject(FaultRule::new(Fault::Drop).times(1));
+        faults.inject(FaultRule::new(Fault::Corrupt));
+
+        let fault = || faults.fault(Phase::Request, "Ping", Some(addr), addr);
+        assert_eq!(fault(), Some(Fault::Drop));
+        assert_eq!(fault(), Some(Fault::Corrupt));
+        assert_eq!(
+            faults.fault(Phase::Response, "Ping", Some(addr), addr),
+            None
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Serving browser clients over WebSocket.
+//!
+//! [`DhtNode::serve_gateway`] accepts WebSocket connections, such as those
+//! of [`WebClient`](crate::dht::web::WebClient), and answers each binary
+//! message as an RPC. Only the requests meant for clients are served:
+//! `Ping`, `ClientStore`, `CoordinatedStore`, `ClientGet` and `GetStats`.
+//! Anything else closes the connection, so browsers cannot pose as nodes and
+//! write replicas directly.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::{Message, protocol::WebSocketConfig};
+use tracing::{debug, warn};
+
+use crate::dht::{DhtNode, connection::transport::MAX_FRAME_LEN, rpc::DhtRpc, tasks};
+
+impl DhtNode {
+    /// Serves browser clients on `addr` until the returned task is aborted
+    /// or the node shuts down.
+    pub async fn serve_gateway(&self, addr: SocketAddr) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind gateway to {}", addr))?;
+
+        let node = self.clone();
+        Ok(tasks::spawn(
+            "gateway-listener",
+            self.until_stopped(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, peer)) => {
+                            let node = node.clone();
+                            tasks::spawn("gateway-connection", async move {
+                                if let Err(e) = node.serve_web_client(socket).await {
+                                    debug!(%peer, error = %e, "gateway connection failed");
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "failed to accept gateway connection");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }),
+        ))
+    }
+
+    async fn serve_web_client(&self, socket: TcpStream) -> Result<()> {
+        let config = WebSocketConfig::default().max_message_size(Some(MAX_FRAME_LEN));
+        let mut socket = tokio_tungstenite::accept_async_with_config(socket, Some(config)).await?;
+
+        while let Some(message) = socket.next().await {
+            let request = match message? {
+                Message::Binary(request) => bincode::deserialize(&request)?,
+                Message::Close(_) => break,
+                // Pings are answered by the WebSocket layer itself
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                Message::Text(_) => bail!("Text messages are not RPCs"),
+            };
+
+            let response = match request {
+                DhtRpc::Ping
+                | DhtRpc::ClientStore(..)
+                | DhtRpc::CoordinatedStore(..)
+                | DhtRpc::ClientGet(_)
+                | DhtRpc::GetStats => self.handle_rpc_admitted(request).await,
+                other => bail!("{} is not served to web clients", other.name()),
+            };
+            socket
+                .send(Message::binary(bincode::serialize(&response)?))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod gateway_tests {
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+    use crate::{
+        dht::{node::NodeId, rpc::DhtRpc},
+        helpers::create_test_node,
+    };
+
+    #[tokio::test]
+    async fn test_gateway_serves_client_requests_only() {
+        let node = create_test_node(8209);
+        let gateway = node
+            .serve_gateway("127.0.0.1:8210".parse().unwrap())
+            .await
+            .unwrap();
+        let (mut socket, _) = connect_async("ws://127.0.0.1:8210").await.unwrap();
+
+        let mut call = async |request: DhtRpc| {
+            let request = bincode::serialize(&request).unwrap();
+            socket.send(Message::binary(request)).await.unwrap();
+            match socket.next().await {
+                Some(Ok(Message::Binary(response))) => {
+                    Some(bincode::deserialize::<DhtRpc>(&response).unwrap())
+                }
+                _ => None,
+            }
+        };
+
+        let store = DhtRpc::ClientStore(b"key".to_vec(), Bytes::from_static(b"value"), None);
+        assert!(matches!(
+            call(store).await,
+            Some(DhtRpc::ClientStoreResponse(Ok(_)))
+        ));
+        assert!(matches!(
+            call(DhtRpc::ClientGet(b"key".to_vec())).await,
+            Some(DhtRpc::ClientGetResponse(Some(value))) if value == b"value"[..]
+        ));
+
+        // Node-to-node requests end the connection
+        assert!(
+            call(DhtRpc::FindNode(NodeId::new(b"target")))
+                .await
+                .is_none()
+        );
+
+        gateway.abort();
+    }
+}
@@ -0,0 +1,296 @@
+//! Best-effort topic messaging over the node's peer connections.
+//!
+//! [`DhtNode::publish`] floods a message to every peer in the routing table,
+//! each of which hands it to its local subscribers, see
+//! [`DhtNode::subscribe_topic`], and relays it to its own peers, up to
+//! `gossip.max_hops` times, whatever the hops a message arrives with. Nodes
+//! remember the ids of the last `gossip.seen_capacity` messages to drop the
+//! copies arriving over other paths, and relay at most `gossip.max_relays`
+//! messages at once.
+//!
+//! Delivery is not guaranteed: a message is lost to the nodes it does not
+//! reach within its hops, to subscribers that fall behind, and to relays
+//! shedding it under load, as gossip is maintenance traffic for
+//! [`overload`](crate::dht::overload).
+
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt, future, stream};
+use tokio::sync::{
+    Semaphore,
+    broadcast::{self, error::RecvError},
+};
+use tracing::debug;
+
+use crate::dht::{DhtNode, config::GossipConfig, overload::Priority, rpc::DhtRpc, tasks};
+
+/// Messages seen and subscribed to by a node.
+#[derive(Debug)]
+pub(super) struct GossipState {
+    seen: Mutex<SeenIds>,
+    messages: broadcast::Sender<(String, Bytes)>,
+    /// Permits for the relays in flight
+    relays: Arc<Semaphore>,
+}
+
+/// The most recent message ids, oldest first.
+#[derive(Debug)]
+struct SeenIds {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl SeenIds {
+    /// Records `id`, returning whether it is new.
+    fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+}
+
+impl GossipState {
+    pub(super) fn new(config: &GossipConfig) -> Self {
+        Self {
+            seen: Mutex::new(SeenIds {
+                ids: HashSet::new(),
+                order: VecDeque::new(),
+                capacity: config.seen_capacity.max(1),
+            }),
+            messages: broadcast::channel(config.subscriber_capacity.max(1)).0,
+            relays: Arc::new(Semaphore::new(config.max_relays)),
+        }
+    }
+
+    fn first_seen(&self, id: u64) -> bool {
+        self.seen.lock().unwrap().insert(id)
+    }
+}
+
+impl DhtNode {
+    /// Publishes `message` on `topic` to the subscribers of every node it
+    /// reaches, and returns the number of peers it was handed to directly.
+    ///
+    /// Subscribers of this node do not receive its own messages.
+    pub async fn publish(&self, topic: impl Into<String>, message: impl Into<Bytes>) -> usize {
+        let id = rand::random();
+        self.gossip.first_seen(id);
+        self.relay(
+            id,
+            topic.into(),
+            message.into(),
+            self.config.gossip.max_hops,
+        )
+        .await
+    }
+
+    /// Follows the messages published on `topic` by other nodes.
+    ///
+    /// The stream only yields messages arriving after the call; those a slow
+    /// subscriber falls behind on are skipped. It ends when every handle to
+    /// the node is dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::StreamExt;
+    /// use rust_p2p_node::dht::DhtNode;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), None);
+    ///     let mut news = Box::pin(node.subscribe_topic("news"));
+    ///
+    ///     while let Some(message) = news.next().await {
+    ///         println!("{}", String::from_utf8_lossy(&message));
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe_topic(
+        &self,
+        topic: impl Into<String>,
+    ) -> impl Stream<Item = Bytes> + Send + 'static {
+        let topic = topic.into();
+        stream::unfold(
+            self.gossip.messages.subscribe(),
+            |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(message) => return Some((message, receiver)),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )
+        .filter_map(move |(message_topic, message)| {
+            future::ready((message_topic == topic).then_some(message))
+        })
+    }
+
+    /// Handles a gossiped message: the first copy is delivered to local
+    /// subscribers and relayed in the background while it has hops left,
+    /// at most `gossip.max_hops` of them. Returns whether it is relayed.
+    pub(super) fn receive_gossip(&self, id: u64, topic: String, message: Bytes, hops: u8) -> bool {
+        if !self.gossip.first_seen(id) {
+            return false;
+        }
+        // No subscribers is fine
+        let _ = self.gossip.messages.send((topic.clone(), message.clone()));
+
+        let hops = hops.min(self.config.gossip.max_hops);
+        if hops <= 1 {
+            return false;
+        }
+        let Ok(permit) = Arc::clone(&self.gossip.relays).try_acquire_owned() else {
+            debug!(id, "too many relays in flight, not relaying gossip");
+            return false;
+        };
+        let node = self.clone();
+        tasks::spawn(
+            "gossip-relay",
+            Priority::Maintenance.scope(async move {
+                node.relay(id, topic, message, hops - 1).await;
+                drop(permit);
+            }),
+        );
+        true
+    }
+
+    /// Sends a message to every peer in the routing table, returning the
+    /// number that took it.
+    async fn relay(&self, id: u64, topic: String, message: Bytes, hops: u8) -> usize {
+        let mut peers = Vec::new();
+        self.for_each_peer(|peer| peers.push(peer.addr));
+
+        let (topic, message) = (&topic, &message);
+        stream::iter(peers)
+            .map(|addr| async move {
+                let gossip = DhtRpc::Gossip(id, topic.clone(), message.clone(), hops);
+                match self.send_rpc(addr, gossip).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        debug!(peer = %addr, error = %e, "failed to relay gossip");
+                        false
+                    }
+                }
+            })
+            .buffer_unordered(self.replication_parallelism())
+            .filter(|sent| future::ready(*sent))
+            .count()
+            .await
+    }
+}
+
+#[cfg(test)]
+mod gossip_tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+    use tokio::time::timeout;
+
+    use super::SeenIds;
+    use crate::{
+        dht::{DhtNode, rpc::DhtRpc},
+        helpers::{create_test_node, test_config},
+    };
+
+    #[test]
+    fn test_seen_ids_are_bounded() {
+        let mut seen = SeenIds {
+            ids: Default::default(),
+            order: Default::default(),
+            capacity: 2,
+        };
+        assert!(seen.insert(1));
+        assert!(!seen.insert(1));
+        assert!(seen.insert(2));
+        assert!(seen.insert(3));
+        assert_eq!(seen.ids.len(), 2);
+        // Forgotten, so new again
+        assert!(seen.insert(1));
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_relayed_once() {
+        let (a, b, c) = (
+            create_test_node(8239),
+            create_test_node(8240),
+            create_test_node(8241),
+        );
+        let _servers = (
+            a.listen().await.unwrap(),
+            b.listen().await.unwrap(),
+            c.listen().await.unwrap(),
+        );
+        // A ring, each node knowing only the next one
+        a.add_peer(b.peer_info());
+        b.add_peer(c.peer_info());
+        c.add_peer(a.peer_info());
+
+        let mut own = Box::pin(a.subscribe_topic("news"));
+        let mut news = Box::pin(c.subscribe_topic("news"));
+        let mut other = Box::pin(c.subscribe_topic("other"));
+
+        assert_eq!(a.publish("news", b"hello".to_vec()).await, 1);
+        let received = timeout(Duration::from_secs(5), news.next()).await.unwrap();
+        assert_eq!(received, Some(Bytes::from_static(b"hello")));
+
+        // Relayed back to a, which drops it as seen; nothing else arrives
+        let quiet = Duration::from_millis(200);
+        assert!(timeout(quiet, news.next()).await.is_err());
+        assert!(timeout(quiet, own.next()).await.is_err());
+        assert!(timeout(quiet, other.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copies_are_delivered_once() {
+        let node = create_test_node(8080);
+        let mut news = Box::pin(node.subscribe_topic("news"));
+
+        // Delivered, but with no hop left to relay
+        let gossip = DhtRpc::Gossip(7, "news".to_string(), Bytes::from_static(b"hi"), 1);
+        assert!(matches!(
+            node.handle_rpc(gossip.clone()).await,
+            DhtRpc::Pong
+        ));
+        assert!(matches!(node.handle_rpc(gossip).await, DhtRpc::Pong));
+        assert_eq!(news.next().await, Some(Bytes::from_static(b"hi")));
+        assert!(
+            timeout(Duration::from_millis(50), news.next())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relays_are_bounded() {
+        let mut config = test_config();
+        config.gossip.max_hops = 1;
+        config.gossip.max_relays = 1;
+        let mut node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+        let hi = || Bytes::from_static(b"hi");
+
+        // Whatever the sender claims, no more hops than configured
+        assert!(!node.receive_gossip(1, "news".to_string(), hi(), u8::MAX));
+
+        node.config.gossip.max_hops = u8::MAX;
+        let permit = node.gossip.relays.clone().try_acquire_owned().unwrap();
+        assert!(!node.receive_gossip(2, "news".to_string(), hi(), 2));
+        drop(permit);
+        assert!(node.receive_gossip(3, "news".to_string(), hi(), 2));
+    }
+}
@@ -0,0 +1,115 @@
+//! Health probes over HTTP.
+//!
+//! [`DhtNode::serve_health`] exposes [`DhtNode::health`] over a minimal HTTP
+//! endpoint meant for orchestrator probes such as Kubernetes':
+//!
+//! - `GET /livez` answers 200 while the maintenance tasks are running
+//! - `GET /readyz` answers 200 once the node is also bound and knows at least
+//!   `min_ready_peers` peers
+//! - `GET /health` returns the full [`HealthReport`](super::HealthReport) as
+//!   JSON
+//!
+//! Every other path answers 404; failing probes answer 503.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use crate::dht::{DhtNode, tasks};
+
+impl DhtNode {
+    /// Serves the health endpoint on `addr` until the returned task is
+    /// aborted or the node shuts down.
+    pub async fn serve_health(&self, addr: SocketAddr) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind health endpoint to {}", addr))?;
+
+        let node = self.clone();
+        Ok(tasks::spawn(
+            "health-listener",
+            self.until_stopped(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, _)) => {
+                            let node = node.clone();
+                            tasks::spawn("health-connection", async move {
+                                if let Err(e) = node.answer_probe(socket).await {
+                                    debug!(error = %e, "health probe failed");
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "failed to accept health probe");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }),
+        ))
+    }
+
+    async fn answer_probe(&self, mut socket: TcpStream) -> Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        timeout(Duration::from_secs(5), async {
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+                let n = socket.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            anyhow::Ok(())
+        })
+        .await
+        .context("Health probe timed out")??;
+
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+        let path = match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some(path)) => path,
+            _ => "",
+        };
+
+        let report = self.health();
+        let (status, content_type, body) = match path {
+            "/livez" if report.is_live() => ("200 OK", "text/plain", "ok".to_string()),
+            "/livez" => (
+                "503 Service Unavailable",
+                "text/plain",
+                "maintenance stopped".to_string(),
+            ),
+            "/readyz" if report.is_ready() => ("200 OK", "text/plain", "ok".to_string()),
+            "/readyz" => (
+                "503 Service Unavailable",
+                "text/plain",
+                "not ready".to_string(),
+            ),
+            "/health" => (
+                "200 OK",
+                "application/json",
+                serde_json::to_string(&report)?,
+            ),
+            _ => ("404 Not Found", "text/plain", "not found".to_string()),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown().await?;
+        Ok(())
+    }
+}
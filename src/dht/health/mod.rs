@@ -0,0 +1,89 @@
+//! Liveness and readiness reporting.
+//!
+//! [`DhtNode::health`] summarizes whether the node is serving, connected to
+//! the network and running its background tasks. With the `http` feature,
+//! [`DhtNode::serve_health`] exposes the same over HTTP, see [`http`].
+//!
+//! The health of the node's peers is checked separately, see [`peers`].
+
+#[cfg(feature = "http")]
+pub mod http;
+pub mod peers;
+
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::dht::{DhtNode, NodeId};
+
+/// Runtime state behind [`DhtNode::health`].
+#[derive(Debug, Default)]
+pub(super) struct HealthState {
+    /// Whether the RPC listener is accepting connections
+    pub(super) bound: AtomicBool,
+    /// Background maintenance tasks of the node
+    pub(super) tasks: Mutex<Vec<JoinHandle<()>>>,
+    /// Set by [`DhtNode::shutdown`]; every background task holds a receiver
+    /// until it stops
+    pub(super) stopping: watch::Sender<bool>,
+    /// Health checks each peer failed in a row, see [`peers`]
+    pub(super) ping_failures: DashMap<NodeId, u8>,
+}
+
+/// Clears [`HealthState::bound`] when the listener task ends or is aborted.
+pub(super) struct BoundGuard(pub(super) Arc<HealthState>);
+
+impl Drop for BoundGuard {
+    fn drop(&mut self) {
+        self.0.bound.store(false, Ordering::Release);
+    }
+}
+
+/// Point-in-time health of a node.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// The node is accepting RPC connections
+    pub bound: bool,
+    /// Number of peers in the routing table
+    pub peers: usize,
+    /// At least `min_ready_peers` peers are known
+    pub bootstrapped: bool,
+    /// Maintenance tasks were started and none of them has stopped
+    pub maintenance_alive: bool,
+}
+
+impl HealthReport {
+    /// Whether the node should be kept running.
+    pub fn is_live(&self) -> bool {
+        self.maintenance_alive
+    }
+
+    /// Whether the node should receive traffic.
+    pub fn is_ready(&self) -> bool {
+        self.bound && self.bootstrapped && self.maintenance_alive
+    }
+}
+
+impl DhtNode {
+    /// Reports the current health of the node.
+    pub fn health(&self) -> HealthReport {
+        let peers = self.peer_count();
+
+        let maintenance_alive = {
+            let tasks = self.health.tasks.lock().unwrap();
+            !tasks.is_empty() && tasks.iter().all(|task| !task.is_finished())
+        };
+
+        HealthReport {
+            bound: self.health.bound.load(Ordering::Acquire),
+            peers,
+            bootstrapped: peers >= self.config.min_ready_peers,
+            maintenance_alive,
+        }
+    }
+}
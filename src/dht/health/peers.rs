@@ -0,0 +1,185 @@
+//! Active health checks of the routing table.
+//!
+//! Every `health_check.interval`, [`DhtNode::start_health_checker`] pings the
+//! peers in the routing table, giving each `health_check.timeout` to answer.
+//! A peer that answers is marked as seen. One that fails
+//! `health_check.max_failures` checks in a row is evicted, and the values it
+//! originated are replicated elsewhere.
+//!
+//! A peer answering [`DhtRpc::Busy`], or a ping shed on this node, counts as
+//! neither an answer nor a failure.
+
+use futures::{StreamExt, stream};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::dht::{
+    DhtNode, PeerInfo,
+    overload::{Busy, Priority},
+    rpc::DhtRpc,
+    tasks,
+};
+
+/// Peers pinged at the same time
+const HEALTH_CHECK_PARALLELISM: usize = 16;
+
+/// How a peer fared in one health check.
+enum Outcome {
+    Alive,
+    Busy,
+    Failed,
+}
+
+impl DhtNode {
+    /// Starts a background task that checks the health of every peer in the
+    /// routing table every `health_check.interval`.
+    pub fn start_health_checker(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        tasks::spawn(
+            "health-checker",
+            self.until_stopped(Priority::Maintenance.scope(async move {
+                let mut interval = node.interval(node.config.health_check.interval);
+
+                loop {
+                    interval.tick().await;
+
+                    node.check_peers_health().await;
+                }
+            })),
+        )
+    }
+
+    /// Pings every peer in the routing table once, evicting those that have
+    /// now failed `health_check.max_failures` checks in a row. Returns the
+    /// number of peers evicted.
+    pub async fn check_peers_health(&self) -> usize {
+        // Snapshot the peers so no bucket stays locked across the pings
+        let mut peers = Vec::new();
+        self.for_each_peer(|peer| peers.push(peer.clone()));
+
+        // Forget the failures of peers that left the table meanwhile
+        self.health
+            .ping_failures
+            .retain(|id, _| peers.iter().any(|peer| &peer.id == id));
+
+        let outcomes: Vec<(PeerInfo, Outcome)> = stream::iter(peers)
+            .map(|peer| async move {
+                let outcome = self.ping_peer(&peer).await;
+                (peer, outcome)
+            })
+            .buffer_unordered(HEALTH_CHECK_PARALLELISM)
+            .collect()
+            .await;
+
+        let max_failures = self.config.health_check.max_failures.max(1);
+        let mut dead_peers = Vec::new();
+        for (peer, outcome) in outcomes {
+            match outcome {
+                Outcome::Alive => {
+                    self.update_peer_last_seen(&peer.id);
+                    self.health.ping_failures.remove(&peer.id);
+                }
+                Outcome::Busy => {}
+                Outcome::Failed => {
+                    let mut failures = self
+                        .health
+                        .ping_failures
+                        .entry(peer.id.clone())
+                        .or_insert(0);
+                    *failures += 1;
+                    debug!(peer = %peer.addr, failures = *failures, "peer failed a health check");
+                    if *failures >= max_failures {
+                        drop(failures);
+                        self.health.ping_failures.remove(&peer.id);
+                        dead_peers.push(peer);
+                    }
+                }
+            }
+        }
+
+        let evicted = dead_peers.len();
+        for peer in dead_peers {
+            self.handle_dead_peer(&peer).await;
+        }
+        evicted
+    }
+
+    /// Number of health checks `peer` failed in a row.
+    pub fn health_check_failures(&self, peer: &PeerInfo) -> u8 {
+        self.health
+            .ping_failures
+            .get(&peer.id)
+            .map_or(0, |failures| *failures)
+    }
+
+    async fn ping_peer(&self, peer: &PeerInfo) -> Outcome {
+        self.metrics.inc_health_checks();
+        let outcome = match self
+            .timeout(
+                self.config.health_check.timeout,
+                self.send_rpc(peer.addr, DhtRpc::Ping),
+            )
+            .await
+        {
+            Ok(Ok(DhtRpc::Pong)) => Outcome::Alive,
+            // Shed here or by the peer, which is alive either way
+            Ok(Err(e)) if e.is::<Busy>() => Outcome::Busy,
+            _ => Outcome::Failed,
+        };
+        if matches!(outcome, Outcome::Failed) {
+            self.metrics.inc_health_check_failures();
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod peers_tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        dht::{DhtNode, NodeId, PeerInfo},
+        helpers::test_config,
+    };
+
+    #[tokio::test]
+    async fn test_peers_are_evicted_after_max_failures() {
+        let mut config = test_config();
+        config.health_check.max_failures = 3;
+        config.health_check.timeout = Duration::from_millis(500);
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+        let alive = DhtNode::new("127.0.0.1:8233".parse().unwrap(), Some(test_config()));
+        let server = alive.listen().await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gone = PeerInfo::new(NodeId::new(b"gone"), listener.local_addr().unwrap());
+        drop(listener);
+        let mut stale = alive.peer_info();
+        stale.last_seen = 0;
+        node.add_peer(stale.clone());
+        node.add_peer(gone.clone());
+
+        for failures in 1..3 {
+            assert_eq!(node.check_peers_health().await, 0);
+            assert_eq!(node.health_check_failures(&gone), failures);
+        }
+        assert_eq!(node.health_check_failures(&stale), 0);
+        assert_eq!(node.check_peers_health().await, 1);
+        assert_eq!(node.health_check_failures(&gone), 0);
+
+        let mut peers = Vec::new();
+        node.for_each_peer(|peer| peers.push(peer.clone()));
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, stale.id);
+        assert!(peers[0].last_seen > 0);
+
+        let stats = node.get_stats();
+        assert_eq!(stats.health_checks, 6);
+        assert_eq!(stats.health_check_failures, 3);
+        assert_eq!(stats.peers_evicted, 1);
+
+        server.abort();
+    }
+}
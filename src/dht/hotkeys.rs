@@ -0,0 +1,261 @@
+//! Spotting the keys requested the most, and keeping one from taking a node
+//! over.
+//!
+//! Every value request a node answers, `FindValue` from peers and
+//! `ClientGet` from clients, counts towards its key over a sliding
+//! `hot_keys.window`. [`DhtNode::hot_keys`] and the node's stats report the
+//! `hot_keys.top` keys requested the most. A key requested at least
+//! `hot_keys.threshold` times within the window is hot, and two optional
+//! measures apply to it:
+//!
+//! - with `hot_keys.cache_ttl` set, `ClientGet` answers are reused for that
+//!   long instead of looking the key up across the network every time;
+//! - with `hot_keys.max_requests` set, the requests beyond that many within
+//!   the window are shed with [`DhtRpc::Busy`](crate::dht::rpc::DhtRpc::Busy).
+//!
+//! At most `hot_keys.max_tracked_keys` keys are counted per window. Keys first
+//! requested once that many are tracked go uncounted until the window moves
+//! on.
+
+use std::{collections::HashMap, mem, sync::Mutex, time::Duration};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use tokio::time::Instant;
+
+use crate::dht::{DhtNode, config::HotKeyConfig, metrics::HotKey};
+
+/// How a request fared against the hot key measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Heat {
+    Cold,
+    Hot,
+    /// Hot, and over `hot_keys.max_requests`
+    Shed,
+}
+
+/// Request counts of the keys a node was asked for.
+#[derive(Debug)]
+pub(super) struct HotKeys {
+    config: HotKeyConfig,
+    window: Mutex<Window>,
+    /// Recent `ClientGet` answers for hot keys, and when they were found
+    cache: DashMap<Vec<u8>, (Instant, Option<Bytes>)>,
+}
+
+/// Counts of the current window and the one before it, which the sliding
+/// window weighs by how much of it still overlaps.
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    current: HashMap<Vec<u8>, u64>,
+    previous: HashMap<Vec<u8>, u64>,
+}
+
+impl Window {
+    /// Moves on to the window `now` falls into, returning the weight of the
+    /// previous window.
+    fn advance(&mut self, now: Instant, length: Duration) -> f64 {
+        let elapsed = now.duration_since(self.started);
+        if elapsed >= length * 2 {
+            self.current.clear();
+            self.previous.clear();
+            self.started = now;
+        } else if elapsed >= length {
+            self.previous = mem::take(&mut self.current);
+            self.started += length;
+        }
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        1.0 - elapsed / length.as_secs_f64()
+    }
+
+    fn estimate(&self, key: &[u8], weight: f64) -> u64 {
+        let current = self.current.get(key).copied().unwrap_or(0);
+        let previous = self.previous.get(key).copied().unwrap_or(0);
+        current + (previous as f64 * weight).round() as u64
+    }
+}
+
+impl HotKeys {
+    pub(super) fn new(config: HotKeyConfig) -> Self {
+        Self {
+            config,
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                current: HashMap::new(),
+                previous: HashMap::new(),
+            }),
+            cache: DashMap::new(),
+        }
+    }
+
+    fn length(&self) -> Duration {
+        self.config.window.max(Duration::from_millis(1))
+    }
+
+    /// Counts a request for `key`, returning the requests for it within the
+    /// window, this one included.
+    fn record(&self, key: &[u8]) -> u64 {
+        let mut window = self.window.lock().unwrap();
+        let weight = window.advance(Instant::now(), self.length());
+        if let Some(count) = window.current.get_mut(key) {
+            *count += 1;
+        } else if window.current.len() < self.config.max_tracked_keys {
+            window.current.insert(key.to_vec(), 1);
+        }
+        window.estimate(key, weight)
+    }
+
+    fn heat(&self, requests: u64) -> Heat {
+        if requests < self.config.threshold {
+            Heat::Cold
+        } else if self.config.max_requests.is_some_and(|max| requests > max) {
+            Heat::Shed
+        } else {
+            Heat::Hot
+        }
+    }
+
+    /// The `hot_keys.top` keys requested the most, most requested first.
+    fn top(&self) -> Vec<HotKey> {
+        let mut window = self.window.lock().unwrap();
+        let weight = window.advance(Instant::now(), self.length());
+        let mut keys: Vec<HotKey> = window
+            .current
+            .keys()
+            .chain(
+                window
+                    .previous
+                    .keys()
+                    .filter(|k| !window.current.contains_key(*k)),
+            )
+            .map(|key| HotKey {
+                key: key.clone(),
+                requests: window.estimate(key, weight),
+            })
+            .filter(|hot| hot.requests > 0)
+            .collect();
+        keys.sort_unstable_by(|a, b| b.requests.cmp(&a.requests).then(a.key.cmp(&b.key)));
+        keys.truncate(self.config.top);
+        keys
+    }
+
+    fn cached(&self, key: &[u8]) -> Option<Option<Bytes>> {
+        let ttl = self.config.cache_ttl?;
+        let entry = self.cache.get(key)?;
+        let (found, value) = &*entry;
+        (found.elapsed() < ttl).then(|| value.clone())
+    }
+
+    fn cache(&self, key: Vec<u8>, value: Option<Bytes>) {
+        let Some(ttl) = self.config.cache_ttl else {
+            return;
+        };
+        self.cache.retain(|_, (found, _)| found.elapsed() < ttl);
+        self.cache.insert(key, (Instant::now(), value));
+    }
+}
+
+impl DhtNode {
+    /// The keys this node was asked for the most within `hot_keys.window`,
+    /// most requested first.
+    pub fn hot_keys(&self) -> Vec<HotKey> {
+        self.hot_keys.top()
+    }
+
+    /// Counts a request for `key` and tells how hot the key now is,
+    /// counting the request as shed if it is to be.
+    pub(super) fn track_key_request(&self, key: &[u8]) -> Heat {
+        let heat = self.hot_keys.heat(self.hot_keys.record(key));
+        if heat == Heat::Shed {
+            self.metrics.inc_hot_key_requests_shed();
+        }
+        heat
+    }
+
+    /// Looks a hot key up like [`DhtNode::find_value`], answering from the
+    /// cache while `hot_keys.cache_ttl` allows.
+    pub(super) async fn find_hot_value(&self, key: Vec<u8>) -> Option<Bytes> {
+        if let Some(value) = self.hot_keys.cached(&key) {
+            return value;
+        }
+        let value = self.find_value(key.clone()).await;
+        self.hot_keys.cache(key, value.clone());
+        value
+    }
+}
+
+#[cfg(test)]
+mod hotkeys_tests {
+    use std::time::Duration;
+
+    use super::{Heat, HotKeys};
+    use crate::{
+        dht::{DhtNode, config::HotKeyConfig, rpc::DhtRpc},
+        helpers::test_config,
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_slide_out_of_the_window() {
+        let hot_keys = HotKeys::new(HotKeyConfig {
+            window: Duration::from_secs(10),
+            max_tracked_keys: 2,
+            top: 2,
+            ..HotKeyConfig::default()
+        });
+        for _ in 0..4 {
+            hot_keys.record(b"a");
+        }
+        hot_keys.record(b"b");
+        // Not tracked, the window is full
+        assert_eq!(hot_keys.record(b"c"), 0);
+        let top = hot_keys.top();
+        assert_eq!((top[0].key.as_slice(), top[0].requests), (&b"a"[..], 4));
+        assert_eq!((top[1].key.as_slice(), top[1].requests), (&b"b"[..], 1));
+
+        // Half of the previous window still counts
+        tokio::time::advance(Duration::from_secs(15)).await;
+        assert_eq!(hot_keys.record(b"c"), 1);
+        assert_eq!(hot_keys.top()[0].requests, 2);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(hot_keys.top().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hot_keys_are_cached_and_shed() {
+        let mut config = test_config();
+        config.hot_keys = HotKeyConfig {
+            threshold: 2,
+            max_requests: Some(3),
+            cache_ttl: Some(Duration::from_secs(60)),
+            ..HotKeyConfig::default()
+        };
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+        let get = || node.handle_rpc(DhtRpc::ClientGet(b"key".to_vec()));
+
+        assert!(matches!(get().await, DhtRpc::ClientGetResponse(None)));
+        // Hot now, so its answer is cached
+        assert!(matches!(get().await, DhtRpc::ClientGetResponse(None)));
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert!(matches!(get().await, DhtRpc::ClientGetResponse(None)));
+        assert!(matches!(get().await, DhtRpc::Busy));
+        assert!(matches!(
+            node.handle_rpc(DhtRpc::FindValue(b"key".to_vec())).await,
+            DhtRpc::Busy
+        ));
+        assert!(matches!(
+            node.handle_rpc(DhtRpc::FindValue(b"other".to_vec())).await,
+            DhtRpc::FindValueResponse(None)
+        ));
+
+        let stats = node.get_stats();
+        assert_eq!(stats.hot_key_requests_shed, 2);
+        assert_eq!(stats.hot_keys[0].key, b"key");
+        assert_eq!(stats.hot_keys[0].requests, 5);
+        assert_eq!(stats.hot_keys[1].key, b"other");
+        assert_eq!(node.track_key_request(b"other"), Heat::Hot);
+    }
+}
@@ -0,0 +1,439 @@
+//! The key pair a node is known by.
+//!
+//! A node's id is the hash of its public key, and it signs the
+//! [`PeerInfo`] announcing it with the private one. A node relaying a
+//! [`FindNodeResponse`](crate::dht::rpc::DhtRpc::FindNodeResponse) can
+//! therefore neither point a known id at another address nor make up
+//! entries for ids it does not hold the key of; receivers drop entries
+//! failing [`PeerInfo::verify`].
+//!
+//! With `id_difficulty` set in [`DhtConfig`](crate::dht::config::DhtConfig),
+//! ids must also solve a proof-of-work puzzle, see [`solves_puzzle`]:
+//! finding a key whose id does takes about `2^difficulty` attempts, which
+//! makes spinning up thousands of identities to surround a region of the
+//! keyspace expensive. Peers learned with ids failing it are dropped like
+//! unsigned ones.
+
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, atomic::Ordering},
+};
+
+use anyhow::{Context, Result, anyhow};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+use tracing::debug;
+
+use crate::dht::{
+    DhtNode,
+    node::{IdHash, NodeId},
+    peer::PeerInfo,
+};
+
+/// An Ed25519 key pair identifying a node.
+///
+/// Its `Debug` form does not reveal the private key.
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    /// Generates a new random identity.
+    pub fn generate() -> Self {
+        Self {
+            key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Generates random identities until one's id solves the puzzle of
+    /// `difficulty` on a network using `hash`, see [`solves_puzzle`].
+    pub fn generate_with_work(hash: IdHash, difficulty: u8) -> Self {
+        loop {
+            let identity = Self::generate();
+            if solves_puzzle(&identity.node_id(hash), hash, difficulty) {
+                return identity;
+            }
+        }
+    }
+
+    /// Restores an identity from its private key.
+    pub fn from_secret_bytes(secret: &[u8; 32]) -> Self {
+        Self {
+            key: SigningKey::from_bytes(secret),
+        }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    pub fn public_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    /// The id of a node with this identity, on a network using `hash`.
+    pub fn node_id(&self, hash: IdHash) -> NodeId {
+        Self::id_of(&self.public_key(), hash)
+    }
+
+    /// The id of a node with the public key `key`, on a network using
+    /// `hash`.
+    pub fn id_of(key: &VerifyingKey, hash: IdHash) -> NodeId {
+        NodeId::with_hash(key.as_bytes(), hash)
+    }
+
+    /// Reads the identity stored at `path`, or generates one and stores it
+    /// there, readable by the owner only, if there is none.
+    ///
+    /// Files written by older versions of the crate are read as well, see
+    /// [`migrate`](crate::dht::migrate); those written by newer ones are
+    /// refused.
+    ///
+    /// The identity's id must solve the puzzle of `difficulty` on a network
+    /// using `hash`.
+    pub fn load_or_generate(path: &Path, hash: IdHash, difficulty: u8) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let (_, identity) = parse_identity_file(&contents)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                if !solves_puzzle(&identity.node_id(hash), hash, difficulty) {
+                    return Err(anyhow!(
+                        "The identity in {} does not meet the id difficulty of {}",
+                        path.display(),
+                        difficulty
+                    ));
+                }
+                Ok(identity)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate_with_work(hash, difficulty);
+                write_identity_file(path, &identity)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                Ok(identity)
+            }
+            Err(e) => {
+                Err(anyhow::Error::new(e).context(format!("Failed to read {}", path.display())))
+            }
+        }
+    }
+
+    /// Signs `message` with this identity's key.
+    pub(crate) fn sign(&self, message: &[u8]) -> Signature {
+        self.key.sign(message)
+    }
+
+    /// Signs the announcement of a node with this identity.
+    pub fn announce(&self, hash: IdHash, addr: SocketAddr, alt_addrs: Vec<SocketAddr>) -> PeerInfo {
+        self.sign_announcement(PeerInfo::new(self.node_id(hash), addr).with_alt_addrs(alt_addrs))
+    }
+
+    /// Signs `peer` again, after changing what it announces.
+    pub(crate) fn sign_announcement(&self, mut peer: PeerInfo) -> PeerInfo {
+        peer.public_key = Some(self.public_key());
+        peer.signature = Some(self.key.sign(&peer.signed_bytes()));
+        peer
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("public_key", &hex::encode(self.public_key().as_bytes()))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Whether `id` solves the proof-of-work puzzle of `difficulty`: hashed
+/// again with `hash`, it must start with `difficulty` zero bits.
+pub fn solves_puzzle(id: &NodeId, hash: IdHash, difficulty: u8) -> bool {
+    NodeId::with_hash(id.as_bytes(), hash).leading_zeros() >= usize::from(difficulty)
+}
+
+/// Start of the first line of an identity file, before its format version
+const IDENTITY_HEADER: &str = "dht-identity";
+/// Format of the identity files written by this version: the header line,
+/// then the private key as hex. Format 0, a bare hex key, has no header.
+pub(super) const IDENTITY_FORMAT: u32 = 1;
+
+/// Parses the contents of an identity file, returning the format it was
+/// written in along with the identity.
+pub(super) fn parse_identity_file(contents: &str) -> Result<(u32, Identity)> {
+    let (format, secret) = match contents.split_once('\n') {
+        Some((header, secret)) if header.starts_with(IDENTITY_HEADER) => {
+            let format = header[IDENTITY_HEADER.len()..]
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid identity file header {:?}", header))?;
+            (format, secret)
+        }
+        _ => (0, contents),
+    };
+    if format > IDENTITY_FORMAT {
+        return Err(anyhow!(
+            "Identity file format {} is newer than the supported {}",
+            format,
+            IDENTITY_FORMAT
+        ));
+    }
+
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(secret.trim(), &mut bytes)
+        .map_err(|_| anyhow!("The file does not hold an identity key"))?;
+    Ok((format, Identity::from_secret_bytes(&bytes)))
+}
+
+/// Stores `identity` at `path` in the current format, readable by the owner
+/// only.
+pub(super) fn write_identity_file(path: &Path, identity: &Identity) -> io::Result<()> {
+    let contents = format!(
+        "{} {}\n{}\n",
+        IDENTITY_HEADER,
+        IDENTITY_FORMAT,
+        hex::encode(identity.secret_bytes())
+    );
+    write_private(path, &contents)
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    fs::write(path, contents)
+}
+
+impl DhtNode {
+    /// Runs the node under `identity` instead of a random one. This
+    /// changes its id.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.id = identity.node_id(self.config.id_hash);
+        self.identity = Arc::new(identity);
+        self
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// This node's signed announcement, for other nodes' routing tables.
+    pub fn peer_info(&self) -> PeerInfo {
+        let peer = self
+            .identity
+            .announce(self.config.id_hash, self.addr, Vec::new());
+        if self.config.mirror {
+            self.identity.sign_announcement(peer.as_mirror())
+        } else {
+            peer
+        }
+    }
+
+    /// Keeps the peers of a `FindNodeResponse` from `from` that are signed
+    /// by their own key, and whose ids solve the configured puzzle.
+    pub(super) fn verified_peers(&self, from: SocketAddr, peers: Vec<PeerInfo>) -> Vec<PeerInfo> {
+        let received = peers.len();
+        let peers: Vec<_> = peers
+            .into_iter()
+            .filter(|peer| peer.verify(self.config.id_hash))
+            .filter(|peer| solves_puzzle(&peer.id, self.config.id_hash, self.config.id_difficulty))
+            .collect();
+        if peers.len() < received {
+            debug!(%from, dropped = received - peers.len(), "dropping unverifiable peers");
+        }
+        peers
+    }
+
+    /// This node's announcement for the requests it sends, while it accepts
+    /// connections: a node that does not is of no use in others' routing
+    /// tables.
+    pub(super) fn sender_info(&self) -> Option<PeerInfo> {
+        self.health
+            .bound
+            .load(Ordering::Acquire)
+            .then(|| self.peer_info())
+    }
+
+    /// Adds the sender of a request that came from `ip` to the routing
+    /// table, or marks it as seen if it is there already. Announcements that
+    /// fail verification, or point at another host than the one the request
+    /// came from, are ignored.
+    pub(super) fn learn_sender(&self, sender: PeerInfo, ip: Option<IpAddr>) {
+        if sender.id == self.id {
+            return;
+        }
+        if ip.is_some_and(|ip| ip.to_canonical() != sender.addr.ip().to_canonical()) {
+            debug!(peer = %sender.addr, ?ip, "ignoring sender announced from another host");
+            return;
+        }
+
+        let bucket_index = self.get_bucket_index(&self.id.distance(&sender.id));
+        let known = self.routing_table.get(&bucket_index).is_some_and(|bucket| {
+            bucket
+                .peers
+                .iter()
+                .any(|peer| peer.id == sender.id && peer.addr == sender.addr)
+        });
+        if known {
+            self.update_peer_last_seen(&sender.id);
+        } else if let Some(mut sender) = self.verified_peers(sender.addr, vec![sender]).pop() {
+            sender.last_seen = self.now();
+            self.add_peer(sender);
+        }
+    }
+}
+
+#[cfg(test)]
+mod identity_tests {
+    use super::{Identity, solves_puzzle};
+    use crate::{
+        dht::{DhtNode, NodeId, PeerInfo, node::IdHash, rpc::DhtRpc},
+        helpers::{create_test_node, test_config},
+    };
+
+    #[test]
+    fn test_announcements_verify_only_untouched() {
+        let identity = Identity::generate();
+        let peer = identity.announce(IdHash::Sha3_256, "127.0.0.1:1".parse().unwrap(), Vec::new());
+        assert!(peer.verify(IdHash::Sha3_256));
+        assert!(!peer.verify(IdHash::Blake3));
+
+        let mut moved = peer.clone();
+        moved.addr = "127.0.0.1:2".parse().unwrap();
+        assert!(!moved.verify(IdHash::Sha3_256));
+
+        let mut renamed = peer.clone();
+        renamed.id = NodeId::new(b"other");
+        assert!(!renamed.verify(IdHash::Sha3_256));
+
+        // Signed correctly, but by a key the id does not derive from
+        let mut stolen = Identity::generate().announce(IdHash::Sha3_256, peer.addr, Vec::new());
+        stolen.id = peer.id.clone();
+        assert!(!stolen.verify(IdHash::Sha3_256));
+
+        let mut seen_later = peer.clone();
+        seen_later.last_seen += 60;
+        assert!(seen_later.verify(IdHash::Sha3_256));
+
+        assert!(!PeerInfo::new(peer.id.clone(), peer.addr).verify(IdHash::Sha3_256));
+    }
+
+    #[test]
+    fn test_ids_without_work_are_dropped() {
+        let mut config = test_config();
+        config.id_difficulty = 8;
+        let node = DhtNode::new("127.0.0.1:1".parse().unwrap(), Some(config));
+        assert!(solves_puzzle(&node.id, IdHash::Sha3_256, 8));
+
+        let worked = Identity::generate_with_work(IdHash::Sha3_256, 8).announce(
+            IdHash::Sha3_256,
+            "127.0.0.1:2".parse().unwrap(),
+            Vec::new(),
+        );
+        let lazy = (0u8..)
+            .map(|i| Identity::from_secret_bytes(&[i; 32]))
+            .find(|identity| {
+                !solves_puzzle(&identity.node_id(IdHash::Sha3_256), IdHash::Sha3_256, 8)
+            })
+            .unwrap()
+            .announce(IdHash::Sha3_256, "127.0.0.1:3".parse().unwrap(), Vec::new());
+
+        let from = "127.0.0.1:4".parse().unwrap();
+        let kept = node.verified_peers(from, vec![worked.clone(), lazy]);
+        assert_eq!(kept, vec![worked]);
+    }
+
+    #[test]
+    fn test_identity_is_stored_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("identity-{}.key", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let created = Identity::load_or_generate(&path, IdHash::Sha3_256, 0).unwrap();
+        let loaded = Identity::load_or_generate(&path, IdHash::Sha3_256, 0).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+        assert!(Identity::load_or_generate(&path, IdHash::Sha3_256, 64).is_err());
+        assert!(!format!("{:?}", loaded).contains(&hex::encode(loaded.secret_bytes())));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_drops_forged_peers() {
+        let seed = create_test_node(8219);
+        let server = seed.listen().await.unwrap();
+
+        let honest = create_test_node(8220);
+        seed.add_peer(honest.peer_info());
+        let mut hijacked = honest.peer_info();
+        hijacked.addr = "127.0.0.1:8221".parse().unwrap();
+        hijacked.id = create_test_node(8221).id;
+        seed.add_peer(hijacked);
+        seed.add_peer(PeerInfo::new(
+            NodeId::new(b"unsigned"),
+            "127.0.0.1:8222".parse().unwrap(),
+        ));
+
+        let joining = DhtNode::new("127.0.0.1:8223".parse().unwrap(), None)
+            .with_identity(Identity::from_secret_bytes(&[7; 32]));
+        assert_eq!(
+            joining.id,
+            Identity::from_secret_bytes(&[7; 32]).node_id(IdHash::default())
+        );
+        joining.bootstrap(vec![seed.addr]).await.unwrap();
+
+        let known: Vec<_> = joining
+            .routing_table
+            .iter()
+            .flat_map(|bucket| bucket.get_peers())
+            .map(|peer| peer.addr)
+            .collect();
+        assert_eq!(known, vec![honest.addr]);
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_requests_teach_their_sender() {
+        let receiver = create_test_node(8234);
+        let sender = create_test_node(8235);
+        let servers = (
+            receiver.listen().await.unwrap(),
+            sender.listen().await.unwrap(),
+        );
+
+        // Not accepting connections, so not announced
+        let silent = create_test_node(8080);
+        silent.send_rpc(receiver.addr, DhtRpc::Ping).await.unwrap();
+        assert_eq!(receiver.peer_count(), 0);
+
+        sender.send_rpc(receiver.addr, DhtRpc::Ping).await.unwrap();
+        let known: Vec<_> = receiver
+            .routing_table
+            .iter()
+            .flat_map(|bucket| bucket.get_peers())
+            .collect();
+        assert_eq!(known.len(), 1);
+        assert_eq!((&known[0].id, known[0].addr), (&sender.id, sender.addr));
+
+        // Forged or relayed from elsewhere
+        let mut forged = silent.peer_info();
+        forged.addr = "127.0.0.1:8236".parse().unwrap();
+        receiver.learn_sender(forged, None);
+        let elsewhere = silent.peer_info();
+        receiver.learn_sender(elsewhere, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(receiver.peer_count(), 1);
+
+        servers.0.abort();
+        servers.1.abort();
+    }
+}
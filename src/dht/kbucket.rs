@@ -3,6 +3,8 @@
 //! The [`KBucket`] struct represents a single bucket in the Kademlia routing table,
 //! containing a list of peers sorted by their last seen time (LRU).
 
+use std::{iter, ops::RangeInclusive};
+
 use crate::dht::{node::NodeId, peer::PeerInfo};
 
 /// A bucket in the Kademlia routing table holds up to `max_size` peers.
@@ -25,7 +27,11 @@ use crate::dht::{node::NodeId, peer::PeerInfo};
 /// let peer = PeerInfo {
 ///     id: NodeId::new(b"peer"),
 ///     addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080),
+///     alt_addrs: Vec::new(),
+///     mirror: false,
 ///     last_seen: 0,
+///     public_key: None,
+///     signature: None,
 /// };
 ///
 /// bucket.update_peer(peer.clone());
@@ -62,10 +68,17 @@ impl KBucket {
     /// Returns a copy of all peers in this bucket.
     ///
     /// Peers are returned in order from most recently seen to least recently seen.
+    /// Prefer [`KBucket::iter`] when the peers are only read.
     pub fn get_peers(&self) -> Vec<PeerInfo> {
         self.peers.clone()
     }
 
+    /// Iterates over the peers in this bucket without copying them, in the
+    /// order of [`KBucket::get_peers`].
+    pub fn iter(&self) -> std::slice::Iter<'_, PeerInfo> {
+        self.peers.iter()
+    }
+
     /// Returns the peer by id.
     pub fn get_peer(&self, peer_id: &NodeId) -> Option<&PeerInfo> {
         self.peers.iter().find(|p| &p.id == peer_id)
@@ -87,17 +100,36 @@ impl KBucket {
     }
 }
 
+/// Groups of bucket indices, from the peers closest to a target to the
+/// farthest, for a target falling in bucket `target`.
+///
+/// A peer of bucket `target` shares one more leading bit with the target
+/// than the peers of any later bucket, which all sit at distances of the same
+/// order from it and form one group. The peers of earlier buckets come
+/// after, one bucket at a time, each farther than the previous. Only within
+/// a group do peers need sorting by distance.
+pub(crate) fn buckets_by_distance(target: u8) -> impl Iterator<Item = RangeInclusive<u8>> {
+    iter::once(target..=target)
+        .chain(target.checked_add(1).map(|next| next..=u8::MAX))
+        .chain((0..target).rev().map(|index| index..=index))
+}
+
 #[cfg(test)]
 mod kbucket_tests {
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
+    use super::buckets_by_distance;
     use crate::dht::{KBucket, NodeId, PeerInfo};
 
     fn create_peer(id: &str) -> PeerInfo {
         PeerInfo {
             id: NodeId::new(id.as_bytes()),
             addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8090),
+            alt_addrs: Vec::new(),
+            mirror: false,
             last_seen: 0,
+            public_key: None,
+            signature: None,
         }
     }
 
@@ -152,4 +184,31 @@ mod kbucket_tests {
         assert_eq!(bucket.len(), 1);
         assert!(bucket.is_full());
     }
+
+    #[test]
+    fn test_buckets_by_distance_cover_all_once() {
+        let groups: Vec<_> = buckets_by_distance(2).collect();
+        assert_eq!(groups[..3], [2..=2, 3..=255, 1..=1]);
+        assert_eq!(groups.last(), Some(&(0..=0)));
+
+        for target in [0, 2, 255] {
+            let mut indices: Vec<u8> = buckets_by_distance(target).flatten().collect();
+            indices.sort();
+            assert!(indices.into_iter().eq(0..=255));
+        }
+    }
+
+    #[test]
+    fn test_iter_matches_get_peers() {
+        let mut bucket = KBucket {
+            peers: Vec::new(),
+            max_size: 3,
+        };
+        bucket.update_peer(create_peer("peer1"));
+        bucket.update_peer(create_peer("peer2"));
+
+        let borrowed: Vec<&PeerInfo> = bucket.iter().collect();
+        assert_eq!(borrowed.len(), 2);
+        assert!(borrowed.into_iter().eq(bucket.get_peers().iter()));
+    }
 }
@@ -0,0 +1,229 @@
+//! Limits on the inbound traffic of each source IP.
+//!
+//! The listener refuses connections from an IP that already holds
+//! `max_connections_per_ip` of them, and an IP sending more than
+//! `max_requests_per_second` requests is greylisted: its connections are
+//! closed and new ones refused for `greylist_duration`. Both limits are set
+//! in [`InboundLimitsConfig`] and are off by default.
+//!
+//! Connections whose source address the transport does not report are not
+//! limited.
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::dht::config::InboundLimitsConfig;
+
+/// Tracks the connections and request rate of each source IP.
+#[derive(Debug)]
+pub struct InboundLimiter {
+    config: InboundLimitsConfig,
+    ips: DashMap<IpAddr, IpState>,
+}
+
+#[derive(Debug)]
+struct IpState {
+    connections: usize,
+    window_start: Instant,
+    requests: u32,
+    greylisted_until: Option<Instant>,
+}
+
+impl IpState {
+    fn new() -> Self {
+        Self {
+            connections: 0,
+            window_start: Instant::now(),
+            requests: 0,
+            greylisted_until: None,
+        }
+    }
+
+    fn is_greylisted(&self) -> bool {
+        self.greylisted_until
+            .is_some_and(|until| until > Instant::now())
+    }
+}
+
+/// A connection admitted by [`InboundLimiter::admit`], counted against its
+/// IP until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<InboundLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl InboundLimiter {
+    pub fn new(config: InboundLimitsConfig) -> Self {
+        Self {
+            config,
+            ips: DashMap::new(),
+        }
+    }
+
+    /// Admits a connection from `ip`, unless the IP is greylisted or
+    /// already holds as many connections as allowed.
+    pub fn admit(self: &Arc<Self>, ip: Option<IpAddr>) -> Option<ConnectionPermit> {
+        if let Some(ip) = ip {
+            let mut state = self.ips.entry(ip).or_insert_with(IpState::new);
+            let max = self.config.max_connections_per_ip;
+            if state.is_greylisted() || (max > 0 && state.connections >= max) {
+                return None;
+            }
+            state.connections += 1;
+        }
+
+        Some(ConnectionPermit {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Counts a request from `ip`, and tells whether it may be served.
+    ///
+    /// An IP going over the rate limit is greylisted.
+    pub fn allow_request(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip else {
+            return true;
+        };
+        let mut state = self.ips.entry(ip).or_insert_with(IpState::new);
+        if state.is_greylisted() {
+            return false;
+        }
+
+        let max = self.config.max_requests_per_second;
+        if max == 0 {
+            return true;
+        }
+        if state.window_start.elapsed() >= Duration::from_secs(1) {
+            state.window_start = Instant::now();
+            state.requests = 0;
+        }
+        state.requests += 1;
+        if state.requests > max {
+            warn!(%ip, "greylisting IP over its request rate");
+            state.greylisted_until = Some(Instant::now() + self.config.greylist_duration);
+            return false;
+        }
+        true
+    }
+
+    /// Whether `ip` is currently greylisted.
+    pub fn is_greylisted(&self, ip: IpAddr) -> bool {
+        self.ips.get(&ip).is_some_and(|state| state.is_greylisted())
+    }
+
+    /// Open connections from `ip`.
+    pub fn connections(&self, ip: IpAddr) -> usize {
+        self.ips.get(&ip).map_or(0, |state| state.connections)
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let Some(ip) = self.ip else {
+            return;
+        };
+        if let Some(mut state) = self.limiter.ips.get_mut(&ip) {
+            state.connections -= 1;
+        }
+        // Forget idle IPs once their greylisting is over
+        self.limiter.ips.remove_if(&ip, |_, state| {
+            state.connections == 0 && !state.is_greylisted()
+        });
+    }
+}
+
+#[cfg(test)]
+mod limits_tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use crate::{
+        dht::{DhtNode, config::InboundLimitsConfig, limits::InboundLimiter, rpc::DhtRpc},
+        helpers::test_config,
+    };
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+
+    fn limiter(max_connections_per_ip: usize, max_requests_per_second: u32) -> Arc<InboundLimiter> {
+        Arc::new(InboundLimiter::new(InboundLimitsConfig {
+            max_connections_per_ip,
+            max_requests_per_second,
+            greylist_duration: Duration::from_secs(60),
+        }))
+    }
+
+    #[test]
+    fn test_connections_are_limited_per_ip() {
+        let limiter = limiter(2, 0);
+        let first = limiter.admit(IP).unwrap();
+        let _second = limiter.admit(IP).unwrap();
+        assert!(limiter.admit(IP).is_none());
+        assert!(
+            limiter
+                .admit(Some(IpAddr::V4(Ipv4Addr::LOCALHOST)))
+                .is_some()
+        );
+        assert!(limiter.admit(None).is_some());
+
+        drop(first);
+        assert_eq!(limiter.connections(IP.unwrap()), 1);
+        assert!(limiter.admit(IP).is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fast_senders_are_greylisted() {
+        let limiter = limiter(0, 3);
+        for _ in 0..3 {
+            assert!(limiter.allow_request(IP));
+        }
+        tokio::time::advance(Duration::from_secs(1)).await;
+        for _ in 0..3 {
+            assert!(limiter.allow_request(IP));
+        }
+        assert!(!limiter.allow_request(IP));
+        assert!(limiter.is_greylisted(IP.unwrap()));
+        assert!(limiter.admit(IP).is_none());
+        assert!(limiter.allow_request(None));
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(!limiter.is_greylisted(IP.unwrap()));
+        assert!(limiter.allow_request(IP));
+    }
+
+    #[tokio::test]
+    async fn test_listener_enforces_limits() {
+        let mut config = test_config();
+        config.inbound_limits.max_connections_per_ip = 4;
+        config.inbound_limits.max_requests_per_second = 2;
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8224);
+        let server = DhtNode::new(addr, Some(config));
+        let listener = server.listen().await.unwrap();
+
+        let client = DhtNode::new(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8225),
+            Some(test_config()),
+        );
+        for _ in 0..2 {
+            assert!(matches!(
+                client.send_rpc(addr, DhtRpc::Ping).await,
+                Ok(DhtRpc::Pong)
+            ));
+        }
+        assert!(client.send_rpc(addr, DhtRpc::Ping).await.is_err());
+        assert!(
+            server
+                .inbound
+                .is_greylisted(IpAddr::V4(Ipv4Addr::LOCALHOST))
+        );
+
+        listener.abort();
+    }
+}
@@ -0,0 +1,426 @@
+//! Conditional writes, and leases built on them.
+//!
+//! [`DhtNode::compare_and_swap`] writes a key only where the copy held has
+//! the expected version. The key's `replication.factor` closest nodes each
+//! check their own copy before replacing it, with a [`DhtRpc::StoreIf`],
+//! and the swap succeeds once a majority of them did. Two swaps from the
+//! same version cannot both win a majority, as long as the nodes making
+//! them agree on which nodes are closest to the key. The new copy gets a
+//! version above the expected one, so the versions of a key written this
+//! way only grow.
+//!
+//! A [`DhtLock`] keeps a lease on a key: who holds it and until when. The
+//! version of the write that acquired or renewed a lease is its fencing
+//! token. A resource guarded by the lock should refuse tokens lower than the
+//! highest it has seen, so that a holder whose lease ran out while it was
+//! paused cannot act on it any more.
+//!
+//! A swap that fails may still have replaced the copies of a minority of
+//! those nodes; the next swap from the version that won replaces them. When
+//! several nodes take a lock at once and each keeps only a minority, none
+//! holds it, and it stays taken until the leases they wrote run out.
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use futures::{StreamExt, future, stream};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    dht::{
+        DhtNode, NodeId,
+        events::DhtEvent,
+        record::record_owner,
+        rpc::DhtRpc,
+        storage::{create_stored_value, decode_header},
+    },
+    helpers::key_hash,
+};
+
+/// Seconds a lease is kept past its expiry, so that the next holder still
+/// sees its version and gets a higher token
+const LEASE_GRACE: u64 = 60;
+
+/// What a lock's key holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LeaseRecord {
+    owner: NodeId,
+    /// Unix time the lease ends at, 0 once released
+    expires_at: u64,
+}
+
+/// A lease held on a [`DhtLock`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// Version of the write that acquired or renewed the lease, higher than
+    /// that of every lease held before on the same lock
+    pub fencing_token: u64,
+    /// Unix time the lease ends at, on this node's clock
+    pub expires_at: u64,
+}
+
+/// A lock on a key of the DHT, held for a lease duration.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use rust_p2p_node::dht::DhtNode;
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let node = DhtNode::new("127.0.0.1:8080".parse()?, None);
+///     let lock = node.lock(b"jobs/compaction".to_vec());
+///
+///     if let Some(lease) = lock.acquire(Duration::from_secs(30)).await? {
+///         println!("compacting with token {}", lease.fencing_token);
+///         lock.release(lease).await?;
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct DhtLock {
+    node: DhtNode,
+    key: Vec<u8>,
+}
+
+impl DhtLock {
+    pub fn new(node: DhtNode, key: Vec<u8>) -> Self {
+        Self { node, key }
+    }
+
+    /// Takes the lock for `duration`, rounded up to whole seconds, unless a
+    /// lease on it is still running. Returns `None` when it is held, or when
+    /// another node took it at the same time.
+    ///
+    /// Fails if the key holds something else than a lock.
+    pub async fn acquire(&self, duration: Duration) -> Result<Option<Lease>> {
+        let expected = match self.node.latest(&self.key).await {
+            Some((version, data)) => {
+                let record: LeaseRecord =
+                    bincode::deserialize(&data).context("Value was not stored as a lock")?;
+                if record.expires_at > self.node.now() {
+                    return Ok(None);
+                }
+                Some(version)
+            }
+            None => None,
+        };
+        self.swap(expected, duration).await
+    }
+
+    /// Extends `lease` to `duration` from now, if no other lease was taken
+    /// since. The renewed lease comes with a new, higher fencing token.
+    pub async fn renew(&self, lease: &Lease, duration: Duration) -> Result<Option<Lease>> {
+        self.swap(Some(lease.fencing_token), duration).await
+    }
+
+    /// Gives up `lease`, so that the lock can be taken before it would have
+    /// run out. Returns whether the lease was still the latest one.
+    pub async fn release(&self, lease: Lease) -> Result<bool> {
+        let record = LeaseRecord {
+            owner: self.node.id.clone(),
+            expires_at: 0,
+        };
+        let swapped = self
+            .node
+            .compare_and_swap(
+                self.key.clone(),
+                Some(lease.fencing_token),
+                bincode::serialize(&record)?,
+                LEASE_GRACE,
+            )
+            .await?;
+        Ok(swapped.is_some())
+    }
+
+    /// Writes a lease of `duration` over the copy of version `expected`.
+    async fn swap(&self, expected: Option<u64>, duration: Duration) -> Result<Option<Lease>> {
+        let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+        let record = LeaseRecord {
+            owner: self.node.id.clone(),
+            expires_at: self.node.now() + seconds.max(1),
+        };
+        let swapped = self
+            .node
+            .compare_and_swap(
+                self.key.clone(),
+                expected,
+                bincode::serialize(&record)?,
+                seconds.max(1) + LEASE_GRACE,
+            )
+            .await?;
+        Ok(swapped.map(|fencing_token| Lease {
+            fencing_token,
+            expires_at: record.expires_at,
+        }))
+    }
+}
+
+impl DhtNode {
+    /// Returns a [`DhtLock`] on `key` over this node.
+    pub fn lock(&self, key: Vec<u8>) -> DhtLock {
+        DhtLock::new(self.clone(), key)
+    }
+
+    /// Stores `value` at `key` for `ttl` seconds in place of the copy of
+    /// version `expected`, or if there is none when `expected` is `None`.
+    /// Returns the version written, or `None` when fewer than a majority of
+    /// the nodes closest to the key held that version.
+    ///
+    /// This node takes part when it is among those nodes. Records cannot be
+    /// written this way, their sequence numbers already order them.
+    pub async fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<u64>,
+        value: impl Into<Bytes>,
+        ttl: u64,
+    ) -> Result<Option<u64>> {
+        if record_owner(&key).is_some() {
+            bail!("Records can only be written with DhtNode::publish_record");
+        }
+        let now = self.now();
+        let mut stored = create_stored_value(value, self.addr, false, Some(ttl), now);
+        stored.version = expected.map_or(now, |expected| now.max(expected + 1));
+        stored.token = self.own_token(&key, None);
+        let serialized = self.check_write(&key, &stored)?;
+
+        let (peers, local) = self.swap_voters(&key);
+        let required = (peers.len() + usize::from(local)) / 2 + 1;
+        let mut swapped = 0;
+        if local && self.insert_if_version(&key, serialized.clone(), stored.version, expected) {
+            self.evict_after_write(&key);
+            self.lookup_cache.invalidate(&key, stored.version);
+            self.emit(|| DhtEvent::ValueStored {
+                key: key.clone(),
+                version: stored.version,
+                is_replica: false,
+            });
+            swapped += 1;
+        }
+
+        let (key_ref, serialized) = (&key, &serialized);
+        swapped += stream::iter(peers)
+            .map(|addr| async move {
+                let request = DhtRpc::StoreIf(key_ref.clone(), serialized.clone(), expected);
+                matches!(
+                    self.timeout(self.config.operation_timeout, self.send_rpc(addr, request))
+                        .await,
+                    Ok(Ok(DhtRpc::StoreResponse(true)))
+                )
+            })
+            .buffer_unordered(self.replication_parallelism())
+            .filter(|kept| future::ready(*kept))
+            .count()
+            .await;
+
+        if swapped < required {
+            debug!(
+                key = %key_hash(&key),
+                swapped,
+                required,
+                "compare-and-swap lost to another version"
+            );
+            return Ok(None);
+        }
+        Ok(Some(stored.version))
+    }
+
+    /// Replaces the local copy of `key` with `value`, of version `version`,
+    /// if that copy has version `expected`, or there is none when `expected`
+    /// is `None`. Returns whether it did.
+    pub(super) fn insert_if_version(
+        &self,
+        key: &[u8],
+        value: Bytes,
+        version: u64,
+        expected: Option<u64>,
+    ) -> bool {
+        let _swapping = self.swaps.lock().unwrap();
+        let current = self
+            .storage
+            .get(key)
+            .and_then(|value| decode_header(&value).ok())
+            .filter(|header| header.is_valid(self.now()))
+            .map(|header| header.version);
+        if current != expected || current.is_some_and(|current| version <= current) {
+            return false;
+        }
+        self.storage.insert(key.to_vec(), value);
+        true
+    }
+
+    /// The nodes a swap of `key` must win a majority of: its
+    /// `replication.factor` closest nodes, with whether this one is among
+    /// them, and the others' addresses.
+    fn swap_voters(&self, key: &[u8]) -> (Vec<SocketAddr>, bool) {
+        let factor = self.config.replication.factor.max(1);
+        let key_id = self.key_id(key);
+        let mut peers: Vec<_> = self
+            .find_closest_peers(&key_id, factor)
+            .into_iter()
+            .filter(|peer| peer.addr != self.addr)
+            .collect();
+        let local = peers.len() < factor
+            || peers
+                .last()
+                .is_none_or(|farthest| key_id.distance(&self.id) < key_id.distance(&farthest.id));
+        if local {
+            peers.truncate(factor - 1);
+        }
+        (peers.into_iter().map(|peer| peer.addr).collect(), local)
+    }
+
+    /// Version and data of the latest copy of `key` among this node and the
+    /// key's replicas.
+    async fn latest(&self, key: &[u8]) -> Option<(u64, Bytes)> {
+        let copies = self.gather(key).await;
+        self.resolve_conflict(copies)
+            .map(|(_, stored)| (stored.version, stored.data))
+    }
+}
+
+#[cfg(test)]
+mod lock_tests {
+    use std::time::Duration;
+
+    use crate::{dht::clock::ManualClock, helpers::create_test_node, testing::TestCluster};
+
+    #[tokio::test]
+    async fn test_compare_and_swap_requires_the_expected_version() {
+        let node = create_test_node(8080);
+        let key = b"config".to_vec();
+
+        let first = node
+            .compare_and_swap(key.clone(), None, b"v1".to_vec(), 60)
+            .await
+            .unwrap()
+            .unwrap();
+        // The key exists now
+        assert_eq!(
+            node.compare_and_swap(key.clone(), None, b"v2".to_vec(), 60)
+                .await
+                .unwrap(),
+            None
+        );
+
+        // Versions grow even within the same second
+        let second = node
+            .compare_and_swap(key.clone(), Some(first), b"v2".to_vec(), 60)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(second > first);
+        assert_eq!(
+            node.compare_and_swap(key.clone(), Some(first), b"v3".to_vec(), 60)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(node.find_value(key).await.unwrap().as_ref(), b"v2");
+    }
+
+    #[tokio::test]
+    async fn test_lock_is_exclusive_until_released() {
+        let cluster = TestCluster::new(3).await;
+        let (first, second) = (
+            cluster.node(0).lock(b"job".to_vec()),
+            cluster.node(1).lock(b"job".to_vec()),
+        );
+        let duration = Duration::from_secs(30);
+
+        let lease = first.acquire(duration).await.unwrap().unwrap();
+        assert_eq!(second.acquire(duration).await.unwrap(), None);
+        assert_eq!(first.acquire(duration).await.unwrap(), None);
+
+        let token = lease.fencing_token;
+        assert!(first.release(lease.clone()).await.unwrap());
+        let taken = second.acquire(duration).await.unwrap().unwrap();
+        assert!(taken.fencing_token > token);
+
+        // The old lease can neither be renewed nor released any more
+        assert_eq!(first.renew(&lease, duration).await.unwrap(), None);
+        assert!(!first.release(lease).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_one_of_concurrent_acquires_wins() {
+        let cluster = TestCluster::new(3).await;
+        let (first, second) = (
+            cluster.node(0).lock(b"job".to_vec()),
+            cluster.node(1).lock(b"job".to_vec()),
+        );
+        let duration = Duration::from_secs(30);
+
+        // The third node settles it, taking whichever swap reaches it first
+        let (a, b) = tokio::join!(first.acquire(duration), second.acquire(duration));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_some() != b.is_some(), "{:?} {:?}", a, b);
+    }
+
+    #[tokio::test]
+    async fn test_swaps_need_a_majority() {
+        let cluster = TestCluster::new(3).await;
+        let (node, peer) = (cluster.node(0), cluster.node(1));
+        let key = b"config".to_vec();
+        let version = node
+            .compare_and_swap(key.clone(), None, b"v1".to_vec(), 60)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // One node alone holding the expected version is not enough
+        cluster.node(2).storage.remove(&key);
+        peer.storage.remove(&key);
+        assert_eq!(
+            node.compare_and_swap(key.clone(), Some(version), b"v2".to_vec(), 60)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(peer.local_value(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_leases_can_be_taken_over() {
+        let clock = ManualClock::new(1_000);
+        let node = create_test_node(8080).with_clock(clock.clone());
+        let lock = node.lock(b"job".to_vec());
+        let duration = Duration::from_secs(10);
+
+        let lease = lock.acquire(duration).await.unwrap().unwrap();
+        assert_eq!(lease.expires_at, 1_010);
+
+        // Renewing moves the expiry and raises the token
+        clock.advance(Duration::from_secs(5));
+        let renewed = lock.renew(&lease, duration).await.unwrap().unwrap();
+        assert_eq!(renewed.expires_at, 1_015);
+        assert!(renewed.fencing_token > lease.fencing_token);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(lock.acquire(duration).await.unwrap(), None);
+        clock.advance(Duration::from_secs(1));
+        let taken = lock.acquire(duration).await.unwrap().unwrap();
+        assert!(taken.fencing_token > renewed.fencing_token);
+        assert_eq!(lock.renew(&renewed, duration).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_other_values_are_not_locks() {
+        let node = create_test_node(8080);
+        node.store(b"job".to_vec(), b"not a lease".to_vec())
+            .await
+            .unwrap();
+        assert!(
+            node.lock(b"job".to_vec())
+                .acquire(Duration::from_secs(1))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,54 +1,426 @@
-use std::net::SocketAddr;
-
-use crate::{
-    dht::{
-        DhtNode,
-        peer::PeerInfo,
-        rpc::DhtRpc,
-        storage::{StoredValue, deserialize_value},
-    },
-    helpers::now,
+use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use serde::Serialize;
+use tokio::time::Instant;
+use tracing::{Instrument, info_span};
+
+use crate::dht::{
+    DhtNode,
+    node::{ID_BITS, NodeId},
+    peer::PeerInfo,
+    record::verify_record,
+    rpc::DhtRpc,
+    storage::{StoredValue, decode_header, deserialize_value, find_in_local_storage},
 };
 
+/// The outcome of a successful value lookup, with provenance.
+#[derive(Debug, Clone)]
+pub struct LookupResult {
+    /// The resolved value
+    pub value: Bytes,
+    /// Address of the node that returned the winning copy (this node if local)
+    pub source: SocketAddr,
+    /// Version of the winning copy
+    pub version: u64,
+    /// Seconds until the winning copy expires, `None` if it never does
+    pub ttl_remaining: Option<u64>,
+    /// Number of valid copies seen during the lookup, including the local one
+    pub replicas: usize,
+    /// Node that last wrote the winning copy
+    pub last_node: SocketAddr,
+    /// Nodes the winning copy was first written to
+    pub original_nodes: Vec<SocketAddr>,
+}
+
+impl LookupResult {
+    pub(super) fn new(source: SocketAddr, stored: StoredValue, replicas: usize, now: u64) -> Self {
+        Self {
+            ttl_remaining: stored.expiration.map(|e| e.saturating_sub(now)),
+            version: stored.version,
+            value: stored.data,
+            last_node: stored.last_node,
+            original_nodes: stored.original_nodes,
+            source,
+            replicas,
+        }
+    }
+}
+
+/// A value lookup, hop by hop, as returned by [`DhtNode::trace_lookup`].
+#[derive(Debug, Clone)]
+pub struct LookupTrace {
+    /// Version of this node's own copy, if it holds one
+    pub local_version: Option<u64>,
+    pub hops: Vec<LookupHop>,
+    /// The copy the lookup settled on
+    pub result: Option<LookupResult>,
+    pub elapsed: Duration,
+    /// Whether the lookup ran out of time, in which case the hop in flight
+    /// is missing from `hops`
+    pub timed_out: bool,
+}
+
+/// One round of a lookup.
+#[derive(Debug, Clone, Serialize)]
+pub struct LookupHop {
+    pub peers: Vec<PeerQuery>,
+    #[serde(with = "humantime_serde")]
+    pub elapsed: Duration,
+}
+
+/// What one peer was asked during a hop and what it answered.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerQuery {
+    pub addr: SocketAddr,
+    pub id: String,
+    /// Base 2 logarithm of the XOR distance between the peer and the key,
+    /// from 0 (same id) to 256
+    pub distance: u32,
+    pub value: ValueAnswer,
+    /// Peers it returned as closer to the key; `None` when it was not asked,
+    /// because the lookup was already satisfied, or did not answer
+    pub closer: Option<Vec<SocketAddr>>,
+}
+
+/// A peer's answer to a value query.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum ValueAnswer {
+    /// It returned a valid copy
+    Found { version: u64 },
+    /// It had no valid copy
+    NotFound,
+    /// The query failed
+    Failed { error: String },
+    /// The lookup finished before it answered
+    Unanswered,
+}
+
+impl LookupHop {
+    fn new(
+        key_id: &NodeId,
+        round: &[PeerInfo],
+        answers: Vec<(SocketAddr, ValueAnswer)>,
+        closer: Vec<(SocketAddr, Option<Vec<PeerInfo>>)>,
+        elapsed: Duration,
+    ) -> Self {
+        let peers = round
+            .iter()
+            .map(|peer| PeerQuery {
+                addr: peer.addr,
+                id: peer.id.to_string(),
+                distance: log_distance(key_id, &peer.id),
+                value: answers
+                    .iter()
+                    .find(|(addr, _)| *addr == peer.addr)
+                    .map_or(ValueAnswer::Unanswered, |(_, answer)| answer.clone()),
+                closer: closer
+                    .iter()
+                    .find(|(addr, _)| *addr == peer.addr)
+                    .and_then(|(_, peers)| peers.as_ref())
+                    .map(|peers| peers.iter().map(|peer| peer.addr).collect()),
+            })
+            .collect();
+
+        Self { peers, elapsed }
+    }
+}
+
+fn log_distance(a: &NodeId, b: &NodeId) -> u32 {
+    let distance = a.distance(b);
+    let leading_zeros = distance
+        .iter()
+        .position(|byte| *byte != 0)
+        .map_or(ID_BITS as u32, |i| {
+            i as u32 * 8 + distance[i].leading_zeros()
+        });
+    ID_BITS as u32 - leading_zeros
+}
+
 impl DhtNode {
+    /// Looks up `key` like [`DhtNode::find_value_detailed`] and records every
+    /// hop along the way, to find out why a lookup misses.
+    pub async fn trace_lookup(&self, key: Vec<u8>) -> LookupTrace {
+        let started = Instant::now();
+        let mut trace = LookupTrace {
+            local_version: self
+                .storage
+                .get(&key)
+                .and_then(|value| decode_header(&value).ok())
+                .filter(|header| header.is_valid(self.now()))
+                .map(|header| header.version),
+            hops: Vec::new(),
+            result: None,
+            elapsed: Duration::ZERO,
+            timed_out: false,
+        };
+
+        trace.result = self.find_value_traced(key, 0, Some(&mut trace.hops)).await;
+        trace.elapsed = started.elapsed();
+        trace.timed_out = trace.elapsed >= self.config.lookup.timeout;
+        trace
+    }
+
+    /// Runs an iterative value lookup for `key`.
+    ///
+    /// Each hop queries the closest not yet queried peers for the value, then
+    /// asks them for peers closer to the key. The lookup ends when it runs out
+    /// of hops, peers or time, or, with `lookup.stop_on_first_value`, as soon
+    /// as a value is found. Copies older than `min_version` are ignored and
+    /// hops are recorded in `trace` when given. Returns the number of peers
+    /// that answered.
+    pub(super) async fn lookup_value(
+        &self,
+        found_values: &mut Vec<(SocketAddr, StoredValue)>,
+        key: Vec<u8>,
+        min_version: u64,
+        mut trace: Option<&mut Vec<LookupHop>>,
+    ) -> usize {
+        let lookup = &self.config.lookup;
+        let key_id = self.key_id(&key);
+
+        let mut candidates = self.find_closest_peers_by_key(&key);
+        self.metrics.set_known_peers(candidates.len() as u64);
+
+        let mut queried = HashSet::new();
+        let mut successes = 0;
+
+        let search = async {
+            for hop in 0..lookup.max_hops {
+                if self.lookup_satisfied(found_values) {
+                    break;
+                }
+
+                let budget = lookup.max_peers_queried.saturating_sub(queried.len());
+                let round: Vec<PeerInfo> = candidates
+                    .iter()
+                    .filter(|peer| peer.addr != self.addr && !queried.contains(&peer.addr))
+                    .take(budget)
+                    .cloned()
+                    .collect();
+
+                if round.is_empty() {
+                    break;
+                }
+
+                queried.extend(round.iter().map(|peer| peer.addr));
+                let hop_started = Instant::now();
+                let hop_span = info_span!("lookup_hop", hop, peers = round.len());
+                let answers = self
+                    .ask_for_value(found_values, key.clone(), round.clone(), min_version)
+                    .instrument(hop_span.clone())
+                    .await;
+                successes += answers
+                    .iter()
+                    .filter(|(_, answer)| {
+                        matches!(answer, ValueAnswer::Found { .. } | ValueAnswer::NotFound)
+                    })
+                    .count();
+
+                let satisfied = self.lookup_satisfied(found_values);
+                let closer = if satisfied {
+                    Vec::new()
+                } else {
+                    self.query_peers_for_closer(&key_id, round.clone())
+                        .instrument(hop_span)
+                        .await
+                };
+
+                for peer in closer
+                    .iter()
+                    .filter_map(|(_, peers)| peers.as_ref())
+                    .flatten()
+                {
+                    if !candidates.iter().any(|c| c.id == peer.id) {
+                        candidates.push(peer.clone());
+                    }
+                }
+                candidates.sort_by_key(|peer| key_id.distance(&peer.id));
+
+                if let Some(trace) = trace.as_deref_mut() {
+                    trace.push(LookupHop::new(
+                        &key_id,
+                        &round,
+                        answers,
+                        closer,
+                        hop_started.elapsed(),
+                    ));
+                }
+                if satisfied {
+                    break;
+                }
+            }
+        };
+
+        let _ = self.timeout(lookup.timeout, search).await;
+
+        successes
+    }
+
+    /// Locates the node with the given id.
+    ///
+    /// The routing table is checked first; otherwise an iterative node lookup
+    /// walks towards `id` within the limits of the lookup configuration.
+    pub async fn find_peer(&self, id: NodeId) -> Option<PeerInfo> {
+        let bucket_index = self.get_bucket_index(&self.id.distance(&id));
+        if let Some(peer) = self
+            .routing_table
+            .get(&bucket_index)
+            .and_then(|bucket| bucket.get_peer(&id).cloned())
+        {
+            return Some(peer);
+        }
+
+        let lookup = &self.config.lookup;
+        let mut candidates = self.find_closest_peers(&id, self.config.kbucket_size);
+        let mut queried = HashSet::new();
+
+        let search = async {
+            for _ in 0..lookup.max_hops {
+                let budget = lookup.max_peers_queried.saturating_sub(queried.len());
+                let round: Vec<PeerInfo> = candidates
+                    .iter()
+                    .filter(|peer| peer.addr != self.addr && !queried.contains(&peer.addr))
+                    .take(budget)
+                    .cloned()
+                    .collect();
+
+                if round.is_empty() {
+                    break;
+                }
+
+                queried.extend(round.iter().map(|peer| peer.addr));
+                let closer = self.query_peers_for_closer(&id, round).await;
+                for peer in closer.into_iter().filter_map(|(_, peers)| peers).flatten() {
+                    if peer.id == id {
+                        return Some(peer);
+                    }
+                    if !candidates.iter().any(|c| c.id == peer.id) {
+                        candidates.push(peer);
+                    }
+                }
+                candidates.sort_by_key(|peer| id.distance(&peer.id));
+            }
+            None
+        };
+
+        self.timeout(lookup.timeout, search).await.ok().flatten()
+    }
+
+    /// Queries `peers` for `key`, up to `replication.parallelism` at a time.
+    ///
+    /// Valid values with a version of at least `min_version` are appended to
+    /// `found_values`. Returns the number of peers that answered.
     pub async fn query_peers_for_value(
         &self,
-        found_values: &mut Vec<StoredValue>,
+        found_values: &mut Vec<(SocketAddr, StoredValue)>,
         key: Vec<u8>,
         peers: Vec<PeerInfo>,
+        min_version: u64,
     ) -> usize {
-        let mut successes = 0;
+        self.ask_for_value(found_values, key, peers, min_version)
+            .await
+            .iter()
+            .filter(|(_, answer)| !matches!(answer, ValueAnswer::Failed { .. }))
+            .count()
+    }
+
+    /// Returns the valid copies of `key` held locally and by its
+    /// `replication.factor` closest peers, without looking further.
+    pub(super) async fn gather(&self, key: &[u8]) -> Vec<(SocketAddr, StoredValue)> {
+        let mut found_values = Vec::new();
+        find_in_local_storage(self, &mut found_values, key.to_vec());
+        let replicas = self.find_closest_peers_by_key(key);
+        self.query_peers_for_value(&mut found_values, key.to_vec(), replicas, 0)
+            .await;
+        found_values
+    }
 
-        for peer in peers {
-            if self
-                .send_query_peers(found_values, key.clone(), peer.addr)
-                .await
-                .is_ok()
-            {
-                successes += 1;
+    /// Does the work of [`DhtNode::query_peers_for_value`] and returns the
+    /// answer of each peer heard from before the lookup was satisfied.
+    async fn ask_for_value(
+        &self,
+        found_values: &mut Vec<(SocketAddr, StoredValue)>,
+        key: Vec<u8>,
+        peers: Vec<PeerInfo>,
+        min_version: u64,
+    ) -> Vec<(SocketAddr, ValueAnswer)> {
+        let key = &key;
+        let mut responses = stream::iter(peers)
+            .map(|peer| async move {
+                (
+                    peer.addr,
+                    self.send_query_peers(key.clone(), peer.addr).await,
+                )
+            })
+            .buffer_unordered(self.replication_parallelism());
+
+        let mut answers = Vec::new();
+        while let Some((addr, response)) = responses.next().await {
+            let answer = match response {
+                Ok(Some(value)) => {
+                    let version = value.1.version;
+                    found_values.extend(Some(value).filter(|(_, v)| v.version >= min_version));
+                    ValueAnswer::Found { version }
+                }
+                Ok(None) => ValueAnswer::NotFound,
+                Err(e) => ValueAnswer::Failed {
+                    error: e.to_string(),
+                },
+            };
+            answers.push((addr, answer));
+
+            if self.lookup_satisfied(found_values) {
+                break;
             }
         }
 
-        successes
+        answers
+    }
+
+    /// Asks `peers` for the nodes they know closest to `target`. Peers that
+    /// did not answer come with `None`.
+    async fn query_peers_for_closer(
+        &self,
+        target: &NodeId,
+        peers: Vec<PeerInfo>,
+    ) -> Vec<(SocketAddr, Option<Vec<PeerInfo>>)> {
+        stream::iter(peers)
+            .map(|peer| async move {
+                match self
+                    .send_rpc(peer.addr, DhtRpc::FindNode(target.clone()))
+                    .await
+                {
+                    Ok(DhtRpc::FindNodeResponse(peers)) => {
+                        (peer.addr, Some(self.verified_peers(peer.addr, peers)))
+                    }
+                    _ => (peer.addr, None),
+                }
+            })
+            .buffer_unordered(self.replication_parallelism())
+            .collect()
+            .await
+    }
+
+    fn lookup_satisfied(&self, found_values: &[(SocketAddr, StoredValue)]) -> bool {
+        self.config.lookup.stop_on_first_value && !found_values.is_empty()
     }
 
     async fn send_query_peers(
         &self,
-        found_values: &mut Vec<StoredValue>,
         key: Vec<u8>,
         addr: SocketAddr,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<Option<(SocketAddr, StoredValue)>> {
         match self.send_rpc(addr, DhtRpc::FindValue(key.clone())).await {
-            Ok(DhtRpc::FindValueResponse(Some(data))) => {
-                if let Ok(stored) = deserialize_value(&data) {
-                    let current_time = now();
-                    if stored.is_valid(current_time) {
-                        found_values.push(stored);
-                    }
-                }
-                Ok(())
-            }
-            Ok(_) => Ok(()),
+            Ok(DhtRpc::FindValueResponse(Some(data))) => Ok(decode_header(&data)
+                .is_ok_and(|header| header.is_valid(self.now()))
+                .then(|| deserialize_value(&data).ok())
+                .flatten()
+                .filter(|stored| verify_record(&key, stored).is_ok())
+                .map(|stored| (addr, stored))),
+            Ok(_) => Ok(None),
             Err(e) => {
                 self.metrics.inc_rpc_failures();
                 Err(e)
@@ -1,6 +1,21 @@
+mod rates;
 pub(super) mod utils;
 
-use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::dht::metrics::rates::RateWindow;
 
 /// Metrics collection for DHT operations
 #[derive(Debug, Default)]
@@ -19,6 +34,55 @@ pub struct DhtMetrics {
     pub rpc_failures: AtomicU64,
     /// Number of peers in routing table
     pub known_peers: AtomicU64,
+    /// Number of values dropped from storage before they expired
+    pub storage_evictions: AtomicU64,
+    /// Number of expired values removed by the expiration sweep
+    pub expired_entries: AtomicU64,
+    /// Number of replica stores attempted, including hinted ones
+    pub replication_attempts: AtomicU64,
+    /// Number of replica stores that succeeded
+    pub replication_successes: AtomicU64,
+    /// Number of peers removed from the routing table for failing health checks
+    pub peers_evicted: AtomicU64,
+    /// Number of peers pinged by the health checker
+    pub health_checks: AtomicU64,
+    /// Number of those pings that went unanswered
+    pub health_check_failures: AtomicU64,
+    /// Number of RPCs not sent or answered for being over the in-flight limit
+    pub rpcs_shed: AtomicU64,
+    /// Number of retried requests answered without being handled again
+    pub requests_deduplicated: AtomicU64,
+    /// Number of requests for a hot key shed over `hot_keys.max_requests`
+    pub hot_key_requests_shed: AtomicU64,
+    /// Number of lookups answered from the lookup cache
+    pub lookup_cache_hits: AtomicU64,
+    store_rate: RateWindow,
+    find_value_rate: RateWindow,
+    rpc_rate: RateWindow,
+    /// Outgoing traffic per peer, bounded by `max_tracked_peers`
+    peers: DashMap<SocketAddr, TrackedPeer>,
+    max_tracked_peers: usize,
+}
+
+/// Outgoing RPC traffic to a single peer.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerStats {
+    /// RPCs sent to the peer
+    pub requests: u64,
+    /// RPCs that failed or timed out
+    pub failures: u64,
+    /// Bytes written to the peer, including framing
+    pub bytes_sent: u64,
+    /// Bytes read from the peer, including framing
+    pub bytes_received: u64,
+    /// Round trip time of the last successful RPC
+    pub last_rtt: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct TrackedPeer {
+    stats: PeerStats,
+    last_active: Instant,
 }
 
 impl DhtMetrics {
@@ -26,8 +90,111 @@ impl DhtMetrics {
         Arc::new(Self::default())
     }
 
+    /// Creates metrics that track at most `max_tracked_peers` peers; the
+    /// least recently active one is forgotten to make room for a new one.
+    pub fn with_peer_capacity(max_tracked_peers: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_tracked_peers,
+            ..Self::default()
+        })
+    }
+
+    /// Records an RPC sent to `peer`. `bytes_received` and `rtt` are `None`
+    /// when it failed.
+    pub fn record_peer_rpc(
+        &self,
+        peer: SocketAddr,
+        bytes_sent: u64,
+        bytes_received: Option<u64>,
+        rtt: Option<Duration>,
+    ) {
+        if self.max_tracked_peers == 0 {
+            return;
+        }
+
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.max_tracked_peers {
+            let idlest = self
+                .peers
+                .iter()
+                .min_by_key(|entry| entry.last_active)
+                .map(|entry| *entry.key());
+            if let Some(idlest) = idlest {
+                self.peers.remove(&idlest);
+            }
+        }
+
+        let mut tracked = self.peers.entry(peer).or_insert_with(|| TrackedPeer {
+            stats: PeerStats::default(),
+            last_active: Instant::now(),
+        });
+        tracked.last_active = Instant::now();
+
+        let stats = &mut tracked.stats;
+        stats.requests += 1;
+        stats.bytes_sent += bytes_sent;
+        match bytes_received {
+            Some(bytes) => {
+                stats.bytes_received += bytes;
+                stats.last_rtt = rtt;
+            }
+            None => stats.failures += 1,
+        }
+    }
+
+    /// Returns the stats of every tracked peer.
+    pub fn peer_stats(&self) -> HashMap<SocketAddr, PeerStats> {
+        self.peers
+            .iter()
+            .map(|entry| (*entry.key(), entry.stats.clone()))
+            .collect()
+    }
+
+    /// Returns the recent rates of store, find_value and RPC operations.
+    pub fn rates(&self) -> DhtRates {
+        DhtRates {
+            store_ops_1m: self.store_rate.per_second(60),
+            store_ops_5m: self.store_rate.per_second(300),
+            find_value_ops_1m: self.find_value_rate.per_second(60),
+            find_value_ops_5m: self.find_value_rate.per_second(300),
+            rpc_requests_1m: self.rpc_rate.per_second(60),
+            rpc_requests_5m: self.rpc_rate.per_second(300),
+        }
+    }
+
+    /// Zeroes every counter, rate and per-peer stat. `known_peers` is a gauge
+    /// and keeps its value.
+    pub fn reset(&self) {
+        for counter in [
+            &self.store_ops,
+            &self.store_success,
+            &self.find_value_ops,
+            &self.find_value_success,
+            &self.rpc_requests,
+            &self.rpc_failures,
+            &self.storage_evictions,
+            &self.expired_entries,
+            &self.replication_attempts,
+            &self.replication_successes,
+            &self.peers_evicted,
+            &self.health_checks,
+            &self.health_check_failures,
+            &self.rpcs_shed,
+            &self.requests_deduplicated,
+            &self.hot_key_requests_shed,
+            &self.lookup_cache_hits,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+
+        self.store_rate.reset();
+        self.find_value_rate.reset();
+        self.rpc_rate.reset();
+        self.peers.clear();
+    }
+
     pub fn inc_store_ops(&self) {
         self.store_ops.fetch_add(1, Ordering::Relaxed);
+        self.store_rate.record();
     }
 
     pub fn inc_store_success(&self) {
@@ -36,6 +203,7 @@ impl DhtMetrics {
 
     pub fn inc_find_value_ops(&self) {
         self.find_value_ops.fetch_add(1, Ordering::Relaxed);
+        self.find_value_rate.record();
     }
 
     pub fn inc_find_value_success(&self) {
@@ -44,6 +212,7 @@ impl DhtMetrics {
 
     pub fn inc_rpc_requests(&self) {
         self.rpc_requests.fetch_add(1, Ordering::Relaxed);
+        self.rpc_rate.record();
     }
 
     pub fn inc_rpc_failures(&self) {
@@ -53,10 +222,54 @@ impl DhtMetrics {
     pub fn set_known_peers(&self, count: u64) {
         self.known_peers.store(count, Ordering::Relaxed);
     }
+
+    pub fn add_storage_evictions(&self, count: u64) {
+        self.storage_evictions.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn add_expired_entries(&self, count: u64) {
+        self.expired_entries.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn inc_replication_attempts(&self) {
+        self.replication_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_replication_successes(&self) {
+        self.replication_successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_peers_evicted(&self) {
+        self.peers_evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_health_checks(&self) {
+        self.health_checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_health_check_failures(&self) {
+        self.health_check_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_rpcs_shed(&self) {
+        self.rpcs_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_requests_deduplicated(&self) {
+        self.requests_deduplicated.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_hot_key_requests_shed(&self) {
+        self.hot_key_requests_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_lookup_cache_hits(&self) {
+        self.lookup_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 /// Snapshot of DHT metrics
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DhtStats {
     pub store_ops: u64,
     pub store_success: u64,
@@ -65,5 +278,81 @@ pub struct DhtStats {
     pub rpc_requests: u64,
     pub rpc_failures: u64,
     pub known_peers: u64,
+    pub storage_evictions: u64,
+    pub expired_entries: u64,
+    pub replication_attempts: u64,
+    pub replication_successes: u64,
+    pub peers_evicted: u64,
+    pub health_checks: u64,
+    pub health_check_failures: u64,
+    pub rpcs_shed: u64,
+    pub requests_deduplicated: u64,
+    pub hot_key_requests_shed: u64,
+    pub lookup_cache_hits: u64,
+    /// Number of values held locally
     pub storage_size: u64,
-}
\ No newline at end of file
+    /// Size of the keys and values held locally, in bytes
+    pub storage_bytes: u64,
+    pub outbox_size: u64,
+    pub rates: DhtRates,
+    /// Keys requested the most, see [`hotkeys`](crate::dht::hotkeys)
+    #[serde(default)]
+    pub hot_keys: Vec<HotKey>,
+}
+
+/// A key and the number of times it was requested within
+/// `hot_keys.window`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HotKey {
+    pub key: Vec<u8>,
+    pub requests: u64,
+}
+
+/// Operations per second, averaged over the last minute and five minutes
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DhtRates {
+    pub store_ops_1m: f64,
+    pub store_ops_5m: f64,
+    pub find_value_ops_1m: f64,
+    pub find_value_ops_5m: f64,
+    pub rpc_requests_1m: f64,
+    pub rpc_requests_5m: f64,
+}
+
+/// Node-wide stats together with the per-peer ones, as emitted by
+/// [`DhtNode::stats_json`](crate::dht::DhtNode::stats_json).
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    #[serde(flatten)]
+    pub stats: DhtStats,
+    pub peers: HashMap<SocketAddr, PeerStats>,
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::dht::metrics::DhtMetrics;
+
+    #[test]
+    fn test_peer_stats_are_bounded() {
+        let metrics = DhtMetrics::with_peer_capacity(2);
+        let peer = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        metrics.record_peer_rpc(peer(1), 10, Some(20), Some(Duration::from_millis(5)));
+        metrics.record_peer_rpc(peer(1), 10, None, None);
+        metrics.record_peer_rpc(peer(2), 10, Some(20), None);
+        metrics.record_peer_rpc(peer(3), 10, Some(20), None);
+
+        let stats = metrics.peer_stats();
+        assert_eq!(stats.len(), 2);
+        assert!(!stats.contains_key(&peer(1)));
+
+        metrics.record_peer_rpc(peer(2), 5, None, None);
+        let peer2 = &metrics.peer_stats()[&peer(2)];
+        assert_eq!(peer2.requests, 2);
+        assert_eq!(peer2.failures, 1);
+        assert_eq!(peer2.bytes_sent, 15);
+        assert_eq!(peer2.bytes_received, 20);
+    }
+}
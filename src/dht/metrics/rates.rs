@@ -0,0 +1,107 @@
+use std::sync::Mutex;
+
+use crate::helpers::now;
+
+/// Longest window a rate can be computed over, in seconds.
+pub(super) const MAX_WINDOW: u64 = 300;
+
+/// Event counts over the last [`MAX_WINDOW`] seconds, in one-second slots.
+#[derive(Debug)]
+pub(super) struct RateWindow {
+    slots: Mutex<Slots>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    /// Events counted in each slot
+    counts: [u64; MAX_WINDOW as usize],
+    /// Second each slot currently counts, so stale slots can be told apart
+    seconds: [u64; MAX_WINDOW as usize],
+    /// When counting started; rates never average over time before it
+    since: u64,
+}
+
+impl Slots {
+    fn new(since: u64) -> Self {
+        Self {
+            counts: [0; MAX_WINDOW as usize],
+            seconds: [0; MAX_WINDOW as usize],
+            since,
+        }
+    }
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self {
+            slots: Mutex::new(Slots::new(now())),
+        }
+    }
+}
+
+impl RateWindow {
+    pub(super) fn record(&self) {
+        self.record_at(now());
+    }
+
+    fn record_at(&self, second: u64) {
+        let mut slots = self.slots.lock().unwrap();
+        let index = (second % MAX_WINDOW) as usize;
+        if slots.seconds[index] != second {
+            slots.seconds[index] = second;
+            slots.counts[index] = 0;
+        }
+        slots.counts[index] += 1;
+    }
+
+    /// Average events per second over the last `window` seconds.
+    pub(super) fn per_second(&self, window: u64) -> f64 {
+        self.per_second_at(window, now())
+    }
+
+    fn per_second_at(&self, window: u64, now: u64) -> f64 {
+        let window = window.clamp(1, MAX_WINDOW);
+        let slots = self.slots.lock().unwrap();
+
+        let events: u64 = slots
+            .seconds
+            .iter()
+            .zip(slots.counts.iter())
+            .filter(|(second, _)| **second <= now && now - **second < window)
+            .map(|(_, count)| count)
+            .sum();
+
+        let elapsed = now.saturating_sub(slots.since) + 1;
+        events as f64 / window.min(elapsed) as f64
+    }
+
+    /// Forgets every recorded event.
+    pub(super) fn reset(&self) {
+        *self.slots.lock().unwrap() = Slots::new(now());
+    }
+}
+
+#[cfg(test)]
+mod rates_tests {
+    use crate::dht::metrics::rates::{RateWindow, Slots};
+
+    #[test]
+    fn test_rates_cover_only_their_window() {
+        let window = RateWindow::default();
+        *window.slots.lock().unwrap() = Slots::new(950);
+
+        for _ in 0..120 {
+            window.record_at(1_000);
+        }
+        for _ in 0..60 {
+            window.record_at(1_250);
+        }
+
+        assert_eq!(window.per_second_at(60, 1_250), 1.0);
+        assert_eq!(window.per_second_at(300, 1_250), 0.6);
+
+        // The slot of second 1_000 is reused once the window wraps around
+        window.record_at(1_300);
+        assert_eq!(window.per_second_at(300, 1_300), 61.0 / 300.0);
+    }
+}
@@ -0,0 +1,235 @@
+//! Upgrading the state persisted by older versions of the crate.
+//!
+//! Everything a node may keep across restarts starts with the version of
+//! its format:
+//!
+//! - encoded [`StoredValue`]s, which a persistent [`Storage`] backend holds,
+//!   with a format tag in their first byte;
+//! - the identity file, with a `dht-identity <format>` first line, see
+//!   [`Identity::load_or_generate`].
+//!
+//! The routing table is not persisted: a restarted node rebuilds it from
+//! its seeds.
+//!
+//! Readers refuse formats newer than theirs instead of misreading them.
+//! [`DhtNode::migrate_storage`] and [`migrate_identity_file`] rewrite older
+//! formats into the current ones, and leave what they cannot read as it is
+//! rather than dropping it.
+//!
+//! [`StoredValue`]: crate::dht::storage::StoredValue
+//! [`Storage`]: crate::dht::backend::Storage
+
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use tracing::debug;
+
+use crate::{
+    dht::{
+        DhtNode,
+        identity::{IDENTITY_FORMAT, parse_identity_file, write_identity_file},
+        storage::{VALUE_FORMAT, deserialize_value},
+    },
+    helpers::key_hash,
+};
+
+/// Turns a value encoded in one format into the next one.
+type ValueMigration = fn(&[u8]) -> Result<Vec<u8>>;
+
+/// Upgrades of stored values, by the format they upgrade from. Format 1 is
+/// the first, so there are none yet.
+const VALUE_MIGRATIONS: &[(u8, ValueMigration)] = &[];
+
+/// Outcome of [`DhtNode::migrate_storage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Values rewritten in the current format
+    pub migrated: usize,
+    /// Values already in the current format
+    pub current: usize,
+    /// Values in a format with no migration, such as a newer one, left as
+    /// they are
+    pub unreadable: usize,
+}
+
+/// Upgrades `data` to the current format, or returns `None` if it already is
+/// in it.
+fn upgrade_value(data: &[u8], migrations: &[(u8, ValueMigration)]) -> Result<Option<Vec<u8>>> {
+    let mut upgraded: Option<Vec<u8>> = None;
+    loop {
+        let value = upgraded.as_deref().unwrap_or(data);
+        let format = *value.first().context("Stored value is empty")?;
+        if format == VALUE_FORMAT {
+            return Ok(upgraded);
+        }
+        let (_, migration) = migrations
+            .iter()
+            .find(|(from, _)| *from == format)
+            .ok_or_else(|| anyhow!("No migration from stored value format {}", format))?;
+        let next = migration(value)?;
+        ensure!(
+            next.first().is_some_and(|next| *next > format),
+            "Migration from stored value format {} did not move to a later one",
+            format
+        );
+        upgraded = Some(next);
+    }
+}
+
+impl DhtNode {
+    /// Rewrites the values in storage that are encoded in an older format
+    /// into the current one.
+    ///
+    /// Only needed with a persistent [`Storage`](crate::dht::backend::Storage)
+    /// backend, after upgrading the crate.
+    pub fn migrate_storage(&self) -> MigrationReport {
+        self.migrate_storage_with(VALUE_MIGRATIONS)
+    }
+
+    fn migrate_storage_with(&self, migrations: &[(u8, ValueMigration)]) -> MigrationReport {
+        let mut report = MigrationReport::default();
+        let values: Vec<_> = self.storage.iter().collect();
+        for (key, value) in values {
+            let upgraded = upgrade_value(&value, migrations).and_then(|upgraded| {
+                if let Some(upgraded) = &upgraded {
+                    deserialize_value(upgraded)?;
+                }
+                Ok(upgraded)
+            });
+            match upgraded {
+                Ok(Some(upgraded)) => {
+                    self.storage.insert(key, upgraded.into());
+                    report.migrated += 1;
+                }
+                Ok(None) => report.current += 1,
+                Err(e) => {
+                    debug!(key = %key_hash(&key), error = %e, "leaving unreadable value as is");
+                    report.unreadable += 1;
+                }
+            }
+        }
+        report
+    }
+}
+
+/// Rewrites the identity file at `path` in the current format if it was
+/// written in an older one, returning whether it was.
+///
+/// The identity itself, and so the node's id, stays the same.
+pub fn migrate_identity_file(path: &Path) -> Result<bool> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (format, identity) = parse_identity_file(&contents)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if format == IDENTITY_FORMAT {
+        return Ok(false);
+    }
+
+    // Written aside first, so a failure leaves the old file in place
+    let upgraded = path.with_extension("migrating");
+    let _ = fs::remove_file(&upgraded);
+    write_identity_file(&upgraded, &identity)
+        .and_then(|_| fs::rename(&upgraded, path))
+        .with_context(|| format!("Failed to rewrite {}", path.display()))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod migrate_tests {
+    use super::{ValueMigration, migrate_identity_file, upgrade_value};
+    use crate::{
+        dht::{
+            identity::Identity,
+            node::IdHash,
+            storage::{create_stored_value, deserialize_value, serialize_value},
+        },
+        helpers::create_test_node,
+    };
+
+    /// A made-up format 0, the current one but for its tag.
+    fn from_format_0(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut upgraded = data.to_vec();
+        upgraded[0] = 1;
+        Ok(upgraded)
+    }
+
+    const MIGRATIONS: &[(u8, ValueMigration)] = &[(0, from_format_0)];
+
+    #[test]
+    fn test_values_are_upgraded_to_the_current_format() {
+        let addr = "127.0.0.1:8080".parse().unwrap();
+        let current = serialize_value(&create_stored_value(
+            b"value".to_vec(),
+            addr,
+            false,
+            None,
+            7,
+        ))
+        .unwrap()
+        .to_vec();
+        let mut old = current.clone();
+        old[0] = 0;
+
+        assert_eq!(upgrade_value(&current, MIGRATIONS).unwrap(), None);
+        assert_eq!(upgrade_value(&old, MIGRATIONS).unwrap(), Some(current));
+        assert!(upgrade_value(&old, &[]).is_err());
+        assert!(upgrade_value(&[9, 0, 0], MIGRATIONS).is_err());
+        assert!(upgrade_value(&[], MIGRATIONS).is_err());
+    }
+
+    #[test]
+    fn test_storage_migration_keeps_unreadable_values() {
+        let node = create_test_node(8080);
+        let current = serialize_value(&create_stored_value(
+            b"value".to_vec(),
+            node.addr,
+            false,
+            None,
+            node.now(),
+        ))
+        .unwrap();
+        let mut old = current.to_vec();
+        old[0] = 0;
+        node.storage.insert(b"current".to_vec(), current);
+        node.storage.insert(b"old".to_vec(), old.into());
+        node.storage.insert(b"newer".to_vec(), vec![9; 32].into());
+
+        let report = node.migrate_storage_with(MIGRATIONS);
+        assert_eq!(
+            (report.migrated, report.current, report.unreadable),
+            (1, 1, 1)
+        );
+        let migrated = node.storage.get(b"old").unwrap();
+        assert_eq!(deserialize_value(&migrated).unwrap().data, &b"value"[..]);
+        assert_eq!(node.storage.get(b"newer").unwrap().len(), 32);
+
+        assert_eq!(node.migrate_storage().current, 2);
+    }
+
+    #[test]
+    fn test_identity_files_are_migrated() {
+        let path = std::env::temp_dir().join(format!("migrate-{}.key", std::process::id()));
+        let identity = Identity::generate();
+        std::fs::write(&path, hex::encode(identity.secret_bytes())).unwrap();
+
+        let loaded = Identity::load_or_generate(&path, IdHash::Sha3_256, 0).unwrap();
+        assert_eq!(loaded.public_key(), identity.public_key());
+        assert!(migrate_identity_file(&path).unwrap());
+        assert!(!migrate_identity_file(&path).unwrap());
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .starts_with("dht-identity 1\n")
+        );
+        let loaded = Identity::load_or_generate(&path, IdHash::Sha3_256, 0).unwrap();
+        assert_eq!(loaded.public_key(), identity.public_key());
+
+        let newer = format!("dht-identity 2\n{}\n", hex::encode(identity.secret_bytes()));
+        std::fs::write(&path, &newer).unwrap();
+        assert!(Identity::load_or_generate(&path, IdHash::Sha3_256, 0).is_err());
+        assert!(migrate_identity_file(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), newer);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
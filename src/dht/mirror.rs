@@ -0,0 +1,210 @@
+//! Nodes keeping a copy of the whole keyspace, as warm backups or for
+//! analytics.
+//!
+//! A node with `mirror` set advertises it in its [`PeerInfo`]. Nodes that
+//! know a mirror send it each value they write, besides its k closest peers,
+//! and every value they hold when they first learn of it, so that it catches
+//! up on what was written before. The mirror stores them as replicas, like
+//! any peer would.
+//!
+//! Any peer could claim to be a mirror, so only the ids listed in
+//! `trusted_mirrors` are sent copies, and only with a valid signature over
+//! their claim.
+//!
+//! Copies to mirrors are maintenance traffic sent in the background: writes
+//! do not wait for them, and mirrors do not count towards
+//! `replication.factor`. A mirror only gets the writes of the nodes that know
+//! it, and needs `storage.max_entries` and `storage.max_bytes` large enough
+//! for the whole network, or it evicts values like any other node.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use bytes::Bytes;
+use tracing::debug;
+
+use crate::dht::{
+    DhtNode, overload::Priority, peer::PeerInfo, rpc::DhtRpc, storage::decode_header, tasks,
+};
+
+/// Values sent per batch when filling a mirror, so that the fill streams
+/// rather than copying the whole store at once
+const FILL_BATCH: usize = 256;
+
+impl DhtNode {
+    /// The peers in the routing table trusted as mirrors.
+    pub fn mirror_peers(&self) -> Vec<PeerInfo> {
+        let mut mirrors = Vec::new();
+        self.for_each_peer(|peer| {
+            if self.is_trusted_mirror(peer) {
+                mirrors.push(peer.clone());
+            }
+        });
+        mirrors
+    }
+
+    /// Whether `peer` advertises itself as a mirror, signed, and is listed in
+    /// `trusted_mirrors`.
+    pub(super) fn is_trusted_mirror(&self, peer: &PeerInfo) -> bool {
+        peer.mirror
+            && peer.id != self.id
+            && self.config.trusted_mirrors.contains(&peer.id)
+            && peer.verify(self.config.id_hash)
+    }
+
+    /// Sends a value just written to every known mirror, in the background.
+    pub(super) fn copy_to_mirrors(&self, key: &[u8], value: &Bytes) {
+        let batches: HashMap<_, _> = self
+            .mirror_peers()
+            .into_iter()
+            .map(|peer| (peer.addr, vec![DhtRpc::Store(key.to_vec(), value.clone())]))
+            .collect();
+        self.send_to_mirrors(batches);
+    }
+
+    /// Sends every valid value held here to a mirror just discovered, in the
+    /// background, `FILL_BATCH` values at a time.
+    pub(super) fn fill_mirror(&self, addr: SocketAddr) {
+        let keys: Vec<_> = self.storage.iter().map(|(key, _)| key).collect();
+        if keys.is_empty() {
+            return;
+        }
+        let node = self.clone();
+        tasks::spawn(
+            "mirror-fill",
+            Priority::Maintenance.scope(async move {
+                for keys in keys.chunks(FILL_BATCH) {
+                    let current_time = node.now();
+                    let stores: Vec<_> = keys
+                        .iter()
+                        .filter_map(|key| Some((key, node.storage.get(key)?)))
+                        .filter(|(_, value)| {
+                            decode_header(value).is_ok_and(|h| h.is_valid(current_time))
+                        })
+                        .map(|(key, value)| DhtRpc::Store(key.clone(), value))
+                        .collect();
+                    if stores.is_empty() {
+                        continue;
+                    }
+                    let batches = HashMap::from([(addr, stores)]);
+                    for (mirror, result) in node.send_batches(batches).await {
+                        if let Err(e) = result {
+                            debug!(%mirror, error = %e, "failed to fill mirror");
+                            return;
+                        }
+                    }
+                }
+            }),
+        );
+    }
+
+    fn send_to_mirrors(&self, batches: HashMap<SocketAddr, Vec<DhtRpc>>) {
+        if batches.is_empty() {
+            return;
+        }
+        let node = self.clone();
+        tasks::spawn(
+            "mirror-copy",
+            Priority::Maintenance.scope(async move {
+                for (mirror, result) in node.send_batches(batches).await {
+                    if let Err(e) = result {
+                        debug!(%mirror, error = %e, "failed to copy values to mirror");
+                    }
+                }
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod mirror_tests {
+    use std::time::Duration;
+
+    use crate::{
+        dht::{DhtNode, node::IdHash},
+        helpers::{create_test_node, test_config},
+        testing::TestCluster,
+    };
+
+    async fn mirrored(node: &DhtNode, key: &[u8]) -> bool {
+        for _ in 0..50 {
+            if node.local_value(key).is_some() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[test]
+    fn test_mirrors_are_advertised_signed() {
+        let mut node = create_test_node(8080);
+        assert!(!node.peer_info().mirror);
+
+        node.config.mirror = true;
+        let peer = node.peer_info();
+        assert!(peer.mirror && peer.verify(IdHash::default()));
+        let mut forged = peer.clone();
+        forged.mirror = false;
+        assert!(!forged.verify(IdHash::default()));
+    }
+
+    #[test]
+    fn test_only_trusted_mirrors_get_copies() {
+        let mut node = create_test_node(8080);
+        let mut claimant = create_test_node(8081);
+        claimant.config.mirror = true;
+        node.add_peer(claimant.peer_info());
+        assert!(node.mirror_peers().is_empty());
+
+        node.config.trusted_mirrors.insert(claimant.id.clone());
+        assert_eq!(node.mirror_peers().len(), 1);
+
+        // The claim must be signed by the trusted id
+        let mut forged = create_test_node(8082).peer_info();
+        forged.mirror = true;
+        assert!(!node.is_trusted_mirror(&forged));
+        node.config.trusted_mirrors.insert(forged.id.clone());
+        assert!(!node.is_trusted_mirror(&forged));
+    }
+
+    #[tokio::test]
+    async fn test_mirrors_get_every_value() {
+        let mut config = test_config();
+        config.storage.default_ttl = 60;
+        config.replication.factor = 1;
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        let mut writer_config = config.clone();
+        writer_config
+            .trusted_mirrors
+            .insert(TestCluster::node_id(1, &config));
+        let mut mirror_config = config.clone();
+        mirror_config.mirror = true;
+        let cluster = TestCluster::with_configs(vec![writer_config, mirror_config, config]).await;
+        let (writer, mirror, peer) = (cluster.node(0), cluster.node(1), cluster.node(2));
+
+        // The writer forgets its peers, to learn of them again later
+        writer.remove_inactive_peers(0);
+        writer.add_peer(peer.peer_info());
+
+        writer
+            .store(b"before".to_vec(), b"old".to_vec())
+            .await
+            .unwrap();
+        assert!(mirror.local_value(b"before").is_none());
+
+        // Learning of the mirror sends it what was written before
+        writer.add_peer(mirror.peer_info());
+        assert_eq!(writer.mirror_peers().len(), 1);
+        assert!(mirrored(mirror, b"before").await);
+
+        // A key the other peer is the replica of, so only the mirroring can
+        // bring it to the mirror
+        let key = (0..)
+            .map(|i| format!("after-{}", i).into_bytes())
+            .find(|key| writer.find_closest_peers_by_key(key)[0].addr == peer.addr)
+            .unwrap();
+        writer.store(key.clone(), b"new".to_vec()).await.unwrap();
+        assert!(mirrored(mirror, &key).await);
+        assert_eq!(mirror.local_value(&key).unwrap().data, &b"new"[..]);
+    }
+}
@@ -3,48 +3,168 @@
 //! This module provides the core functionality for a peer-to-peer represents
 //! a node in te network with routing, storage, and communication capabilities.
 
-pub mod config;
+pub mod node;
 pub mod peer;
 pub mod rpc;
-
-mod connection;
-mod kbucket;
-mod lookup;
-mod metrics;
-mod node;
+#[cfg(feature = "wasm-client")]
+pub mod web;
+
+#[cfg(feature = "node")]
+pub mod admin;
+#[cfg(feature = "node")]
+pub mod auth;
+#[cfg(feature = "node")]
+pub mod authz;
+#[cfg(feature = "node")]
+pub mod backend;
+#[cfg(feature = "node")]
+pub mod blocklist;
+#[cfg(feature = "node")]
+pub mod cache;
+#[cfg(feature = "node")]
+pub mod cancel;
+#[cfg(feature = "node")]
+pub mod client;
+#[cfg(feature = "node")]
+pub mod clock;
+#[cfg(feature = "node")]
+pub mod config;
+#[cfg(feature = "node")]
+pub mod conflict;
+#[cfg(feature = "node")]
+pub mod connection;
+#[cfg(feature = "node")]
+pub mod counter;
+#[cfg(feature = "node")]
+pub mod debug;
+#[cfg(feature = "node")]
+pub mod dedup;
+#[cfg(feature = "node")]
+pub mod events;
+#[cfg(all(feature = "node", any(test, feature = "testing")))]
+pub mod faults;
+#[cfg(feature = "gateway")]
+pub mod gateway;
+#[cfg(feature = "node")]
+pub mod gossip;
+#[cfg(feature = "node")]
+pub mod health;
+#[cfg(feature = "node")]
+pub mod hotkeys;
+#[cfg(feature = "node")]
+pub mod identity;
+#[cfg(feature = "node")]
+pub mod kbucket;
+#[cfg(feature = "node")]
+pub mod limits;
+#[cfg(feature = "node")]
+pub mod lock;
+#[cfg(feature = "node")]
+pub mod lookup;
+#[cfg(feature = "node")]
+pub mod metrics;
+#[cfg(feature = "node")]
+pub mod migrate;
+#[cfg(feature = "node")]
+pub mod mirror;
+#[cfg(feature = "node")]
+pub mod options;
+#[cfg(feature = "node")]
+pub mod overload;
+#[cfg(feature = "node")]
+pub mod rebalance;
+#[cfg(feature = "node")]
+pub mod record;
+#[cfg(feature = "node")]
 mod replication;
-mod storage;
-
-use anyhow::{Context, Result};
+#[cfg(feature = "node")]
+pub mod sealed;
+#[cfg(feature = "node")]
+mod server;
+#[cfg(feature = "node")]
+pub mod session;
+#[cfg(feature = "node")]
+pub mod snapshot;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+#[cfg(feature = "node")]
+pub mod storage;
+#[cfg(feature = "node")]
+pub mod tasks;
+#[cfg(feature = "node")]
+pub mod typed;
+#[cfg(feature = "node")]
+pub mod validation;
+
+#[cfg(feature = "node")]
+use anyhow::{Context, Result, anyhow, bail};
+#[cfg(feature = "node")]
+use bytes::Bytes;
+#[cfg(feature = "node")]
 use dashmap::DashMap;
+#[cfg(feature = "node")]
+use futures::{StreamExt, stream};
+#[cfg(feature = "node")]
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    time::timeout,
+    io::{AsyncRead, AsyncWrite},
+    sync::broadcast,
+    time::Instant,
 };
+#[cfg(feature = "node")]
+use tracing::{Span, debug, field::display, info, instrument, warn};
 
+#[cfg(feature = "node")]
 use std::{
-    net::SocketAddr,
-    sync::{Arc, atomic::Ordering},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, atomic::Ordering},
 };
 
+#[cfg(feature = "node")]
 use crate::{
     dht::{
+        admin::Ban,
+        authz::{AllowAll, WriteAuthorizer},
+        backend::{MemoryStorage, Storage},
+        blocklist::KeyFilter,
+        cache::LookupCache,
+        clock::{Clock, SystemClock},
         config::DhtConfig,
-        connection::ConnectionPool,
-        kbucket::KBucket,
+        conflict::{ConflictResolver, LastWriteWins},
+        connection::{
+            ConnectionPool,
+            buffers::FRAME_BUFFERS,
+            connector::{Connector, TcpConnector, TransportConfig},
+            mux,
+            transport::{MAX_FRAME_LEN, Transport, read_frame, write_frame},
+        },
+        dedup::RecentRequests,
+        events::DhtEvent,
+        gossip::GossipState,
+        health::HealthState,
+        hotkeys::{Heat, HotKeys},
+        identity::Identity,
+        kbucket::{KBucket, buckets_by_distance},
+        limits::InboundLimiter,
+        lookup::{LookupHop, LookupResult},
         metrics::{
-            DhtMetrics, DhtStats,
+            DhtMetrics, DhtStats, PeerStats, StatsSnapshot,
             utils::{record_find_attempt, record_store_attempt},
         },
-        node::NodeId,
+        node::{ID_BITS, ID_LEN, NodeId},
+        options::StoreOptions,
+        overload::{Busy, InFlightLimiter, Priority},
         peer::PeerInfo,
-        rpc::DhtRpc,
+        record::record_owner,
+        replication::PendingWrite,
+        rpc::{DhtRpc, Envelope, WriteReport},
         storage::{
-            StoredValue, create_stored_value, deserialize_value, find_in_local_storage,
-            serialize_value,
+            RecentWrites, StoredValue, create_stored_value, decode_header, deserialize_value,
+            find_in_local_storage, jitter_ttl, serialize_value,
         },
+        validation::{AcceptAll, RecordValidator},
     },
-    helpers::now,
+    helpers::key_hash,
 };
 
 /// A node in the Kademlia DHT network.
@@ -70,9 +190,10 @@ use crate::{
 ///
 ///     // Retrieve a value
 ///     let value = node.find_value(b"key".to_vec()).await;
-///     assert_eq!(value, Some(b"value".to_vec()));
+///     assert_eq!(value.as_deref(), Some(&b"value"[..]));
 /// }
 /// ```
+#[cfg(feature = "node")]
 #[derive(Clone)]
 pub struct DhtNode {
     /// This node's identifier
@@ -82,55 +203,207 @@ pub struct DhtNode {
     /// Kademlia routing table (organized as 256 k-buckets)
     pub routing_table: Arc<DashMap<u8, KBucket>>,
     /// Distibuted key-value storage
-    pub storage: Arc<DashMap<Vec<u8>, Vec<u8>>>,
-    /// Pool of TCP connections to other nodes
+    pub storage: Arc<dyn Storage>,
+    /// Writes that reached no peer, waiting to be replicated
+    pub outbox: Arc<DashMap<Vec<u8>, Bytes>>,
+    /// Pool of connections to other nodes
     pub connection_pool: ConnectionPool,
+    /// Listens for other nodes, TCP unless set by [`DhtNode::with_network`]
+    network: Arc<dyn Transport>,
     pub config: DhtConfig,
     pub metrics: Arc<DhtMetrics>,
+    /// Picks the winning copy when replicas disagree
+    pub conflict_resolver: Arc<dyn ConflictResolver>,
+    /// Decides which writes are applied, see [`authz`]
+    pub write_authorizer: Arc<dyn WriteAuthorizer>,
+    /// Checks values against application rules, see [`validation`]
+    pub record_validator: Arc<dyn RecordValidator>,
+    /// Blocks keys on top of the configured blocklist, set by
+    /// [`DhtNode::with_key_filter`]
+    key_filter: Option<KeyFilter>,
+    events: broadcast::Sender<DhtEvent>,
+    health: Arc<HealthState>,
+    /// Peers an operator banned
+    banned: Arc<DashMap<SocketAddr, Ban>>,
+    /// Per-IP limits applied by [`DhtNode::listen`]
+    inbound: Arc<InboundLimiter>,
+    /// Slots for the RPCs in flight, see [`overload`]
+    in_flight: Arc<InFlightLimiter>,
+    /// Topic messages seen and subscribed to, see [`gossip`]
+    gossip: Arc<GossipState>,
+    /// Responses to the requests that carried an id, see [`dedup`]
+    recent_requests: Arc<RecentRequests>,
+    /// Request counts per key, see [`hotkeys`]
+    hot_keys: Arc<HotKeys>,
+    lookup_cache: Arc<LookupCache>,
+    /// Keys written last, to order eviction within a version
+    recent_writes: Arc<RecentWrites>,
+    /// Held while a conditional write checks and replaces a local copy, see
+    /// [`lock`]
+    swaps: Arc<Mutex<()>>,
+    /// Failures to inject into RPCs, set by [`DhtNode::with_fault_injector`]
+    #[cfg(any(test, feature = "testing"))]
+    faults: Option<Arc<faults::FaultInjector>>,
+    /// Source of time, set by [`DhtNode::with_clock`]
+    clock: Arc<dyn Clock>,
+    /// Key pair the node's id derives from, set by [`DhtNode::with_identity`]
+    identity: Arc<Identity>,
 }
 
+#[cfg(feature = "node")]
 impl DhtNode {
     /// Creates a new DHT node with the specified address.
     ///
-    /// The node's ID is generated by hashing its address.
+    /// The node gets a random [`Identity`], and its ID is the hash of the
+    /// identity's public key with the configured [`IdHash`](node::IdHash).
+    /// With `id_difficulty` set, generating the identity takes about
+    /// `2^id_difficulty` attempts, see [`identity`].
     pub fn new(addr: SocketAddr, config: Option<DhtConfig>) -> Self {
-        let id = NodeId::new(addr.to_string().as_bytes());
         let config = config.unwrap_or_default();
+        let identity = Identity::generate_with_work(config.id_hash, config.id_difficulty);
+        let id = identity.node_id(config.id_hash);
 
         Self {
             id,
             addr,
             routing_table: Self::create_routing_table(),
             storage: Self::create_storage(config.storage.max_entries),
+            outbox: Arc::new(DashMap::new()),
             connection_pool: ConnectionPool::new(
                 config.connection_pool.max_connections_per_peer,
                 config.connection_pool.max_idle_time,
-            ),
+            )
+            .with_max_total_connections(config.connection_pool.max_total_connections)
+            .with_connect_timeout(config.connection_pool.connect_timeout)
+            .with_request_timeout(config.operation_timeout)
+            .with_dial_backoff(
+                config.connection_pool.dial_backoff_base,
+                config.connection_pool.dial_backoff_max,
+            )
+            .with_happy_eyeballs_delay(config.connection_pool.happy_eyeballs_delay),
+            network: Arc::new(TcpConnector),
+            events: broadcast::channel(config.event_capacity.max(1)).0,
+            health: Arc::new(HealthState::default()),
+            banned: Arc::new(DashMap::new()),
+            inbound: Arc::new(InboundLimiter::new(config.inbound_limits.clone())),
+            in_flight: Arc::new(InFlightLimiter::new(&config.overload)),
+            gossip: Arc::new(GossipState::new(&config.gossip)),
+            recent_requests: Arc::new(RecentRequests::new(
+                config.dedup_capacity,
+                config.dedup_per_sender,
+            )),
+            hot_keys: Arc::new(HotKeys::new(config.hot_keys.clone())),
+            recent_writes: Arc::default(),
+            swaps: Arc::default(),
+            lookup_cache: Arc::new(LookupCache::new(
+                config.lookup.cache_capacity,
+                config.lookup.cache_max_age,
+            )),
+            #[cfg(any(test, feature = "testing"))]
+            faults: None,
+            clock: Arc::new(SystemClock),
+            identity: Arc::new(identity),
+            metrics: DhtMetrics::with_peer_capacity(config.max_tracked_peers),
             config,
-            metrics: DhtMetrics::new(),
+            conflict_resolver: Arc::new(LastWriteWins),
+            write_authorizer: Arc::new(AllowAll),
+            record_validator: Arc::new(AcceptAll),
+            key_filter: None,
         }
     }
 
+    /// Dials peers through `connector` instead of plain TCP.
+    pub fn with_connector(mut self, connector: impl Connector + 'static) -> Self {
+        self.connection_pool = self.connection_pool.with_connector(connector);
+        self
+    }
+
+    /// Runs the node over `transport`: peers are dialed through it and
+    /// [`DhtNode::listen`] accepts their connections from it, instead of
+    /// TCP.
+    ///
+    /// This replaces the connectors set so far; fallbacks can be registered
+    /// after it with [`DhtNode::with_transport`].
+    pub fn with_network(mut self, transport: impl Transport + Clone + 'static) -> Self {
+        self.connection_pool = self.connection_pool.with_connector(transport.clone());
+        self.network = Arc::new(transport);
+        self
+    }
+
+    /// Registers a fallback transport, see [`ConnectionPool::with_transport`].
+    pub fn with_transport(
+        mut self,
+        name: &'static str,
+        connector: impl Connector + 'static,
+        config: TransportConfig,
+    ) -> Self {
+        self.connection_pool = self.connection_pool.with_transport(name, connector, config);
+        self
+    }
+
+    /// Keeps the node's values in `storage` instead of the default
+    /// [`MemoryStorage`]. Values already in `storage` are served as if this
+    /// node had stored them.
+    pub fn with_storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = Arc::new(storage);
+        self
+    }
+
+    /// Replaces the default [`LastWriteWins`] conflict resolver.
+    pub fn with_conflict_resolver(mut self, resolver: impl ConflictResolver + 'static) -> Self {
+        self.conflict_resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Replaces the default [`AllowAll`] write authorizer.
+    pub fn with_write_authorizer(mut self, authorizer: impl WriteAuthorizer + 'static) -> Self {
+        self.write_authorizer = Arc::new(authorizer);
+        self
+    }
+
+    /// Replaces the default [`AcceptAll`] record validator.
+    pub fn with_record_validator(mut self, validator: impl RecordValidator + 'static) -> Self {
+        self.record_validator = Arc::new(validator);
+        self
+    }
+
     /// Adds a peer to the routing table.
     ///
     /// The peer is placed in the appropriate k-bucket based on its distance
-    /// from this node. If the bucket is full, the peer may not be added.
+    /// from this node. If the bucket is full, the peer may not be added, and
+    /// banned peers never are.
+    /// Alternative addresses are handed to the connection pool for dialing.
     pub fn add_peer(&self, peer: PeerInfo) {
+        if self.is_banned(&peer.addr) || !self.is_allowed(&peer) {
+            return;
+        }
+
         let distance = self.id.distance(&peer.id);
         let bucket_index = self.get_bucket_index(&distance);
 
-        self.routing_table
+        let mut bucket = self
+            .routing_table
             .entry(bucket_index)
             .or_insert_with(|| KBucket {
                 peers: Vec::new(),
                 max_size: 20,
-            })
-            .get_peers()
-            .retain(|p| p.id != peer.id);
-
-        if let Some(mut bucket) = self.routing_table.get_mut(&bucket_index) {
-            if bucket.peers.len() < bucket.max_size {
-                bucket.peers.push(peer);
+            });
+
+        let known = bucket.peers.len();
+        bucket.peers.retain(|p| p.id != peer.id);
+        let discovered = bucket.peers.len() == known;
+
+        if bucket.peers.len() < bucket.max_size {
+            bucket.peers.push(peer.clone());
+            drop(bucket);
+            // Only for peers in the routing table, which eviction clears
+            self.connection_pool
+                .set_alt_addrs(peer.addr, peer.alt_addrs.clone());
+            if discovered {
+                if self.is_trusted_mirror(&peer) {
+                    self.fill_mirror(peer.addr);
+                }
+                self.emit(|| DhtEvent::PeerDiscovered(peer));
             }
         }
     }
@@ -139,13 +412,13 @@ impl DhtNode {
     ///
     /// This helps maintain fresh routing information by marking active peers.
     pub fn update_peer_last_seen(&self, peer_id: &NodeId) {
-        let distance = self.id.distance(&peer_id);
+        let distance = self.id.distance(peer_id);
         let bucket_index = self.get_bucket_index(&distance);
 
-        if let Some(mut bucket) = self.routing_table.get_mut(&bucket_index) {
-            if let Some(peer) = bucket.peers.iter_mut().find(|p| &p.id == peer_id) {
-                peer.last_seen = now();
-            }
+        if let Some(mut bucket) = self.routing_table.get_mut(&bucket_index)
+            && let Some(peer) = bucket.peers.iter_mut().find(|p| &p.id == peer_id)
+        {
+            peer.last_seen = self.now();
         }
     }
 
@@ -153,96 +426,416 @@ impl DhtNode {
     ///
     /// This helps maintain an up-to-date routing table by removing stale entries.
     pub fn remove_inactive_peers(&self, inactive_duration: u64) {
-        let now = now();
+        let now = self.now();
+        let mut evicted = Vec::new();
 
         for mut bucket in self.routing_table.iter_mut() {
-            bucket
-                .value_mut()
-                .peers
-                .retain(|peer| now.saturating_sub(peer.last_seen) < inactive_duration);
+            bucket.value_mut().peers.retain(|peer| {
+                let active = now.saturating_sub(peer.last_seen) < inactive_duration;
+                if !active {
+                    evicted.push(peer.clone());
+                }
+                active
+            });
+        }
+
+        for peer in evicted {
+            self.connection_pool.forget_peer(peer.addr);
+            self.emit(|| DhtEvent::PeerEvicted(peer));
         }
     }
 
     /// Stores a key-value pair in the DHT.
     ///
-    /// The value is stored locally and replicated on the k closest nodes.
-    pub async fn store(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        let stored = create_stored_value(
-            value,
-            self.addr,
-            false,
-            Some(self.config.storage.default_ttl),
-        );
-        let serialized = serialize_value(&stored)?;
+    /// The value is stored locally and replicated on the k closest nodes. If
+    /// no peer can be reached the write is kept in the outbox and replicated
+    /// once connectivity returns, or only kept locally while the outbox is
+    /// full.
+    pub async fn store(&self, key: Vec<u8>, value: impl Into<Bytes>) -> Result<()> {
+        self.store_with_ttl(key, value, self.config.storage.default_ttl)
+            .await
+    }
+
+    /// Stores a key-value pair that expires after `ttl` seconds instead of
+    /// the configured default, see [`DhtNode::store`].
+    pub async fn store_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        ttl: u64,
+    ) -> Result<()> {
+        self.store_versioned(key, value.into(), ttl, None)
+            .await
+            .map(|_| ())
+    }
+
+    /// Stores a key-value pair with a `token` authorizing the write, obtained
+    /// from an issuer, instead of one issued by the node's
+    /// [`WriteAuthorizer`], see [`authz`].
+    pub async fn store_with_token(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        token: Vec<u8>,
+    ) -> Result<()> {
+        self.store_versioned(
+            key,
+            value.into(),
+            self.config.storage.default_ttl,
+            Some(token),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Stores a key-value pair and returns the version it was written with.
+    ///
+    /// Without a `token`, the write carries the one the node's
+    /// [`WriteAuthorizer`] issues for `key`, if any.
+    pub(super) async fn store_versioned(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        ttl: u64,
+        token: Option<Vec<u8>>,
+    ) -> Result<u64> {
+        let options = StoreOptions {
+            ttl: Some(ttl),
+            ..StoreOptions::default()
+        };
+        let token = self.own_token(&key, token);
+        self.store_with(key, value.into(), token, &options)
+            .await
+            .map(|report| report.version)
+    }
+
+    /// Token for a write starting on this node: the given one, or else the
+    /// one the node's [`WriteAuthorizer`] issues for `key`.
+    ///
+    /// Writes the node coordinates for clients carry the client's token
+    /// only, so that they never write with the node's authority.
+    fn own_token(&self, key: &[u8], token: Option<Vec<u8>>) -> Option<Vec<u8>> {
+        token.or_else(|| self.write_authorizer.issue(key))
+    }
+
+    /// Stores a key-value pair as `options` say, carrying `token`, see
+    /// [`DhtNode::store_versioned`].
+    #[instrument(
+        name = "store",
+        skip_all,
+        fields(key = %key_hash(&key), version, replicas, outcome)
+    )]
+    pub(super) async fn store_with(
+        &self,
+        key: Vec<u8>,
+        value: Bytes,
+        token: Option<Vec<u8>>,
+        options: &StoreOptions,
+    ) -> Result<WriteReport> {
+        if record_owner(&key).is_some() {
+            bail!("Records can only be written with DhtNode::publish_record");
+        }
+        let ttl = options.ttl.unwrap_or(self.config.storage.default_ttl);
+        let ttl = self.jittered_ttl(ttl);
+        let mut stored = create_stored_value(value, self.addr, false, Some(ttl), self.now());
+        stored.token = token;
+        self.store_value_with(key, stored, options).await
+    }
 
-        let closest_peers = self.find_closest_peers_by_key(&key);
+    /// Stores `stored` locally and on the closest peers, see
+    /// [`DhtNode::store`].
+    pub(super) async fn store_value(&self, key: Vec<u8>, stored: StoredValue) -> Result<u64> {
+        self.store_value_with(key, stored, &StoreOptions::default())
+            .await
+            .map(|report| report.version)
+    }
 
-        self.metrics.set_known_peers(closest_peers.len() as u64);
+    /// Stores `stored` locally and on `options.replication` of the closest
+    /// peers, failing unless `options.consistency` is met.
+    async fn store_value_with(
+        &self,
+        key: Vec<u8>,
+        stored: StoredValue,
+        options: &StoreOptions,
+    ) -> Result<WriteReport> {
+        let serialized = self.check_write(&key, &stored)?;
         self.storage.insert(key.clone(), serialized.clone());
+        self.evict_after_write(&key);
+        self.lookup_cache.invalidate(&key, stored.version);
+        self.emit(|| DhtEvent::ValueStored {
+            key: key.clone(),
+            version: stored.version,
+            is_replica: false,
+        });
 
+        let factor = options
+            .replication
+            .unwrap_or(self.config.replication.factor);
+        self.copy_to_mirrors(&key, &serialized);
+        let pending = PendingWrite::new(self, key.clone(), serialized.clone());
         let successes = self
-            .replicate_to_peers_store(key.clone(), serialized.clone(), closest_peers)
+            .replicate_to(key.clone(), serialized.clone(), factor)
             .await;
+        pending.disarm();
 
         record_store_attempt(&self.metrics, successes > 0);
 
-        Ok(())
+        let span = Span::current();
+        span.record("version", stored.version);
+        span.record("replicas", successes);
+
+        if successes == 0 {
+            if self.buffer_offline_write(key, serialized) {
+                span.record("outcome", "buffered");
+            } else {
+                span.record("outcome", "local");
+            }
+        } else {
+            span.record("outcome", "replicated");
+        }
+
+        let required = options.consistency.required(factor);
+        if successes < required {
+            span.record("outcome", "underreplicated");
+            bail!(
+                "Only {} of the {} replicas required acknowledged the write",
+                successes,
+                required
+            );
+        }
+        Ok(WriteReport {
+            version: stored.version,
+            replicas: factor as u32,
+            acknowledged: successes as u32,
+            required: required as u32,
+        })
+    }
+
+    /// Checks that this node may write `stored` at `key`, and serializes it.
+    pub(super) fn check_write(&self, key: &[u8], stored: &StoredValue) -> Result<Bytes> {
+        if self.is_key_blocked(key) {
+            bail!("Key {} is blocked", key_hash(key));
+        }
+        if !self
+            .write_authorizer
+            .authorize(key, stored.token.as_deref())
+        {
+            bail!("Not authorized to write this key");
+        }
+        self.record_validator.validate(key, stored)?;
+        let serialized = serialize_value(stored)?;
+        if !self.fits_storage(key.len() + serialized.len()) {
+            bail!(
+                "Value of {} bytes exceeds the storage limit of {} bytes",
+                serialized.len(),
+                self.config.storage.max_bytes
+            );
+        }
+        Ok(serialized)
     }
 
     /// Looks up a value by key in the DHT
     ///
-    /// Checks local storage first, then queries the k closest nodes if not found.
-    pub async fn find_value(&self, key: Vec<u8>) -> Option<Vec<u8>> {
-        let mut found_values = vec![];
+    /// Checks local storage first, then queries the k closest nodes and walks
+    /// towards closer ones within the limits set by [`LookupConfig`]. With
+    /// `lookup.cache_capacity` set, recent results are reused, see
+    /// [`cache`].
+    ///
+    /// [`LookupConfig`]: config::LookupConfig
+    pub async fn find_value(&self, key: Vec<u8>) -> Option<Bytes> {
+        self.find_value_detailed(key)
+            .await
+            .map(|result| result.value)
+    }
+
+    /// Looks up a value by key and reports where the winning copy came from.
+    ///
+    /// Behaves like [`DhtNode::find_value`], but also returns the answering
+    /// peer, the value version, the remaining TTL and the number of replicas
+    /// that responded, so callers can make their own freshness decisions.
+    pub async fn find_value_detailed(&self, key: Vec<u8>) -> Option<LookupResult> {
+        if !self.is_key_blocked(&key)
+            && let Some(cached) = self.cached_lookup(&key)
+        {
+            return Some(cached);
+        }
+        let result = self.find_value_since(key.clone(), 0).await;
+        if let Some(found) = &result {
+            self.cache_lookup(key, found);
+        }
+        result
+    }
+
+    /// Looks up several values at once, returning them in the order of
+    /// `keys`.
+    ///
+    /// Each key is looked up like [`DhtNode::find_value`], up to
+    /// `lookup.max_concurrent` at a time, over the same routing table and
+    /// pooled connections. A key given more than once is looked up once.
+    pub async fn get_many(&self, keys: Vec<Vec<u8>>) -> Vec<Option<Bytes>> {
+        let distinct: HashSet<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        let found: HashMap<&[u8], Option<Bytes>> = stream::iter(distinct)
+            .map(|key| async move { (key, self.find_value(key.to_vec()).await) })
+            .buffer_unordered(self.config.lookup.max_concurrent.max(1))
+            .collect()
+            .await;
+
+        keys.iter()
+            .map(|key| found[key.as_slice()].clone())
+            .collect()
+    }
 
-        find_in_local_storage(&self, &mut found_values, key.clone());
+    /// Looks up a value, ignoring every copy older than `min_version`.
+    #[instrument(
+        name = "find_value",
+        skip_all,
+        fields(key = %key_hash(&key), min_version, outcome, source, replicas)
+    )]
+    pub(super) async fn find_value_since(
+        &self,
+        key: Vec<u8>,
+        min_version: u64,
+    ) -> Option<LookupResult> {
+        self.find_value_traced(key, min_version, None).await
+    }
 
-        let closest_peers = self.find_closest_peers_by_key(&key);
+    /// Looks up a value, recording each hop in `trace` when given.
+    async fn find_value_traced(
+        &self,
+        key: Vec<u8>,
+        min_version: u64,
+        trace: Option<&mut Vec<LookupHop>>,
+    ) -> Option<LookupResult> {
+        if self.is_key_blocked(&key) {
+            return None;
+        }
+        let started = Instant::now();
+        let mut found_values = vec![];
 
-        self.metrics.set_known_peers(closest_peers.len() as u64);
+        find_in_local_storage(self, &mut found_values, key.clone());
+        found_values.retain(|(_, v)| v.version >= min_version);
 
+        let key_for_event = key.clone();
         let successes = self
-            .query_peers_for_value(&mut found_values, key.clone(), closest_peers)
+            .lookup_value(&mut found_values, key, min_version, trace)
             .await;
 
         record_find_attempt(&self.metrics, successes > 0);
 
-        self.resolve_conflict(found_values).map(|v| v.data)
+        let replicas = found_values.len();
+        let result = self
+            .resolve_conflict(found_values)
+            .map(|(source, stored)| LookupResult::new(source, stored, replicas, self.now()));
+
+        let span = Span::current();
+        span.record("replicas", replicas);
+        match &result {
+            Some(found) => span
+                .record("outcome", "found")
+                .record("source", display(found.source)),
+            None => span.record("outcome", "not_found"),
+        };
+
+        self.emit(|| DhtEvent::LookupCompleted {
+            key: key_for_event,
+            source: result.as_ref().map(|found| found.source),
+            replicas,
+            elapsed: started.elapsed(),
+        });
+
+        result
     }
 
     /// Handles incoming RPC messages.
     ///
     /// This is the main request processing entry poing for the DHT node.
     pub async fn handle_rpc(&self, rpc: DhtRpc) -> DhtRpc {
+        #[cfg(any(test, feature = "testing"))]
+        self.suffer_handling_fault(&rpc).await;
+        self.answer_rpc(rpc).await
+    }
+
+    async fn answer_rpc(&self, rpc: DhtRpc) -> DhtRpc {
         self.metrics.inc_rpc_requests();
         match rpc {
             DhtRpc::Ping => DhtRpc::Pong,
             DhtRpc::FindNode(target) => {
-                let peers = self.find_closest_peers(&target, 8);
+                // Lookups converge on the closest peers first, so they only
+                // ever need the k closest known ones, closest first
+                let peers = self.find_closest_peers(&target, self.config.kbucket_size);
                 DhtRpc::FindNodeResponse(peers)
             }
             DhtRpc::FindValue(key) => {
-                let value = self.storage.get(&key).map(|v| v.clone());
+                if self.track_key_request(&key) == Heat::Shed {
+                    return DhtRpc::Busy;
+                }
+                let value = if self.is_key_blocked(&key) {
+                    None
+                } else {
+                    self.storage.get(&key)
+                };
                 DhtRpc::FindValueResponse(value)
             }
-            DhtRpc::Store(key, value) => {
-                self.metrics.inc_store_ops();
-
-                if let Ok(mut stored) = deserialize_value(&value) {
-                    stored.last_node = self.addr;
-                    stored.is_replica = true;
-
-                    if let Ok(value) = serialize_value(&stored) {
-                        self.storage.insert(key, value);
-                        self.metrics.inc_store_success();
-                    } else {
-                        self.metrics.inc_rpc_failures();
-                    }
-                } else {
+            DhtRpc::Store(key, value) => DhtRpc::StoreResponse(self.keep_replica(key, value, None)),
+            DhtRpc::StoreIf(key, value, expected) => {
+                DhtRpc::StoreResponse(self.keep_replica(key, value, Some(expected)))
+            }
+            DhtRpc::Expire(key, version, token, signature) => {
+                if !self.write_authorizer.authorize(&key, token.as_deref()) {
+                    debug!(key = %key_hash(&key), "rejecting unauthorized expiry");
+                    self.metrics.inc_rpc_failures();
+                    return DhtRpc::Pong;
+                }
+                if !self.may_expire(&key, version, signature.as_ref()) {
+                    debug!(key = %key_hash(&key), "rejecting expiry of a record not signed by its owner");
                     self.metrics.inc_rpc_failures();
+                    return DhtRpc::Pong;
+                }
+                let removed = self.storage.remove_if(&key, &|value| {
+                    decode_header(value)
+                        .map(|h| h.version <= version)
+                        .unwrap_or(true)
+                });
+                self.lookup_cache.invalidate(&key, version);
+                if removed {
+                    self.metrics.add_storage_evictions(1);
+                    self.emit(|| DhtEvent::ValueExpired { key });
                 }
                 DhtRpc::Pong
             }
+            DhtRpc::ClientStore(key, value, token) => DhtRpc::ClientStoreResponse(
+                self.store_with(key, value, token, &StoreOptions::default())
+                    .await
+                    .map(|report| report.version)
+                    .map_err(|e| format!("{:#}", e)),
+            ),
+            DhtRpc::CoordinatedStore(key, value, request) => DhtRpc::CoordinatedStoreResponse(
+                self.coordinate_client_write(key, value, request)
+                    .await
+                    .map_err(|e| format!("{:#}", e)),
+            ),
+            DhtRpc::ClientGet(key) => match self.track_key_request(&key) {
+                Heat::Cold => DhtRpc::ClientGetResponse(self.find_value(key).await),
+                Heat::Hot => DhtRpc::ClientGetResponse(self.find_hot_value(key).await),
+                Heat::Shed => DhtRpc::Busy,
+            },
+            DhtRpc::GetStats => DhtRpc::StatsResponse(
+                serde_json::to_string(&self.get_stats()).expect("stats are always serializable"),
+            ),
+            DhtRpc::Batch(requests) => {
+                let mut responses = Vec::with_capacity(requests.len());
+                for request in requests {
+                    responses.push(match request {
+                        DhtRpc::Batch(_) => DhtRpc::BatchResponse(Vec::new()),
+                        request => Box::pin(self.answer_rpc(request)).await,
+                    });
+                }
+                DhtRpc::BatchResponse(responses)
+            }
+            DhtRpc::Gossip(id, topic, message, hops) => {
+                self.receive_gossip(id, topic, message, hops);
+                DhtRpc::Pong
+            }
             _ => DhtRpc::Pong,
         }
     }
@@ -250,51 +843,252 @@ impl DhtNode {
     /// Sends an RPC message to another node and returns the response.
     ///
     /// This handles connection management and message serialization.
+    #[instrument(name = "rpc", skip_all, fields(%peer, rpc = message.name(), outcome))]
     pub async fn send_rpc(&self, peer: SocketAddr, message: DhtRpc) -> Result<DhtRpc> {
-        let mut conn = self.connection_pool.get_connection(peer).await?;
+        if self.is_banned(&peer) {
+            return Err(anyhow!("Peer {} is banned", peer));
+        }
+        let Some(_permit) = self.admit_rpc(&message) else {
+            return Err(Busy.into());
+        };
 
-        let serialized = bincode::serialize(&message)?;
-        let len = (serialized.len() as u32).to_be_bytes();
+        let request = Envelope {
+            sender: self.sender_info(),
+            id: None,
+            rpc: message,
+        };
+        let mut serialized = FRAME_BUFFERS.take();
+        bincode::serialize_into(&mut serialized, &request)?;
+        let serialized = self.seal_frame(serialized);
+        // Both framings add a 4 byte header, plus the stream id when multiplexed
+        let framing = if self.config.connection_pool.multiplexing {
+            8
+        } else {
+            4
+        };
 
-        conn.write_all(&len)
-            .await
-            .context("Failed to send message length")?;
+        let started = Instant::now();
+        let priority = Priority::current(&request.rpc);
+        #[cfg(any(test, feature = "testing"))]
+        let exchange = self.exchange_with_faults(peer, request.rpc.name(), priority, &serialized);
+        #[cfg(not(any(test, feature = "testing")))]
+        let exchange = self.exchange_rpc(peer, priority, &serialized);
+        let result = exchange.await.and_then(|response_buf| {
+            let received = response_buf.len();
+            let response_buf = self.open_frame(response_buf)?;
+            let response: DhtRpc = bincode::deserialize(&response_buf)?;
+            FRAME_BUFFERS.give(response_buf);
+            Ok((response, received))
+        });
 
-        conn.write_all(&serialized)
-            .await
-            .context("Failed to send message")?;
+        let bytes_sent = (serialized.len() + framing) as u64;
+        FRAME_BUFFERS.give(serialized);
+        let span = Span::current();
+        match result {
+            Ok((response, received)) => {
+                self.metrics.record_peer_rpc(
+                    peer,
+                    bytes_sent,
+                    Some((received + framing) as u64),
+                    Some(started.elapsed()),
+                );
+                span.record("outcome", response.name());
+                match response {
+                    DhtRpc::Busy => Err(Busy.into()),
+                    response => Ok(response),
+                }
+            }
+            Err(e) => {
+                self.metrics.record_peer_rpc(peer, bytes_sent, None, None);
+                span.record("outcome", display(&e));
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes one framed request and reads the framed response.
+    async fn exchange_rpc(
+        &self,
+        peer: SocketAddr,
+        priority: Priority,
+        serialized: &[u8],
+    ) -> Result<Vec<u8>> {
+        if self.config.connection_pool.multiplexing {
+            let conn = self.connection_pool.get_multiplexed(peer).await?;
+            return conn.request(serialized).await;
+        }
+
+        let mut conn = self
+            .connection_pool
+            .get_connection_prioritized(peer, priority)
+            .await?;
+        conn.begin_exchange();
 
-        let mut len_buf = [0u8; 4];
-        conn.read_exact(&mut len_buf)
+        write_frame(&mut conn, serialized)
             .await
-            .context("Failed to read response length")?;
+            .context("Failed to send message")?;
 
-        let len = u32::from_be_bytes(len_buf) as usize;
-        let mut response_buf = vec![0u8; len];
-        conn.read_exact(&mut response_buf)
+        let response_buf = read_frame(&mut conn, MAX_FRAME_LEN)
             .await
             .context("Failed to read response")?;
 
-        let response = bincode::deserialize(&response_buf)?;
-        Ok(response)
+        conn.end_exchange();
+        Ok(response_buf)
+    }
+
+    /// Serves multiplexed RPCs arriving on an accepted connection until the
+    /// peer closes it.
+    ///
+    /// This is the counterpart of `connection_pool.multiplexing` on the
+    /// calling side.
+    pub async fn serve_multiplexed<S>(&self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.serve_multiplexed_from(stream, None).await
+    }
+
+    /// Serves a multiplexed connection from `ip`, refusing the requests
+    /// over its [inbound limits](limits).
+    pub(super) async fn serve_multiplexed_from<S>(&self, stream: S, ip: Option<IpAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let node = self.clone();
+        mux::serve(stream, move |request| {
+            let node = node.clone();
+            async move {
+                if !node.inbound.allow_request(ip) {
+                    return Vec::new();
+                }
+                let request = node.open_frame(request).and_then(|request| {
+                    let envelope = bincode::deserialize(&request)?;
+                    FRAME_BUFFERS.give(request);
+                    Ok(envelope)
+                });
+                match request {
+                    Ok(envelope) => {
+                        let response = node.handle_request(envelope, ip).await;
+                        let mut encoded = FRAME_BUFFERS.take();
+                        match bincode::serialize_into(&mut encoded, &response) {
+                            Ok(()) => node.seal_frame(encoded),
+                            Err(_) => Vec::new(),
+                        }
+                    }
+                    // An empty frame fails to decode on the caller's side
+                    Err(e) => {
+                        debug!(error = %e, "rejecting multiplexed request");
+                        node.metrics.inc_rpc_failures();
+                        Vec::new()
+                    }
+                }
+            }
+        })
+        .await;
+    }
+
+    /// Keeps connections to the `connection_pool.prewarm_peers` closest
+    /// routing neighbors open, so the first RPC after an idle period does not
+    /// pay for a new connection.
+    pub async fn prewarm_connections(&self) {
+        let count = self.config.connection_pool.prewarm_peers;
+        if count == 0 {
+            return;
+        }
+
+        let neighbors = self.find_closest_peers(&self.id, count);
+        stream::iter(neighbors)
+            .for_each_concurrent(
+                self.config.replication.parallelism.max(1),
+                |peer| async move {
+                    if self.config.connection_pool.multiplexing {
+                        let _ = self.connection_pool.get_multiplexed(peer.addr).await;
+                    } else {
+                        let _ = self.connection_pool.warm(peer.addr).await;
+                    }
+                },
+            )
+            .await;
     }
 
     /// Connects to known peers to join the DHT network
+    ///
+    /// Up to `bootstrap.parallelism` seeds are dialed at once. Bootstrapping
+    /// ends as soon as `bootstrap.quorum` of them answered, dropping the
+    /// dials still under way, so that dead seeds only cost time when too few
+    /// live ones are left.
     pub async fn bootstrap(&self, known_peers: Vec<SocketAddr>) -> Result<()> {
-        for peer in known_peers {
-            match self.send_rpc(peer, DhtRpc::FindNode(self.id.clone())).await {
+        let quorum = match self.config.bootstrap.quorum {
+            0 => known_peers.len(),
+            quorum => quorum.min(known_peers.len()),
+        };
+        let mut answers = stream::iter(known_peers)
+            .map(|peer| async move {
+                let response = self.send_rpc(peer, DhtRpc::FindNode(self.id.clone())).await;
+                (peer, response)
+            })
+            .buffer_unordered(self.config.bootstrap.parallelism.max(1));
+
+        let mut reached = 0;
+        while reached < quorum
+            && let Some((peer, response)) = answers.next().await
+        {
+            match response {
                 Ok(DhtRpc::FindNodeResponse(peers)) => {
+                    reached += 1;
+                    let peers = self.verified_peers(peer, peers);
+                    debug!(%peer, discovered = peers.len(), "bootstrapped from peer");
                     for peer_info in peers {
                         self.add_peer(peer_info);
                     }
                 }
-                _ => continue,
+                Ok(response) => {
+                    debug!(%peer, response = response.name(), "unexpected bootstrap response")
+                }
+                Err(e) => debug!(%peer, error = %e, "bootstrap peer unreachable"),
             }
         }
+        drop(answers);
+
+        if reached > 0 {
+            self.emit(|| DhtEvent::Bootstrapped {
+                reached,
+                known_peers: self.metrics.known_peers.load(Ordering::Relaxed) as usize,
+            });
+            self.flush_outbox().await;
+        } else {
+            warn!("no bootstrap peer could be reached");
+        }
 
         Ok(())
     }
 
+    /// Returns outgoing traffic statistics of the most recently active peers.
+    ///
+    /// At most `max_tracked_peers` peers are tracked. Only RPCs this node
+    /// sends are counted, including those that failed before reaching the
+    /// peer.
+    pub fn peer_stats(&self) -> HashMap<SocketAddr, PeerStats> {
+        self.metrics.peer_stats()
+    }
+
+    /// Returns [`get_stats`](Self::get_stats) and
+    /// [`peer_stats`](Self::peer_stats) as one JSON object, with the per-peer
+    /// stats under `peers` keyed by address.
+    pub fn stats_json(&self) -> String {
+        let snapshot = StatsSnapshot {
+            stats: self.get_stats(),
+            peers: self.peer_stats(),
+        };
+        serde_json::to_string(&snapshot).expect("stats are always serializable")
+    }
+
+    /// Starts all counters, rates and per-peer stats over from zero, so the
+    /// stats reflect only what happened since the call.
+    pub fn reset_metrics(&self) {
+        self.metrics.reset();
+    }
+
     /// Returns a photo DHT stats
     pub fn get_stats(&self) -> DhtStats {
         DhtStats {
@@ -305,118 +1099,298 @@ impl DhtNode {
             rpc_requests: self.metrics.rpc_requests.load(Ordering::Relaxed),
             rpc_failures: self.metrics.rpc_failures.load(Ordering::Relaxed),
             known_peers: self.metrics.known_peers.load(Ordering::Relaxed),
+            storage_evictions: self.metrics.storage_evictions.load(Ordering::Relaxed),
+            expired_entries: self.metrics.expired_entries.load(Ordering::Relaxed),
+            replication_attempts: self.metrics.replication_attempts.load(Ordering::Relaxed),
+            replication_successes: self.metrics.replication_successes.load(Ordering::Relaxed),
+            peers_evicted: self.metrics.peers_evicted.load(Ordering::Relaxed),
+            health_checks: self.metrics.health_checks.load(Ordering::Relaxed),
+            health_check_failures: self.metrics.health_check_failures.load(Ordering::Relaxed),
+            rpcs_shed: self.metrics.rpcs_shed.load(Ordering::Relaxed),
+            requests_deduplicated: self.metrics.requests_deduplicated.load(Ordering::Relaxed),
+            hot_key_requests_shed: self.metrics.hot_key_requests_shed.load(Ordering::Relaxed),
+            lookup_cache_hits: self.metrics.lookup_cache_hits.load(Ordering::Relaxed),
+            hot_keys: self.hot_keys(),
             storage_size: self.storage.len() as u64,
+            storage_bytes: self.storage.size_bytes() as u64,
+            outbox_size: self.outbox.len() as u64,
+            rates: self.metrics.rates(),
         }
     }
 
     pub async fn start_maintenance_service(&self) {
         let node = self.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(node.config.maintenance_interval);
+        let maintenance = tasks::spawn(
+            "maintenance",
+            self.until_stopped(Priority::Maintenance.scope(async move {
+                let mut interval = node.interval(node.config.maintenance_interval);
+
+                loop {
+                    interval.tick().await;
+                    let started = Instant::now();
+
+                    node.clean_expired().await;
+
+                    node.hand_off_hinted_values().await;
 
-            loop {
-                interval.tick().await;
+                    node.flush_outbox().await;
 
-                node.check_peers_health().await;
+                    node.prewarm_connections().await;
 
-                node.clean_expired().await;
+                    node.connection_pool.clean_stale_connections();
 
-                node.check_data_availability().await;
+                    node.emit(|| DhtEvent::MaintenanceCompleted {
+                        elapsed: started.elapsed(),
+                    });
+                }
+            })),
+        );
+
+        let replication = self.start_replication_checker();
+        let health_checks = self.start_health_checker();
+        self.health
+            .tasks
+            .lock()
+            .unwrap()
+            .extend([maintenance, replication, health_checks]);
+    }
+
+    /// Stops the node: cancels the maintenance tasks, closes the RPC
+    /// listener, health endpoint and StatsD exporter, then the connection
+    /// pool, giving RPCs in flight up to `operation_timeout` to finish.
+    /// Resolves once all of them have stopped.
+    ///
+    /// Connections accepted before the call are served until their peer
+    /// closes them. Stored data is left as is; see
+    /// [`DhtNode::hand_off_keys`] to move it elsewhere first.
+    pub async fn shutdown(&self) {
+        self.health.stopping.send_replace(true);
+        self.health.stopping.closed().await;
+
+        let in_use = self
+            .connection_pool
+            .shutdown(self.config.operation_timeout)
+            .await;
+        if in_use > 0 {
+            debug!(in_use, "connections still in use at shutdown");
+        }
+    }
+
+    /// Runs `task` until it ends or [`DhtNode::shutdown`] is called.
+    pub(super) fn until_stopped<F>(&self, task: F) -> impl Future<Output = ()> + use<F>
+    where
+        F: Future<Output = ()>,
+    {
+        // Subscribing now rather than on first poll, so a shutdown racing
+        // with the spawn still waits for this task
+        let mut stopping = self.health.stopping.subscribe();
+        async move {
+            tokio::select! {
+                _ = stopping.wait_for(|stopping| *stopping) => {}
+                _ = task => {}
             }
-        });
+        }
+    }
+
+    /// Position of `key` in the keyspace, hashed with the configured
+    /// [`IdHash`](node::IdHash).
+    pub fn key_id(&self, key: &[u8]) -> NodeId {
+        NodeId::with_hash(key, self.config.id_hash)
     }
 
     /// Calculates the k-bucket index for a given distance.
     ///
     /// This implements the Kademlia routing table structure where each bucket
     /// holds nodes at specific distance ranges.
-    fn get_bucket_index(&self, distance: &[u8; 32]) -> u8 {
-        for i in 0..32 {
+    pub(crate) fn get_bucket_index(&self, distance: &[u8; ID_LEN]) -> u8 {
+        for (i, byte) in distance.iter().enumerate() {
             for j in (0..8).rev() {
-                if (distance[i] >> j) & 1 == 1 {
+                if (byte >> j) & 1 == 1 {
                     return (i * 8 + (7 - j)) as u8;
                 }
             }
         }
-        255
+        (ID_BITS - 1) as u8
     }
 
     fn create_routing_table() -> Arc<DashMap<u8, KBucket>> {
-        Arc::new(DashMap::with_capacity(256))
+        Arc::new(DashMap::with_capacity(ID_BITS))
     }
 
-    fn create_storage(max_entries: usize) -> Arc<DashMap<Vec<u8>, Vec<u8>>> {
-        Arc::new(DashMap::with_capacity(max_entries))
+    fn create_storage(max_entries: usize) -> Arc<dyn Storage> {
+        Arc::new(MemoryStorage::with_capacity(max_entries))
     }
 
     /// Finds the k closest peers to a given key according to the XOR metric.
     ///
     /// This is a core Kademlia operation used for routing and value lookup.
+    /// Buckets are visited from the closest to `key` on, see
+    /// [`buckets_by_distance`], so only the peers of the buckets needed to
+    /// fill `k` are copied.
     fn find_closest_peers(&self, key: &NodeId, k: usize) -> Vec<PeerInfo> {
-        let rt = &self.routing_table;
-        let mut all_peers = Vec::new();
+        let mut closest = Vec::with_capacity(k.min(self.peer_count()));
 
-        for bucket in rt.iter() {
-            all_peers.extend(bucket.value().peers.iter().cloned());
+        for group in buckets_by_distance(self.get_bucket_index(&self.id.distance(key))) {
+            let start = closest.len();
+            for index in group {
+                if let Some(bucket) = self.routing_table.get(&index) {
+                    closest.extend(bucket.iter().cloned());
+                }
+            }
+            closest[start..].sort_by_cached_key(|peer| key.distance(&peer.id));
+            if closest.len() >= k {
+                break;
+            }
         }
 
-        all_peers.sort_by(|a, b| {
-            let dist_a = key.distance(&a.id);
-            let dist_b = key.distance(&b.id);
-            dist_a.cmp(&dist_b)
-        });
+        closest.truncate(k);
+        closest
+    }
 
-        all_peers.into_iter().take(k).collect()
+    /// Calls `f` on every peer of the routing table, without copying them.
+    ///
+    /// Each bucket stays locked while `f` runs on its peers, so `f` must not
+    /// modify the routing table.
+    pub fn for_each_peer(&self, mut f: impl FnMut(&PeerInfo)) {
+        for bucket in self.routing_table.iter() {
+            bucket.iter().for_each(&mut f);
+        }
     }
 
-    fn find_closest_peers_by_key(&self, key: &Vec<u8>) -> Vec<PeerInfo> {
-        let key_id = NodeId::new(&key);
+    /// Number of peers in the routing table.
+    pub fn peer_count(&self) -> usize {
+        self.routing_table.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    /// Applies `storage.ttl_jitter_percent` to the `ttl` of a value written
+    /// by this node.
+    fn jittered_ttl(&self, ttl: u64) -> u64 {
+        jitter_ttl(ttl, self.config.storage.ttl_jitter_percent)
+    }
+
+    /// Returns the replication-factor closest peers to the hash of `key`.
+    ///
+    /// This is the placement rule shared by stores and lookups.
+    fn find_closest_peers_by_key(&self, key: &[u8]) -> Vec<PeerInfo> {
+        let key_id = self.key_id(key);
         let replication_factor = self.config.replication.factor;
         self.find_closest_peers(&key_id, replication_factor)
     }
 
-    fn resolve_conflict(&self, values: Vec<StoredValue>) -> Option<StoredValue> {
-        values.into_iter().max_by_key(|v| v.version)
+    fn resolve_conflict(
+        &self,
+        mut values: Vec<(SocketAddr, StoredValue)>,
+    ) -> Option<(SocketAddr, StoredValue)> {
+        // Only the latest version of a record competes
+        if let Some(latest) = values
+            .iter()
+            .filter_map(|(_, v)| v.record.as_ref().map(|r| r.sequence))
+            .max()
+        {
+            values.retain(|(_, v)| v.record.as_ref().is_none_or(|r| r.sequence == latest));
+        }
+        let (sources, mut candidates): (Vec<_>, Vec<_>) = values.into_iter().unzip();
+        let winner = self.conflict_resolver.resolve(&candidates)?;
+        Some((sources[winner], candidates.swap_remove(winner)))
     }
 
-    async fn repair_replication(&self, key: &Vec<u8>, value: Vec<u8>) {
-        let current_value = match self.storage.get(key) {
-            Some(v) => v.clone(),
-            None => return,
+    /// Stores a copy of `key` a peer sent, unless it is refused, and returns
+    /// whether it was kept.
+    ///
+    /// With `expected`, the copy is only kept in place of a local one of
+    /// that version, or of none when it is `Some(None)`, see
+    /// [`DhtNode::compare_and_swap`]. Otherwise it is kept unless the local
+    /// copy wins over it.
+    fn keep_replica(&self, key: Vec<u8>, value: Bytes, expected: Option<Option<u64>>) -> bool {
+        self.metrics.inc_store_ops();
+
+        let Ok(mut stored) = deserialize_value(&value) else {
+            debug!(key = %key_hash(&key), "rejecting undecodable stored value");
+            self.metrics.inc_rpc_failures();
+            return false;
         };
-
-        if let Ok(stored_value) = deserialize_value(&current_value) {
-            if stored_value.is_replica {
-                return;
+        stored.last_node = self.addr;
+        stored.is_replica = true;
+
+        if self.is_key_blocked(&key) {
+            debug!(key = %key_hash(&key), "refusing blocked key");
+            self.metrics.inc_rpc_failures();
+        } else if !self
+            .write_authorizer
+            .authorize(&key, stored.token.as_deref())
+        {
+            debug!(key = %key_hash(&key), "rejecting unauthorized write");
+            self.metrics.inc_rpc_failures();
+        } else if let Err(e) = self.record_validator.validate(&key, &stored) {
+            debug!(key = %key_hash(&key), error = %e, "rejecting invalid value");
+            self.metrics.inc_rpc_failures();
+        } else if let Err(e) = self.accept_record(&key, &stored) {
+            debug!(key = %key_hash(&key), error = %e, "rejecting record");
+            self.metrics.inc_rpc_failures();
+        } else if expected.is_none() && self.local_copy_wins(&key, &stored) {
+            debug!(key = %key_hash(&key), "refusing value older than the local copy");
+        } else if !self.fits_storage(key.len() + value.len()) {
+            debug!(key = %key_hash(&key), "refusing value over the storage limit");
+            self.metrics.inc_rpc_failures();
+        } else if let Ok(value) = serialize_value(&stored) {
+            let kept = match expected {
+                Some(expected) => self.insert_if_version(&key, value, stored.version, expected),
+                None => {
+                    self.storage.insert(key.clone(), value);
+                    true
+                }
+            };
+            if !kept {
+                debug!(key = %key_hash(&key), "refusing swap of another version");
+                return false;
             }
+            self.evict_after_write(&key);
+            self.lookup_cache.invalidate(&key, stored.version);
+            self.metrics.inc_store_success();
+            self.emit(|| DhtEvent::ValueStored {
+                key,
+                version: stored.version,
+                is_replica: true,
+            });
+            return true;
+        } else {
+            self.metrics.inc_rpc_failures();
         }
+        false
+    }
 
-        let key_id = NodeId::new(key);
-        let replication_factor = self.config.replication.factor * 2;
-        let closest_peers = self.find_closest_peers(&key_id, replication_factor);
+    /// Checks whether a valid local copy of `key` beats an incoming one.
+    fn local_copy_wins(&self, key: &[u8], incoming: &StoredValue) -> bool {
+        let Some(existing) = self
+            .storage
+            .get(key)
+            .and_then(|v| deserialize_value(&v).ok())
+            .filter(|v| v.is_valid(self.now()))
+        else {
+            return false;
+        };
 
-        for peer in closest_peers {
-            if let Ok(DhtRpc::FindValueResponse(None)) = self
-                .send_rpc(peer.addr, DhtRpc::FindValue(key.clone()))
-                .await
-            {
-                let _ = self
-                    .send_rpc(peer.addr, DhtRpc::Store(key.clone(), value.clone()))
-                    .await;
-            }
+        // Versions of a record are ordered by their sequence numbers
+        if let (Some(local), Some(remote)) = (&existing.record, &incoming.record) {
+            return local.sequence >= remote.sequence;
         }
+
+        self.conflict_resolver
+            .resolve(&[existing, incoming.clone()])
+            == Some(0)
     }
 
     async fn store_with_fallback(
         &self,
         key: Vec<u8>,
-        value: Vec<u8>,
+        value: StoredValue,
         original_nodes: Vec<SocketAddr>,
     ) -> Result<()> {
-        let key_id = NodeId::new(&key);
+        let key_id = self.key_id(&key);
         let replication_factor = self.config.replication.factor * 2;
         let closest_peers = self.find_closest_peers(&key_id, replication_factor);
 
+        let ttl = self.jittered_ttl(self.config.storage.default_ttl);
         let mut success_count = 0;
         for peer in closest_peers {
             if success_count >= self.config.replication.factor {
@@ -425,21 +1399,27 @@ impl DhtNode {
 
             let value_to_store = if original_nodes.contains(&peer.addr) {
                 StoredValue {
-                    data: value.clone(),
-                    version: now(),
+                    data: value.data.clone(),
+                    version: value.version,
                     last_node: self.addr,
                     is_replica: false,
-                    expiration: Some(now() + self.config.storage.default_ttl),
+                    expiration: Some(self.now() + ttl),
                     original_nodes: original_nodes.clone(),
+                    hinted_for: None,
+                    record: value.record.clone(),
+                    token: value.token.clone(),
                 }
             } else {
                 StoredValue {
-                    data: value.clone(),
-                    version: now(),
+                    data: value.data.clone(),
+                    version: value.version,
                     last_node: self.addr,
                     is_replica: true,
-                    expiration: Some(now() + self.config.storage.default_ttl),
+                    expiration: Some(self.now() + ttl),
                     original_nodes: original_nodes.clone(),
+                    hinted_for: None,
+                    record: value.record.clone(),
+                    token: value.token.clone(),
                 }
             };
 
@@ -450,8 +1430,8 @@ impl DhtNode {
                 )
                 .await
             {
-                Ok(_) => success_count += 1,
-                Err(_) => continue,
+                Ok(DhtRpc::StoreResponse(true)) => success_count += 1,
+                _ => continue,
             }
         }
 
@@ -462,104 +1442,104 @@ impl DhtNode {
         }
     }
 
-    async fn check_peers_health(&self) {
-        let mut dead_peers = Vec::new();
-
-        for bucket in self.routing_table.iter() {
-            for peer in bucket.value().peers.iter() {
-                match timeout(
-                    self.config.operation_timeout,
-                    self.send_rpc(peer.addr, DhtRpc::Ping),
-                )
-                .await
-                {
-                    Ok(Ok(DhtRpc::Pong)) => {
-                        self.update_peer_last_seen(&peer.id);
-                    }
-                    _ => {
-                        dead_peers.push(peer.clone());
-                    }
-                }
-            }
-        }
-
-        for peer in dead_peers {
-            self.handle_dead_peer(&peer).await;
-        }
-    }
-
     async fn handle_dead_peer(&self, peer: &PeerInfo) {
+        info!(peer = %peer.addr, "evicting unresponsive peer");
+
         let distance = self.id.distance(&peer.id);
         let bucket_index = self.get_bucket_index(&distance);
 
         if let Some(mut bucket) = self.routing_table.get_mut(&bucket_index) {
             bucket.peers.retain(|p| p.id != peer.id);
         }
+        self.connection_pool.forget_peer(peer.addr);
+        self.metrics.inc_peers_evicted();
+        self.emit(|| DhtEvent::PeerEvicted(peer.clone()));
 
         let mut to_replicate = Vec::new();
 
-        for entry in self.storage.iter() {
-            if let Ok(value) = deserialize_value(entry.value()) {
-                if value.original_nodes.contains(&peer.addr) {
-                    to_replicate.push((entry.key().clone(), value.data));
-                }
+        for (key, value) in self.storage.iter() {
+            if let Ok(value) = deserialize_value(&value)
+                && value.original_nodes.contains(&peer.addr)
+            {
+                to_replicate.push((key, value));
             }
         }
 
         for (key, value) in to_replicate {
-            let _ = self.store_with_fallback(key, value, vec![peer.addr]).await;
+            if let Err(e) = self
+                .store_with_fallback(key.clone(), value, vec![peer.addr])
+                .await
+            {
+                warn!(key = %key_hash(&key), error = %e, "could not re-replicate key of evicted peer");
+            }
         }
     }
 
-    async fn check_data_availability(&self) {
-        for entry in self.storage.iter() {
-            if let Ok(value) = deserialize_value(entry.value()) {
-                if !value.is_replica {
-                    let key = entry.key();
-                    let existing_replicas = self.count_existing_replicas(key).await;
-
-                    if existing_replicas < self.config.replication.factor {
-                        self.repair_replication(key, value.data).await;
+    /// Drops expired values and tells replicas of values this node
+    /// originated to drop theirs too.
+    async fn clean_expired(&self) {
+        let current_time = self.now();
+        let mut expired = Vec::new();
+        let mut dropped = Vec::new();
+        let mut corrupt = 0;
+
+        self.storage
+            .retain(&mut |key, value| match decode_header(value) {
+                Ok(h) if h.is_valid(current_time) => true,
+                Ok(h) => {
+                    // Only the expiry notice needs the body, for the token
+                    if !h.is_replica {
+                        let token = deserialize_value(value).ok().and_then(|v| v.token);
+                        expired.push((key.to_vec(), h.version, token));
                     }
+                    dropped.push(key.to_vec());
+                    false
                 }
-            }
-        }
-    }
+                Err(_) => {
+                    corrupt += 1;
+                    false
+                }
+            });
 
-    async fn count_existing_replicas(&self, key: &Vec<u8>) -> usize {
-        let key_id = NodeId::new(key);
-        let closest_peers = self.find_closest_peers(&key_id, self.config.replication.factor * 2);
+        self.metrics.add_expired_entries(dropped.len() as u64);
+        self.metrics.add_storage_evictions(corrupt);
 
-        let mut count = 0;
-        for peer in closest_peers {
-            if let Ok(DhtRpc::FindValueResponse(Some(_))) = self
-                .send_rpc(peer.addr, DhtRpc::FindValue(key.clone()))
-                .await
-            {
-                count += 1;
-            }
+        if !dropped.is_empty() {
+            debug!(count = dropped.len(), "dropped expired values");
         }
-
-        count
-    }
-
-    async fn clean_expired(&self) {
-        let current_time = now();
-        self.storage.retain(|_, value| {
-            deserialize_value(value)
-                .map(|v| v.is_valid(current_time))
-                .unwrap_or(false)
-        });
+        for key in dropped {
+            self.emit(|| DhtEvent::ValueExpired { key });
+        }
+        self.send_expiry_notices_batched(expired).await;
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "node"))]
 mod dht_node_tests {
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::{
+        collections::HashSet,
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use tokio::{net::TcpListener, time::timeout};
 
     use crate::{
-        dht::{NodeId, PeerInfo},
-        helpers::create_test_node,
+        dht::{
+            DhtNode, DhtRpc, NodeId, PeerInfo,
+            clock::ManualClock,
+            conflict::ConflictResolver,
+            events::DhtEvent,
+            faults::{Fault, FaultRule, Phase},
+            lookup::ValueAnswer,
+            storage::{
+                StoredValue, ValueHeader, create_stored_value, decode_header, deserialize_value,
+                jitter_ttl, serialize_value,
+            },
+        },
+        helpers::{create_test_node, now, test_config},
+        testing::TestCluster,
     };
 
     #[tokio::test]
@@ -572,7 +1552,479 @@ mod dht_node_tests {
         node.store(key.clone(), value.clone()).await.unwrap();
 
         let found = node.find_value(key).await;
-        assert_eq!(found, Some(value));
+        assert_eq!(found, Some(value.into()));
+    }
+
+    #[tokio::test]
+    async fn test_get_many_keeps_the_order_of_keys() {
+        let node = create_test_node(8090);
+        node.store(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+        node.store(b"b".to_vec(), b"2".to_vec()).await.unwrap();
+
+        let keys = [&b"b"[..], b"missing", b"a", b"b"];
+        let found = node
+            .get_many(keys.iter().map(|key| key.to_vec()).collect())
+            .await;
+        assert_eq!(
+            found,
+            vec![
+                Some(Bytes::from_static(b"2")),
+                None,
+                Some(Bytes::from_static(b"1")),
+                Some(Bytes::from_static(b"2")),
+            ]
+        );
+        assert_eq!(node.get_stats().find_value_ops, 3);
+        assert!(node.get_many(Vec::new()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_find_value_detailed_reports_provenance() {
+        let node = create_test_node(8090);
+
+        let key = b"detailed_key".to_vec();
+        let value = b"detailed_value".to_vec();
+
+        node.store(key.clone(), value.clone()).await.unwrap();
+
+        let result = node.find_value_detailed(key).await.unwrap();
+        assert_eq!(result.value, value);
+        assert_eq!(result.source, node.addr);
+        assert_eq!(result.replicas, 1);
+        assert!(result.ttl_remaining.unwrap() <= node.config.storage.default_ttl);
+    }
+
+    #[tokio::test]
+    async fn test_stats_json_includes_peer_stats() {
+        let node = create_test_node(8090);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        drop(listener);
+        node.add_peer(PeerInfo::new(NodeId::new(b"gone"), peer_addr));
+
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        let stats: serde_json::Value = serde_json::from_str(&node.stats_json()).unwrap();
+        assert_eq!(stats["store_ops"], 1);
+        assert_eq!(stats["outbox_size"], 1);
+        assert_eq!(stats["peers"][peer_addr.to_string()]["failures"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_storage_evicts_replicas_and_old_values_first() {
+        let mut config = test_config();
+        config.storage.max_entries = 10;
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+
+        for version in 1..=10u64 {
+            let mut stored =
+                create_stored_value(vec![0; 100], node.addr, version <= 4, None, node.now());
+            stored.version = version;
+            node.storage
+                .insert(vec![version as u8], serialize_value(&stored).unwrap());
+        }
+        assert_eq!(node.evict_to_fit(), 0);
+
+        node.store(b"new".to_vec(), vec![0; 100]).await.unwrap();
+        // Down to 90% of the 10 entries allowed, oldest replicas first
+        let mut kept: Vec<_> = node.storage.iter().map(|(key, _)| key).collect();
+        kept.sort();
+        let mut expected: Vec<_> = (3..=10u8).map(|key| vec![key]).collect();
+        expected.push(b"new".to_vec());
+        assert_eq!(kept, expected);
+        assert_eq!(node.get_stats().storage_evictions, 2);
+    }
+
+    #[tokio::test]
+    async fn test_storage_bytes_are_limited() {
+        let mut config = test_config();
+        config.storage.max_bytes = 1000;
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+
+        assert!(node.store(b"huge".to_vec(), vec![0; 1000]).await.is_err());
+        for i in 0..5u8 {
+            node.store(vec![i], vec![0; 150]).await.unwrap();
+        }
+
+        let stats = node.get_stats();
+        assert!(stats.storage_bytes <= 900);
+        assert_eq!(stats.storage_bytes, node.storage.size_bytes() as u64);
+        assert!(stats.storage_size < 5);
+        assert!(node.storage.contains_key(&[4]));
+    }
+
+    #[tokio::test]
+    async fn test_eviction_spares_the_value_just_written() {
+        let mut config = test_config();
+        config.storage.max_entries = 3;
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+
+        // All within a second or two, so mostly of the same version
+        for i in 0..6u8 {
+            node.store(vec![i], vec![0; 10]).await.unwrap();
+            assert!(node.storage.contains_key(&[i]));
+        }
+        let mut kept: Vec<_> = node.storage.iter().map(|(key, _)| key).collect();
+        kept.sort();
+        assert_eq!(kept, vec![vec![3], vec![4], vec![5]]);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_does_not_wait_for_silent_seeds() {
+        let mut config = test_config();
+        config.bootstrap.quorum = 1;
+        config.operation_timeout = Duration::from_secs(30);
+        let cluster = TestCluster::with_config(3, config).await;
+        let (node, silent, seed) = (cluster.node(0), cluster.node(1), cluster.node(2));
+        // Takes requests and never answers
+        cluster.faults().inject(
+            FaultRule::new(Fault::Drop)
+                .at(Phase::Handling)
+                .on("FindNode")
+                .to(silent.addr),
+        );
+        let mut events = node.events.subscribe();
+
+        timeout(
+            Duration::from_secs(5),
+            node.bootstrap(vec![silent.addr, silent.addr, seed.addr]),
+        )
+        .await
+        .expect("bootstrap waited for a silent seed")
+        .unwrap();
+        loop {
+            if let DhtEvent::Bootstrapped { reached, .. } = events.recv().await.unwrap() {
+                assert_eq!(reached, 1);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn test_value_header_decodes_without_the_body() {
+        let addr = "127.0.0.1:8090".parse().unwrap();
+        let mut stored = create_stored_value(vec![7; 4096], addr, true, Some(60), 1_000);
+        stored.hinted_for = Some(addr);
+        let encoded = serialize_value(&stored).unwrap();
+
+        let expected = ValueHeader {
+            version: 1_000,
+            expiration: Some(1_060),
+            is_replica: true,
+            is_hinted: true,
+        };
+        // The header alone is enough, the payload is never read
+        assert_eq!(decode_header(&encoded[..18]).unwrap(), expected);
+        assert!(deserialize_value(&encoded[..18]).is_err());
+        assert!(decode_header(&encoded[..17]).is_err());
+
+        let decoded = deserialize_value(&encoded).unwrap();
+        assert_eq!(decoded.data, stored.data);
+        assert_eq!(decoded.hinted_for, Some(addr));
+        assert_eq!(decoded.expiration, Some(1_060));
+
+        // An expiration of zero is not confused with none
+        stored.expiration = Some(0);
+        let header = decode_header(&serialize_value(&stored).unwrap()).unwrap();
+        assert_eq!(header.expiration, Some(0));
+        assert!(!header.is_valid(0));
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_outcomes_are_counted() {
+        let node = create_test_node(8090);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        drop(listener);
+        node.add_peer(PeerInfo::new(NodeId::new(b"gone"), peer_addr));
+
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        let mut stale = create_stored_value(b"stale".to_vec(), node.addr, true, None, node.now());
+        stale.expiration = Some(0);
+        node.storage
+            .insert(b"stale".to_vec(), serialize_value(&stale).unwrap());
+        node.storage
+            .insert(b"corrupt".to_vec(), Bytes::from_static(&[0xff]));
+        node.clean_expired().await;
+
+        for _ in 0..node.config.health_check.max_failures {
+            node.check_peers_health().await;
+        }
+
+        let stats = node.get_stats();
+        assert_eq!(stats.replication_attempts, 1);
+        assert_eq!(stats.replication_successes, 0);
+        assert_eq!(stats.expired_entries, 1);
+        assert_eq!(stats.storage_evictions, 1);
+        assert_eq!(stats.peers_evicted, 1);
+        assert!(stats.rates.store_ops_1m > 0.0);
+
+        node.reset_metrics();
+        let stats = node.get_stats();
+        assert_eq!(stats.store_ops, 0);
+        assert_eq!(stats.peers_evicted, 0);
+        assert_eq!(stats.rates.store_ops_1m, 0.0);
+        assert!(node.peer_stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_debug_dump_reports_state() {
+        let node = create_test_node(8090);
+        node.add_peer(PeerInfo::new(
+            NodeId::new(b"peer"),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8001),
+        ));
+        for key in ["users:1", "users:2", "orders:1", "plain"] {
+            node.store(key.as_bytes().to_vec(), b"value".to_vec())
+                .await
+                .unwrap();
+        }
+
+        let dump = node.debug_dump().await;
+        assert_eq!(dump.routing_table.len(), 1);
+        assert_eq!(dump.routing_table[0].peers[0].addr.port(), 8001);
+        assert_eq!(dump.storage.keys, 4);
+        assert_eq!(dump.storage.namespaces["users"], 2);
+        assert_eq!(dump.storage.namespaces["orders"], 1);
+        assert_eq!(dump.storage.namespaces[""], 1);
+        assert_eq!(dump.tasks.outbox, 4);
+
+        let json: serde_json::Value = serde_json::to_value(&dump).unwrap();
+        assert_eq!(
+            json["connection_pool"]["peers"]["127.0.0.1:8001"]["dial_failures"],
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_entries_filter_by_prefix() {
+        let node = create_test_node(8090);
+        for key in ["users:2", "users:1", "orders:1"] {
+            node.store(key.as_bytes().to_vec(), b"value".to_vec())
+                .await
+                .unwrap();
+        }
+
+        let mut replica =
+            create_stored_value(b"replicated".to_vec(), node.addr, true, None, node.now());
+        replica.version = 7;
+        node.storage
+            .insert(b"users:3".to_vec(), serialize_value(&replica).unwrap());
+        let mut expired = create_stored_value(b"old".to_vec(), node.addr, false, None, node.now());
+        expired.expiration = Some(0);
+        node.storage
+            .insert(b"users:4".to_vec(), serialize_value(&expired).unwrap());
+
+        let entries = node.local_entries(b"users:");
+        let keys: Vec<&[u8]> = entries.iter().map(|e| e.key.as_slice()).collect();
+        assert_eq!(keys, [&b"users:1"[..], b"users:2", b"users:3"]);
+        assert_eq!(entries[0].size, 5);
+        assert!(!entries[0].is_replica);
+        assert!(entries[0].ttl_remaining.unwrap() <= node.config.storage.default_ttl);
+        assert_eq!(entries[2].ttl_remaining, None);
+        assert!(entries[2].is_replica);
+
+        assert_eq!(node.local_entries(b"").len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_background_tasks() {
+        let node = create_test_node(8312);
+        let listener = node.listen().await.unwrap();
+        node.start_maintenance_service().await;
+        assert!(node.health().bound);
+        assert!(node.health().maintenance_alive);
+
+        timeout(Duration::from_secs(5), node.shutdown())
+            .await
+            .unwrap();
+
+        assert!(listener.is_finished());
+        assert!(!node.health().bound);
+        assert!(!node.health().maintenance_alive);
+        assert!(tokio::net::TcpStream::connect(node.addr).await.is_err());
+        assert!(node.send_rpc(node.addr, DhtRpc::Ping).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_store_with_ttl_overrides_default_ttl() {
+        let node = create_test_node(8090);
+        node.store_with_ttl(b"long".to_vec(), b"value".to_vec(), 600)
+            .await
+            .unwrap();
+        node.store(b"default".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        let long = node.find_value_detailed(b"long".to_vec()).await.unwrap();
+        assert!((599..=600).contains(&long.ttl_remaining.unwrap()));
+        let default = node.local_value(b"default").unwrap();
+        assert!(default.expiration.unwrap() <= now() + node.config.storage.default_ttl);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_jitter_spreads_expirations() {
+        assert_eq!(jitter_ttl(600, 0), 600);
+        assert_eq!(jitter_ttl(5, 10), 5);
+        assert!((540..=600).contains(&jitter_ttl(600, 10)));
+
+        let mut config = test_config();
+        config.storage.ttl_jitter_percent = 50;
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+        let mut lifetimes = HashSet::new();
+        for i in 0..20u8 {
+            node.store_with_ttl(vec![i], b"value".to_vec(), 1000)
+                .await
+                .unwrap();
+            let stored = node.local_value(&[i]).unwrap();
+            let lifetime = stored.expiration.unwrap() - stored.version;
+            assert!((500..=1000).contains(&lifetime));
+            lifetimes.insert(lifetime);
+        }
+        assert!(lifetimes.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_local_value_exposes_metadata() {
+        let node = create_test_node(8090);
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        let local = node.local_value(b"key").unwrap();
+        assert_eq!(local.data, &b"value"[..]);
+        assert!(!local.is_replica);
+        assert_eq!(local.original_nodes, [node.addr]);
+
+        let found = node.find_value_detailed(b"key".to_vec()).await.unwrap();
+        assert_eq!(found.version, local.version);
+        assert_eq!(found.original_nodes, [node.addr]);
+
+        let mut expired = create_stored_value(b"old".to_vec(), node.addr, false, None, node.now());
+        expired.expiration = Some(0);
+        node.storage
+            .insert(b"old".to_vec(), serialize_value(&expired).unwrap());
+        assert!(node.local_value(b"old").is_none());
+        assert!(node.local_value(b"missing").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_banned_peers_are_dropped_and_refused() {
+        let node = create_test_node(8090);
+        let peer = PeerInfo::new(
+            NodeId::new(b"127.0.0.1:8001"),
+            "127.0.0.1:8001".parse().unwrap(),
+        );
+        node.add_peer(peer.clone());
+
+        assert!(node.ban_peer(peer.addr, None));
+        assert!(node.find_closest_peers(&peer.id, 8).is_empty());
+
+        node.add_peer(peer.clone());
+        assert!(node.find_closest_peers(&peer.id, 8).is_empty());
+        let err = node.send_rpc(peer.addr, DhtRpc::Ping).await.unwrap_err();
+        assert!(err.to_string().contains("banned"));
+        assert_eq!(node.banned_peers()[0].addr, peer.addr);
+        assert_eq!(node.banned_peers()[0].id, Some(peer.id.clone()));
+
+        assert!(node.unban_peer(peer.addr));
+        node.add_peer(peer.clone());
+        assert_eq!(node.find_closest_peers(&peer.id, 8).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_temporary_bans_expire() {
+        let clock = ManualClock::starting_now();
+        let node = create_test_node(8090).with_clock(clock.clone());
+        let addr: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+
+        node.ban_peer(addr, Some(Duration::from_secs(60)));
+        assert!(node.is_banned(&addr));
+        assert!(node.banned_peers()[0].until.is_some());
+        assert_eq!(node.banned_peers()[0].id, None);
+
+        clock.advance(Duration::from_secs(60));
+        assert!(!node.is_banned(&addr));
+        assert!(node.banned_peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drop_key_removes_local_copies() {
+        let node = create_test_node(8090);
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        assert!(node.drop_key(b"key"));
+        assert!(node.storage.is_empty());
+        assert!(node.outbox.is_empty());
+        assert!(!node.drop_key(b"key"));
+    }
+
+    #[tokio::test]
+    async fn test_offline_writes_are_buffered() {
+        let mut node = create_test_node(8090);
+        node.config.storage.max_outbox_entries = 1;
+
+        node.store(b"first".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        node.store(b"first".to_vec(), b"newer".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(node.get_stats().outbox_size, 1);
+
+        // Past the outbox, writes are still stored locally
+        node.store(b"second".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(node.get_stats().outbox_size, 1);
+        assert!(node.local_value(b"second").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_batches_are_answered_in_order() {
+        let node = create_test_node(8080);
+        let batch = DhtRpc::Batch(vec![
+            DhtRpc::Store(
+                b"key".to_vec(),
+                serialize_value(&create_stored_value(
+                    b"value".to_vec(),
+                    node.addr,
+                    false,
+                    None,
+                    node.now(),
+                ))
+                .unwrap(),
+            ),
+            DhtRpc::FindValue(b"key".to_vec()),
+            DhtRpc::Ping,
+            DhtRpc::Batch(vec![DhtRpc::Ping]),
+        ]);
+
+        let DhtRpc::BatchResponse(responses) = node.handle_rpc(batch).await else {
+            panic!("expected a BatchResponse");
+        };
+        let names: Vec<_> = responses.iter().map(DhtRpc::name).collect();
+        assert_eq!(
+            names,
+            [
+                "StoreResponse",
+                "FindValueResponse",
+                "Pong",
+                "BatchResponse"
+            ]
+        );
+        assert!(matches!(&responses[1], DhtRpc::FindValueResponse(Some(_))));
+        // Batches do not nest
+        assert!(matches!(&responses[3], DhtRpc::BatchResponse(nested) if nested.is_empty()));
     }
 
     #[tokio::test]
@@ -584,6 +2036,10 @@ mod dht_node_tests {
                 id: NodeId::new(&[i; 32]),
                 addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000 + i as u16),
                 last_seen: 0,
+                public_key: None,
+                signature: None,
+                alt_addrs: Vec::new(),
+                mirror: false,
             };
             node.add_peer(peer);
         }
@@ -600,9 +2056,101 @@ mod dht_node_tests {
     }
 
     #[tokio::test]
-    async fn test_handle_rpc() {
-        use crate::dht::DhtRpc;
+    async fn test_find_node_answers_k_closest_peers() {
+        let mut config = test_config();
+        config.kbucket_size = 4;
+        let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(config));
+        for i in 0..50u16 {
+            node.add_peer(PeerInfo::new(
+                NodeId::new(&i.to_be_bytes()),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10_000 + i),
+            ));
+        }
+
+        let target = NodeId::new(b"target");
+        let DhtRpc::FindNodeResponse(peers) =
+            node.handle_rpc(DhtRpc::FindNode(target.clone())).await
+        else {
+            panic!("Expected FindNodeResponse");
+        };
+        let mut all = Vec::new();
+        node.for_each_peer(|peer| all.push(peer.clone()));
+        all.sort_by_key(|peer| target.distance(&peer.id));
+        all.truncate(4);
+        assert_eq!(peers, all);
+    }
+
+    #[test]
+    fn test_find_closest_peers_matches_full_sort() {
+        let node = create_test_node(8090);
+        for i in 0..500u16 {
+            node.add_peer(PeerInfo::new(
+                NodeId::new(&i.to_be_bytes()),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 10_000 + i),
+            ));
+        }
+
+        let mut all = Vec::new();
+        node.for_each_peer(|peer| all.push(peer.clone()));
+        assert_eq!(all.len(), node.peer_count());
+
+        for target in [node.id.clone(), NodeId::new(b"a"), NodeId::new(b"b")] {
+            all.sort_by_key(|peer| target.distance(&peer.id));
+            for k in [1, 8, 20, all.len() + 1] {
+                let expected: Vec<_> = all.iter().take(k).cloned().collect();
+                assert_eq!(node.find_closest_peers(&target, k), expected);
+            }
+        }
+    }
 
+    #[tokio::test]
+    async fn test_store_targets_closest_to_key() {
+        let node = create_test_node(8090);
+
+        let mut peers = Vec::new();
+        for i in 0..10 {
+            let peer = PeerInfo {
+                id: NodeId::new(&[i; 32]),
+                addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8000 + i as u16),
+                last_seen: 0,
+                public_key: None,
+                signature: None,
+                alt_addrs: Vec::new(),
+                mirror: false,
+            };
+            node.add_peer(peer.clone());
+            peers.push(peer);
+        }
+
+        let key = b"placement_key".to_vec();
+        let key_id = NodeId::new(&key);
+        let targets = node.find_closest_peers_by_key(&key);
+
+        assert_eq!(targets.len(), node.config.replication.factor);
+
+        for other in peers.iter().filter(|p| !targets.contains(p)) {
+            for target in &targets {
+                assert!(key_id.distance(&target.id) <= key_id.distance(&other.id));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_peer_in_routing_table() {
+        let node = create_test_node(8090);
+
+        let peer = PeerInfo::new(
+            NodeId::new(b"peer"),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8001),
+        );
+        node.add_peer(peer.clone());
+
+        assert_eq!(node.find_peer(peer.id.clone()).await, Some(peer));
+        assert_eq!(node.find_peer(NodeId::new(b"unknown")).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_handle_rpc() {
         let node = create_test_node(8090);
 
         match node.handle_rpc(DhtRpc::Ping).await {
@@ -617,18 +2165,225 @@ mod dht_node_tests {
         };
 
         let key = b"key".to_vec();
-        let value = b"value".to_vec();
+        let value = serialize_value(&create_stored_value(
+            b"value".to_vec(),
+            node.addr,
+            false,
+            None,
+            node.now(),
+        ))
+        .unwrap();
         match node
             .handle_rpc(DhtRpc::Store(key.clone(), value.clone()))
             .await
         {
-            DhtRpc::Pong => (),
-            _ => panic!("Expected Pong"),
+            DhtRpc::StoreResponse(true) => (),
+            _ => panic!("Expected StoreResponse(true)"),
         };
 
         assert!(node.storage.contains_key(&key));
     }
 
+    #[tokio::test]
+    async fn test_lookup_respects_time_budget() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let mut node = create_test_node(8090);
+        node.config.lookup.timeout = Duration::from_millis(200);
+        node.add_peer(PeerInfo::new(NodeId::new(b"silent"), silent_addr));
+
+        let key = b"key".to_vec();
+        let stored = create_stored_value(b"value".to_vec(), node.addr, false, None, node.now());
+        node.storage
+            .insert(key.clone(), serialize_value(&stored).unwrap());
+
+        let found = timeout(Duration::from_secs(2), node.find_value(key))
+            .await
+            .expect("lookup exceeded its time budget");
+        assert_eq!(found, Some(Bytes::from_static(b"value")));
+    }
+
+    #[tokio::test]
+    async fn test_trace_lookup_records_each_peer() {
+        let remote = create_test_node(8311);
+        remote.listen().await.unwrap();
+        let key = b"key".to_vec();
+        let stored = create_stored_value(b"value".to_vec(), remote.addr, false, None, remote.now());
+        let version = stored.version;
+        remote
+            .storage
+            .insert(key.clone(), serialize_value(&stored).unwrap());
+
+        let node = create_test_node(8310);
+        node.add_peer(PeerInfo::new(remote.id.clone(), remote.addr));
+        let dead: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        node.add_peer(PeerInfo::new(NodeId::new(b"dead"), dead));
+
+        let trace = node.trace_lookup(key).await;
+        assert_eq!(trace.local_version, None);
+        assert_eq!(trace.result.unwrap().source, remote.addr);
+        assert!(!trace.timed_out);
+
+        let first_hop = &trace.hops[0].peers;
+        let answer = |addr| &first_hop.iter().find(|q| q.addr == addr).unwrap().value;
+        assert_eq!(answer(remote.addr), &ValueAnswer::Found { version });
+        assert_ne!(answer(dead), &ValueAnswer::NotFound);
+        assert!(first_hop.iter().all(|q| q.distance <= 256));
+    }
+
+    #[tokio::test]
+    async fn test_expire_rpc_keeps_newer_versions() {
+        let node = create_test_node(8090);
+        let key = b"key".to_vec();
+
+        let mut stored = create_stored_value(b"value".to_vec(), node.addr, false, None, node.now());
+        stored.version = 10;
+        node.storage
+            .insert(key.clone(), serialize_value(&stored).unwrap());
+
+        node.handle_rpc(DhtRpc::Expire(key.clone(), 9, None, None))
+            .await;
+        assert!(node.storage.contains_key(&key));
+
+        node.handle_rpc(DhtRpc::Expire(key.clone(), 10, None, None))
+            .await;
+        assert!(!node.storage.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_injected_conflict_resolver() {
+        struct KeepFirst;
+
+        impl ConflictResolver for KeepFirst {
+            fn resolve(&self, candidates: &[StoredValue]) -> Option<usize> {
+                (!candidates.is_empty()).then_some(0)
+            }
+        }
+
+        let node = create_test_node(8090).with_conflict_resolver(KeepFirst);
+        let key = b"key".to_vec();
+
+        for data in [b"first".to_vec(), b"second".to_vec()] {
+            let value = serialize_value(&create_stored_value(
+                data,
+                node.addr,
+                false,
+                None,
+                node.now(),
+            ))
+            .unwrap();
+            node.handle_rpc(DhtRpc::Store(key.clone(), value)).await;
+        }
+
+        assert_eq!(
+            node.find_value(key).await,
+            Some(Bytes::from_static(b"first"))
+        );
+    }
+
+    #[test]
+    fn test_alt_addrs_are_kept_for_peers_in_the_table_only() {
+        let node = create_test_node(8080);
+        let alt: SocketAddr = "[::1]:9000".parse().unwrap();
+        let index = |peer: &PeerInfo| node.get_bucket_index(&node.id.distance(&peer.id));
+
+        // Enough peers for one bucket and one more
+        let mut peers = (0..)
+            .map(|i: u32| {
+                let addr = SocketAddr::from((Ipv4Addr::from(0x0a01_0000 + i), 8000));
+                PeerInfo::new(NodeId::new(&i.to_be_bytes()), addr)
+            })
+            .peekable();
+        let bucket = index(peers.peek().unwrap());
+        let peers: Vec<_> = peers
+            .filter(|peer| index(peer) == bucket)
+            .take(21)
+            .collect();
+        for peer in &peers[..20] {
+            node.add_peer(peer.clone());
+        }
+
+        let left_out = peers[20].clone().with_alt_addrs(vec![alt]);
+        node.add_peer(left_out.clone());
+        assert_eq!(node.connection_pool.alt_addrs(left_out.addr), None);
+
+        let known = peers[0].clone().with_alt_addrs(vec![alt]);
+        node.add_peer(known.clone());
+        assert_eq!(node.connection_pool.alt_addrs(known.addr), Some(vec![alt]));
+        node.remove_inactive_peers(0);
+        assert_eq!(node.connection_pool.alt_addrs(known.addr), None);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_replicas_are_hinted_and_handed_off() {
+        let mut config = test_config();
+        config.replication.factor = 1;
+        config.storage.default_ttl = 60;
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        let cluster = TestCluster::with_config(4, config).await;
+        let (writer, intended, fallback) = (cluster.node(0), cluster.node(1), cluster.node(2));
+
+        // A key whose closest node is `intended`, then `fallback`
+        let key = (0..)
+            .map(|i| format!("key-{}", i).into_bytes())
+            .find(|key| {
+                let key_id = writer.key_id(key);
+                let mut nodes: Vec<_> = cluster.nodes().iter().collect();
+                nodes.sort_by_key(|node| key_id.distance(&node.id));
+                nodes[0].addr == intended.addr && nodes[1].addr == fallback.addr
+            })
+            .unwrap();
+
+        // The next closest node stands in for the one that is down
+        cluster.kill(1);
+        writer.store(key.clone(), b"value".to_vec()).await.unwrap();
+        let hinted = fallback.local_value(&key).unwrap();
+        assert_eq!(hinted.hinted_for, Some(intended.addr));
+        assert!(intended.local_value(&key).is_none());
+
+        // A node that refuses the value leaves the stand-in's copy in place
+        cluster.revive(1);
+        let mut newer = hinted.clone();
+        newer.version += 60;
+        newer.hinted_for = None;
+        intended
+            .storage
+            .insert(key.clone(), serialize_value(&newer).unwrap());
+        fallback.hand_off_hinted_values().await;
+        assert!(fallback.local_value(&key).is_some());
+
+        // Once it takes it, the stand-in hands the value over and drops it
+        intended.storage.remove(&key);
+        fallback.hand_off_hinted_values().await;
+        let handed = intended.local_value(&key).unwrap();
+        assert_eq!(handed.data, &b"value"[..]);
+        assert_eq!(handed.hinted_for, None);
+        assert!(fallback.local_value(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_values_of_evicted_peers_keep_their_version() {
+        let cluster = TestCluster::new(3).await;
+        let (node, peer, dead) = (cluster.node(0), cluster.node(1), cluster.node(2));
+        let key = b"key".to_vec();
+        let mut stored = create_stored_value(b"value".to_vec(), dead.addr, true, None, 5);
+        stored.original_nodes = vec![dead.addr];
+        node.storage
+            .insert(key.clone(), serialize_value(&stored).unwrap());
+
+        cluster.kill(2);
+        node.handle_dead_peer(&dead.peer_info()).await;
+        let copy = deserialize_value(&peer.storage.get(&key).unwrap()).unwrap();
+        assert_eq!(copy.version, 5);
+    }
+
     // #[tokio::test]
     // async fn test_stored_value_validation() {
     //     let node = create_test_node(8090);
@@ -3,9 +3,56 @@
 //! This module defines the [`NodeId`] type which represents a 256-bit identifier
 //! in the Kademlia DHT, along with methods for calculating distances between nodes.
 
-use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha1::Sha1;
+use sha2::Sha256;
 use sha3::{Digest, Sha3_256};
 
+/// Length of a [`NodeId`] in bytes.
+pub const ID_LEN: usize = 32;
+
+/// Length of a [`NodeId`] in bits, which is also the number of k-buckets.
+pub const ID_BITS: usize = ID_LEN * 8;
+
+/// Hash function turning node addresses and keys into [`NodeId`]s.
+///
+/// Every node of a network must use the same one. Digests shorter than
+/// [`ID_LEN`] are padded with trailing zeros, which keeps their XOR order and
+/// bucket indices, so SHA-1 IDs route like those of a 160-bit network.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdHash {
+    /// SHA3-256, the default
+    #[default]
+    Sha3_256,
+    Sha256,
+    Sha1,
+    Blake3,
+}
+
+impl IdHash {
+    /// Number of meaningful bytes in the IDs this hash produces.
+    pub fn digest_len(self) -> usize {
+        match self {
+            IdHash::Sha1 => 20,
+            IdHash::Sha3_256 | IdHash::Sha256 | IdHash::Blake3 => 32,
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> [u8; ID_LEN] {
+        let mut id = [0u8; ID_LEN];
+        match self {
+            IdHash::Sha3_256 => id.copy_from_slice(&Sha3_256::digest(data)),
+            IdHash::Sha256 => id.copy_from_slice(&Sha256::digest(data)),
+            IdHash::Sha1 => id[..20].copy_from_slice(&Sha1::digest(data)),
+            IdHash::Blake3 => id = *blake3::hash(data).as_bytes(),
+        }
+        id
+    }
+}
+
 /// A 256-bit node identifier used in the Kademlia DHT.
 ///
 /// NodeIds are used to determine the position of nodes in the DHT keyspace and to
@@ -23,9 +70,15 @@ use sha3::{Digest, Sha3_256};
 ///
 /// // Compare distances
 /// assert!(!id1.closer_than(&id2, &NodeId::new(b"far_away")));
+///
+/// // Written and parsed as hex
+/// assert_eq!(id1.to_string().parse::<NodeId>().unwrap(), id1);
 /// ```
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct NodeId([u8; 32]);
+///
+/// Human-readable formats such as JSON and TOML hold it as a hex string,
+/// binary ones as raw bytes.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct NodeId([u8; ID_LEN]);
 
 impl NodeId {
     /// Creates a new NodeId by hashing the input data with SHA3-256.
@@ -41,10 +94,20 @@ impl NodeId {
     /// let id = NodeId::new(b"node_data");
     /// ```
     pub fn new(data: &[u8]) -> Self {
-        let mut hasher = Sha3_256::new();
-        hasher.update(data);
-        let result = hasher.finalize();
-        Self(result.into())
+        Self::with_hash(data, IdHash::Sha3_256)
+    }
+
+    /// Creates a NodeId by hashing the input data with `hash`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use rust_p2p_node::dht::node::{IdHash, NodeId};
+    /// let id = NodeId::with_hash(b"node_data", IdHash::Blake3);
+    /// assert_ne!(id, NodeId::new(b"node_data"));
+    /// ```
+    pub fn with_hash(data: &[u8], hash: IdHash) -> Self {
+        Self(hash.digest(data))
     }
 
     /// Calculates the XOR distance between two NodeIds.
@@ -61,11 +124,11 @@ impl NodeId {
     ///
     /// # Returns
     ///
-    /// A 32-byte array representing the XOR distance
-    pub fn distance(&self, other: &NodeId) -> [u8; 32] {
-        let mut res = [0u8; 32];
-        for i in 0..32 {
-            res[i] = self.0[i] ^ other.0[i];
+    /// A [`ID_LEN`]-byte array representing the XOR distance
+    pub fn distance(&self, other: &NodeId) -> [u8; ID_LEN] {
+        let mut res = [0u8; ID_LEN];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
         }
         res
     }
@@ -95,14 +158,8 @@ impl NodeId {
         let dist_a = self.distance(a);
         let dist_b = self.distance(b);
 
-        // Compare byte by byte from most significant to least
-        for i in 0..32 {
-            if dist_a[i] != dist_b[i] {
-                return dist_a[i] < dist_b[i];
-            }
-        }
-
-        false // Equal distances
+        // Arrays compare byte by byte from most significant to least
+        dist_a < dist_b
     }
 
     /// Returns the number of leading zero bits in the NodeId.
@@ -128,17 +185,95 @@ impl NodeId {
         }
         count
     }
+
+    /// The raw bytes of the id.
+    pub fn as_bytes(&self) -> &[u8; ID_LEN] {
+        &self.0
+    }
 }
 
-impl std::fmt::Display for NodeId {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", hex::encode(self.0))
     }
 }
 
-#[cfg(test)]
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+/// A string is not the hex form of a [`NodeId`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseNodeIdError {
+    /// Holds the number of hex digits found
+    InvalidLength(usize),
+    InvalidHex,
+}
+
+impl fmt::Display for ParseNodeIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseNodeIdError::InvalidLength(len) => write!(
+                f,
+                "Node id must be {} hex digits, found {}",
+                ID_LEN * 2,
+                len
+            ),
+            ParseNodeIdError::InvalidHex => f.write_str("Node id is not valid hex"),
+        }
+    }
+}
+
+impl std::error::Error for ParseNodeIdError {}
+
+impl FromStr for NodeId {
+    type Err = ParseNodeIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != ID_LEN * 2 {
+            return Err(ParseNodeIdError::InvalidLength(s.len()));
+        }
+        let mut id = [0u8; ID_LEN];
+        hex::decode_to_slice(s, &mut id).map_err(|_| ParseNodeIdError::InvalidHex)?;
+        Ok(Self(id))
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let id = String::deserialize(deserializer)?;
+            id.parse().map_err(de::Error::custom)
+        } else {
+            <[u8; ID_LEN]>::deserialize(deserializer).map(Self)
+        }
+    }
+}
+
+#[cfg(all(test, feature = "node"))]
 mod node_id_tests {
-    use crate::dht::NodeId;
+    use bytes::Bytes;
+
+    use crate::{
+        dht::{
+            DhtNode, NodeId,
+            config::DhtConfig,
+            node::{ID_LEN, IdHash, ParseNodeIdError},
+        },
+        helpers::test_config,
+    };
 
     #[test]
     fn test_node_id_creation() {
@@ -181,4 +316,92 @@ mod node_id_tests {
         assert!(!base.closer_than(&far, &close));
         assert!(!base.closer_than(&close, &close));
     }
+
+    #[test]
+    fn test_id_hashes() {
+        let hashes = [
+            IdHash::Sha3_256,
+            IdHash::Sha256,
+            IdHash::Sha1,
+            IdHash::Blake3,
+        ];
+        for (i, a) in hashes.iter().enumerate() {
+            assert_eq!(
+                NodeId::with_hash(b"data", *a),
+                NodeId::with_hash(b"data", *a)
+            );
+            for b in &hashes[i + 1..] {
+                assert_ne!(
+                    NodeId::with_hash(b"data", *a),
+                    NodeId::with_hash(b"data", *b)
+                );
+            }
+        }
+        assert_eq!(
+            NodeId::with_hash(b"data", IdHash::Sha3_256),
+            NodeId::new(b"data")
+        );
+
+        // SHA-1 IDs are padded, so the trailing bytes never add distance
+        let a = NodeId::with_hash(b"a", IdHash::Sha1);
+        let b = NodeId::with_hash(b"b", IdHash::Sha1);
+        assert!(
+            a.distance(&b)[IdHash::Sha1.digest_len()..]
+                .iter()
+                .all(|byte| *byte == 0)
+        );
+        assert_eq!(IdHash::Sha1.digest_len() + 12, ID_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_node_hashes_ids_and_keys_with_configured_hash() {
+        let config = DhtConfig {
+            id_hash: IdHash::Sha256,
+            ..test_config()
+        };
+        let node = DhtNode::new("127.0.0.1:8214".parse().unwrap(), Some(config));
+
+        assert_eq!(
+            node.id,
+            NodeId::with_hash(node.identity().public_key().as_bytes(), IdHash::Sha256)
+        );
+        assert_eq!(
+            node.key_id(b"key"),
+            NodeId::with_hash(b"key", IdHash::Sha256)
+        );
+
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            node.find_value(b"key".to_vec()).await,
+            Some(Bytes::from_static(b"value"))
+        );
+    }
+
+    #[test]
+    fn test_parse_and_serde_forms() {
+        let id = NodeId::new(b"node");
+        let hex = id.to_string();
+        assert_eq!(hex.parse::<NodeId>().unwrap(), id);
+        assert_eq!(hex.to_uppercase().parse::<NodeId>().unwrap(), id);
+        assert_eq!(
+            "abc".parse::<NodeId>(),
+            Err(ParseNodeIdError::InvalidLength(3))
+        );
+        assert_eq!(
+            "zz".repeat(ID_LEN).parse::<NodeId>(),
+            Err(ParseNodeIdError::InvalidHex)
+        );
+
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", hex));
+        assert_eq!(serde_json::from_str::<NodeId>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<NodeId>("\"abc\"").is_err());
+
+        // The wire format is unchanged: the raw bytes
+        let bytes = bincode::serialize(&id).unwrap();
+        assert_eq!(bytes.len(), ID_LEN);
+        assert_eq!(bincode::deserialize::<NodeId>(&bytes).unwrap(), id);
+    }
 }
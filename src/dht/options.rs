@@ -0,0 +1,273 @@
+//! Per-call settings of a write.
+//!
+//! Keys of one application rarely share durability needs: a session token
+//! may live a minute on a couple of nodes, while an account record must
+//! reach most of its replicas before the write counts. [`StoreOptions`]
+//! overrides the node's configuration for one call to
+//! [`DhtNode::store_with_options`].
+//!
+//! Clients choose them too: a node receiving a
+//! [`DhtRpc::CoordinatedStore`] coordinates the write for the client,
+//! replicating it as its [`WriteRequest`] says, and answers with a
+//! [`WriteReport`] of the replicas that acknowledged it, see
+//! [`DhtNode::store_coordinated`].
+//!
+//! [`DhtRpc::CoordinatedStore`]: crate::dht::rpc::DhtRpc::CoordinatedStore
+
+use anyhow::{Result, ensure};
+use bytes::Bytes;
+
+pub use crate::dht::rpc::Consistency;
+use crate::dht::{
+    DhtNode,
+    rpc::{WriteReport, WriteRequest},
+};
+
+/// Overrides of the node's configuration for one write. Fields left unset
+/// fall back to it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_p2p_node::dht::{
+///     DhtNode,
+///     options::{Consistency, StoreOptions},
+/// };
+///
+/// # async fn run(node: DhtNode) -> anyhow::Result<()> {
+/// let options = StoreOptions {
+///     replication: Some(7),
+///     ttl: Some(24 * 3600),
+///     consistency: Consistency::Quorum,
+/// };
+/// node.store_with_options(b"account".to_vec(), b"...".to_vec(), options)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreOptions {
+    /// Number of peers the value is replicated on, instead of
+    /// `replication.factor`
+    pub replication: Option<usize>,
+    /// Seconds until the value expires, instead of `storage.default_ttl`
+    pub ttl: Option<u64>,
+    pub consistency: Consistency,
+}
+
+impl From<WriteRequest> for StoreOptions {
+    fn from(request: WriteRequest) -> Self {
+        Self {
+            replication: request.replication.map(|replication| replication as usize),
+            ttl: request.ttl,
+            consistency: request.consistency,
+        }
+    }
+}
+
+impl DhtNode {
+    /// Stores a key-value pair like [`DhtNode::store`], as `options` say.
+    ///
+    /// The write fails when fewer replicas than `options.consistency`
+    /// requires acknowledged it. It is not rolled back: the local copy and
+    /// the replicas that were written stay, and the outbox still retries
+    /// when no peer could be reached.
+    ///
+    /// The replication checker keeps at least `replication.factor` copies of
+    /// the values this node originated, so a smaller `options.replication`
+    /// only lightens the write itself. `options.replication` must be
+    /// between 1 and `kbucket_size`.
+    pub async fn store_with_options(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        options: StoreOptions,
+    ) -> Result<()> {
+        self.check_options(&options)?;
+        let token = self.own_token(&key, None);
+        self.store_with(key, value.into(), token, &options)
+            .await
+            .map(|_| ())
+    }
+
+    /// Stores a key-value pair as a client's `request` says, and reports
+    /// which replicas acknowledged it.
+    ///
+    /// The node coordinates the write like its own, see
+    /// [`DhtNode::store_with_options`]: it locates the replicas closest to
+    /// the key, sends them the value and waits for as many
+    /// acknowledgements as `request.consistency` requires.
+    ///
+    /// Without `request.token`, the write carries the token the node's
+    /// [`WriteAuthorizer`](crate::dht::authz::WriteAuthorizer) issues, as it
+    /// starts on this node. `request.replication` must be between 1 and
+    /// `kbucket_size`.
+    pub async fn store_coordinated(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        mut request: WriteRequest,
+    ) -> Result<WriteReport> {
+        request.token = self.own_token(&key, request.token);
+        self.coordinate_client_write(key, value.into(), request)
+            .await
+    }
+
+    /// Coordinates a write a client asked for with a `CoordinatedStore`,
+    /// carrying only the client's own token.
+    pub(super) async fn coordinate_client_write(
+        &self,
+        key: Vec<u8>,
+        value: Bytes,
+        mut request: WriteRequest,
+    ) -> Result<WriteReport> {
+        let token = request.token.take();
+        let options = request.into();
+        // Requests come from anyone, and their replication sizes allocations
+        self.check_options(&options)?;
+        self.store_with(key, value, token, &options).await
+    }
+
+    /// Fails unless `options.replication`, when set, is between 1 and
+    /// `kbucket_size`.
+    fn check_options(&self, options: &StoreOptions) -> Result<()> {
+        if let Some(replication) = options.replication {
+            let max = self.config.kbucket_size;
+            ensure!(
+                (1..=max).contains(&replication),
+                "Replication must be between 1 and {}, not {}",
+                max,
+                replication
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod options_tests {
+    use bytes::Bytes;
+
+    use super::{Consistency, StoreOptions};
+    use crate::{
+        dht::{
+            NodeId,
+            rpc::{DhtRpc, WriteRequest},
+            storage::{create_stored_value, serialize_value},
+        },
+        helpers::create_test_node,
+        testing::TestCluster,
+    };
+
+    #[test]
+    fn test_required_acknowledgements() {
+        assert_eq!(Consistency::Any.required(5), 0);
+        assert_eq!(Consistency::One.required(5), 1);
+        assert_eq!(Consistency::One.required(0), 0);
+        assert_eq!(Consistency::Quorum.required(5), 3);
+        assert_eq!(Consistency::Quorum.required(4), 3);
+        assert_eq!(Consistency::All.required(5), 5);
+    }
+
+    #[tokio::test]
+    async fn test_options_override_the_config() {
+        let cluster = TestCluster::new(3).await;
+        let (node, peer) = (cluster.node(0), cluster.node(1));
+        cluster.kill(2);
+
+        let options = StoreOptions {
+            ttl: Some(3600),
+            consistency: Consistency::One,
+            ..StoreOptions::default()
+        };
+        node.store_with_options(b"key".to_vec(), b"value".to_vec(), options)
+            .await
+            .unwrap();
+        let stored = peer.local_value(b"key").unwrap();
+        assert_eq!(stored.data, Bytes::from_static(b"value"));
+        assert_eq!(stored.expiration, Some(stored.version + 3600));
+
+        // Only one of the two peers can acknowledge
+        let all = StoreOptions {
+            consistency: Consistency::All,
+            ..StoreOptions::default()
+        };
+        let error = node
+            .store_with_options(b"strict".to_vec(), b"value".to_vec(), all.clone())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Only 1 of the 5"), "{}", error);
+        assert!(node.local_value(b"strict").is_some());
+
+        let narrow = StoreOptions {
+            replication: Some(1),
+            ..all
+        };
+        node.store_with_options(b"narrow".to_vec(), b"value".to_vec(), narrow)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_out_of_range_replication_is_refused() {
+        let node = create_test_node(8080);
+        for replication in [0, u32::MAX] {
+            let request = WriteRequest {
+                replication: Some(replication),
+                ..WriteRequest::default()
+            };
+            let response = node
+                .handle_rpc(DhtRpc::CoordinatedStore(
+                    b"key".to_vec(),
+                    Bytes::from_static(b"value"),
+                    request,
+                ))
+                .await;
+            assert!(matches!(
+                response,
+                DhtRpc::CoordinatedStoreResponse(Err(e)) if e.contains("Replication must be")
+            ));
+
+            let options = StoreOptions {
+                replication: Some(replication as usize),
+                ..StoreOptions::default()
+            };
+            let error = node
+                .store_with_options(b"key".to_vec(), b"value".to_vec(), options)
+                .await
+                .unwrap_err();
+            assert!(
+                error.to_string().contains("Replication must be"),
+                "{}",
+                error
+            );
+        }
+        assert!(node.local_value(b"key").is_none());
+        assert!(
+            node.find_closest_peers(&NodeId::new(b"key"), usize::MAX)
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refused_writes_are_not_acknowledged() {
+        let cluster = TestCluster::new(2).await;
+        let (node, peer) = (cluster.node(0), cluster.node(1));
+
+        // The peer holds a newer copy, and refuses the write
+        let newer = create_stored_value(b"newer".to_vec(), peer.addr, true, None, node.now() + 60);
+        peer.storage
+            .insert(b"key".to_vec(), serialize_value(&newer).unwrap());
+
+        let options = StoreOptions {
+            consistency: Consistency::One,
+            ..StoreOptions::default()
+        };
+        let error = node
+            .store_with_options(b"key".to_vec(), b"older".to_vec(), options)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Only 0 of the 1"), "{}", error);
+        assert_eq!(peer.local_value(b"key").unwrap().data, newer.data);
+    }
+}
@@ -0,0 +1,299 @@
+//! A node-wide limit on the RPCs in flight.
+//!
+//! The RPCs a node sends and the inbound requests it is answering share
+//! `overload.max_in_flight` slots. Maintenance traffic (pings, expiry
+//! notices, batches and gossip) is shed first: it only gets a slot while
+//! fewer than `overload.maintenance_percent` of them are taken, so lookups
+//! and stores keep the rest. Work over the limit is not queued: an outbound RPC fails
+//! with [`Busy`] and an inbound request is answered with [`DhtRpc::Busy`],
+//! which the caller sees as [`Busy`] too.
+//!
+//! A peer answering [`DhtRpc::Busy`] is alive, so health checks do not count
+//! it, nor a ping shed on this node, as a failure.
+//!
+//! The maintenance service and the replication checker run in a
+//! [`Priority::Maintenance`] scope, so every RPC they send, stores included,
+//! is maintenance traffic. Such RPCs also wait for a connection to a peer
+//! behind the user operations waiting for one, see
+//! [`ConnectionPool::get_connection_prioritized`].
+//!
+//! [`ConnectionPool::get_connection_prioritized`]: crate::dht::connection::ConnectionPool::get_connection_prioritized
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tracing::debug;
+
+use crate::dht::{DhtNode, config::OverloadConfig, rpc::DhtRpc};
+
+/// An RPC was shed because this node or its peer had too many in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy;
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Too many RPCs in flight")
+    }
+}
+
+impl std::error::Error for Busy {}
+
+/// Which work goes first when slots run out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Pings, expiry notices, batches and gossip; shed first
+    Maintenance,
+    /// Lookups, stores and client requests
+    Normal,
+}
+
+tokio::task_local! {
+    /// Set by [`Priority::scope`]
+    static SCOPE: Priority;
+}
+
+impl Priority {
+    /// The priority of `rpc`, sent or answered.
+    pub fn of(rpc: &DhtRpc) -> Self {
+        match rpc {
+            DhtRpc::Ping | DhtRpc::Expire(..) | DhtRpc::Batch(_) | DhtRpc::Gossip(..) => {
+                Self::Maintenance
+            }
+            _ => Self::Normal,
+        }
+    }
+
+    /// The priority of `rpc` in the current task: that of the enclosing
+    /// [`Priority::scope`], or else [`Priority::of`].
+    pub fn current(rpc: &DhtRpc) -> Self {
+        SCOPE
+            .try_with(|priority| *priority)
+            .unwrap_or_else(|_| Self::of(rpc))
+    }
+
+    /// Runs `task` with every RPC it sends at this priority. Tasks it spawns
+    /// are not affected.
+    pub async fn scope<F: Future>(self, task: F) -> F::Output {
+        SCOPE.scope(self, task).await
+    }
+}
+
+/// Counts the RPCs in flight against [`OverloadConfig`].
+#[derive(Debug)]
+pub struct InFlightLimiter {
+    max: usize,
+    max_maintenance: usize,
+    in_flight: AtomicUsize,
+}
+
+/// A slot taken by [`InFlightLimiter::try_acquire`], given back when dropped.
+#[derive(Debug)]
+pub struct InFlightPermit<'a> {
+    limiter: &'a InFlightLimiter,
+}
+
+impl InFlightLimiter {
+    pub fn new(config: &OverloadConfig) -> Self {
+        let (max, max_maintenance) = match config.max_in_flight {
+            0 => (usize::MAX, usize::MAX),
+            max => {
+                let percent = usize::from(config.maintenance_percent.min(100));
+                (max, max.saturating_mul(percent) / 100)
+            }
+        };
+        Self {
+            max,
+            max_maintenance,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a slot for work of `priority`, unless the slots it may use are
+    /// all taken.
+    pub fn try_acquire(&self, priority: Priority) -> Option<InFlightPermit<'_>> {
+        let limit = match priority {
+            Priority::Maintenance => self.max_maintenance,
+            Priority::Normal => self.max,
+        };
+        self.in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |taken| {
+                (taken < limit).then_some(taken + 1)
+            })
+            .ok()
+            .map(|_| InFlightPermit { limiter: self })
+    }
+
+    /// Number of slots taken.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for InFlightPermit<'_> {
+    fn drop(&mut self) {
+        self.limiter.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl DhtNode {
+    /// Takes an in-flight slot for sending or answering `rpc` at its
+    /// [current](Priority::current) priority, counting it as shed when there
+    /// is none.
+    pub(super) fn admit_rpc(&self, rpc: &DhtRpc) -> Option<InFlightPermit<'_>> {
+        let permit = self.in_flight.try_acquire(Priority::current(rpc));
+        if permit.is_none() {
+            debug!(rpc = rpc.name(), "shedding RPC over the in-flight limit");
+            self.metrics.inc_rpcs_shed();
+        }
+        permit
+    }
+
+    /// Answers an inbound request like [`DhtNode::handle_rpc`], or with
+    /// [`DhtRpc::Busy`] when it is shed.
+    pub(super) async fn handle_rpc_admitted(&self, rpc: DhtRpc) -> DhtRpc {
+        let Some(_permit) = self.admit_rpc(&rpc) else {
+            return DhtRpc::Busy;
+        };
+        self.handle_rpc(rpc).await
+    }
+
+    /// Number of RPCs this node is sending or answering.
+    pub fn rpcs_in_flight(&self) -> usize {
+        self.in_flight.in_flight()
+    }
+}
+
+#[cfg(test)]
+mod overload_tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        dht::{
+            DhtNode, NodeId, PeerInfo,
+            config::OverloadConfig,
+            overload::{Busy, InFlightLimiter, Priority},
+            rpc::DhtRpc,
+        },
+        helpers::test_config,
+    };
+
+    fn limited_node(port: u16, max_in_flight: usize) -> DhtNode {
+        let mut config = test_config();
+        config.overload = OverloadConfig {
+            max_in_flight,
+            maintenance_percent: 50,
+        };
+        DhtNode::new(SocketAddr::from(([127, 0, 0, 1], port)), Some(config))
+    }
+
+    #[test]
+    fn test_maintenance_is_shed_first() {
+        let limiter = InFlightLimiter::new(&OverloadConfig {
+            max_in_flight: 4,
+            maintenance_percent: 50,
+        });
+
+        let first = limiter.try_acquire(Priority::Maintenance).unwrap();
+        let _second = limiter.try_acquire(Priority::Maintenance).unwrap();
+        assert!(limiter.try_acquire(Priority::Maintenance).is_none());
+
+        let _normal = limiter.try_acquire(Priority::Normal).unwrap();
+        let _last = limiter.try_acquire(Priority::Normal).unwrap();
+        assert!(limiter.try_acquire(Priority::Normal).is_none());
+        assert_eq!(limiter.in_flight(), 4);
+
+        drop(first);
+        assert!(limiter.try_acquire(Priority::Maintenance).is_none());
+        assert!(limiter.try_acquire(Priority::Normal).is_some());
+
+        let unlimited = InFlightLimiter::new(&OverloadConfig {
+            max_in_flight: 0,
+            maintenance_percent: 75,
+        });
+        let permits: Vec<_> = (0..1000)
+            .map(|_| unlimited.try_acquire(Priority::Maintenance).unwrap())
+            .collect();
+        assert_eq!(unlimited.in_flight(), permits.len());
+    }
+
+    #[tokio::test]
+    async fn test_rpcs_over_the_limit_are_busy() {
+        let node = limited_node(8080, 2);
+        let _held = node.admit_rpc(&DhtRpc::FindValue(b"key".to_vec())).unwrap();
+
+        // One slot left, which maintenance may not take
+        let error = node
+            .send_rpc("127.0.0.1:1".parse().unwrap(), DhtRpc::Ping)
+            .await
+            .unwrap_err();
+        assert_eq!(error.downcast_ref::<Busy>(), Some(&Busy));
+        assert!(matches!(
+            node.handle_rpc_admitted(DhtRpc::Ping).await,
+            DhtRpc::Busy
+        ));
+        assert!(matches!(
+            node.handle_rpc_admitted(DhtRpc::FindValue(b"key".to_vec()))
+                .await,
+            DhtRpc::FindValueResponse(None)
+        ));
+        assert_eq!(node.get_stats().rpcs_shed, 2);
+        assert_eq!(node.rpcs_in_flight(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_background_stores_are_maintenance() {
+        let store = DhtRpc::Store(b"key".to_vec(), b"value".to_vec().into());
+        assert_eq!(Priority::current(&store), Priority::Normal);
+        assert_eq!(Priority::current(&DhtRpc::Ping), Priority::Maintenance);
+
+        let node = limited_node(8080, 2);
+        let _held = node.admit_rpc(&DhtRpc::GetStats).unwrap();
+        let error = Priority::Maintenance
+            .scope(async {
+                assert_eq!(Priority::current(&store), Priority::Maintenance);
+                node.send_rpc("127.0.0.1:1".parse().unwrap(), store.clone())
+                    .await
+                    .unwrap_err()
+            })
+            .await;
+        assert_eq!(error.downcast_ref::<Busy>(), Some(&Busy));
+
+        // The same store on behalf of a user still gets the last slot
+        let error = node
+            .send_rpc("127.0.0.1:1".parse().unwrap(), store)
+            .await
+            .unwrap_err();
+        assert!(!error.is::<Busy>());
+    }
+
+    #[tokio::test]
+    async fn test_busy_peers_are_not_evicted() {
+        let busy = limited_node(8230, 1);
+        let server = busy.listen().await.unwrap();
+        let _held = busy.admit_rpc(&DhtRpc::GetStats).unwrap();
+
+        let node = limited_node(8080, 8);
+        node.add_peer(PeerInfo::new(NodeId::new(b"busy"), busy.addr));
+        let error = node.send_rpc(busy.addr, DhtRpc::Ping).await.unwrap_err();
+        assert_eq!(error.downcast_ref::<Busy>(), Some(&Busy));
+
+        node.check_peers_health().await;
+        assert_eq!(node.peer_count(), 1);
+
+        // A peer that is really gone still is
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gone = listener.local_addr().unwrap();
+        drop(listener);
+        node.add_peer(PeerInfo::new(NodeId::new(b"gone"), gone));
+        for _ in 0..node.config.health_check.max_failures {
+            node.check_peers_health().await;
+        }
+        assert_eq!(node.peer_count(), 1);
+
+        server.abort();
+    }
+}
@@ -1,28 +1,96 @@
 use std::net::SocketAddr;
 
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
-use crate::{dht::node::NodeId, helpers::now};
+use crate::dht::node::{IdHash, NodeId};
+#[cfg(feature = "node")]
+use crate::helpers::now;
 
 /// Information about a peer in the DHT network.
 ///
-/// Contains the peer's indentifier, network address, and last contact time.
+/// Contains the peer's indentifier, network addresses, and last contact time.
+///
+/// A peer announces itself signed with its identity key, so that nodes
+/// relaying it cannot alter it, see [`PeerInfo::verify`].
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PeerInfo {
-    /// The node's unique identifier (typically a hash of its address/public key)
+    /// The node's unique identifier, the hash of its public key
     pub id: NodeId,
     /// Network address where the peer can be reached
     pub addr: SocketAddr,
+    /// Further addresses of the peer, typically in the other IP family
+    #[serde(default)]
+    pub alt_addrs: Vec<SocketAddr>,
+    /// Whether the peer keeps a copy of the whole keyspace, see
+    /// [`mirror`](crate::dht::mirror)
+    #[serde(default)]
+    pub mirror: bool,
     /// Unix timestamp of last successful communication
     pub last_seen: u64,
+    /// Key the peer signs its announcements with
+    #[serde(default)]
+    pub public_key: Option<VerifyingKey>,
+    /// Signature of the announcement by `public_key`
+    #[serde(default)]
+    pub signature: Option<Signature>,
 }
 
 impl PeerInfo {
+    /// Creates a peer last seen now.
+    #[cfg(feature = "node")]
     pub fn new(id: NodeId, addr: SocketAddr) -> Self {
         Self {
             id,
             addr,
+            alt_addrs: Vec::new(),
+            mirror: false,
             last_seen: now(),
+            public_key: None,
+            signature: None,
+        }
+    }
+
+    /// Advertises further addresses the peer can be reached at.
+    ///
+    /// This invalidates the signature; the peer has to sign again.
+    pub fn with_alt_addrs(mut self, alt_addrs: Vec<SocketAddr>) -> Self {
+        self.alt_addrs = alt_addrs;
+        self
+    }
+
+    /// Advertises the peer as a mirror of the whole keyspace.
+    ///
+    /// This invalidates the signature; the peer has to sign again.
+    pub fn as_mirror(mut self) -> Self {
+        self.mirror = true;
+        self
+    }
+
+    /// The bytes a peer signs: its id, addresses and whether it is a mirror.
+    /// `last_seen` is left out, as every node keeps its own.
+    pub(crate) fn signed_bytes(&self) -> Vec<u8> {
+        let announcement = (b"peer-info/1", &self.id, self.addr, &self.alt_addrs);
+        let mut bytes =
+            bincode::serialize(&announcement).expect("announcements are always serializable");
+        // Only appended when set, so that announcements signed before mirrors
+        // existed still verify
+        if self.mirror {
+            bytes.extend_from_slice(b"/mirror");
         }
+        bytes
+    }
+
+    /// Checks that the peer signed this announcement with the key its id is
+    /// derived from, using the network's `hash`.
+    ///
+    /// Unsigned announcements fail, as do those whose id or addresses were
+    /// altered after signing.
+    pub fn verify(&self, hash: IdHash) -> bool {
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return false;
+        };
+        NodeId::with_hash(public_key.as_bytes(), hash) == self.id
+            && public_key.verify(&self.signed_bytes(), signature).is_ok()
     }
 }
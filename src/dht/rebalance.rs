@@ -0,0 +1,330 @@
+//! Moving values to the nodes now responsible for them.
+//!
+//! Which nodes hold a key follows from `replication.factor` and the routing
+//! table, so raising the factor, or nodes joining, leaves values on nodes no
+//! longer among their closest and missing from the ones that now are.
+//! Regular replication only repairs the keys this node originated, a sample
+//! at a time. [`DhtNode::rebalance`] goes over every value held locally:
+//!
+//! - the closest peers for its key that lack it, or hold an older version,
+//!   are sent a copy;
+//! - a replica this node is no longer among the closest nodes for is
+//!   dropped, once every one of those peers holds it. Peers that kept a
+//!   pushed copy are asked again before that, in case they evicted it
+//!   meanwhile.
+//!
+//! Values this node originated are kept, as are transient replicas, which
+//! hinted hand-off moves, and everything on a mirror. Keys are handled a few
+//! hundred at a time, with a [`DhtEvent::RebalanceProgress`] after each
+//! round.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use serde::Serialize;
+use tracing::{debug, info};
+
+use crate::{
+    dht::{DhtNode, events::DhtEvent, rpc::DhtRpc, storage::decode_header},
+    helpers::key_hash,
+};
+
+/// Keys checked per round, each round costing a batch or two per peer
+const REBALANCE_ROUND: usize = 256;
+
+/// Outcome of [`DhtNode::rebalance`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RebalanceReport {
+    /// Values held locally that were checked
+    pub checked: usize,
+    /// Copies sent to peers that lacked them, and that they kept
+    pub pushed: usize,
+    /// Replicas dropped from local storage
+    pub dropped: usize,
+}
+
+/// Placement of one local value.
+struct Placement {
+    key: Vec<u8>,
+    /// Closest peers for the key, besides this node
+    peers: Vec<SocketAddr>,
+    /// Version of this node's copy
+    version: u64,
+    /// Whether this node's copy is a replica it is no longer responsible for
+    surplus: bool,
+}
+
+impl DhtNode {
+    /// Re-evaluates where every valid value held locally belongs, after the
+    /// replication factor or the set of nodes changed.
+    ///
+    /// Best run once the routing table has settled: a node that does not
+    /// know its closest peers yet would push copies to, and hand its
+    /// replicas over to, the wrong ones.
+    pub async fn rebalance(&self) -> RebalanceReport {
+        let current_time = self.now();
+        let keys: Vec<_> = self
+            .storage
+            .iter()
+            .filter(|(_, value)| {
+                decode_header(value).is_ok_and(|h| !h.is_hinted && h.is_valid(current_time))
+            })
+            .map(|(key, _)| key)
+            .collect();
+
+        let mut report = RebalanceReport::default();
+        for round in keys.chunks(REBALANCE_ROUND) {
+            self.rebalance_round(round, &mut report).await;
+            report.checked += round.len();
+            self.emit(|| DhtEvent::RebalanceProgress {
+                report,
+                total: keys.len(),
+            });
+        }
+
+        info!(
+            checked = report.checked,
+            pushed = report.pushed,
+            dropped = report.dropped,
+            "storage rebalanced"
+        );
+        report
+    }
+
+    async fn rebalance_round(&self, keys: &[Vec<u8>], report: &mut RebalanceReport) {
+        let factor = self.config.replication.factor;
+        let placements: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                let key_id = self.key_id(key);
+                let closest = self.find_closest_peers(&key_id, factor);
+                let responsible = closest.len() < factor
+                    || closest.last().is_none_or(|farthest| {
+                        key_id.distance(&self.id) < key_id.distance(&farthest.id)
+                    });
+                let header = self
+                    .storage
+                    .get(key)
+                    .and_then(|value| decode_header(&value).ok());
+                let replica = header.is_some_and(|h| h.is_replica);
+                Placement {
+                    key: key.clone(),
+                    peers: closest
+                        .into_iter()
+                        .map(|peer| peer.addr)
+                        .filter(|addr| *addr != self.addr)
+                        .collect(),
+                    version: header.map_or(0, |h| h.version),
+                    surplus: !responsible && replica && !self.config.mirror,
+                }
+            })
+            .collect();
+
+        // Ask each peer about all the keys it should hold at once
+        let mut asked: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        for (index, placement) in placements.iter().enumerate() {
+            for peer in &placement.peers {
+                asked.entry(*peer).or_default().push(index);
+            }
+        }
+        let find_values = |asked: &HashMap<SocketAddr, Vec<usize>>| {
+            asked
+                .iter()
+                .filter(|(_, indices)| !indices.is_empty())
+                .map(|(peer, indices)| {
+                    let requests = indices
+                        .iter()
+                        .map(|&index| DhtRpc::FindValue(placements[index].key.clone()))
+                        .collect();
+                    (*peer, requests)
+                })
+                .collect()
+        };
+
+        let mut holders = vec![0; placements.len()];
+        let mut pushes: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        let mut stores: HashMap<SocketAddr, Vec<DhtRpc>> = HashMap::new();
+        for (peer, responses) in self.send_batches(find_values(&asked)).await {
+            let Ok(responses) = responses else {
+                continue;
+            };
+            for (&index, response) in asked[&peer].iter().zip(responses) {
+                match response {
+                    response if holds_version(&response, placements[index].version) => {
+                        holders[index] += 1
+                    }
+                    DhtRpc::FindValueResponse(_) => {
+                        // Read again, the value may have changed while peers
+                        // were asked
+                        let key = &placements[index].key;
+                        if let Some(value) = self.storage.get(key) {
+                            pushes.entry(peer).or_default().push(index);
+                            stores
+                                .entry(peer)
+                                .or_default()
+                                .push(DhtRpc::Store(key.clone(), value));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Only copies acknowledged and then found on the peer count as held
+        let mut acknowledged: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        for (peer, responses) in self.send_batches(stores).await {
+            match responses {
+                Ok(responses) => {
+                    acknowledged.entry(peer).or_default().extend(
+                        pushes[&peer]
+                            .iter()
+                            .zip(&responses)
+                            .filter(|(_, response)| matches!(response, DhtRpc::StoreResponse(true)))
+                            .map(|(&index, _)| index),
+                    );
+                }
+                Err(e) => debug!(%peer, error = %e, "failed to push copies while rebalancing"),
+            }
+        }
+        for (peer, responses) in self.send_batches(find_values(&acknowledged)).await {
+            let Ok(responses) = responses else {
+                continue;
+            };
+            for (&index, response) in acknowledged[&peer].iter().zip(&responses) {
+                if holds_version(response, placements[index].version) {
+                    holders[index] += 1;
+                    report.pushed += 1;
+                }
+            }
+        }
+
+        for (index, placement) in placements.iter().enumerate() {
+            if !placement.surplus
+                || placement.peers.is_empty()
+                || holders[index] < placement.peers.len()
+            {
+                continue;
+            }
+            let key = &placement.key;
+            // A replica written again since is left to the next rebalance
+            if self.storage.remove_if(key, &|value| {
+                decode_header(value).is_ok_and(|h| h.is_replica)
+            }) {
+                debug!(key = %key_hash(key), "dropped surplus replica");
+                self.emit(|| DhtEvent::ValueRemoved { key: key.clone() });
+                report.dropped += 1;
+            }
+        }
+    }
+}
+
+/// Whether `response` to a `FindValue` holds a copy at least as recent as
+/// `version`.
+fn holds_version(response: &DhtRpc, version: u64) -> bool {
+    matches!(
+        response,
+        DhtRpc::FindValueResponse(Some(value))
+            if decode_header(value).is_ok_and(|h| h.version >= version)
+    )
+}
+
+#[cfg(test)]
+mod rebalance_tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use crate::{
+        dht::{
+            DhtNode,
+            events::DhtEvent,
+            storage::{create_stored_value, serialize_value},
+        },
+        helpers::test_config,
+        testing::TestCluster,
+    };
+
+    /// Two nodes that keep one copy of each value.
+    async fn pair() -> TestCluster {
+        let mut config = test_config();
+        config.replication.factor = 1;
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        TestCluster::with_config(2, config).await
+    }
+
+    /// Keys `peer` is closer to than `node`.
+    fn keys_closer_to<'a>(node: &'a DhtNode, peer: &'a DhtNode) -> impl Iterator<Item = Vec<u8>> {
+        (0..)
+            .map(|i| format!("key-{}", i).into_bytes())
+            .filter(move |key| {
+                let key_id = node.key_id(key);
+                key_id.distance(&peer.id) < key_id.distance(&node.id)
+            })
+    }
+
+    /// Stores `key` on `node` directly, with the given version.
+    fn insert(node: &DhtNode, key: &[u8], is_replica: bool, version: u64) {
+        let mut value = create_stored_value(
+            b"value".to_vec(),
+            node.addr,
+            is_replica,
+            Some(60),
+            node.now(),
+        );
+        value.version = version;
+        node.storage
+            .insert(key.to_vec(), serialize_value(&value).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_rebalance_moves_values_to_responsible_peers() {
+        let cluster = pair().await;
+        let (node, peer) = (cluster.node(0), cluster.node(1));
+
+        // Keys the peer is closer to than this node, held here as replicas
+        // and as values this node wrote
+        let mut keys = keys_closer_to(node, peer);
+        let (replica, original) = (keys.next().unwrap(), keys.next().unwrap());
+        insert(node, &replica, true, node.now());
+        insert(node, &original, false, node.now());
+
+        let mut events = Box::pin(node.subscribe());
+        let report = node.rebalance().await;
+        assert_eq!((report.checked, report.pushed, report.dropped), (2, 2, 1));
+        assert!(node.local_value(&replica).is_none());
+        assert!(node.local_value(&original).is_some());
+        assert!(peer.local_value(&replica).is_some());
+        assert!(peer.local_value(&original).is_some());
+
+        assert_eq!(
+            events.next().await,
+            Some(DhtEvent::ValueRemoved {
+                key: replica.clone()
+            })
+        );
+        assert_eq!(
+            events.next().await,
+            Some(DhtEvent::RebalanceProgress { report, total: 2 })
+        );
+
+        // Nothing left to move
+        let report = node.rebalance().await;
+        assert_eq!((report.checked, report.pushed, report.dropped), (1, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_older_copies_are_replaced_before_dropping() {
+        let cluster = pair().await;
+        let (node, peer) = (cluster.node(0), cluster.node(1));
+
+        let key = keys_closer_to(node, peer).next().unwrap();
+        let version = node.now();
+        insert(node, &key, true, version);
+        insert(peer, &key, true, version - 10);
+
+        // The peer's stale copy does not count as holding the value
+        let report = node.rebalance().await;
+        assert_eq!((report.checked, report.pushed, report.dropped), (1, 1, 1));
+        assert!(node.local_value(&key).is_none());
+        assert_eq!(peer.local_value(&key).unwrap().version, version);
+    }
+}
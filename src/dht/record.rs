@@ -0,0 +1,374 @@
+//! Mutable records owned by their publisher.
+//!
+//! A record lives under a key naming its publisher's public key, see
+//! [`record_key`]. Every version carries a sequence number and the
+//! publisher's signature over the key, the data and that number. Nodes
+//! only store a record whose signature checks out and whose sequence
+//! number is higher than that of the copy they hold, so that no other node
+//! can overwrite it, nor roll it back to an older version.
+//!
+//! Records are written with [`DhtNode::publish_record`] and read like any
+//! other value; lookups ignore copies that fail verification. Nodes drop
+//! their copy of a record on an `Expire` notice only once that copy has
+//! expired, or when the publisher signed the notice.
+
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::dht::{
+    DhtNode,
+    storage::{StoredValue, create_stored_value, decode_header, deserialize_value},
+};
+
+/// Prefix of the keys of signed records.
+pub const RECORD_KEY_PREFIX: &[u8] = b"record:";
+
+/// Sequence number and signature of one version of a record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordSignature {
+    pub sequence: u64,
+    pub signature: Signature,
+}
+
+/// Key of the record `name` published by the owner of `public_key`:
+/// `record:<public key as hex>/<name>`.
+pub fn record_key(public_key: &VerifyingKey, name: &[u8]) -> Vec<u8> {
+    let mut key = RECORD_KEY_PREFIX.to_vec();
+    key.extend_from_slice(hex::encode(public_key.as_bytes()).as_bytes());
+    key.push(b'/');
+    key.extend_from_slice(name);
+    key
+}
+
+/// The publisher `key` belongs to: `None` for ordinary keys, an error for
+/// record keys naming no valid public key.
+pub fn record_owner(key: &[u8]) -> Option<Result<VerifyingKey>> {
+    let rest = key.strip_prefix(RECORD_KEY_PREFIX)?;
+    Some(parse_owner(rest))
+}
+
+fn parse_owner(rest: &[u8]) -> Result<VerifyingKey> {
+    let hex_key = rest
+        .get(..64)
+        .filter(|_| rest.get(64) == Some(&b'/'))
+        .ok_or_else(|| anyhow!("Record key does not name a public key"))?;
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(hex_key, &mut bytes)?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+fn signed_bytes(key: &[u8], data: &[u8], sequence: u64) -> Vec<u8> {
+    bincode::serialize(&(b"record/1", key, data, sequence))
+        .expect("records are always serializable")
+}
+
+fn expiry_bytes(key: &[u8], version: u64) -> Vec<u8> {
+    bincode::serialize(&(b"expire/1", key, version)).expect("notices are always serializable")
+}
+
+/// Checks that `stored` may be held under `key`: values of record keys
+/// must be signed by the key's owner. Other keys accept any value.
+pub fn verify_record(key: &[u8], stored: &StoredValue) -> Result<()> {
+    let Some(owner) = record_owner(key) else {
+        return Ok(());
+    };
+    let record = stored
+        .record
+        .as_ref()
+        .ok_or_else(|| anyhow!("Record is not signed"))?;
+    owner?
+        .verify(
+            &signed_bytes(key, &stored.data, record.sequence),
+            &record.signature,
+        )
+        .map_err(|_| anyhow!("Record signature does not match its key"))
+}
+
+impl DhtNode {
+    /// Publishes `data` as version `sequence` of the record `name` owned by
+    /// this node's identity, and returns the record's key.
+    ///
+    /// `sequence` must be higher than that of every earlier version, or
+    /// nodes holding one keep it.
+    pub async fn publish_record(
+        &self,
+        name: &[u8],
+        data: impl Into<Bytes>,
+        sequence: u64,
+    ) -> Result<Vec<u8>> {
+        let identity = self.identity();
+        let key = record_key(&identity.public_key(), name);
+
+        if let Some(current) = self.local_sequence(&key)
+            && current >= sequence
+        {
+            bail!("Sequence {} is not above the current {}", sequence, current);
+        }
+
+        let mut stored = create_stored_value(
+            data.into(),
+            self.addr,
+            false,
+            Some(self.jittered_ttl(self.config.storage.default_ttl)),
+            self.now(),
+        );
+        stored.token = self.write_authorizer.issue(&key);
+        stored.record = Some(RecordSignature {
+            sequence,
+            signature: identity.sign(&signed_bytes(&key, &stored.data, sequence)),
+        });
+        self.store_value(key.clone(), stored).await?;
+        Ok(key)
+    }
+
+    /// Sequence number of the local copy of the record at `key`.
+    pub(super) fn local_sequence(&self, key: &[u8]) -> Option<u64> {
+        let stored = deserialize_value(&self.storage.get(key)?).ok()?;
+        stored.record.map(|record| record.sequence)
+    }
+
+    /// The signature of an `Expire` notice for versions up to `version` of
+    /// `key`, if it names a record owned by this node's identity.
+    pub(super) fn sign_expiry(&self, key: &[u8], version: u64) -> Option<Signature> {
+        let owner = record_owner(key)?.ok()?;
+        let identity = self.identity();
+        (owner == identity.public_key()).then(|| identity.sign(&expiry_bytes(key, version)))
+    }
+
+    /// Whether an `Expire` notice may drop the local copy of `key`. Any may
+    /// drop that of an ordinary key, or of a record that already expired;
+    /// other records only go on a notice signed by their owner.
+    pub(super) fn may_expire(
+        &self,
+        key: &[u8],
+        version: u64,
+        signature: Option<&Signature>,
+    ) -> bool {
+        let Some(owner) = record_owner(key) else {
+            return true;
+        };
+        let signed = owner.is_ok_and(|owner| {
+            signature.is_some_and(|signature| {
+                owner.verify(&expiry_bytes(key, version), signature).is_ok()
+            })
+        });
+        signed
+            || self
+                .storage
+                .get(key)
+                .is_none_or(|value| !decode_header(&value).is_ok_and(|h| h.is_valid(self.now())))
+    }
+
+    /// Checks an incoming copy of the value at `key`, see [`verify_record`],
+    /// and that it is newer than the local copy of a record.
+    pub(super) fn accept_record(&self, key: &[u8], incoming: &StoredValue) -> Result<()> {
+        verify_record(key, incoming)?;
+        if let (Some(record), Some(current)) = (&incoming.record, self.local_sequence(key))
+            && record.sequence < current
+        {
+            bail!(
+                "Sequence {} is older than the current {}",
+                record.sequence,
+                current
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod record_tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use crate::{
+        dht::{
+            clock::ManualClock,
+            identity::Identity,
+            record::{expiry_bytes, record_key, record_owner, verify_record},
+            rpc::DhtRpc,
+            storage::{deserialize_value, serialize_value},
+        },
+        helpers::create_test_node,
+    };
+
+    #[test]
+    fn test_record_keys_name_their_owner() {
+        let identity = Identity::from_secret_bytes(&[1; 32]);
+        let key = record_key(&identity.public_key(), b"profile");
+
+        assert!(key.starts_with(b"record:"));
+        assert!(key.ends_with(b"/profile"));
+        assert_eq!(record_owner(&key).unwrap().unwrap(), identity.public_key());
+        assert!(record_owner(b"plain key").is_none());
+        assert!(record_owner(b"record:not-a-key/profile").unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_records_only_move_forward() {
+        let clock = ManualClock::new(1_000);
+        let publisher = create_test_node(8080).with_clock(clock.clone());
+        let holder = create_test_node(8081).with_clock(clock.clone());
+        let key = publisher
+            .publish_record(b"profile", b"v2".to_vec(), 2)
+            .await
+            .unwrap();
+        let version_2 = publisher.storage.get(&key).unwrap();
+        assert!(verify_record(&key, &deserialize_value(&version_2).unwrap()).is_ok());
+
+        holder
+            .handle_rpc(DhtRpc::Store(key.clone(), version_2.clone()))
+            .await;
+        assert_eq!(holder.local_sequence(&key), Some(2));
+
+        // Sequence numbers must keep growing
+        assert!(
+            publisher
+                .publish_record(b"profile", b"v1".to_vec(), 1)
+                .await
+                .is_err()
+        );
+        holder.storage.remove(&key);
+        publisher.storage.remove(&key);
+        publisher
+            .publish_record(b"profile", b"v1".to_vec(), 1)
+            .await
+            .unwrap();
+        let version_1 = publisher.storage.get(&key).unwrap();
+        holder
+            .handle_rpc(DhtRpc::Store(key.clone(), version_2))
+            .await;
+        holder
+            .handle_rpc(DhtRpc::Store(key.clone(), version_1))
+            .await;
+        assert_eq!(holder.local_sequence(&key), Some(2));
+
+        publisher
+            .publish_record(b"profile", b"v3".to_vec(), 3)
+            .await
+            .unwrap();
+        let version_3 = publisher.storage.get(&key).unwrap();
+        holder
+            .handle_rpc(DhtRpc::Store(key.clone(), version_3))
+            .await;
+        assert_eq!(holder.local_sequence(&key), Some(3));
+        assert_eq!(
+            holder.find_value(key).await,
+            Some(Bytes::from_static(b"v3"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_other_nodes_cannot_overwrite_records() {
+        let clock = ManualClock::new(1_000);
+        let publisher = create_test_node(8080).with_clock(clock.clone());
+        let holder = create_test_node(8081).with_clock(clock.clone());
+        let key = publisher
+            .publish_record(b"profile", b"genuine".to_vec(), 1)
+            .await
+            .unwrap();
+        holder
+            .handle_rpc(DhtRpc::Store(
+                key.clone(),
+                publisher.storage.get(&key).unwrap(),
+            ))
+            .await;
+
+        // A tampered copy with a higher sequence number
+        let mut forged = deserialize_value(&publisher.storage.get(&key).unwrap()).unwrap();
+        forged.data = Bytes::from_static(b"forged");
+        forged.record.as_mut().unwrap().sequence = 9;
+        holder
+            .handle_rpc(DhtRpc::Store(
+                key.clone(),
+                serialize_value(&forged).unwrap(),
+            ))
+            .await;
+
+        // An unsigned one
+        forged.record = None;
+        holder
+            .handle_rpc(DhtRpc::Store(
+                key.clone(),
+                serialize_value(&forged).unwrap(),
+            ))
+            .await;
+
+        // One signed by another node under its own key
+        let other = create_test_node(8082);
+        let other_key = other
+            .publish_record(b"profile", b"forged".to_vec(), 9)
+            .await
+            .unwrap();
+        holder
+            .handle_rpc(DhtRpc::Store(
+                key.clone(),
+                other.storage.get(&other_key).unwrap(),
+            ))
+            .await;
+
+        assert_eq!(holder.local_sequence(&key), Some(1));
+        assert_eq!(
+            holder.find_value(key.clone()).await,
+            Some(Bytes::from_static(b"genuine"))
+        );
+        assert_eq!(holder.get_stats().rpc_failures, 3);
+
+        assert!(holder.store(key, b"forged".to_vec()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_only_owners_expire_live_records() {
+        let clock = ManualClock::new(1_000);
+        let publisher = create_test_node(8080).with_clock(clock.clone());
+        let holder = create_test_node(8081).with_clock(clock.clone());
+        let key = publisher
+            .publish_record(b"profile", b"genuine".to_vec(), 1)
+            .await
+            .unwrap();
+        let stored = publisher.storage.get(&key).unwrap();
+        let version = deserialize_value(&stored).unwrap().version;
+        holder
+            .handle_rpc(DhtRpc::Store(key.clone(), stored.clone()))
+            .await;
+
+        // Another node's notice, unsigned or signed with its own key
+        let other = create_test_node(8082);
+        holder
+            .handle_rpc(DhtRpc::Expire(key.clone(), u64::MAX, None, None))
+            .await;
+        holder
+            .handle_rpc(DhtRpc::Expire(
+                key.clone(),
+                u64::MAX,
+                None,
+                Some(other.identity().sign(&expiry_bytes(&key, u64::MAX))),
+            ))
+            .await;
+        assert!(holder.storage.get(&key).is_some());
+        assert!(other.sign_expiry(&key, version).is_none());
+
+        holder
+            .handle_rpc(DhtRpc::Expire(
+                key.clone(),
+                version,
+                None,
+                publisher.sign_expiry(&key, version),
+            ))
+            .await;
+        assert!(holder.storage.get(&key).is_none());
+
+        // Anyone may drop a copy that has expired anyway
+        holder.storage.insert(key.clone(), stored);
+        clock.advance(Duration::from_secs(
+            publisher.config.storage.default_ttl * 2,
+        ));
+        holder
+            .handle_rpc(DhtRpc::Expire(key.clone(), u64::MAX, None, None))
+            .await;
+        assert!(holder.storage.get(&key).is_none());
+    }
+}
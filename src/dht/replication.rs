@@ -1,26 +1,469 @@
-use crate::dht::{DhtNode, peer::PeerInfo, rpc::utils::send_store_rpc};
+use std::{collections::HashMap, net::SocketAddr};
+
+use bytes::Bytes;
+use futures::{StreamExt, stream};
+use rand::seq::IteratorRandom;
+use tokio::task::JoinHandle;
+use tracing::{Span, debug, info, instrument, warn};
+
+use crate::{
+    dht::{
+        DhtNode,
+        overload::Priority,
+        rpc::{
+            DhtRpc,
+            utils::{send_batch_rpc, send_store_rpc},
+        },
+        storage::{decode_header, deserialize_value, serialize_value},
+        tasks,
+    },
+    helpers::key_hash,
+};
 
 impl DhtNode {
-    pub async fn replicate_to_peers_store(
-        &self,
-        key: Vec<u8>,
-        value: Vec<u8>,
-        peers: Vec<PeerInfo>,
-    ) -> usize {
+    /// Replicates a serialized value to the k nodes closest to the key.
+    ///
+    /// Targets are selected by XOR distance between the peer ids and the key
+    /// hash, the same metric used by lookups, so reads and writes agree on
+    /// where the data lives. Up to `replication.parallelism` stores are in
+    /// flight at once.
+    ///
+    /// Writes are sloppy: when one of the k closest nodes cannot be reached,
+    /// the value goes to the next-closest node instead, as a transient replica
+    /// hinted for the unreachable one (see [`DhtNode::hand_off_hinted_values`]).
+    /// Returns the number of peers that kept the value; those that refused
+    /// it are not replaced.
+    pub async fn replicate_to_peers_store(&self, key: Vec<u8>, value: Bytes) -> usize {
+        self.replicate_to(key, value, self.config.replication.factor)
+            .await
+    }
+
+    /// Replicates `value` like [`DhtNode::replicate_to_peers_store`], on
+    /// `factor` peers.
+    #[instrument(name = "replicate", skip_all, fields(key = %key_hash(&key), replicas))]
+    pub(super) async fn replicate_to(&self, key: Vec<u8>, value: Bytes, factor: usize) -> usize {
+        let mut candidates = self.find_closest_peers(&self.key_id(&key), factor.saturating_mul(2));
+        let fallback = candidates.split_off(candidates.len().min(factor));
+        self.metrics.set_known_peers(candidates.len() as u64);
+
         let mut successes = 0;
-        for peer in peers {
+        let mut remote = Vec::with_capacity(candidates.len());
+        for peer in candidates {
             if peer.addr == self.addr {
                 successes += 1;
+            } else {
+                remote.push(peer.addr);
+            }
+        }
+
+        let (key, value) = (&key, &value);
+        let mut unreachable = Vec::new();
+        let mut results = stream::iter(remote)
+            .map(|addr| async move {
+                let result = send_store_rpc(self, addr, key.clone(), value.clone()).await;
+                (addr, result)
+            })
+            .buffer_unordered(self.replication_parallelism());
+
+        while let Some((addr, result)) = results.next().await {
+            self.metrics.inc_replication_attempts();
+            match result {
+                Ok(true) => {
+                    self.metrics.inc_replication_successes();
+                    successes += 1;
+                }
+                Ok(false) => debug!(peer = %addr, "replica refused the value"),
+                Err(_) => unreachable.push(addr),
+            }
+        }
+
+        let mut fallback = fallback
+            .into_iter()
+            .map(|peer| peer.addr)
+            .filter(|addr| *addr != self.addr);
+
+        while !unreachable.is_empty() {
+            let batch: Vec<_> = unreachable.drain(..).zip(fallback.by_ref()).collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut results = stream::iter(batch)
+                .map(|(intended, addr)| async move {
+                    let result = self.store_hinted(addr, key, value, intended).await;
+                    (intended, result)
+                })
+                .buffer_unordered(self.replication_parallelism());
+
+            while let Some((intended, result)) = results.next().await {
+                self.metrics.inc_replication_attempts();
+                match result {
+                    Ok(true) => {
+                        self.metrics.inc_replication_successes();
+                        successes += 1;
+                    }
+                    Ok(false) | Err(_) => unreachable.push(intended),
+                }
+            }
+        }
+
+        Span::current().record("replicas", successes);
+        successes
+    }
+
+    /// Pushes transient replicas held by this node to the nodes they were
+    /// hinted for, dropping each local copy once its node kept it.
+    ///
+    /// The values hinted for the same node are sent to it in batches, see
+    /// [`DhtNode::send_batches`].
+    pub async fn hand_off_hinted_values(&self) {
+        let mut stores: HashMap<SocketAddr, Vec<DhtRpc>> = HashMap::new();
+        let mut versions: HashMap<SocketAddr, Vec<(Vec<u8>, u64)>> = HashMap::new();
+
+        for (key, value) in self.storage.iter() {
+            if !decode_header(&value).is_ok_and(|h| h.is_hinted) {
+                continue;
+            }
+            let Ok(mut stored) = deserialize_value(&value) else {
+                continue;
+            };
+            let Some(target) = stored.hinted_for.take() else {
+                continue;
+            };
+            let Ok(value) = serialize_value(&stored) else {
+                continue;
+            };
+
+            stores
+                .entry(target)
+                .or_default()
+                .push(DhtRpc::Store(key.clone(), value));
+            versions
+                .entry(target)
+                .or_default()
+                .push((key, stored.version));
+        }
+
+        for (target, result) in self.send_batches(stores).await {
+            let Ok(responses) = result else {
+                continue;
+            };
+            for ((key, version), response) in versions[&target].iter().zip(responses) {
+                if !matches!(response, DhtRpc::StoreResponse(true)) {
+                    debug!(key = %key_hash(key), %target, "hinted value refused, keeping it");
+                    continue;
+                }
+                if self.storage.remove_if(key, &|current| {
+                    deserialize_value(current)
+                        .map(|v| v.version == *version && v.hinted_for == Some(target))
+                        .unwrap_or(false)
+                }) {
+                    self.metrics.add_storage_evictions(1);
+                }
+            }
+        }
+    }
+
+    /// Keeps a write that reached no peer until it can be replicated.
+    /// Returns whether it was kept: with the outbox full, it only stays in
+    /// local storage, for replication to copy once peers are back.
+    ///
+    /// A newer write to a key already in the outbox replaces it.
+    pub(super) fn buffer_offline_write(&self, key: Vec<u8>, value: Bytes) -> bool {
+        if !self.outbox.contains_key(&key)
+            && self.outbox.len() >= self.config.storage.max_outbox_entries
+        {
+            warn!(key = %key_hash(&key), "no peer reachable and the write outbox is full, keeping write locally");
+            return false;
+        }
+
+        debug!(key = %key_hash(&key), "no peer reachable, buffering write");
+        self.outbox.insert(key, value);
+        true
+    }
+
+    /// Replicates buffered writes, keeping those that still reach no peer and
+    /// dropping those that expired meanwhile. Returns the number of writes
+    /// flushed.
+    pub async fn flush_outbox(&self) -> usize {
+        let pending: Vec<_> = self
+            .outbox
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+
+        let current_time = self.now();
+        let mut flushed = 0;
+        for (key, value) in pending {
+            let expired = decode_header(&value)
+                .map(|h| !h.is_valid(current_time))
+                .unwrap_or(true);
+            if expired {
+                self.outbox.remove(&key);
                 continue;
             }
 
-            if send_store_rpc(self, peer.addr, key.clone(), value.clone())
+            if self
+                .replicate_to_peers_store(key.clone(), value.clone())
                 .await
-                .is_ok()
+                > 0
             {
-                successes += 1;
+                self.outbox.remove_if(&key, |_, current| *current == value);
+                flushed += 1;
             }
         }
-        successes
+
+        if flushed > 0 {
+            info!(
+                flushed,
+                remaining = self.outbox.len(),
+                "replicated buffered writes"
+            );
+        }
+        flushed
+    }
+
+    /// Pushes every valid value held locally to the closest other peers for
+    /// its key.
+    ///
+    /// Meant to run before a planned shutdown so that the node leaving does
+    /// not drop keys below the replication factor. Returns the number of keys
+    /// that reached at least one peer.
+    pub async fn hand_off_keys(&self) -> usize {
+        let current_time = self.now();
+        let entries: Vec<_> = self
+            .storage
+            .iter()
+            .filter(|(_, value)| decode_header(value).is_ok_and(|h| h.is_valid(current_time)))
+            .collect();
+
+        let mut handed_off = 0;
+        for (key, value) in entries {
+            let peers = self
+                .find_closest_peers_by_key(&key)
+                .into_iter()
+                .filter(|peer| peer.addr != self.addr);
+
+            let stored = stream::iter(peers)
+                .map(|peer| send_store_rpc(self, peer.addr, key.clone(), value.clone()))
+                .buffer_unordered(self.replication_parallelism())
+                .fold(false, |stored, result| async move {
+                    stored || result.is_ok_and(|accepted| accepted)
+                })
+                .await;
+
+            if stored {
+                handed_off += 1;
+            } else {
+                debug!(key = %key_hash(&key), "no peer accepted key during hand-off");
+            }
+        }
+
+        handed_off
+    }
+
+    /// Starts a background task that verifies replication every
+    /// `replication.check_interval`.
+    pub fn start_replication_checker(&self) -> JoinHandle<()> {
+        let node = self.clone();
+        tasks::spawn(
+            "replication-checker",
+            self.until_stopped(Priority::Maintenance.scope(async move {
+                let mut interval = node.interval(node.config.replication.check_interval);
+
+                loop {
+                    interval.tick().await;
+
+                    node.check_replication().await;
+                }
+            })),
+        )
+    }
+
+    /// Samples up to `replication.check_sample_size` keys originated by this
+    /// node and re-replicates those held by fewer than `replication.factor`
+    /// of the closest peers. Returns the number of keys repaired.
+    ///
+    /// Each peer is asked about all the sampled keys it should hold, and sent
+    /// the ones it lacks, in batches, see [`DhtNode::send_batches`].
+    #[instrument(skip_all, fields(repaired))]
+    pub async fn check_replication(&self) -> usize {
+        let current_time = self.now();
+        let sample = self
+            .storage
+            .iter()
+            .filter(|(_, value)| {
+                decode_header(value).is_ok_and(|h| !h.is_replica && h.is_valid(current_time))
+            })
+            .map(|(key, _)| key)
+            .choose_multiple(
+                &mut rand::thread_rng(),
+                self.config.replication.check_sample_size,
+            );
+
+        let factor = self.config.replication.factor;
+        let mut asked: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        for (index, key) in sample.iter().enumerate() {
+            for peer in self.find_closest_peers(&self.key_id(key), factor * 2) {
+                asked.entry(peer.addr).or_default().push(index);
+            }
+        }
+
+        let queries = asked
+            .iter()
+            .map(|(peer, indices)| {
+                let requests = indices
+                    .iter()
+                    .map(|&index| DhtRpc::FindValue(sample[index].clone()))
+                    .collect();
+                (*peer, requests)
+            })
+            .collect();
+
+        let mut holders = vec![0; sample.len()];
+        let mut lacking = vec![Vec::new(); sample.len()];
+        for (peer, responses) in self.send_batches(queries).await {
+            let Ok(responses) = responses else {
+                continue;
+            };
+            for (&index, response) in asked[&peer].iter().zip(responses) {
+                match response {
+                    DhtRpc::FindValueResponse(Some(_)) => holders[index] += 1,
+                    DhtRpc::FindValueResponse(None) => lacking[index].push(peer),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut repaired = 0;
+        let mut repairs: HashMap<SocketAddr, Vec<DhtRpc>> = HashMap::new();
+        for (index, key) in sample.iter().enumerate() {
+            if holders[index] >= factor {
+                continue;
+            }
+            debug!(key = %key_hash(key), "re-replicating under-replicated key");
+            repaired += 1;
+
+            // Read again, the value may have changed since it was sampled
+            if let Some(value) = self.storage.get(key)
+                && decode_header(&value).is_ok_and(|h| !h.is_replica)
+            {
+                for peer in &lacking[index] {
+                    repairs
+                        .entry(*peer)
+                        .or_default()
+                        .push(DhtRpc::Store(key.clone(), value.clone()));
+                }
+            }
+        }
+        self.send_batches(repairs).await;
+
+        Span::current().record("repaired", repaired);
+        repaired
+    }
+
+    /// Tells the replicas of an expired key to drop their copies.
+    ///
+    /// Notices are best effort: replicas that miss one still drop the value
+    /// on their own expiration check. They carry the `token` the value was
+    /// written with, see [`authz`](crate::dht::authz).
+    pub async fn send_expiry_notices(&self, key: Vec<u8>, version: u64, token: Option<Vec<u8>>) {
+        self.send_expiry_notices_batched(vec![(key, version, token)])
+            .await;
+    }
+
+    /// Sends the expiry notices of several keys, those for the same replica
+    /// in batches, see [`DhtNode::send_batches`].
+    pub(super) async fn send_expiry_notices_batched(
+        &self,
+        expired: Vec<(Vec<u8>, u64, Option<Vec<u8>>)>,
+    ) {
+        let mut notices: HashMap<SocketAddr, Vec<DhtRpc>> = HashMap::new();
+        for (key, version, token) in expired {
+            for peer in self.find_closest_peers_by_key(&key) {
+                if peer.addr != self.addr {
+                    notices.entry(peer.addr).or_default().push(DhtRpc::Expire(
+                        key.clone(),
+                        version,
+                        token.clone(),
+                        self.sign_expiry(&key, version),
+                    ));
+                }
+            }
+        }
+
+        self.send_batches(notices).await;
+    }
+
+    /// Sends the requests queued for each peer, up to
+    /// `replication.batch_size` of them per round trip, to up to
+    /// `replication.parallelism` peers at once.
+    ///
+    /// Maintenance gathers its work per peer this way, so that large
+    /// routing tables and key sets cost a few RPCs per peer rather than one
+    /// per key. Returns the responses of each peer in the order of its
+    /// requests, or why it could not be reached.
+    pub(super) async fn send_batches(
+        &self,
+        batches: HashMap<SocketAddr, Vec<DhtRpc>>,
+    ) -> Vec<(SocketAddr, anyhow::Result<Vec<DhtRpc>>)> {
+        stream::iter(batches)
+            .map(|(peer, requests)| async move {
+                (peer, send_batch_rpc(self, peer, requests).await)
+            })
+            .buffer_unordered(self.replication_parallelism())
+            .collect()
+            .await
+    }
+
+    /// Maximum number of concurrent replication RPCs.
+    pub(super) fn replication_parallelism(&self) -> usize {
+        self.config.replication.parallelism.max(1)
+    }
+
+    /// Stores `value` on `addr` as a transient replica hinted for `intended`.
+    /// Returns whether `addr` kept it.
+    async fn store_hinted(
+        &self,
+        addr: SocketAddr,
+        key: &[u8],
+        value: &[u8],
+        intended: SocketAddr,
+    ) -> anyhow::Result<bool> {
+        let mut stored = deserialize_value(value)?;
+        stored.hinted_for = Some(intended);
+        send_store_rpc(self, addr, key.to_vec(), serialize_value(&stored)?).await
+    }
+}
+
+/// A local write whose replication is under way.
+///
+/// Dropped before [`disarm`](Self::disarm), for example because the store
+/// was cancelled, it keeps the write in the outbox so that it is still
+/// replicated later.
+pub(super) struct PendingWrite<'a> {
+    node: &'a DhtNode,
+    write: Option<(Vec<u8>, Bytes)>,
+}
+
+impl<'a> PendingWrite<'a> {
+    pub(super) fn new(node: &'a DhtNode, key: Vec<u8>, value: Bytes) -> Self {
+        Self {
+            node,
+            write: Some((key, value)),
+        }
+    }
+
+    /// Marks replication as finished, successfully or not.
+    pub(super) fn disarm(mut self) {
+        self.write = None;
+    }
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        if let Some((key, value)) = self.write.take() {
+            self.node.buffer_offline_write(key, value);
+        }
     }
 }
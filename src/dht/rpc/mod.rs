@@ -1,5 +1,8 @@
+#[cfg(feature = "node")]
 pub(super) mod utils;
 
+use bytes::Bytes;
+use ed25519_dalek::Signature;
 use serde::{Deserialize, Serialize};
 
 use crate::dht::{node::NodeId, peer::PeerInfo};
@@ -7,7 +10,7 @@ use crate::dht::{node::NodeId, peer::PeerInfo};
 /// Remote Procedure Calls (RPCs) used in DHT communication.
 ///
 /// These messages are echanged between nodes to implement the DHT protocol.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DhtRpc {
     /// Ping request (check if node is alive)
     Ping,
@@ -15,12 +18,171 @@ pub enum DhtRpc {
     Pong,
     /// Request to fing nodes closest to a given key
     FindNode(NodeId),
-    /// Response containing closest known nodes
+    /// Response containing the `kbucket_size` closest known nodes, closest
+    /// first
     FindNodeResponse(Vec<PeerInfo>),
     /// Request to find a value by key
     FindValue(Vec<u8>),
     /// Response containing found value (if exists)
-    FindValueResponse(Option<Vec<u8>>),
+    FindValueResponse(Option<Bytes>),
     /// Request to store a key-value pair
-    Store(Vec<u8>, Vec<u8>),
+    Store(Vec<u8>, Bytes),
+    /// Notice that a key expired on its originating node; replicas drop
+    /// copies whose version is not newer than the given one. Carries the
+    /// token the value was written with, see [`authz`](crate::dht::authz),
+    /// and for records the owner's signature of the notice, see
+    /// [`record`](crate::dht::record)
+    Expire(Vec<u8>, u64, Option<Vec<u8>>, Option<Signature>),
+    /// Request from a client to store a value, replicated as if it were
+    /// written on the receiving node. Carries the client's token authorizing
+    /// the write, see [`authz`](crate::dht::authz)
+    ClientStore(Vec<u8>, Bytes, Option<Vec<u8>>),
+    /// Version written for a `ClientStore`, or why it failed
+    ClientStoreResponse(Result<u64, String>),
+    /// Request from a client to look a value up across the network
+    ClientGet(Vec<u8>),
+    /// The value found for a `ClientGet`, if any
+    ClientGetResponse(Option<Bytes>),
+    /// Request for the receiving node's statistics
+    GetStats,
+    /// [`DhtStats`](crate::dht::metrics::DhtStats) as JSON, which unlike
+    /// bincode handles its flattened rates
+    StatsResponse(String),
+    /// Several requests to the same peer in one round trip, used by
+    /// maintenance; batches do not nest
+    Batch(Vec<DhtRpc>),
+    /// Responses to a `Batch`, in the order of its requests
+    BatchResponse(Vec<DhtRpc>),
+    /// The request was shed because the receiving node had too many RPCs in
+    /// flight, see [`overload`](crate::dht::overload), or too many requests
+    /// for its key, see [`hotkeys`](crate::dht::hotkeys)
+    Busy,
+    /// A message published on a topic, with its id and the number of hops
+    /// it may still travel, see [`gossip`](crate::dht::gossip)
+    Gossip(u64, String, Bytes, u8),
+    /// Request from a client to store a value, replicated by the receiving
+    /// node as the request says
+    CoordinatedStore(Vec<u8>, Bytes, WriteRequest),
+    /// How a `CoordinatedStore` went, or why it failed
+    CoordinatedStoreResponse(Result<WriteReport, String>),
+    /// Whether the receiving node kept the value of a `Store`. It refuses
+    /// values that are blocked, unauthorized, invalid, older than its own
+    /// copy or over its storage limit
+    StoreResponse(bool),
+    /// Request to store a key-value pair in place of the copy of the given
+    /// version, or if the receiving node holds none when `None`, see
+    /// [`DhtNode::compare_and_swap`](crate::dht::DhtNode::compare_and_swap);
+    /// answered with a `StoreResponse`
+    StoreIf(Vec<u8>, Bytes, Option<u64>),
+}
+
+/// How many replicas must acknowledge a write before it succeeds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Consistency {
+    /// None: the write succeeds once stored locally, and is kept in the
+    /// outbox when no peer can be reached, like `DhtNode::store`
+    #[default]
+    Any,
+    /// At least one peer
+    One,
+    /// A majority of the replication factor
+    Quorum,
+    /// Every replica of the replication factor
+    All,
+}
+
+impl Consistency {
+    /// Number of acknowledgements required out of `factor` replicas.
+    pub fn required(self, factor: usize) -> usize {
+        match self {
+            Self::Any => 0,
+            Self::One => 1.min(factor),
+            Self::Quorum => factor / 2 + 1,
+            Self::All => factor,
+        }
+    }
+}
+
+/// How the node coordinating a `CoordinatedStore` writes it. Fields left
+/// unset fall back to the node's configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteRequest {
+    /// Number of peers the value is replicated on, from 1 to the
+    /// coordinator's `kbucket_size`
+    pub replication: Option<u32>,
+    /// Seconds until the value expires
+    pub ttl: Option<u64>,
+    pub consistency: Consistency,
+    /// Token authorizing the write, see [`authz`](crate::dht::authz)
+    pub token: Option<Vec<u8>>,
+}
+
+/// Outcome of a write, as the node coordinating it saw it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteReport {
+    /// Version the value was written with
+    pub version: u64,
+    /// Replicas the write was meant for
+    pub replicas: u32,
+    /// Replicas that acknowledged it, the coordinator included when it is
+    /// one of them
+    pub acknowledged: u32,
+    /// Acknowledgements the consistency required
+    pub required: u32,
+}
+
+/// A request as sent between nodes: the RPC and the node sending it.
+///
+/// The receiver adds the sender to its routing table, or marks it as seen,
+/// as every request shows that its sender is alive. Responses go back bare.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Signed announcement of the sending node, `None` from clients and from
+    /// nodes that do not accept connections
+    pub sender: Option<PeerInfo>,
+    /// Id the sender gave the request, kept when it retries it so that it
+    /// is not applied twice, see [`dedup`](crate::dht::dedup)
+    pub id: Option<u64>,
+    pub rpc: DhtRpc,
+}
+
+impl Envelope {
+    /// Wraps a request that does not come from a reachable node.
+    pub fn anonymous(rpc: DhtRpc) -> Self {
+        Self {
+            sender: None,
+            id: None,
+            rpc,
+        }
+    }
+}
+
+impl DhtRpc {
+    /// Name of the message type, for logs and traces.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ping => "Ping",
+            Self::Pong => "Pong",
+            Self::FindNode(_) => "FindNode",
+            Self::FindNodeResponse(_) => "FindNodeResponse",
+            Self::FindValue(_) => "FindValue",
+            Self::FindValueResponse(_) => "FindValueResponse",
+            Self::Store(..) => "Store",
+            Self::Expire(..) => "Expire",
+            Self::ClientStore(..) => "ClientStore",
+            Self::ClientStoreResponse(_) => "ClientStoreResponse",
+            Self::ClientGet(_) => "ClientGet",
+            Self::ClientGetResponse(_) => "ClientGetResponse",
+            Self::GetStats => "GetStats",
+            Self::StatsResponse(_) => "StatsResponse",
+            Self::Batch(_) => "Batch",
+            Self::BatchResponse(_) => "BatchResponse",
+            Self::Busy => "Busy",
+            Self::Gossip(..) => "Gossip",
+            Self::CoordinatedStore(..) => "CoordinatedStore",
+            Self::CoordinatedStoreResponse(_) => "CoordinatedStoreResponse",
+            Self::StoreResponse(_) => "StoreResponse",
+            Self::StoreIf(..) => "StoreIf",
+        }
+    }
 }
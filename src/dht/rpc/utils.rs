@@ -1,22 +1,29 @@
 use std::net::SocketAddr;
 
-use tokio::time::timeout;
+use bytes::Bytes;
 
 use crate::dht::{DhtNode, rpc::DhtRpc};
 
+/// Sends a `Store` to `peer`. Returns whether the peer kept the value, or
+/// why it could not be reached.
 pub async fn send_store_rpc(
     node: &DhtNode,
     peer: SocketAddr,
     key: Vec<u8>,
-    value: Vec<u8>,
-) -> anyhow::Result<()> {
-    match timeout(
-        node.config.operation_timeout,
-        node.send_rpc(peer, DhtRpc::Store(key, value)),
-    )
-    .await
+    value: Bytes,
+) -> anyhow::Result<bool> {
+    match node
+        .timeout(
+            node.config.operation_timeout,
+            node.send_rpc(peer, DhtRpc::Store(key, value)),
+        )
+        .await
     {
-        Ok(Ok(_)) => Ok(()),
+        Ok(Ok(DhtRpc::StoreResponse(accepted))) => Ok(accepted),
+        Ok(Ok(response)) => {
+            node.metrics.inc_rpc_failures();
+            Err(anyhow::anyhow!("Unexpected {} to a Store", response.name()))
+        }
         Ok(Err(e)) => {
             node.metrics.inc_rpc_failures();
             Err(e)
@@ -27,3 +34,53 @@ pub async fn send_store_rpc(
         }
     }
 }
+
+/// Sends `requests` to `peer` in batches of up to `replication.batch_size`,
+/// returning the responses in the order of the requests.
+///
+/// Fails as a whole as soon as one batch does.
+pub async fn send_batch_rpc(
+    node: &DhtNode,
+    peer: SocketAddr,
+    requests: Vec<DhtRpc>,
+) -> anyhow::Result<Vec<DhtRpc>> {
+    let batch_size = node.config.replication.batch_size.max(1);
+    let mut responses = Vec::with_capacity(requests.len());
+    let mut requests = requests.into_iter().peekable();
+
+    while requests.peek().is_some() {
+        let mut batch: Vec<_> = requests.by_ref().take(batch_size).collect();
+        let sent = batch.len();
+        let request = if batch_size == 1 {
+            batch.remove(0)
+        } else {
+            DhtRpc::Batch(batch)
+        };
+
+        match node
+            .timeout(node.config.operation_timeout, node.send_rpc(peer, request))
+            .await
+        {
+            Ok(Ok(DhtRpc::BatchResponse(batch))) if batch.len() == sent => responses.extend(batch),
+            Ok(Ok(response)) if batch_size == 1 => responses.push(response),
+            Ok(Ok(response)) => {
+                node.metrics.inc_rpc_failures();
+                return Err(anyhow::anyhow!(
+                    "Unexpected {} to a batch of {}",
+                    response.name(),
+                    sent
+                ));
+            }
+            Ok(Err(e)) => {
+                node.metrics.inc_rpc_failures();
+                return Err(e);
+            }
+            Err(_) => {
+                node.metrics.inc_rpc_failures();
+                return Err(anyhow::anyhow!("Batch operation timeout"));
+            }
+        }
+    }
+
+    Ok(responses)
+}
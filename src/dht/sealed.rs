@@ -0,0 +1,321 @@
+//! Encrypting values before they leave the writer.
+//!
+//! [`DhtNode::store_sealed`] encrypts a value with a [`SealingKey`] before
+//! storing it, and [`DhtNode::get_sealed`] decrypts it with the matching
+//! [`OpeningKey`], so the nodes holding the value, and the links it crosses,
+//! only ever see ciphertext. Values are sealed either with a key shared by
+//! writers and readers, or for a recipient's X25519 public key, which only
+//! the holder of the matching [`RecipientKey`] can open.
+//!
+//! Values are encrypted with XChaCha20-Poly1305 and bound to their DHT key:
+//! a value copied under another key fails to open. Keys and the size of
+//! values are not hidden.
+
+use std::fmt;
+
+use anyhow::{Result, anyhow, bail};
+use chacha20poly1305::{
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::dht::DhtNode;
+
+/// Sealed with a [`SymmetricKey`]: tag, nonce, ciphertext.
+const SYMMETRIC: u8 = 1;
+/// Sealed for a recipient: tag, ephemeral public key, nonce, ciphertext.
+const RECIPIENT: u8 = 2;
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit key shared by the writers and readers of sealed values.
+///
+/// Its `Debug` form does not reveal it.
+#[derive(Clone)]
+pub struct SymmetricKey([u8; 32]);
+
+impl SymmetricKey {
+    pub fn generate() -> Self {
+        Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl fmt::Debug for SymmetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SymmetricKey").finish_non_exhaustive()
+    }
+}
+
+/// The X25519 key pair of a recipient of sealed values.
+///
+/// Its `Debug` form does not reveal the private key.
+#[derive(Clone)]
+pub struct RecipientKey(StaticSecret);
+
+impl RecipientKey {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(StaticSecret::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    /// The key senders seal values for this recipient with.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.0)
+    }
+}
+
+impl fmt::Debug for RecipientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecipientKey")
+            .field("public_key", &hex::encode(self.public_key().as_bytes()))
+            .finish_non_exhaustive()
+    }
+}
+
+/// What values are encrypted with.
+#[derive(Debug, Clone)]
+pub enum SealingKey {
+    Symmetric(SymmetricKey),
+    Recipient(PublicKey),
+}
+
+/// What sealed values are decrypted with.
+#[derive(Debug, Clone)]
+pub enum OpeningKey {
+    Symmetric(SymmetricKey),
+    Recipient(RecipientKey),
+}
+
+impl SealingKey {
+    /// Encrypts `value`, to be stored under `key`.
+    pub fn seal(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Symmetric(symmetric) => encrypt(&symmetric.0, vec![SYMMETRIC], key, value),
+            Self::Recipient(recipient) => {
+                let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral_public = PublicKey::from(&ephemeral);
+                let shared = ephemeral.diffie_hellman(recipient);
+                let cipher_key = derive_key(shared.as_bytes(), &ephemeral_public, recipient);
+
+                let mut header = vec![RECIPIENT];
+                header.extend_from_slice(ephemeral_public.as_bytes());
+                encrypt(&cipher_key, header, key, value)
+            }
+        }
+    }
+}
+
+impl OpeningKey {
+    /// Decrypts a value sealed for this key and stored under `key`.
+    pub fn open(&self, key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+        let (&tag, rest) = sealed
+            .split_first()
+            .ok_or_else(|| anyhow!("Sealed value is empty"))?;
+        match (self, tag) {
+            (Self::Symmetric(symmetric), SYMMETRIC) => decrypt(&symmetric.0, key, rest),
+            (Self::Recipient(recipient), RECIPIENT) => {
+                let Some((ephemeral, rest)) = rest.split_first_chunk::<32>() else {
+                    bail!("Sealed value is truncated");
+                };
+                let ephemeral = PublicKey::from(*ephemeral);
+                let shared = recipient.0.diffie_hellman(&ephemeral);
+                let cipher_key = derive_key(shared.as_bytes(), &ephemeral, &recipient.public_key());
+                decrypt(&cipher_key, key, rest)
+            }
+            (_, SYMMETRIC | RECIPIENT) => bail!("Value was sealed with another kind of key"),
+            _ => bail!("Value is not sealed"),
+        }
+    }
+}
+
+fn derive_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"sealed/1", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+fn encrypt(cipher_key: &[u8; 32], mut header: Vec<u8>, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(cipher_key.into())
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: value,
+                aad: key,
+            },
+        )
+        .expect("encrypting in memory cannot fail");
+    header.extend_from_slice(&nonce);
+    header.extend_from_slice(&ciphertext);
+    header
+}
+
+fn decrypt(cipher_key: &[u8; 32], key: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        bail!("Sealed value is truncated");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    XChaCha20Poly1305::new(cipher_key.into())
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: key,
+            },
+        )
+        .map_err(|_| anyhow!("Sealed value failed to open"))
+}
+
+impl DhtNode {
+    /// Encrypts `value` with `sealing` and stores it, see [`sealed`](self).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use rust_p2p_node::dht::{
+    ///     DhtNode,
+    ///     sealed::{OpeningKey, RecipientKey, SealingKey},
+    /// };
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let node = DhtNode::new("127.0.0.1:8080".parse()?, None);
+    ///     let recipient = RecipientKey::generate();
+    ///
+    ///     let sealing = SealingKey::Recipient(recipient.public_key());
+    ///     node.store_sealed(b"inbox".to_vec(), b"hello", &sealing).await?;
+    ///
+    ///     let opening = OpeningKey::Recipient(recipient);
+    ///     let value = node.get_sealed(b"inbox".to_vec(), &opening).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn store_sealed(
+        &self,
+        key: Vec<u8>,
+        value: &[u8],
+        sealing: &SealingKey,
+    ) -> Result<()> {
+        let sealed = sealing.seal(&key, value);
+        self.store(key, sealed).await
+    }
+
+    /// Looks up a value written by [`DhtNode::store_sealed`] and decrypts it
+    /// with `opening`.
+    ///
+    /// Fails if the value was not sealed for `opening`, or was tampered
+    /// with.
+    pub async fn get_sealed(&self, key: Vec<u8>, opening: &OpeningKey) -> Result<Option<Vec<u8>>> {
+        let Some(sealed) = self.find_value(key.clone()).await else {
+            return Ok(None);
+        };
+        opening.open(&key, &sealed).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod sealed_tests {
+    use super::{OpeningKey, RecipientKey, SealingKey, SymmetricKey};
+    use crate::{dht::clock::ManualClock, helpers::create_test_node};
+
+    #[test]
+    fn test_symmetric_seal_round_trips() {
+        let key = SymmetricKey::generate();
+        let sealed = SealingKey::Symmetric(key.clone()).seal(b"key", b"secret");
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+
+        let opening = OpeningKey::Symmetric(key);
+        assert_eq!(opening.open(b"key", &sealed).unwrap(), b"secret");
+        assert!(opening.open(b"other", &sealed).is_err());
+        assert!(
+            OpeningKey::Symmetric(SymmetricKey::generate())
+                .open(b"key", &sealed)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_only_the_recipient_opens() {
+        let recipient = RecipientKey::generate();
+        let sealing = SealingKey::Recipient(recipient.public_key());
+        let sealed = sealing.seal(b"key", b"secret");
+        assert_ne!(sealed, sealing.seal(b"key", b"secret"));
+
+        assert_eq!(
+            OpeningKey::Recipient(recipient.clone())
+                .open(b"key", &sealed)
+                .unwrap(),
+            b"secret"
+        );
+        assert!(
+            OpeningKey::Recipient(RecipientKey::generate())
+                .open(b"key", &sealed)
+                .is_err()
+        );
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(
+            OpeningKey::Recipient(recipient.clone())
+                .open(b"key", &tampered)
+                .is_err()
+        );
+        assert!(
+            OpeningKey::Symmetric(SymmetricKey::from_bytes(recipient.to_bytes()))
+                .open(b"key", &sealed)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nodes_only_hold_ciphertext() {
+        let node = create_test_node(8080).with_clock(ManualClock::new(1_000));
+        let key = SymmetricKey::generate();
+        node.store_sealed(
+            b"key".to_vec(),
+            b"secret",
+            &SealingKey::Symmetric(key.clone()),
+        )
+        .await
+        .unwrap();
+
+        let stored = node.find_value(b"key".to_vec()).await.unwrap();
+        assert!(!stored.windows(6).any(|w| w == b"secret"));
+        assert_eq!(
+            node.get_sealed(b"key".to_vec(), &OpeningKey::Symmetric(key))
+                .await
+                .unwrap(),
+            Some(b"secret".to_vec())
+        );
+        assert_eq!(
+            node.get_sealed(
+                b"missing".to_vec(),
+                &OpeningKey::Symmetric(SymmetricKey::generate())
+            )
+            .await
+            .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{io, net::IpAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::dht::{
+    DhtNode,
+    connection::{
+        buffers::FRAME_BUFFERS,
+        connector::PeerStream,
+        transport::{MAX_FRAME_LEN, read_frame, write_frame},
+    },
+    dedup::is_write,
+    health::BoundGuard,
+    rpc::{DhtRpc, Envelope},
+    tasks,
+};
+
+impl DhtNode {
+    /// Binds the node's address and serves RPCs from other nodes until the
+    /// returned task is aborted or the node shuts down.
+    ///
+    /// Connections speak the length-prefixed framing used by
+    /// [`DhtNode::send_rpc`], or the multiplexed one when
+    /// `connection_pool.multiplexing` is enabled. They are accepted over TCP
+    /// unless the node runs over another transport, see
+    /// [`DhtNode::with_network`].
+    pub async fn listen(&self) -> Result<JoinHandle<()>> {
+        let mut listener = self
+            .network
+            .bind(self.addr)
+            .await
+            .with_context(|| format!("Failed to bind {}", self.addr))?;
+
+        let node = self.clone();
+        let bound = BoundGuard(Arc::clone(&self.health));
+        self.health
+            .bound
+            .store(true, std::sync::atomic::Ordering::Release);
+
+        Ok(tasks::spawn(
+            "rpc-listener",
+            self.until_stopped(async move {
+                let _bound = bound;
+                loop {
+                    match listener.accept().await {
+                        Ok((socket, peer)) => {
+                            let ip = peer.map(|peer| peer.ip());
+                            let Some(permit) = node.inbound.admit(ip) else {
+                                debug!(?peer, "refusing connection over the inbound limits");
+                                continue;
+                            };
+                            let node = node.clone();
+                            tasks::spawn("rpc-connection", async move {
+                                let _permit = permit;
+                                if node.config.connection_pool.multiplexing {
+                                    node.serve_multiplexed_from(socket, ip).await;
+                                } else if let Err(e) = node.serve_connection(socket, ip).await {
+                                    debug!(?peer, error = %e, "connection closed");
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "failed to accept connection");
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                        }
+                    }
+                }
+            }),
+        ))
+    }
+
+    /// Answers framed RPCs on one connection until the peer closes it.
+    async fn serve_connection(
+        &self,
+        mut socket: Box<dyn PeerStream>,
+        ip: Option<IpAddr>,
+    ) -> Result<()> {
+        // Responses are encoded into the same buffer for the whole connection
+        let mut encoded = Vec::new();
+        loop {
+            let buf = match read_frame(&mut socket, MAX_FRAME_LEN).await {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::InvalidData {
+                        self.metrics.inc_rpc_failures();
+                    }
+                    return Err(anyhow::Error::new(e).context("Failed to read request"));
+                }
+            };
+
+            if !self.inbound.allow_request(ip) {
+                bail!("Request rate over the inbound limits");
+            }
+
+            let request = self.open_frame(buf).and_then(|buf| {
+                let envelope = bincode::deserialize::<Envelope>(&buf)?;
+                FRAME_BUFFERS.give(buf);
+                Ok(envelope)
+            });
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    self.metrics.inc_rpc_failures();
+                    return Err(e);
+                }
+            };
+            let response = self.handle_request(request, ip).await;
+            encoded.clear();
+            bincode::serialize_into(&mut encoded, &response)?;
+            let frame = self.seal_frame(std::mem::take(&mut encoded));
+
+            write_frame(&mut socket, &frame)
+                .await
+                .context("Failed to send response")?;
+            encoded = frame;
+        }
+    }
+
+    /// Answers a request that came from `ip`, learning its sender first.
+    /// Retries of a write answered earlier get the same response.
+    pub(super) async fn handle_request(&self, request: Envelope, ip: Option<IpAddr>) -> DhtRpc {
+        if let Some(sender) = request.sender {
+            self.learn_sender(sender, ip);
+        }
+        match request.id {
+            Some(id) if is_write(&request.rpc) => self.handle_once(id, ip, request.rpc).await,
+            _ => self.handle_rpc_admitted(request.rpc).await,
+        }
+    }
+}
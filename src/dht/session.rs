@@ -0,0 +1,114 @@
+//! Client sessions with read-your-writes consistency.
+//!
+//! A [`Session`] remembers the version of every value it has written and
+//! never returns an older copy of those keys, even when a lagging replica
+//! answers first.
+
+use anyhow::Result;
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::dht::{DhtNode, lookup::LookupResult};
+
+/// A lightweight client session over a [`DhtNode`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use rust_p2p_node::dht::{DhtNode, config::DhtConfig};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let node = DhtNode::new("127.0.0.1:8080".parse().unwrap(), Some(DhtConfig::default()));
+///     let session = node.session();
+///
+///     session.store(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+///
+///     // Never older than the write above
+///     let value = session.find_value(b"key".to_vec()).await;
+///     assert_eq!(value.as_deref(), Some(&b"value"[..]));
+/// }
+/// ```
+pub struct Session {
+    node: DhtNode,
+    written: DashMap<Vec<u8>, u64>,
+}
+
+impl Session {
+    /// Creates a session that has not written anything yet.
+    pub fn new(node: DhtNode) -> Self {
+        Self {
+            node,
+            written: DashMap::new(),
+        }
+    }
+
+    /// Stores a key-value pair and remembers the version written.
+    pub async fn store(&self, key: Vec<u8>, value: impl Into<Bytes>) -> Result<()> {
+        let version = self
+            .node
+            .store_versioned(
+                key.clone(),
+                value.into(),
+                self.node.config.storage.default_ttl,
+                None,
+            )
+            .await?;
+        self.written
+            .entry(key)
+            .and_modify(|v| *v = (*v).max(version))
+            .or_insert(version);
+        Ok(())
+    }
+
+    /// Looks up a value, skipping replicas older than this session's writes.
+    pub async fn find_value(&self, key: Vec<u8>) -> Option<Bytes> {
+        self.find_value_detailed(key)
+            .await
+            .map(|result| result.value)
+    }
+
+    /// Like [`Session::find_value`], with lookup provenance.
+    pub async fn find_value_detailed(&self, key: Vec<u8>) -> Option<LookupResult> {
+        let min_version = self.written.get(&key).map(|v| *v).unwrap_or(0);
+        self.node.find_value_since(key, min_version).await
+    }
+}
+
+impl DhtNode {
+    /// Opens a new read-your-writes [`Session`] on this node.
+    pub fn session(&self) -> Session {
+        Session::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use bytes::Bytes;
+
+    use crate::{
+        dht::storage::{create_stored_value, serialize_value},
+        helpers::create_test_node,
+    };
+
+    #[tokio::test]
+    async fn test_session_rejects_stale_reads() {
+        let mut node = create_test_node(8090);
+        node.config.storage.default_ttl = 60;
+        let session = node.session();
+
+        let key = b"key".to_vec();
+        session.store(key.clone(), b"fresh".to_vec()).await.unwrap();
+
+        let mut stale = create_stored_value(b"stale".to_vec(), node.addr, false, None, node.now());
+        stale.version = 1;
+        node.storage
+            .insert(key.clone(), serialize_value(&stale).unwrap());
+
+        assert_eq!(
+            node.find_value(key.clone()).await,
+            Some(Bytes::from_static(b"stale"))
+        );
+        assert_eq!(session.find_value(key).await, None);
+    }
+}
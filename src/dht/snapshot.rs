@@ -0,0 +1,334 @@
+//! Periodic backups of a node's storage.
+//!
+//! [`DhtNode::take_snapshot`] serializes every valid value the node holds,
+//! and [`DhtNode::back_up`] hands the snapshot to a [`SnapshotSink`], which
+//! keeps it somewhere else: [`FileSink`] writes it to a directory, and
+//! embedders can implement the trait over object storage such as S3.
+//! [`DhtNode::start_snapshots`] backs up every `snapshots.interval`, keeping
+//! the last `snapshots.retain` snapshots in the sink.
+//!
+//! A snapshot is named after the second it was taken at, so names sort from
+//! oldest to newest. [`DhtNode::restore_snapshot`] loads one back, keeping
+//! the local copies that win over the snapshot's as a replica's would.
+
+use std::{io::ErrorKind, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, task::JoinHandle};
+use tracing::{info, warn};
+
+use crate::dht::{
+    DhtNode,
+    node::NodeId,
+    overload::Priority,
+    storage::{decode_header, deserialize_value},
+    tasks,
+};
+
+/// Format of the snapshots written by this version, in their first byte
+const SNAPSHOT_FORMAT: u8 = 1;
+
+/// Start and end of snapshot names, around the time they were taken at
+const NAME_PREFIX: &str = "snapshot-";
+const NAME_SUFFIX: &str = ".bin";
+
+/// Somewhere snapshots are kept, by name.
+///
+/// # Examples
+///
+/// ```
+/// use std::{collections::BTreeMap, sync::Mutex};
+///
+/// use anyhow::{Context, Result};
+/// use async_trait::async_trait;
+/// use bytes::Bytes;
+/// use rust_p2p_node::dht::snapshot::SnapshotSink;
+///
+/// /// Keeps snapshots in memory, as a stand-in for a bucket.
+/// #[derive(Default)]
+/// struct MemorySink(Mutex<BTreeMap<String, Bytes>>);
+///
+/// #[async_trait]
+/// impl SnapshotSink for MemorySink {
+///     async fn upload(&self, name: &str, snapshot: Bytes) -> Result<()> {
+///         self.0.lock().unwrap().insert(name.to_string(), snapshot);
+///         Ok(())
+///     }
+///
+///     async fn download(&self, name: &str) -> Result<Bytes> {
+///         self.0.lock().unwrap().get(name).cloned().context("No such snapshot")
+///     }
+///
+///     async fn list(&self) -> Result<Vec<String>> {
+///         Ok(self.0.lock().unwrap().keys().cloned().collect())
+///     }
+///
+///     async fn delete(&self, name: &str) -> Result<()> {
+///         self.0.lock().unwrap().remove(name);
+///         Ok(())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait SnapshotSink: Send + Sync {
+    /// Stores `snapshot` under `name`, replacing any snapshot of that name.
+    async fn upload(&self, name: &str, snapshot: Bytes) -> Result<()>;
+
+    async fn download(&self, name: &str) -> Result<Bytes>;
+
+    /// Names of the snapshots kept, in any order. Other objects the sink
+    /// holds may be listed too; only snapshot names are acted on.
+    async fn list(&self) -> Result<Vec<String>>;
+
+    async fn delete(&self, name: &str) -> Result<()>;
+}
+
+/// Keeps snapshots as files in a directory, created if missing.
+#[derive(Debug, Clone)]
+pub struct FileSink {
+    dir: PathBuf,
+}
+
+impl FileSink {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl SnapshotSink for FileSink {
+    async fn upload(&self, name: &str, snapshot: Bytes) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        // Written aside first, so a crash never leaves a partial snapshot
+        let partial = self.dir.join(format!("{}.partial", name));
+        let path = self.dir.join(name);
+        fs::write(&partial, &snapshot)
+            .await
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        fs::rename(&partial, &path)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    async fn download(&self, name: &str) -> Result<Bytes> {
+        let path = self.dir.join(name);
+        let snapshot = fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(snapshot.into())
+    }
+
+    async fn list(&self) -> Result<Vec<String>> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to list {}", self.dir.display()));
+            }
+        };
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let path = self.dir.join(name);
+        fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Failed to delete {}", path.display()))
+    }
+}
+
+/// What a snapshot holds, after its format byte.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// Node the snapshot was taken on
+    node: NodeId,
+    /// Unix timestamp it was taken at
+    taken_at: u64,
+    /// Keys and their encoded stored values
+    entries: Vec<(Vec<u8>, Bytes)>,
+}
+
+fn is_snapshot_name(name: &str) -> bool {
+    name.starts_with(NAME_PREFIX) && name.ends_with(NAME_SUFFIX)
+}
+
+impl DhtNode {
+    /// Serializes every value the node holds that has not expired.
+    pub fn take_snapshot(&self) -> Result<Bytes> {
+        let current_time = self.now();
+        let snapshot = Snapshot {
+            node: self.id.clone(),
+            taken_at: current_time,
+            entries: self
+                .storage
+                .iter()
+                .filter(|(_, value)| decode_header(value).is_ok_and(|h| h.is_valid(current_time)))
+                .collect(),
+        };
+        let mut data = vec![SNAPSHOT_FORMAT];
+        bincode::serialize_into(&mut data, &snapshot)?;
+        Ok(data.into())
+    }
+
+    /// Loads the values of a snapshot into storage, returning how many
+    /// were.
+    ///
+    /// Values that expired since the snapshot was taken are skipped, as are
+    /// those whose local copy wins over them.
+    pub fn restore_snapshot(&self, data: &[u8]) -> Result<usize> {
+        let Some((&format, data)) = data.split_first() else {
+            bail!("Snapshot is empty");
+        };
+        if format != SNAPSHOT_FORMAT {
+            bail!("Unsupported snapshot format {}", format);
+        }
+        let snapshot: Snapshot = bincode::deserialize(data).context("Invalid snapshot")?;
+
+        let current_time = self.now();
+        let mut restored = 0;
+        for (key, value) in snapshot.entries {
+            let Ok(stored) = deserialize_value(&value) else {
+                continue;
+            };
+            if stored.is_valid(current_time) && !self.local_copy_wins(&key, &stored) {
+                self.lookup_cache.invalidate(&key, stored.version);
+                self.storage.insert(key, value);
+                restored += 1;
+            }
+        }
+        self.evict_to_fit();
+        Ok(restored)
+    }
+
+    /// Uploads a snapshot of the node's storage to `sink`, then deletes the
+    /// snapshots beyond the last `snapshots.retain`. Returns the name of
+    /// the snapshot.
+    pub async fn back_up(&self, sink: &dyn SnapshotSink) -> Result<String> {
+        let name = format!("{}{:020}{}", NAME_PREFIX, self.now(), NAME_SUFFIX);
+        sink.upload(&name, self.take_snapshot()?).await?;
+
+        let retain = self.config.snapshots.retain;
+        if retain > 0 {
+            let mut names: Vec<_> = sink
+                .list()
+                .await?
+                .into_iter()
+                .filter(|name| is_snapshot_name(name))
+                .collect();
+            names.sort_unstable();
+            let expired = names.len().saturating_sub(retain);
+            for name in &names[..expired] {
+                sink.delete(name).await?;
+            }
+        }
+        Ok(name)
+    }
+
+    /// Starts a background task that backs up to `sink` every
+    /// `snapshots.interval`.
+    pub fn start_snapshots(&self, sink: impl SnapshotSink + 'static) -> JoinHandle<()> {
+        let node = self.clone();
+        tasks::spawn(
+            "snapshots",
+            self.until_stopped(Priority::Maintenance.scope(async move {
+                let mut interval = node.interval(node.config.snapshots.interval);
+                // The first tick is immediate; the node has nothing to back up yet
+                interval.tick().await;
+
+                loop {
+                    interval.tick().await;
+
+                    match node.back_up(&sink).await {
+                        Ok(name) => info!(%name, "storage backed up"),
+                        Err(e) => warn!(error = %e, "failed to back up storage"),
+                    }
+                }
+            })),
+        )
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::{FileSink, SnapshotSink};
+    use crate::{
+        dht::storage::{create_stored_value, serialize_value},
+        helpers::create_test_node,
+    };
+
+    #[tokio::test]
+    async fn test_snapshots_are_restored() {
+        let mut node = create_test_node(8080);
+        node.config.storage.default_ttl = 60;
+        node.store(b"a".to_vec(), b"old".to_vec()).await.unwrap();
+        node.store(b"b".to_vec(), b"kept".to_vec()).await.unwrap();
+        let snapshot = node.take_snapshot().unwrap();
+
+        let restored = create_test_node(8080);
+        let newer = create_stored_value(
+            b"new".to_vec(),
+            restored.addr,
+            false,
+            Some(60),
+            restored.now() + 10,
+        );
+        restored
+            .storage
+            .insert(b"a".to_vec(), serialize_value(&newer).unwrap());
+        // The local copy of "a" is newer, and wins
+        assert_eq!(restored.restore_snapshot(&snapshot).unwrap(), 1);
+        assert_eq!(restored.local_value(b"a").unwrap().data, &b"new"[..]);
+        assert_eq!(restored.local_value(b"b").unwrap().data, &b"kept"[..]);
+
+        assert!(restored.restore_snapshot(&[9]).is_err());
+        assert!(restored.restore_snapshot(&[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_old_snapshots_are_deleted() {
+        let dir = std::env::temp_dir().join(format!("snapshots-{}", std::process::id()));
+        let sink = FileSink::new(&dir);
+        let mut node = create_test_node(8080);
+        node.config.snapshots.retain = 2;
+        node.config.storage.default_ttl = 60;
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        for old in [
+            "snapshot-00000000000000000001.bin",
+            "snapshot-00000000000000000002.bin",
+        ] {
+            sink.upload(old, Default::default()).await.unwrap();
+        }
+        sink.upload("notes.txt", Default::default()).await.unwrap();
+        let name = node.back_up(&sink).await.unwrap();
+
+        let mut names = sink.list().await.unwrap();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "notes.txt",
+                "snapshot-00000000000000000002.bin",
+                name.as_str()
+            ]
+        );
+        let restored = create_test_node(8080);
+        let snapshot = sink.download(&name).await.unwrap();
+        assert_eq!(restored.restore_snapshot(&snapshot).unwrap(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
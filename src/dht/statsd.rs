@@ -0,0 +1,162 @@
+//! Push-based metrics export for environments without a scraper.
+//!
+//! [`DhtNode::start_statsd_exporter`] sends the node's [`DhtStats`] to a
+//! StatsD daemon over UDP every `statsd.interval`, one datagram per push with
+//! one metric per line:
+//!
+//! - counters (`store_ops`, `rpc_failures`, ...) as `|c` deltas since the
+//!   previous push
+//! - `known_peers`, `storage_size`, `storage_bytes` and `outbox_size` as
+//!   `|g` gauges
+//!
+//! Every name is prefixed with `statsd.prefix`, e.g. `dht.store_ops:3|c`.
+
+use std::{fmt::Write, net::SocketAddr};
+
+use anyhow::{Context, Result};
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tracing::debug;
+
+use crate::dht::{DhtNode, metrics::DhtStats, tasks};
+
+impl DhtNode {
+    /// Pushes the node's stats to the StatsD daemon at `addr` until the
+    /// returned task is aborted or the node shuts down.
+    pub async fn start_statsd_exporter(&self, addr: SocketAddr) -> Result<JoinHandle<()>> {
+        let bind_addr: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .context("Failed to bind StatsD socket")?;
+        socket
+            .connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect to StatsD at {}", addr))?;
+
+        let node = self.clone();
+        Ok(tasks::spawn(
+            "statsd-exporter",
+            self.until_stopped(async move {
+                let mut interval = node.interval(node.config.statsd.interval);
+                let mut previous: Option<DhtStats> = None;
+
+                loop {
+                    interval.tick().await;
+
+                    let stats = node.get_stats();
+                    let payload =
+                        statsd_payload(&node.config.statsd.prefix, previous.as_ref(), &stats);
+                    if let Err(e) = socket.send(payload.as_bytes()).await {
+                        debug!(%addr, error = %e, "failed to push stats");
+                    }
+                    previous = Some(stats);
+                }
+            }),
+        ))
+    }
+}
+
+/// Formats `stats` as StatsD lines, with counters relative to `previous`.
+fn statsd_payload(prefix: &str, previous: Option<&DhtStats>, stats: &DhtStats) -> String {
+    let counter = |select: fn(&DhtStats) -> u64| {
+        let current = select(stats);
+        match previous.map(select) {
+            // A lower total means the metrics were reset in between
+            Some(last) if last <= current => current - last,
+            _ => current,
+        }
+    };
+
+    let counters = [
+        ("store_ops", counter(|s| s.store_ops)),
+        ("store_success", counter(|s| s.store_success)),
+        ("find_value_ops", counter(|s| s.find_value_ops)),
+        ("find_value_success", counter(|s| s.find_value_success)),
+        ("rpc_requests", counter(|s| s.rpc_requests)),
+        ("rpc_failures", counter(|s| s.rpc_failures)),
+        ("storage_evictions", counter(|s| s.storage_evictions)),
+        ("expired_entries", counter(|s| s.expired_entries)),
+        ("replication_attempts", counter(|s| s.replication_attempts)),
+        (
+            "replication_successes",
+            counter(|s| s.replication_successes),
+        ),
+        ("peers_evicted", counter(|s| s.peers_evicted)),
+        ("health_checks", counter(|s| s.health_checks)),
+        (
+            "health_check_failures",
+            counter(|s| s.health_check_failures),
+        ),
+        ("rpcs_shed", counter(|s| s.rpcs_shed)),
+        (
+            "requests_deduplicated",
+            counter(|s| s.requests_deduplicated),
+        ),
+        (
+            "hot_key_requests_shed",
+            counter(|s| s.hot_key_requests_shed),
+        ),
+        ("lookup_cache_hits", counter(|s| s.lookup_cache_hits)),
+    ];
+    let gauges = [
+        ("known_peers", stats.known_peers),
+        ("storage_size", stats.storage_size),
+        ("storage_bytes", stats.storage_bytes),
+        ("outbox_size", stats.outbox_size),
+    ];
+
+    let mut payload = String::new();
+    for (name, value) in counters {
+        let _ = writeln!(payload, "{}.{}:{}|c", prefix, name, value);
+    }
+    for (name, value) in gauges {
+        let _ = writeln!(payload, "{}.{}:{}|g", prefix, name, value);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod statsd_tests {
+    use std::time::Duration;
+
+    use tokio::{net::UdpSocket, time::timeout};
+
+    use crate::helpers::create_test_node;
+
+    #[tokio::test]
+    async fn test_exporter_pushes_counter_deltas() {
+        let mut node = create_test_node(8090);
+        node.config.statsd.interval = Duration::from_millis(20);
+
+        let daemon = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let exporter = node
+            .start_statsd_exporter(daemon.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let first = receive(&daemon).await;
+        assert!(first.contains("dht.store_ops:0|c\n"));
+        assert!(first.contains("dht.storage_size:0|g\n"));
+
+        node.store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+
+        while !receive(&daemon).await.contains("dht.store_ops:1|c\n") {}
+        assert!(receive(&daemon).await.contains("dht.store_ops:0|c\n"));
+
+        exporter.abort();
+    }
+
+    async fn receive(daemon: &UdpSocket) -> String {
+        let mut buf = [0u8; 2048];
+        let len = timeout(Duration::from_secs(1), daemon.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+}
@@ -1,63 +1,359 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Mutex,
+};
 
 use anyhow::Context;
+use bytes::Bytes;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
-use crate::{dht::DhtNode, helpers::now};
+use tracing::debug;
 
-pub(super) fn serialize_value(value: &StoredValue) -> anyhow::Result<Vec<u8>> {
-    bincode::serialize(value).context("Failed to serialize stored value")
+use crate::dht::{DhtNode, events::DhtEvent, record::RecordSignature};
+
+/// Tag of the encoding below, in the first byte of every encoded value
+pub(super) const VALUE_FORMAT: u8 = 1;
+/// Format tag, version, expiration and flags
+const HEADER_LEN: usize = 1 + 8 + 8 + 1;
+
+const FLAG_REPLICA: u8 = 1;
+const FLAG_EXPIRES: u8 = 1 << 1;
+const FLAG_HINTED: u8 = 1 << 2;
+
+/// Local writes remembered to order values of the same version on eviction
+const RECENT_WRITES: usize = 1024;
+
+/// Keys written locally last, oldest first.
+///
+/// Versions are whole seconds, so they cannot tell apart the values written
+/// within the same second; this can, for the last [`RECENT_WRITES`] writes.
+#[derive(Default)]
+pub(super) struct RecentWrites(Mutex<VecDeque<Vec<u8>>>);
+
+impl RecentWrites {
+    fn record(&self, key: &[u8]) {
+        let mut keys = self.0.lock().unwrap();
+        if keys.len() == RECENT_WRITES {
+            keys.pop_front();
+        }
+        keys.push_back(key.to_vec());
+    }
+
+    /// Position of each key's last write, higher for later ones.
+    fn order(&self) -> HashMap<Vec<u8>, usize> {
+        let keys = self.0.lock().unwrap();
+        keys.iter()
+            .enumerate()
+            .map(|(position, key)| (key.clone(), position + 1))
+            .collect()
+    }
+}
+
+/// The fixed-size start of an encoded [`StoredValue`], enough for validity
+/// and version checks without decoding the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueHeader {
+    pub version: u64,
+    pub expiration: Option<u64>,
+    pub is_replica: bool,
+    /// Whether the value is a transient replica, see [`StoredValue::hinted_for`]
+    pub is_hinted: bool,
+}
+
+impl ValueHeader {
+    pub fn is_valid(&self, current_time: u64) -> bool {
+        self.expiration.is_none_or(|e| e > current_time)
+    }
+}
+
+/// The rest of a [`StoredValue`], encoded with bincode after the header.
+#[derive(Serialize)]
+struct BodyRef<'a> {
+    data: &'a Bytes,
+    last_node: &'a SocketAddr,
+    original_nodes: &'a [SocketAddr],
+    hinted_for: &'a Option<SocketAddr>,
+    record: &'a Option<RecordSignature>,
+    token: &'a Option<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct Body {
+    data: Bytes,
+    last_node: SocketAddr,
+    original_nodes: Vec<SocketAddr>,
+    hinted_for: Option<SocketAddr>,
+    record: Option<RecordSignature>,
+    token: Option<Vec<u8>>,
+}
+
+pub(super) fn serialize_value(value: &StoredValue) -> anyhow::Result<Bytes> {
+    let body = BodyRef {
+        data: &value.data,
+        last_node: &value.last_node,
+        original_nodes: &value.original_nodes,
+        hinted_for: &value.hinted_for,
+        record: &value.record,
+        token: &value.token,
+    };
+    let body_len =
+        bincode::serialized_size(&body).context("Failed to serialize stored value")? as usize;
+
+    let mut flags = 0;
+    if value.is_replica {
+        flags |= FLAG_REPLICA;
+    }
+    if value.expiration.is_some() {
+        flags |= FLAG_EXPIRES;
+    }
+    if value.hinted_for.is_some() {
+        flags |= FLAG_HINTED;
+    }
+
+    let mut encoded = Vec::with_capacity(HEADER_LEN + body_len);
+    encoded.push(VALUE_FORMAT);
+    encoded.extend_from_slice(&value.version.to_be_bytes());
+    encoded.extend_from_slice(&value.expiration.unwrap_or(0).to_be_bytes());
+    encoded.push(flags);
+    bincode::serialize_into(&mut encoded, &body).context("Failed to serialize stored value")?;
+    Ok(encoded.into())
+}
+
+/// Decodes only the header of an encoded [`StoredValue`].
+pub(super) fn decode_header(data: &[u8]) -> anyhow::Result<ValueHeader> {
+    let header = data
+        .get(..HEADER_LEN)
+        .context("Stored value is shorter than its header")?;
+    anyhow::ensure!(
+        header[0] == VALUE_FORMAT,
+        "Unknown stored value format {}",
+        header[0]
+    );
+
+    let u64_at = |at: usize| u64::from_be_bytes(header[at..at + 8].try_into().unwrap());
+    let flags = header[17];
+    Ok(ValueHeader {
+        version: u64_at(1),
+        expiration: (flags & FLAG_EXPIRES != 0).then(|| u64_at(9)),
+        is_replica: flags & FLAG_REPLICA != 0,
+        is_hinted: flags & FLAG_HINTED != 0,
+    })
 }
 
 pub(super) fn deserialize_value(data: &[u8]) -> anyhow::Result<StoredValue> {
-    bincode::deserialize(data).context("Failed to deserialize stored value in storage")
+    let header = decode_header(data)?;
+    let body: Body = bincode::deserialize(&data[HEADER_LEN..])
+        .context("Failed to deserialize stored value in storage")?;
+
+    Ok(StoredValue {
+        data: body.data,
+        version: header.version,
+        last_node: body.last_node,
+        is_replica: header.is_replica,
+        expiration: header.expiration,
+        original_nodes: body.original_nodes,
+        hinted_for: body.hinted_for,
+        record: body.record,
+        token: body.token,
+    })
+}
+
+/// Shortens `ttl` by a random amount of up to `percent` of it.
+pub(super) fn jitter_ttl(ttl: u64, percent: u8) -> u64 {
+    let spread = ttl.saturating_mul(u64::from(percent.min(100))) / 100;
+    if spread == 0 {
+        return ttl;
+    }
+    ttl - rand::thread_rng().gen_range(0..=spread)
 }
 
 pub(super) fn create_stored_value(
-    data: Vec<u8>,
+    data: impl Into<Bytes>,
     addr: SocketAddr,
     is_replica: bool,
     ttl: Option<u64>,
+    now: u64,
 ) -> StoredValue {
     StoredValue {
-        data,
-        version: now(),
+        data: data.into(),
+        version: now,
         last_node: addr,
         is_replica,
-        expiration: ttl.map(|t| now() + t),
+        expiration: ttl.map(|t| now + t),
         original_nodes: if is_replica { vec![] } else { vec![addr] },
+        hinted_for: None,
+        record: None,
+        token: None,
     }
 }
 
 pub(super) fn find_in_local_storage(
     node: &DhtNode,
-    found_values: &mut Vec<StoredValue>,
+    found_values: &mut Vec<(SocketAddr, StoredValue)>,
     key: Vec<u8>,
 ) {
-    if let Some(value) = node.storage.get(&key) {
-        if let Ok(stored) = deserialize_value(&value) {
-            let current_time = now();
-            if stored.is_valid(current_time) {
-                found_values.push(stored);
-            } else {
-                node.storage.remove(&key);
-            }
+    let Some(value) = node.storage.get(&key) else {
+        return;
+    };
+
+    match decode_header(&value) {
+        Ok(header) if !header.is_valid(node.now()) => {
+            node.storage.remove(&key);
         }
+        Ok(_) => found_values.extend(deserialize_value(&value).ok().map(|v| (node.addr, v))),
+        Err(_) => {}
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StoredValue {
-    pub data: Vec<u8>,
+    pub data: Bytes,
     pub version: u64,
     pub last_node: SocketAddr,
     pub is_replica: bool,
     pub expiration: Option<u64>,
     pub original_nodes: Vec<SocketAddr>,
+    /// Set on transient replicas written in place of an unreachable node;
+    /// the value is handed off to that node once it is reachable again.
+    pub hinted_for: Option<SocketAddr>,
+    /// Sequence number and publisher's signature, on the values of
+    /// [records](crate::dht::record)
+    pub record: Option<RecordSignature>,
+    /// Capability token authorizing the write, see [`authz`](crate::dht::authz)
+    pub token: Option<Vec<u8>>,
 }
 
 impl StoredValue {
     pub fn is_valid(&self, current_time: u64) -> bool {
-        self.expiration.map_or(true, |e| e > current_time)
+        self.expiration.is_none_or(|e| e > current_time)
+    }
+}
+
+/// A value held in the node's local storage, see [`DhtNode::local_entries`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalEntry {
+    pub key: Vec<u8>,
+    /// Size of the value in bytes
+    pub size: usize,
+    pub version: u64,
+    /// Seconds until the value expires, `None` if it never does
+    pub ttl_remaining: Option<u64>,
+    /// Held as a replica of another node's write
+    pub is_replica: bool,
+    /// Node a transient replica is waiting to be handed off to
+    pub hinted_for: Option<SocketAddr>,
+}
+
+impl DhtNode {
+    /// Lists the unexpired values held locally whose key starts with
+    /// `prefix`, ordered by key.
+    ///
+    /// Only this node's storage is read; no peer is contacted.
+    pub fn local_entries(&self, prefix: &[u8]) -> Vec<LocalEntry> {
+        let current_time = self.now();
+        let mut entries: Vec<LocalEntry> = self
+            .storage
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .filter_map(|(key, value)| {
+                let stored = deserialize_value(&value).ok()?;
+                stored.is_valid(current_time).then(|| LocalEntry {
+                    key,
+                    size: stored.data.len(),
+                    version: stored.version,
+                    ttl_remaining: stored.expiration.map(|e| e - current_time),
+                    is_replica: stored.is_replica,
+                    hinted_for: stored.hinted_for,
+                })
+            })
+            .collect();
+
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    /// Returns the local copy of `key` with its metadata, `None` if there is
+    /// none or it has expired.
+    pub fn local_value(&self, key: &[u8]) -> Option<StoredValue> {
+        let stored = deserialize_value(&self.storage.get(key)?).ok()?;
+        stored.is_valid(self.now()).then_some(stored)
+    }
+
+    /// Whether a value of `size` bytes with its key could be stored at all
+    /// under `storage.max_bytes`.
+    pub(super) fn fits_storage(&self, size: usize) -> bool {
+        let max_bytes = self.config.storage.max_bytes;
+        max_bytes == 0 || size <= max_bytes
+    }
+
+    /// Evicts values while the storage holds more than `storage.max_entries`
+    /// values or `storage.max_bytes` bytes. Returns the number evicted.
+    ///
+    /// Undecodable values go first, then replicas, then this node's own
+    /// values, oldest version first within each, and least recently written
+    /// first within a version. Once over a limit, values are evicted down to
+    /// 90% of it, so that a full node does not go through its storage again
+    /// on every write.
+    pub fn evict_to_fit(&self) -> usize {
+        self.evict_sparing(None)
+    }
+
+    /// Records a local write of `key`, then evicts as
+    /// [`DhtNode::evict_to_fit`] does, sparing `key` itself.
+    pub(super) fn evict_after_write(&self, key: &[u8]) -> usize {
+        self.recent_writes.record(key);
+        self.evict_sparing(Some(key))
+    }
+
+    fn evict_sparing(&self, spared: Option<&[u8]>) -> usize {
+        let limits = &self.config.storage;
+        let mut entries = self.storage.len();
+        let mut bytes = self.storage.size_bytes();
+        let over = |limit: usize, used: usize| limit != 0 && used > limit;
+        if !over(limits.max_entries, entries) && !over(limits.max_bytes, bytes) {
+            return 0;
+        }
+
+        let written = self.recent_writes.order();
+        let mut candidates: Vec<_> = self
+            .storage
+            .iter()
+            .filter(|(key, _)| spared != Some(key.as_slice()))
+            .map(|(key, value)| {
+                let rank = decode_header(&value).ok().map(|header| {
+                    let recency = written.get(&key).copied().unwrap_or(0);
+                    (!header.is_replica, header.version, recency)
+                });
+                (rank, key.len() + value.len(), key)
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|(rank, _, _)| *rank);
+
+        let low_water = |limit: usize| limit - limit / 10;
+        let mut evicted = 0;
+        for (_, size, key) in candidates {
+            if !over(low_water(limits.max_entries), entries)
+                && !over(low_water(limits.max_bytes), bytes)
+            {
+                break;
+            }
+            if self.storage.remove(&key).is_some() {
+                entries -= 1;
+                bytes = bytes.saturating_sub(size);
+                evicted += 1;
+                self.emit(|| DhtEvent::ValueEvicted { key });
+            }
+        }
+
+        if evicted > 0 {
+            debug!(
+                evicted,
+                entries, bytes, "evicted values to fit storage limits"
+            );
+            self.metrics.add_storage_evictions(evicted as u64);
+        }
+        evicted
     }
 }
@@ -0,0 +1,33 @@
+//! Spawning the node's tasks under a name.
+//!
+//! Every task a node runs in the background, from the maintenance loop to
+//! the handler of each inbound connection, is spawned through [`spawn`]
+//! with a name saying what it is. With the `console` feature, in a build
+//! with `RUSTFLAGS="--cfg tokio_unstable"`, tokio-console lists the tasks
+//! under these names, so one that leaks or spins can be told apart from
+//! the others. Otherwise the name is not kept.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+/// Spawns `task` on the current runtime, named `name` for tokio-console.
+pub fn spawn<F>(name: &'static str, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(task)
+            .expect("spawning on the current runtime cannot fail")
+    }
+
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(task)
+    }
+}
@@ -0,0 +1,208 @@
+//! Storing Rust values instead of raw bytes.
+//!
+//! [`DhtNode::store_typed`] and [`DhtNode::get_typed`] wrap the byte-level
+//! [`DhtNode::store`] and [`DhtNode::find_value`], encoding values with
+//! bincode. Values can carry a schema version so readers notice data written
+//! by an older or newer version of their type instead of misreading it.
+
+use std::fmt;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::dht::DhtNode;
+
+/// What typed values are stored as.
+#[derive(Serialize, Deserialize)]
+struct TypedValue {
+    schema: Option<u32>,
+    payload: Vec<u8>,
+}
+
+/// A typed value was written with another schema version than the one
+/// asked for.
+///
+/// Returned inside the [`anyhow::Error`] of
+/// [`DhtNode::get_typed_with_schema`], from which it can be downcast to
+/// migrate old data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaMismatch {
+    pub expected: u32,
+    /// Version the value was written with, `None` if it has none
+    pub found: Option<u32>,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.found {
+            Some(found) => write!(
+                f,
+                "Expected schema version {}, found {}",
+                self.expected, found
+            ),
+            None => write!(f, "Expected schema version {}, found none", self.expected),
+        }
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+impl DhtNode {
+    /// Serializes `value` and stores it, see [`DhtNode::store`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use serde::{Deserialize, Serialize};
+    /// use rust_p2p_node::dht::DhtNode;
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Profile {
+    ///     name: String,
+    ///     age: u8,
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> anyhow::Result<()> {
+    ///     let node = DhtNode::new("127.0.0.1:8080".parse()?, None);
+    ///     let profile = Profile { name: "ada".into(), age: 36 };
+    ///     node.store_typed(b"profile:ada".to_vec(), &profile).await?;
+    ///
+    ///     let profile: Option<Profile> = node.get_typed(b"profile:ada".to_vec()).await?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub async fn store_typed<T: Serialize + ?Sized>(&self, key: Vec<u8>, value: &T) -> Result<()> {
+        self.store(key, encode(value, None)?).await
+    }
+
+    /// Serializes `value` and stores it tagged with schema version `schema`,
+    /// for reading back with [`DhtNode::get_typed_with_schema`].
+    pub async fn store_typed_with_schema<T: Serialize + ?Sized>(
+        &self,
+        key: Vec<u8>,
+        value: &T,
+        schema: u32,
+    ) -> Result<()> {
+        self.store(key, encode(value, Some(schema))?).await
+    }
+
+    /// Looks up a value written by [`DhtNode::store_typed`] and deserializes
+    /// it, whatever its schema version.
+    ///
+    /// Fails if the value was not stored as a typed value or does not
+    /// deserialize as a `T`.
+    pub async fn get_typed<T: DeserializeOwned>(&self, key: Vec<u8>) -> Result<Option<T>> {
+        let Some(value) = self.find_value(key).await else {
+            return Ok(None);
+        };
+        let typed = decode_envelope(&value)?;
+        decode_payload(&typed.payload).map(Some)
+    }
+
+    /// Looks up a value like [`DhtNode::get_typed`], failing with a
+    /// [`SchemaMismatch`] if it was not written with schema version
+    /// `schema`.
+    pub async fn get_typed_with_schema<T: DeserializeOwned>(
+        &self,
+        key: Vec<u8>,
+        schema: u32,
+    ) -> Result<Option<T>> {
+        let Some(value) = self.find_value(key).await else {
+            return Ok(None);
+        };
+        let typed = decode_envelope(&value)?;
+        if typed.schema != Some(schema) {
+            return Err(SchemaMismatch {
+                expected: schema,
+                found: typed.schema,
+            }
+            .into());
+        }
+        decode_payload(&typed.payload).map(Some)
+    }
+}
+
+fn encode<T: Serialize + ?Sized>(value: &T, schema: Option<u32>) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(value).context("Failed to serialize value")?;
+    Ok(bincode::serialize(&TypedValue { schema, payload })?)
+}
+
+fn decode_envelope(value: &[u8]) -> Result<TypedValue> {
+    bincode::deserialize(value).context("Value was not stored as a typed value")
+}
+
+fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T> {
+    bincode::deserialize(payload).context("Failed to deserialize value")
+}
+
+#[cfg(test)]
+mod typed_tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::SchemaMismatch;
+    use crate::helpers::create_test_node;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        age: u8,
+    }
+
+    #[tokio::test]
+    async fn test_typed_values_round_trip() {
+        let node = create_test_node(8204);
+        let profile = Profile {
+            name: "ada".into(),
+            age: 36,
+        };
+
+        node.store_typed(b"ada".to_vec(), &profile).await.unwrap();
+        assert_eq!(
+            node.get_typed::<Profile>(b"ada".to_vec()).await.unwrap(),
+            Some(profile)
+        );
+        assert_eq!(
+            node.get_typed::<Profile>(b"missing".to_vec())
+                .await
+                .unwrap(),
+            None
+        );
+
+        node.store(b"raw".to_vec(), b"bytes".to_vec())
+            .await
+            .unwrap();
+        assert!(node.get_typed::<Profile>(b"raw".to_vec()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_schema_versions_are_checked() {
+        let node = create_test_node(8205);
+        node.store_typed_with_schema(b"count".to_vec(), &7u32, 2)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            node.get_typed_with_schema::<u32>(b"count".to_vec(), 2)
+                .await
+                .unwrap(),
+            Some(7)
+        );
+        assert_eq!(
+            node.get_typed::<u32>(b"count".to_vec()).await.unwrap(),
+            Some(7)
+        );
+
+        let error = node
+            .get_typed_with_schema::<u32>(b"count".to_vec(), 3)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<SchemaMismatch>(),
+            Some(&SchemaMismatch {
+                expected: 3,
+                found: Some(2),
+            })
+        );
+    }
+}
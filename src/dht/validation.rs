@@ -0,0 +1,108 @@
+//! Application rules on stored values.
+//!
+//! A [`RecordValidator`] checks every value a node is asked to store, its
+//! own writes as well as `Store` RPCs from other nodes, so applications can
+//! enforce key formats, value schemas or sizes at the DHT layer. Values it
+//! rejects are not stored. [`AcceptAll`] is used unless another validator is
+//! set with
+//! [`DhtNode::with_record_validator`](crate::dht::DhtNode::with_record_validator);
+//! closures taking the key and the value work as validators too.
+
+use anyhow::Result;
+
+use crate::dht::storage::StoredValue;
+
+/// Decides whether a value may be stored under a key.
+///
+/// # Examples
+///
+/// ```
+/// use anyhow::{Result, bail};
+/// use rust_p2p_node::dht::{storage::StoredValue, validation::RecordValidator};
+///
+/// /// Only accepts small JSON documents under `doc/`.
+/// struct JsonDocuments;
+///
+/// impl RecordValidator for JsonDocuments {
+///     fn validate(&self, key: &[u8], value: &StoredValue) -> Result<()> {
+///         if !key.starts_with(b"doc/") {
+///             bail!("Not a document key");
+///         }
+///         if value.data.len() > 4096 {
+///             bail!("Document too large");
+///         }
+///         serde_json::from_slice::<serde_json::Value>(&value.data)?;
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait RecordValidator: Send + Sync {
+    /// Returns why `value` may not be stored under `key`, if it may not.
+    fn validate(&self, key: &[u8], value: &StoredValue) -> Result<()>;
+}
+
+impl<F> RecordValidator for F
+where
+    F: Fn(&[u8], &StoredValue) -> Result<()> + Send + Sync,
+{
+    fn validate(&self, key: &[u8], value: &StoredValue) -> Result<()> {
+        self(key, value)
+    }
+}
+
+/// Accepts every value.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AcceptAll;
+
+impl RecordValidator for AcceptAll {
+    fn validate(&self, _key: &[u8], _value: &StoredValue) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use anyhow::{Result, bail};
+
+    use crate::{
+        dht::{
+            rpc::DhtRpc,
+            storage::{StoredValue, create_stored_value, serialize_value},
+        },
+        helpers::create_test_node,
+    };
+
+    fn small_values(_key: &[u8], value: &StoredValue) -> Result<()> {
+        if value.data.len() > 4 {
+            bail!("Value of {} bytes is too large", value.data.len());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_values_are_not_stored() {
+        let node = create_test_node(8080).with_record_validator(small_values);
+
+        for (key, data) in [
+            (b"small", b"tiny".to_vec()),
+            (b"large", b"too large".to_vec()),
+        ] {
+            let stored = create_stored_value(data, node.addr, false, None, node.now());
+            node.handle_rpc(DhtRpc::Store(
+                key.to_vec(),
+                serialize_value(&stored).unwrap(),
+            ))
+            .await;
+        }
+        assert!(node.storage.get(b"small").is_some());
+        assert!(node.storage.get(b"large").is_none());
+        assert_eq!(node.get_stats().rpc_failures, 1);
+
+        let error = node
+            .store(b"own".to_vec(), b"too large".to_vec())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Value of 9 bytes is too large");
+        assert!(node.storage.get(b"own").is_none());
+    }
+}
@@ -0,0 +1,149 @@
+//! Reaching the DHT from a browser.
+//!
+//! Browsers can neither open TCP connections nor run a node, so a web app
+//! talks to a gateway node instead: one serving
+//! [`DhtNode::serve_gateway`](crate::dht::DhtNode::serve_gateway), which
+//! reads and writes the DHT on its behalf. Each RPC travels as one binary
+//! WebSocket message, encoded like the node's own.
+//!
+//! Building with `--no-default-features --features wasm-client` leaves the
+//! node out, so that the crate compiles to `wasm32-unknown-unknown`.
+
+use std::{fmt::Display, pin::pin, time::Duration};
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::{
+    SinkExt, StreamExt,
+    future::{self, Either},
+};
+use gloo_net::websocket::{Message, futures::WebSocket};
+
+use crate::dht::rpc::DhtRpc;
+
+/// Client of a gateway node, over WebSocket.
+///
+/// Like [`DhtClient`](crate::dht::client::DhtClient), every call opens a
+/// connection, sends one RPC and waits for the answer.
+///
+/// # Examples
+///
+/// ```ignore
+/// use rust_p2p_node::dht::web::WebClient;
+///
+/// async fn remember(name: &str) -> anyhow::Result<()> {
+///     let client = WebClient::new("wss://gateway.example.com:8090");
+///     client.store(b"name".to_vec(), name.as_bytes().to_vec()).await?;
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct WebClient {
+    url: String,
+    timeout: Duration,
+}
+
+impl WebClient {
+    /// Creates a client of the gateway at `url`, a `ws://` or `wss://` URL.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Gives up on calls that take longer than `timeout`. 5s by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Checks that the gateway answers.
+    pub async fn ping(&self) -> Result<()> {
+        match self.call(DhtRpc::Ping).await? {
+            DhtRpc::Pong => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Stores a value through the gateway. Returns the version written.
+    pub async fn store(&self, key: Vec<u8>, value: impl Into<Bytes>) -> Result<u64> {
+        self.store_authorized(key, value.into(), None).await
+    }
+
+    /// Stores a value through the gateway with a `token` authorizing the write,
+    /// obtained from an issuer, see [`authz`](crate::dht::authz). Returns
+    /// the version written.
+    pub async fn store_with_token(
+        &self,
+        key: Vec<u8>,
+        value: impl Into<Bytes>,
+        token: Vec<u8>,
+    ) -> Result<u64> {
+        self.store_authorized(key, value.into(), Some(token)).await
+    }
+
+    async fn store_authorized(
+        &self,
+        key: Vec<u8>,
+        value: Bytes,
+        token: Option<Vec<u8>>,
+    ) -> Result<u64> {
+        match self.call(DhtRpc::ClientStore(key, value, token)).await? {
+            DhtRpc::ClientStoreResponse(result) => result.map_err(|e| anyhow!(e)),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Looks a value up through the gateway.
+    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Bytes>> {
+        match self.call(DhtRpc::ClientGet(key)).await? {
+            DhtRpc::ClientGetResponse(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    async fn call(&self, request: DhtRpc) -> Result<DhtRpc> {
+        let request = bincode::serialize(&request)?;
+        let exchange = pin!(self.exchange(request));
+        let deadline = pin!(gloo_timers::future::sleep(self.timeout));
+
+        match future::select(exchange, deadline).await {
+            Either::Left((response, _)) => Ok(bincode::deserialize(&response?)?),
+            Either::Right(_) => Err(anyhow!(
+                "No answer from {} within {:?}",
+                self.url,
+                self.timeout
+            )),
+        }
+    }
+
+    /// Sends one request message and waits for the response message.
+    async fn exchange(&self, request: Vec<u8>) -> Result<Vec<u8>> {
+        // The browser's errors hold JavaScript values, which cannot be sent
+        // across threads as anyhow requires
+        let failed = |e: &dyn Display| anyhow!("Request to {} failed: {}", self.url, e);
+        let mut socket = WebSocket::open(&self.url).map_err(|e| failed(&e))?;
+        socket
+            .send(Message::Bytes(request))
+            .await
+            .map_err(|e| failed(&e))?;
+
+        let response = match socket.next().await {
+            Some(Ok(Message::Bytes(response))) => response,
+            Some(Ok(Message::Text(_))) => return Err(anyhow!("Text message from {}", self.url)),
+            Some(Err(e)) => return Err(failed(&e)),
+            None => return Err(anyhow!("{} closed the connection", self.url)),
+        };
+        let _ = socket.close(None, None);
+        Ok(response)
+    }
+}
+
+fn unexpected(response: DhtRpc) -> anyhow::Error {
+    anyhow!("Unexpected {} from the gateway", response.name())
+}
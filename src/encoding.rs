@@ -0,0 +1,43 @@
+//! Text forms of binary keys and values on the command line.
+
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum, Serialize, Deserialize)]
+pub enum BytesFormat {
+    /// The text as is
+    #[default]
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl BytesFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            BytesFormat::Utf8 => "utf8",
+            BytesFormat::Hex => "hex",
+            BytesFormat::Base64 => "base64",
+        }
+    }
+
+    pub fn decode(self, text: &str) -> Result<Vec<u8>> {
+        match self {
+            BytesFormat::Utf8 => Ok(text.as_bytes().to_vec()),
+            BytesFormat::Hex => hex::decode(text).context("Invalid hex"),
+            BytesFormat::Base64 => BASE64.decode(text).context("Invalid base64"),
+        }
+    }
+
+    /// Renders `bytes`, or `None` when they are not valid UTF-8 and the
+    /// format is [`BytesFormat::Utf8`].
+    pub fn encode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            BytesFormat::Utf8 => std::str::from_utf8(bytes).ok().map(str::to_string),
+            BytesFormat::Hex => Some(hex::encode(bytes)),
+            BytesFormat::Base64 => Some(BASE64.encode(bytes)),
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Storing whole files under a key.
+//!
+//! Files up to [`CHUNK_SIZE`] are stored as the value itself. Larger ones are
+//! split into chunks stored under `<key>/chunk/<n>`, and the key holds a
+//! [`FileManifest`] prefixed with [`MANIFEST_MAGIC`] that lists them.
+
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow, bail};
+use rust_p2p_node::dht::DhtNode;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+/// Largest file stored as a single value, and the size of every chunk.
+const CHUNK_SIZE: usize = 512 * 1024;
+
+/// Marks a value as the manifest of a chunked file.
+const MANIFEST_MAGIC: &[u8] = b"dht-file-manifest\0";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FileManifest {
+    size: u64,
+    chunks: u32,
+    sha3: [u8; 32],
+}
+
+/// Stores the contents of `path` under `key`, returning the file size.
+pub async fn put_file(node: &DhtNode, key: &str, path: &Path) -> Result<u64> {
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let size = data.len() as u64;
+
+    if data.len() <= CHUNK_SIZE && !data.starts_with(MANIFEST_MAGIC) {
+        node.store(key.as_bytes().to_vec(), data).await?;
+        return Ok(size);
+    }
+
+    let mut chunks = 0;
+    for (index, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        node.store(chunk_key(key, index), chunk.to_vec())
+            .await
+            .with_context(|| format!("Failed to store chunk {}", index))?;
+        chunks += 1;
+    }
+
+    let manifest = FileManifest {
+        size,
+        chunks,
+        sha3: Sha3_256::digest(&data).into(),
+    };
+    let mut value = MANIFEST_MAGIC.to_vec();
+    value.extend(bincode::serialize(&manifest)?);
+    node.store(key.as_bytes().to_vec(), value).await?;
+
+    Ok(size)
+}
+
+/// Fetches the value under `key` into `path`, reassembling chunked files.
+/// Returns the file size.
+pub async fn get_file(node: &DhtNode, key: &str, path: &Path) -> Result<u64> {
+    let value = node
+        .find_value(key.as_bytes().to_vec())
+        .await
+        .ok_or_else(|| anyhow!("Key {:?} not found", key))?;
+
+    let data = match value.strip_prefix(MANIFEST_MAGIC) {
+        Some(manifest) => {
+            let manifest: FileManifest =
+                bincode::deserialize(manifest).context("Corrupt file manifest")?;
+            fetch_chunks(node, key, &manifest).await?
+        }
+        None => value.to_vec(),
+    };
+
+    tokio::fs::write(path, &data)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(data.len() as u64)
+}
+
+async fn fetch_chunks(node: &DhtNode, key: &str, manifest: &FileManifest) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(manifest.size as usize);
+    for index in 0..manifest.chunks as usize {
+        let chunk = node
+            .find_value(chunk_key(key, index))
+            .await
+            .ok_or_else(|| anyhow!("Chunk {} of {} is missing", index, manifest.chunks))?;
+        data.extend(chunk);
+    }
+
+    if data.len() as u64 != manifest.size || Sha3_256::digest(&data)[..] != manifest.sha3 {
+        bail!("Reassembled file does not match its manifest");
+    }
+    Ok(data)
+}
+
+fn chunk_key(key: &str, index: usize) -> Vec<u8> {
+    format!("{}/chunk/{}", key, index).into_bytes()
+}
@@ -1,34 +1,313 @@
 use std::{
+    collections::HashSet,
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::OnceLock,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::{Result, bail};
+use tokio::time::Instant;
+
 use crate::dht::{
-    config::{DhtConfig, ReplicationConfig, StorageConfig}, DhtNode
+    DhtNode,
+    config::{DhtConfig, ReplicationConfig, StorageConfig},
+    node::NodeId,
 };
 
+/// Current Unix time in seconds.
+///
+/// Time is taken from the system clock once, then advanced by tokio's clock,
+/// so that it follows virtual time in tests that pause the clock (see
+/// [`testing`](crate::testing)).
 pub fn now() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
+    static START: OnceLock<(u64, Instant)> = OnceLock::new();
+    let (unix, started) = START.get_or_init(|| {
+        let unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        (unix, Instant::now())
+    });
+    unix + Instant::now().saturating_duration_since(*started).as_secs()
 }
 
-pub fn create_test_node(port: u16) -> DhtNode {
-    let config = DhtConfig {
+/// Short, stable identifier of a key for logs and traces, so raw keys never
+/// end up in telemetry.
+pub fn key_hash(key: &[u8]) -> String {
+    NodeId::new(key).to_string()[..16].to_string()
+}
+
+/// Configuration of the nodes of [`create_test_node`] and
+/// [`TestCluster`](crate::testing::TestCluster): short TTLs and a replication
+/// factor of 5.
+pub fn test_config() -> DhtConfig {
+    DhtConfig {
         replication: ReplicationConfig {
             factor: 5,
             check_interval: Duration::from_secs(60),
             parallelism: 3,
+            check_sample_size: 64,
+            batch_size: 64,
         },
         storage: StorageConfig {
             max_entries: 2048,
+            max_bytes: 64 * 1024 * 1024,
             default_ttl: 1,
+            ttl_jitter_percent: 0,
             expiration_check_interval: 1,
+            max_outbox_entries: 1024,
         },
         ..Default::default()
-    };
+    }
+}
 
+pub fn create_test_node(port: u16) -> DhtNode {
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
-    DhtNode::new(addr, Some(config))
+    DhtNode::new(addr, Some(test_config()))
+}
+
+/// Checks the invariants of a node's routing table, for property tests:
+///
+/// - every peer sits in the bucket matching its distance to the node
+/// - no bucket holds more peers than its capacity
+/// - no peer appears twice
+pub fn check_routing_table(node: &DhtNode) -> Result<()> {
+    let mut seen = HashSet::new();
+    for bucket in node.routing_table.iter() {
+        let (&index, bucket) = bucket.pair();
+        if bucket.len() > bucket.max_size {
+            bail!(
+                "Bucket {} holds {} peers, more than its {}",
+                index,
+                bucket.len(),
+                bucket.max_size
+            );
+        }
+
+        for peer in &bucket.peers {
+            let expected = node.get_bucket_index(&node.id.distance(&peer.id));
+            if expected != index {
+                bail!(
+                    "Peer {} is in bucket {} instead of {}",
+                    peer.addr,
+                    index,
+                    expected
+                );
+            }
+            if !seen.insert(peer.id.clone()) {
+                bail!("Peer {} appears twice", peer.id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// [Proptest](https://docs.rs/proptest) strategies generating the DHT's
+/// types, enabled by the `proptest` feature.
+///
+/// # Examples
+///
+/// ```ignore
+/// use proptest::prelude::*;
+/// use rust_p2p_node::helpers::{check_routing_table, create_test_node, strategies};
+///
+/// proptest! {
+///     #[test]
+///     fn routing_table_stays_consistent(peers in prop::collection::vec(strategies::peer_info(), 0..100)) {
+///         let node = create_test_node(8080);
+///         for peer in peers {
+///             node.add_peer(peer);
+///         }
+///         check_routing_table(&node).unwrap();
+///     }
+/// }
+/// ```
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies {
+    use std::net::{IpAddr, SocketAddr};
+
+    use bytes::Bytes;
+    use ed25519_dalek::Signature;
+    use proptest::{collection::vec, option, prelude::*};
+
+    use crate::dht::{
+        node::NodeId,
+        peer::PeerInfo,
+        rpc::{Consistency, DhtRpc, WriteReport, WriteRequest},
+    };
+
+    /// Keys of 1 to 64 bytes.
+    pub fn key() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 1..=64)
+    }
+
+    /// Values of up to 1 KiB.
+    pub fn value() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..=1024)
+    }
+
+    /// Node IDs spread over the whole keyspace.
+    pub fn node_id() -> impl Strategy<Value = NodeId> {
+        any::<[u8; 32]>().prop_map(|seed| NodeId::new(&seed))
+    }
+
+    /// IPv4 or IPv6 addresses, on any port but 0.
+    pub fn socket_addr() -> impl Strategy<Value = SocketAddr> {
+        (any::<IpAddr>(), 1..=u16::MAX).prop_map(|(ip, port)| SocketAddr::new(ip, port))
+    }
+
+    /// Peers with up to two alternative addresses.
+    pub fn peer_info() -> impl Strategy<Value = PeerInfo> {
+        (
+            node_id(),
+            socket_addr(),
+            vec(socket_addr(), 0..=2),
+            any::<u64>(),
+        )
+            .prop_map(|(id, addr, alt_addrs, last_seen)| PeerInfo {
+                id,
+                addr,
+                alt_addrs,
+                mirror: false,
+                last_seen,
+                public_key: None,
+                signature: None,
+            })
+    }
+
+    /// Write settings a client may ask a coordinating node for.
+    pub fn write_request() -> impl Strategy<Value = WriteRequest> {
+        (
+            option::of(any::<u32>()),
+            option::of(any::<u64>()),
+            prop_oneof![
+                Just(Consistency::Any),
+                Just(Consistency::One),
+                Just(Consistency::Quorum),
+                Just(Consistency::All),
+            ],
+            option::of(value()),
+        )
+            .prop_map(|(replication, ttl, consistency, token)| WriteRequest {
+                replication,
+                ttl,
+                consistency,
+                token,
+            })
+    }
+
+    pub fn write_report() -> impl Strategy<Value = WriteReport> {
+        any::<(u64, u32, u32, u32)>().prop_map(|(version, replicas, acknowledged, required)| {
+            WriteReport {
+                version,
+                replicas,
+                acknowledged,
+                required,
+            }
+        })
+    }
+
+    /// Any RPC message, requests and responses alike.
+    pub fn rpc() -> impl Strategy<Value = DhtRpc> {
+        prop_oneof![
+            Just(DhtRpc::Ping),
+            Just(DhtRpc::Pong),
+            node_id().prop_map(DhtRpc::FindNode),
+            vec(peer_info(), 0..=20).prop_map(DhtRpc::FindNodeResponse),
+            key().prop_map(DhtRpc::FindValue),
+            option::of(value().prop_map(Bytes::from)).prop_map(DhtRpc::FindValueResponse),
+            (key(), value()).prop_map(|(key, value)| DhtRpc::Store(key, value.into())),
+            (
+                key(),
+                any::<u64>(),
+                option::of(value()),
+                option::of(any::<([u8; 32], [u8; 32])>())
+            )
+                .prop_map(|(key, version, token, signature)| DhtRpc::Expire(
+                    key,
+                    version,
+                    token,
+                    signature.map(|(r, s)| Signature::from_components(r, s))
+                )),
+            (key(), value(), option::of(value()))
+                .prop_map(|(key, value, token)| DhtRpc::ClientStore(key, value.into(), token)),
+            prop_oneof![any::<u64>().prop_map(Ok), any::<String>().prop_map(Err)]
+                .prop_map(DhtRpc::ClientStoreResponse),
+            key().prop_map(DhtRpc::ClientGet),
+            option::of(value().prop_map(Bytes::from)).prop_map(DhtRpc::ClientGetResponse),
+            Just(DhtRpc::GetStats),
+            any::<String>().prop_map(DhtRpc::StatsResponse),
+            vec(key().prop_map(DhtRpc::FindValue), 0..=4).prop_map(DhtRpc::Batch),
+            vec(
+                option::of(value().prop_map(Bytes::from)).prop_map(DhtRpc::FindValueResponse),
+                0..=4
+            )
+            .prop_map(DhtRpc::BatchResponse),
+            Just(DhtRpc::Busy),
+            (any::<u64>(), any::<String>(), value(), any::<u8>())
+                .prop_map(|(id, topic, data, hops)| DhtRpc::Gossip(id, topic, data.into(), hops)),
+            (key(), value(), write_request()).prop_map(|(key, value, request)| {
+                DhtRpc::CoordinatedStore(key, value.into(), request)
+            }),
+            prop_oneof![write_report().prop_map(Ok), any::<String>().prop_map(Err)]
+                .prop_map(DhtRpc::CoordinatedStoreResponse),
+            any::<bool>().prop_map(DhtRpc::StoreResponse),
+            (key(), value(), option::of(any::<u64>()))
+                .prop_map(|(key, value, expected)| DhtRpc::StoreIf(key, value.into(), expected)),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod helpers_tests {
+    use proptest::{collection::vec, prelude::*};
+
+    use super::{check_routing_table, create_test_node, strategies};
+    use crate::dht::{kbucket::KBucket, peer::PeerInfo, rpc::DhtRpc};
+
+    proptest! {
+        #[test]
+        fn test_rpcs_survive_encoding(rpc in strategies::rpc()) {
+            let encoded = bincode::serialize(&rpc).unwrap();
+            let decoded: DhtRpc = bincode::deserialize(&encoded).unwrap();
+            prop_assert_eq!(decoded.name(), rpc.name());
+            prop_assert_eq!(bincode::serialize(&decoded).unwrap(), encoded);
+        }
+
+        #[test]
+        fn test_routing_table_stays_consistent(
+            peers in vec(strategies::peer_info(), 0..100),
+            repeated in vec(any::<prop::sample::Index>(), 0..20),
+        ) {
+            let node = create_test_node(8080);
+            for peer in &peers {
+                node.add_peer(peer.clone());
+            }
+            if !peers.is_empty() {
+                for index in repeated {
+                    node.add_peer(index.get(&peers).clone());
+                }
+            }
+            prop_assert!(check_routing_table(&node).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_misplaced_peers_are_reported() {
+        let node = create_test_node(8080);
+        let peer = PeerInfo::new(node.id.clone(), "127.0.0.1:1".parse().unwrap());
+        node.routing_table.insert(
+            0,
+            KBucket {
+                peers: vec![peer],
+                max_size: 20,
+            },
+        );
+
+        let error = check_routing_table(&node).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Peer 127.0.0.1:1 is in bucket 0 instead of 255"
+        );
+    }
 }
@@ -1,2 +1,9 @@
+#[cfg(feature = "compat")]
+pub mod compat;
 pub mod dht;
+#[cfg(feature = "node")]
 pub mod helpers;
+#[cfg(feature = "otel")]
+pub mod telemetry;
+#[cfg(feature = "node")]
+pub mod testing;
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+/// Keeps telemetry exporters alive until the end of `main`.
+pub struct LoggingGuard {
+    filter: LogFilter,
+    #[cfg(feature = "otel")]
+    _otlp: Option<rust_p2p_node::telemetry::OtlpGuard>,
+}
+
+impl LoggingGuard {
+    pub fn filter(&self) -> LogFilter {
+        self.filter.clone()
+    }
+}
+
+/// Changes the log filter of a running process.
+#[derive(Clone)]
+pub struct LogFilter(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilter {
+    /// Replaces the filter with `directives`, in the `RUST_LOG` syntax.
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.0.reload(filter)?;
+        Ok(())
+    }
+
+    /// The filter in effect, in the `RUST_LOG` syntax.
+    pub fn current(&self) -> Result<String> {
+        Ok(self.0.with_current(|filter| filter.to_string())?)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per event
+    Json,
+}
+
+/// Installs the global subscriber.
+///
+/// Events go to stderr so they never mix with command output. `filter` uses
+/// the `RUST_LOG` syntax and falls back to that variable, then to warnings
+/// only.
+///
+/// With the `console` feature, tasks are also reported to tokio-console,
+/// which connects on port 6669 by default (see `TOKIO_CONSOLE_BIND`).
+pub fn init(format: LogFormat, filter: Option<&str>, otlp: bool) -> Result<LoggingGuard> {
+    let filter = match filter {
+        Some(directives) => EnvFilter::try_new(directives)?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn")),
+    };
+
+    let output = match format {
+        LogFormat::Text => fmt::layer().with_writer(std::io::stderr).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(std::io::stderr).boxed(),
+    };
+
+    let (filter, handle) = reload::Layer::new(filter);
+    let filter_handle = LogFilter(handle);
+    let registry = tracing_subscriber::registry().with(output.with_filter(filter));
+    // Task events reach tokio-console whatever the log filter
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+
+    #[cfg(feature = "otel")]
+    {
+        use tracing_subscriber::filter::LevelFilter;
+
+        // Spans are exported independently of the log filter
+        let (layer, guard) = if otlp {
+            let (layer, guard) = rust_p2p_node::telemetry::otlp_layer("rust_p2p_node")?;
+            (Some(layer.with_filter(LevelFilter::INFO)), Some(guard))
+        } else {
+            (None, None)
+        };
+        registry.with(layer).try_init()?;
+        Ok(LoggingGuard {
+            filter: filter_handle,
+            _otlp: guard,
+        })
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = otlp;
+        registry.try_init()?;
+        Ok(LoggingGuard {
+            filter: filter_handle,
+        })
+    }
+}
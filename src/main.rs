@@ -1,90 +1,559 @@
+mod admin;
 mod app;
+mod bench;
 mod cli;
+mod cluster;
+mod control;
+mod dashboard;
+mod encoding;
+mod files;
+mod logging;
+mod repl;
+mod settings;
 
+use std::{io::Write, path::PathBuf, pin::pin, process::ExitCode, time::Duration};
+
+use anyhow::{Context, anyhow};
 use clap::Parser;
-use rust_p2p_node::dht::{DhtNode, config::DhtConfig};
-use tokio::sync::mpsc;
+use futures::{Stream, StreamExt};
+use rust_p2p_node::dht::{
+    DhtNode, identity::Identity, migrate::migrate_identity_file, rpc::WriteRequest,
+    snapshot::FileSink, tasks,
+};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::oneshot,
+    time::timeout,
+};
+use tracing::{info, warn};
 
 use crate::{
-    app::{AppCommand, DhtApp},
+    admin::Admin,
+    app::{AppCommand, AppHandle, CommandFailed, DhtApp},
+    bench::BenchOptions,
     cli::{Cli, Commands},
+    control::ControlClient,
+    encoding::BytesFormat,
+    repl::Repl,
+    settings::Settings,
 };
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
+    let settings = Settings::load(&cli)?;
 
-    let node = DhtNode::new(cli.addr, Some(DhtConfig::default()));
-    node.start_maintenance_service().await;
-
-    let (command_sender, command_receiver) = mpsc::channel(32);
+    #[cfg(feature = "otel")]
+    let otlp = cli.otlp;
+    #[cfg(not(feature = "otel"))]
+    let otlp = false;
+    let logging = logging::init(cli.log_format, settings.log_filter.as_deref(), otlp)?;
 
-    let app_handle = tokio::spawn(async move {
-        let app = DhtApp::new(node, command_receiver);
-        app.run().await;
-    });
+    let raw = matches!(
+        cli.command,
+        Some(Commands::Get {
+            raw: true,
+            output: None,
+            ..
+        })
+    );
+    let json = cli.json || raw;
+    let mode = match cli.command {
+        None => Mode::Interactive,
+        Some(Commands::Daemon) => Mode::Daemon,
+        Some(Commands::Watch { key }) => Mode::Watch(key),
+        Some(Commands::Dashboard) => Mode::Dashboard,
+        Some(Commands::DevCluster { nodes }) => Mode::DevCluster(nodes),
+        Some(Commands::Migrate) => return migrate(&settings),
+        Some(command) => Mode::Command(app_command(command)?),
+    };
 
-    if let Some(command) = cli.command {
-        match command {
-            Commands::Store { key, value } => {
-                command_sender.send(AppCommand::Store(key, value)).await?;
+    // Hand the command to a running daemon rather than starting a node for it
+    if let Mode::Command(_) | Mode::Watch(_) | Mode::Dashboard = &mode
+        && let Some(control) = &settings.control
+        && let Ok(mut client) = ControlClient::connect(control).await
+    {
+        match mode {
+            Mode::Command(command) => {
+                return match client.execute(command, json).await {
+                    Ok(output) => print_output(&output, raw).map(|_| ExitCode::SUCCESS),
+                    Err(e) => report_failure(e),
+                };
             }
-            Commands::Get { key } => {
-                command_sender.send(AppCommand::Get(key)).await?;
-            }
-            Commands::Peers => {
-                command_sender.send(AppCommand::ListPeers).await?;
-            }
-            Commands::Stats => {
-                command_sender.send(AppCommand::GetStats).await?;
+            Mode::Watch(key) => print_changes(client.watch(key, cli.json).await?).await?,
+            Mode::Dashboard => {
+                dashboard::run(async || {
+                    let snapshot = client.execute(AppCommand::DashboardSnapshot, true).await?;
+                    Ok(serde_json::from_str(&snapshot)?)
+                })
+                .await?
             }
+            Mode::Interactive | Mode::Daemon | Mode::DevCluster(_) => unreachable!(),
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(data_dir) = &settings.data_dir {
+        std::fs::create_dir_all(data_dir)
+            .with_context(|| format!("Failed to create {}", data_dir.display()))?;
+    }
+
+    let addr = match (&mode, settings.addr) {
+        (Mode::DevCluster(_), None) => cluster::DEFAULT_ADDR.parse()?,
+        _ => settings.bind_addr()?,
+    };
+    let mut node = DhtNode::new(addr, Some(settings.dht));
+    if let Some(data_dir) = &settings.data_dir {
+        let identity = Identity::load_or_generate(
+            &data_dir.join("identity.key"),
+            node.config.id_hash,
+            node.config.id_difficulty,
+        )?;
+        node = node.with_identity(identity);
+    }
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    match rust_p2p_node::dht::connection::uring::UringTransport::new() {
+        Ok(transport) => node = node.with_network(transport),
+        Err(e) => warn!(error = %e, "io_uring is not available, using epoll"),
+    }
+    info!(id = %node.id, "node identity");
+    let listener = node.listen().await?;
+    node.start_maintenance_service().await;
+    if let Mode::DevCluster(count) = mode {
+        let nodes = cluster::start(&node, count).await?;
+        println!(
+            "Started {} nodes on {} to {}",
+            nodes.len(),
+            node.addr,
+            nodes[nodes.len() - 1].addr
+        );
+    }
+    if let Some(health_addr) = cli.health_addr {
+        node.serve_health(health_addr).await?;
+    }
+    if let Some(gateway_addr) = cli.gateway_addr {
+        node.serve_gateway(gateway_addr).await?;
+    }
+    if let Some(statsd_addr) = cli.statsd {
+        node.start_statsd_exporter(statsd_addr).await?;
+    }
+    if let Some(snapshot_dir) = &cli.snapshot_dir {
+        node.start_snapshots(FileSink::new(snapshot_dir));
+    }
+
+    let admin_server = match &settings.admin_socket {
+        Some(path) => {
+            let admin = Admin {
+                node: node.clone(),
+                log_filter: logging.filter(),
+            };
+            Some((admin::serve(path, admin).await?, path))
         }
+        None => None,
+    };
+
+    let shutdown_node = node.clone();
+    let (app, app_handle) = DhtApp::new(node, settings.peers, logging.filter());
+    let (stop_app, app_stopped) = oneshot::channel::<()>();
+    let mut app_task = tasks::spawn(
+        "app",
+        app.run(async {
+            let _ = app_stopped.await;
+        }),
+    );
+
+    let mut outcome = Ok(ExitCode::SUCCESS);
+    if let Mode::Daemon = mode {
+        let control = settings
+            .control
+            .ok_or_else(|| anyhow!("No control socket: pass --control or --data-dir"))?;
+        let server = control::serve(&control, app_handle).await?;
+        info!(control = %control.display(), "daemon ready");
+
+        shutdown_signal().await?;
+        info!("shutting down");
+        server.abort();
+        let _ = std::fs::remove_file(&control);
+    } else if let Mode::Command(command) = mode {
+        outcome = tokio::select! {
+            result = app_handle.execute(command, json) => match result {
+                Ok(output) => print_output(&output, raw).map(|_| ExitCode::SUCCESS),
+                Err(e) => report_failure(e),
+            },
+            result = shutdown_signal() => result.and(Err(anyhow!("Interrupted"))),
+        };
+    } else if let Mode::Watch(key) = mode {
+        print_changes(app_handle.watch(key, cli.json).map(Ok)).await?;
+    } else if let Mode::Dashboard = mode {
+        outcome = show_dashboard(&app_handle).await.map(|_| ExitCode::SUCCESS);
     } else {
         // Interactive mode
         println!("Running in interactive mode. Type 'help' for commands.");
-        let mut input = String::new();
-        loop {
-            print!("> ");
-            std::io::stdin().read_line(&mut input)?;
-
-            let parts: Vec<&str> = input.trim().split_whitespace().collect();
-            match parts.as_slice() {
-                ["store", key, value] => {
-                    command_sender
-                        .send(AppCommand::Store(key.to_string(), value.to_string()))
-                        .await?;
+        let history = settings.data_dir.as_ref().map(|dir| dir.join("history"));
+        let mut repl = Repl::new(history)?;
+        while let Some(input) = repl.read_line()? {
+            let parts: Vec<&str> = input.split_whitespace().collect();
+            let (command, json) = match parts.as_slice() {
+                [] => continue,
+                ["store", key, value] => (
+                    AppCommand::Store(
+                        key.as_bytes().to_vec(),
+                        value.as_bytes().to_vec(),
+                        WriteRequest::default(),
+                    ),
+                    cli.json,
+                ),
+                ["store", key, value, "--ttl", ttl] => match ttl.parse() {
+                    Ok(ttl) => (
+                        AppCommand::Store(
+                            key.as_bytes().to_vec(),
+                            value.as_bytes().to_vec(),
+                            WriteRequest {
+                                ttl: Some(ttl),
+                                ..WriteRequest::default()
+                            },
+                        ),
+                        cli.json,
+                    ),
+                    Err(_) => {
+                        println!("Invalid TTL: {}", ttl);
+                        continue;
+                    }
+                },
+                ["get", key] => (
+                    AppCommand::Get {
+                        key: key.as_bytes().to_vec(),
+                        format: None,
+                        output: None,
+                    },
+                    cli.json,
+                ),
+                ["get", key, "--output", path] => (
+                    AppCommand::Get {
+                        key: key.as_bytes().to_vec(),
+                        format: None,
+                        output: Some(path.into()),
+                    },
+                    cli.json,
+                ),
+                ["lookup", key] => (AppCommand::Lookup(key.to_string()), cli.json),
+                ["info", key] => (AppCommand::Info(key.to_string()), cli.json),
+                ["peers"] => (AppCommand::ListPeers, cli.json),
+                ["stats"] => (AppCommand::GetStats, cli.json),
+                ["stats", "--json"] => (AppCommand::GetStats, true),
+                ["stats", "reset"] => (AppCommand::ResetStats, cli.json),
+                ["put-file", key, path] => {
+                    (AppCommand::PutFile(key.to_string(), path.into()), cli.json)
+                }
+                ["get-file", key, path] => {
+                    (AppCommand::GetFile(key.to_string(), path.into()), cli.json)
                 }
-                ["get", key] => {
-                    command_sender
-                        .send(AppCommand::Get(key.to_string()))
-                        .await?;
+                ["keys", args @ ..] => match parse_keys_args(args) {
+                    Some((prefix, limit)) => (AppCommand::ListKeys { prefix, limit }, cli.json),
+                    None => {
+                        println!("Usage: keys [--prefix P] [--limit N]");
+                        continue;
+                    }
+                },
+                ["dump"] => (AppCommand::Dump, cli.json),
+                ["log-level"] => (AppCommand::LogLevel(None), cli.json),
+                ["log-level", filter] => (AppCommand::LogLevel(Some(filter.to_string())), cli.json),
+                ["rebalance"] => (AppCommand::Rebalance, cli.json),
+                ["ban", target] => (
+                    AppCommand::Ban {
+                        target: target.to_string(),
+                        duration: None,
+                    },
+                    cli.json,
+                ),
+                ["ban", target, "--duration", duration] => {
+                    match humantime::parse_duration(duration) {
+                        Ok(duration) => (
+                            AppCommand::Ban {
+                                target: target.to_string(),
+                                duration: Some(duration),
+                            },
+                            cli.json,
+                        ),
+                        Err(e) => {
+                            println!("Invalid duration: {}", e);
+                            continue;
+                        }
+                    }
                 }
-                ["peers"] => {
-                    command_sender.send(AppCommand::ListPeers).await?;
+                ["unban", target] => (AppCommand::Unban(target.to_string()), cli.json),
+                ["bench", args @ ..] => match parse_bench_args(args) {
+                    Some(options) => (AppCommand::Bench(options), cli.json),
+                    None => {
+                        println!(
+                            "Usage: bench [--writes N] [--reads N] [--concurrency N] [--value-size N]"
+                        );
+                        continue;
+                    }
+                },
+                ["dashboard"] => {
+                    if let Err(e) = show_dashboard(&app_handle).await {
+                        eprintln!("Error: {:#}", e);
+                    }
+                    continue;
                 }
-                ["stats"] => {
-                    command_sender.send(AppCommand::GetStats).await?;
+                ["watch", key] => {
+                    println!("Watching {}, press Ctrl-C to stop", key);
+                    print_changes(app_handle.watch(key.to_string(), cli.json).map(Ok)).await?;
+                    continue;
                 }
                 ["help"] => {
-                    print_help();
+                    repl::print_help(None);
+                    continue;
+                }
+                ["help", command] => {
+                    repl::print_help(Some(command));
+                    continue;
                 }
                 ["exit"] => break,
-                _ => println!("Unknown command. Type 'help' for available commands."),
+                _ => {
+                    println!("{}", repl::unknown_command(&input));
+                    continue;
+                }
+            };
+
+            match app_handle.execute(command, json).await {
+                Ok(output) => println!("{}", output),
+                Err(e) => eprintln!("Error: {:#}", e),
             }
-            input.clear();
         }
     }
 
-    app_handle.abort();
+    // Let commands in flight finish rather than dropping them halfway
+    let _ = stop_app.send(());
+    if timeout(SHUTDOWN_GRACE, &mut app_task).await.is_err() {
+        warn!("commands still running at shutdown were dropped");
+        app_task.abort();
+    }
+    if let Some((server, path)) = admin_server {
+        server.abort();
+        let _ = std::fs::remove_file(path);
+    }
+
+    listener.abort();
+    shutdown_node.flush_outbox().await;
+    if settings.hand_off_keys {
+        let handed_off = shutdown_node.hand_off_keys().await;
+        if handed_off > 0 {
+            info!(handed_off, "handed off keys before shutdown");
+        }
+    }
+
+    shutdown_node.shutdown().await;
+
+    outcome
+}
+
+/// Upgrades the files in the data directory to the current formats.
+fn migrate(settings: &Settings) -> anyhow::Result<ExitCode> {
+    let data_dir = settings
+        .data_dir
+        .as_ref()
+        .ok_or_else(|| anyhow!("No data directory: pass --data-dir"))?;
+    let identity = data_dir.join("identity.key");
+    if !identity.exists() {
+        println!("Nothing to migrate in {}", data_dir.display());
+    } else if migrate_identity_file(&identity)? {
+        println!("Migrated {}", identity.display());
+    } else {
+        println!("{} is up to date", identity.display());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// How long shutdown waits for running commands.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// What this invocation of the binary does.
+enum Mode {
+    /// Read commands from the terminal
+    Interactive,
+    /// Serve commands on the control socket until interrupted
+    Daemon,
+    /// Run one command
+    Command(AppCommand),
+    /// Follow a key until interrupted
+    Watch(String),
+    /// Show the live dashboard until the user quits
+    Dashboard,
+    /// Start a local cluster and read commands for its first node
+    DevCluster(usize),
+}
+
+/// Prints the output of a command that failed with one and turns it into a
+/// failing exit code; other errors are passed on.
+fn report_failure(e: anyhow::Error) -> anyhow::Result<ExitCode> {
+    match e.downcast::<CommandFailed>() {
+        Ok(CommandFailed(output)) => {
+            println!("{}", output);
+            Ok(ExitCode::FAILURE)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Resolves on Ctrl-C or SIGTERM.
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// Prints a command's output. With `raw` it is the JSON answer of a
+/// `get --raw`, whose value is written to stdout as bytes.
+fn print_output(output: &str, raw: bool) -> anyhow::Result<()> {
+    if !raw {
+        println!("{}", output);
+        return Ok(());
+    }
 
+    let answer: serde_json::Value = serde_json::from_str(output)?;
+    let value = answer["value"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Value not found"))?;
+    std::io::stdout().write_all(&BytesFormat::Base64.decode(value)?)?;
     Ok(())
 }
 
-fn print_help() {
-    println!("Available commands:");
-    println!("  store <key> <value> - Store a key-value pair");
-    println!("  get <key>           - Retrieve a value by key");
-    println!("  peers               - List known peers");
-    println!("  stats               - Show DHT statistics");
-    println!("  exit                - Exit the application");
+/// Prints watched changes until the stream ends or Ctrl-C is pressed.
+async fn print_changes(changes: impl Stream<Item = anyhow::Result<String>>) -> anyhow::Result<()> {
+    let mut changes = pin!(changes);
+    let mut interrupted = pin!(tokio::signal::ctrl_c());
+
+    loop {
+        tokio::select! {
+            change = changes.next() => match change {
+                Some(change) => println!("{}", change?),
+                None => return Ok(()),
+            },
+            _ = &mut interrupted => return Ok(()),
+        }
+    }
+}
+
+/// Runs the dashboard on snapshots from the in-process app.
+async fn show_dashboard(app: &AppHandle) -> anyhow::Result<()> {
+    dashboard::run(async || {
+        let snapshot = app.execute(AppCommand::DashboardSnapshot, true).await?;
+        Ok(serde_json::from_str(&snapshot)?)
+    })
+    .await
+}
+
+/// Maps a one-shot subcommand to the command the app runs for it.
+///
+/// File paths are made absolute, since a daemon resolves them against its own
+/// working directory.
+fn app_command(command: Commands) -> anyhow::Result<AppCommand> {
+    let absolute = |path: PathBuf| {
+        std::path::absolute(&path).with_context(|| format!("Invalid path {}", path.display()))
+    };
+
+    Ok(match command {
+        Commands::Store {
+            key,
+            value,
+            key_format,
+            value_format,
+            ttl,
+            replicas,
+            consistency,
+        } => AppCommand::Store(
+            key_format.decode(&key).context("Invalid key")?,
+            value_format.decode(&value).context("Invalid value")?,
+            WriteRequest {
+                replication: replicas,
+                ttl,
+                consistency: consistency.into(),
+                token: None,
+            },
+        ),
+        Commands::Get {
+            key,
+            key_format,
+            value_format,
+            output,
+            raw,
+        } => AppCommand::Get {
+            key: key_format.decode(&key).context("Invalid key")?,
+            // Raw values travel as base64 and are decoded by `print_output`
+            format: if raw {
+                Some(BytesFormat::Base64)
+            } else {
+                value_format
+            },
+            output: output.map(absolute).transpose()?,
+        },
+        Commands::Lookup { key } => AppCommand::Lookup(key),
+        Commands::Info { key } => AppCommand::Info(key),
+        Commands::Peers => AppCommand::ListPeers,
+        Commands::Stats => AppCommand::GetStats,
+        Commands::PutFile { key, path } => AppCommand::PutFile(key, absolute(path)?),
+        Commands::GetFile { key, path } => AppCommand::GetFile(key, absolute(path)?),
+        Commands::Keys { prefix, limit } => AppCommand::ListKeys { prefix, limit },
+        Commands::Dump => AppCommand::Dump,
+        Commands::LogLevel { filter } => AppCommand::LogLevel(filter),
+        Commands::Rebalance => AppCommand::Rebalance,
+        Commands::Ban { target, duration } => AppCommand::Ban { target, duration },
+        Commands::Unban { target } => AppCommand::Unban(target),
+        Commands::Bench {
+            writes,
+            reads,
+            concurrency,
+            value_size,
+        } => AppCommand::Bench(BenchOptions {
+            writes,
+            reads,
+            concurrency,
+            value_size,
+        }),
+        Commands::Daemon
+        | Commands::Migrate
+        | Commands::Watch { .. }
+        | Commands::Dashboard
+        | Commands::DevCluster { .. } => {
+            unreachable!("not a single command")
+        }
+    })
+}
+
+/// Parses the arguments of the interactive `keys` command.
+fn parse_keys_args(mut args: &[&str]) -> Option<(Option<String>, Option<usize>)> {
+    let (mut prefix, mut limit) = (None, None);
+    while let [flag, value, rest @ ..] = args {
+        match *flag {
+            "--prefix" => prefix = Some(value.to_string()),
+            "--limit" => limit = Some(value.parse().ok()?),
+            _ => return None,
+        }
+        args = rest;
+    }
+
+    args.is_empty().then_some((prefix, limit))
+}
+
+/// Parses the arguments of the interactive `bench` command.
+fn parse_bench_args(mut args: &[&str]) -> Option<BenchOptions> {
+    let mut options = BenchOptions::default();
+    while let [flag, value, rest @ ..] = args {
+        let value = value.parse().ok()?;
+        match *flag {
+            "--writes" => options.writes = value,
+            "--reads" => options.reads = value,
+            "--concurrency" => options.concurrency = value,
+            "--value-size" => options.value_size = value,
+            _ => return None,
+        }
+        args = rest;
+    }
+
+    args.is_empty().then_some(options)
 }
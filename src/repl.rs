@@ -0,0 +1,298 @@
+//! Line editing for the interactive mode.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use rustyline::{
+    Context, Editor, Helper, completion::Completer, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator,
+};
+
+/// A command of the interactive mode, as described by `help`.
+pub struct CommandHelp {
+    pub name: &'static str,
+    /// Arguments, after the name
+    pub args: &'static str,
+    pub summary: &'static str,
+    /// Shown by `help <command>` under the summary
+    pub details: &'static str,
+}
+
+/// Every interactive command, in the order `help` lists them.
+pub const COMMANDS: &[CommandHelp] = &[
+    CommandHelp {
+        name: "store",
+        args: "<key> <value> [--ttl <secs>]",
+        summary: "Store a key-value pair",
+        details: "The value expires after --ttl seconds, or the configured default TTL.\n\
+                  Example: store greeting hello --ttl 600",
+    },
+    CommandHelp {
+        name: "get",
+        args: "<key> [--output <path>]",
+        summary: "Retrieve a value by key",
+        details: "With --output the value's bytes are written to the file as is.\n\
+                  Example: get greeting",
+    },
+    CommandHelp {
+        name: "lookup",
+        args: "<key>",
+        summary: "Trace each hop of a lookup",
+        details: "Prints the peers asked at each hop, what they answered and how long it took.\n\
+                  Example: lookup greeting",
+    },
+    CommandHelp {
+        name: "info",
+        args: "<key>",
+        summary: "Show the version and replicas of a value",
+        details: "Looks the key up, then prints the winning copy's version, expiration and \
+                  original nodes next to the local copy's.\n\
+                  Example: info greeting",
+    },
+    CommandHelp {
+        name: "put-file",
+        args: "<key> <path>",
+        summary: "Store a file's contents under a key",
+        details: "Large files are split into chunks stored under <key>/chunk/<n>.\n\
+                  Example: put-file photo ./photo.jpg",
+    },
+    CommandHelp {
+        name: "get-file",
+        args: "<key> <path>",
+        summary: "Fetch a key's value into a file",
+        details: "Chunked files are reassembled and checked against their digest.\n\
+                  Example: get-file photo ./copy.jpg",
+    },
+    CommandHelp {
+        name: "peers",
+        args: "",
+        summary: "List known peers",
+        details: "Prints each peer of the routing table with its request statistics.",
+    },
+    CommandHelp {
+        name: "stats",
+        args: "[--json | reset]",
+        summary: "Show or reset DHT statistics",
+        details: "Example: stats --json",
+    },
+    CommandHelp {
+        name: "keys",
+        args: "[--prefix <prefix>] [--limit <n>]",
+        summary: "List locally held keys",
+        details: "Only this node's storage is read.\n\
+                  Example: keys --prefix users: --limit 20",
+    },
+    CommandHelp {
+        name: "dump",
+        args: "",
+        summary: "Print internal state as JSON",
+        details: "Includes the routing table, connection pool, storage summary and background \
+                  tasks, for bug reports.",
+    },
+    CommandHelp {
+        name: "log-level",
+        args: "[<filter>]",
+        summary: "Show or change the log filter",
+        details: "The filter uses the RUST_LOG syntax.\n\
+                  Example: log-level rust_p2p_node=debug",
+    },
+    CommandHelp {
+        name: "rebalance",
+        args: "",
+        summary: "Move local values to the nodes now responsible for them",
+        details: "Copies values to the closest peers lacking them and drops the replicas this \
+                  node is no longer among the closest nodes for. Run it after changing the \
+                  replication factor or adding nodes.",
+    },
+    CommandHelp {
+        name: "ban",
+        args: "<addr|id> [--duration <duration>]",
+        summary: "Ban a peer and print the ban list",
+        details: "Peers can be named by address or by a unique prefix of their id. Bans \
+                  without --duration last until lifted.\n\
+                  Example: ban 10.0.0.5:8080 --duration 1h",
+    },
+    CommandHelp {
+        name: "unban",
+        args: "<addr|id>",
+        summary: "Lift a ban and print the ban list",
+        details: "Example: unban 10.0.0.5:8080",
+    },
+    CommandHelp {
+        name: "bench",
+        args: "[--writes <n>] [--reads <n>] [--concurrency <n>] [--value-size <n>]",
+        summary: "Measure store/get throughput and latency",
+        details: "Writes random values under bench:<run>:<n> keys, then reads them back.\n\
+                  Example: bench --writes 100 --reads 500 --concurrency 4",
+    },
+    CommandHelp {
+        name: "dashboard",
+        args: "",
+        summary: "Show live stats until q is pressed",
+        details: "Refreshes stats, buckets, peers and recent operations every second.",
+    },
+    CommandHelp {
+        name: "watch",
+        args: "<key>",
+        summary: "Print changes to a key until Ctrl-C",
+        details: "Example: watch greeting",
+    },
+    CommandHelp {
+        name: "help",
+        args: "[<command>]",
+        summary: "List commands, or describe one",
+        details: "Example: help store",
+    },
+    CommandHelp {
+        name: "exit",
+        args: "",
+        summary: "Exit the application (or Ctrl-D)",
+        details: "",
+    },
+];
+
+/// Prints every command, or the details of `command`.
+pub fn print_help(command: Option<&str>) {
+    let Some(name) = command else {
+        println!("Available commands:");
+        for command in COMMANDS {
+            println!("  {:<12} {}", command.name, command.summary);
+        }
+        println!("Type 'help <command>' for its arguments and examples.");
+        return;
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            println!("{} {}", command.name, command.args);
+            println!("  {}", command.summary);
+            for line in command.details.lines().filter(|line| !line.is_empty()) {
+                println!("  {}", line);
+            }
+        }
+        None => println!("{}", unknown_command(name)),
+    }
+}
+
+/// Explains that `input` is not a valid command line, with the usage of its
+/// command or the closest command name.
+pub fn unknown_command(input: &str) -> String {
+    let name = input.split_whitespace().next().unwrap_or_default();
+    if let Some(command) = COMMANDS.iter().find(|command| command.name == name) {
+        return format!("Usage: {} {}", command.name, command.args);
+    }
+
+    let closest = COMMANDS
+        .iter()
+        .map(|command| (edit_distance(name, command.name), command.name))
+        .min()
+        .filter(|(distance, _)| *distance <= 2);
+    match closest {
+        Some((_, suggestion)) => {
+            format!("Unknown command '{}'. Did you mean '{}'?", name, suggestion)
+        }
+        None => format!(
+            "Unknown command '{}'. Type 'help' for available commands.",
+            name
+        ),
+    }
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Completes command names at the start of the line.
+pub struct CommandHelper;
+
+impl Completer for CommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let word = &line[..pos];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(word))
+            .map(|command| command.name.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CommandHelper {}
+
+impl Validator for CommandHelper {}
+
+impl Helper for CommandHelper {}
+
+/// Line editor of the interactive mode, with history kept in `history` if
+/// given.
+pub struct Repl {
+    editor: Editor<CommandHelper, DefaultHistory>,
+    history: Option<PathBuf>,
+}
+
+impl Repl {
+    pub fn new(history: Option<PathBuf>) -> Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(CommandHelper));
+        if let Some(path) = &history {
+            // A missing history file just means a fresh start
+            let _ = editor.load_history(path);
+        }
+
+        Ok(Self { editor, history })
+    }
+
+    /// Reads the next line, or `None` once the user ends the session with
+    /// Ctrl-D. Ctrl-C discards the line being edited.
+    pub fn read_line(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.editor.readline("> ") {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        self.editor.add_history_entry(line.as_str())?;
+                    }
+                    return Ok(Some(line));
+                }
+                Err(rustyline::error::ReadlineError::Interrupted) => continue,
+                Err(rustyline::error::ReadlineError::Eof) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for Repl {
+    fn drop(&mut self) {
+        if let Some(path) = &self.history {
+            let _ = self.editor.save_history(path);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fs, net::SocketAddr, path::PathBuf};
+
+use anyhow::{Context, Result, anyhow};
+use rust_p2p_node::dht::config::DhtConfig;
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::cli::Cli;
+
+/// How the value of an environment variable is read.
+#[derive(Clone, Copy)]
+enum EnvKind {
+    String,
+    Integer,
+    /// Comma separated strings
+    List,
+}
+
+/// Environment variables and the config file key each one sets.
+const ENV_VARS: &[(&str, &str, EnvKind)] = &[
+    ("DHT_ADDR", "addr", EnvKind::String),
+    ("DHT_PEERS", "peers", EnvKind::List),
+    ("DHT_DATA_DIR", "data_dir", EnvKind::String),
+    ("DHT_CONTROL", "control", EnvKind::String),
+    ("DHT_ADMIN_SOCKET", "admin_socket", EnvKind::String),
+    ("DHT_LOG_LEVEL", "log_level", EnvKind::String),
+    (
+        "DHT_OPERATION_TIMEOUT",
+        "dht.operation_timeout",
+        EnvKind::String,
+    ),
+    (
+        "DHT_MAX_ENTRIES",
+        "dht.storage.max_entries",
+        EnvKind::Integer,
+    ),
+    (
+        "DHT_MAX_STORAGE_BYTES",
+        "dht.storage.max_bytes",
+        EnvKind::Integer,
+    ),
+    (
+        "DHT_MAX_OUTBOX_ENTRIES",
+        "dht.storage.max_outbox_entries",
+        EnvKind::Integer,
+    ),
+    (
+        "DHT_DEFAULT_TTL",
+        "dht.storage.default_ttl",
+        EnvKind::Integer,
+    ),
+    (
+        "DHT_MAX_CONNECTIONS",
+        "dht.connection_pool.max_total_connections",
+        EnvKind::Integer,
+    ),
+    (
+        "DHT_MAX_CONNECTIONS_PER_PEER",
+        "dht.connection_pool.max_connections_per_peer",
+        EnvKind::Integer,
+    ),
+    (
+        "DHT_REPLICATION_FACTOR",
+        "dht.replication.factor",
+        EnvKind::Integer,
+    ),
+    ("DHT_CLUSTER_SECRET", "dht.cluster_secret", EnvKind::String),
+];
+
+/// Contents of the `--config` TOML file. Every key is optional; keys the
+/// file leaves out can come from the [`ENV_VARS`] environment variables.
+///
+/// ```toml
+/// addr = "127.0.0.1:8080"
+/// peers = ["127.0.0.1:8081"]
+/// data_dir = "/var/lib/dht"
+/// control = "/run/dht.sock"
+/// admin_socket = "/run/dht-admin.sock"
+/// log_level = "info"
+/// hand_off_keys = true
+///
+/// [dht]
+/// operation_timeout = "3s"
+///
+/// [dht.storage]
+/// default_ttl = 3600
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    addr: Option<SocketAddr>,
+    peers: Vec<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    control: Option<PathBuf>,
+    admin_socket: Option<PathBuf>,
+    log_level: Option<String>,
+    hand_off_keys: Option<bool>,
+    dht: DhtConfig,
+}
+
+/// Node settings: command line flags first, then the config file, then
+/// `DHT_*` environment variables.
+#[derive(Debug)]
+pub struct Settings {
+    /// Only needed to run a node; clients of a daemon go without
+    pub addr: Option<SocketAddr>,
+    pub peers: Vec<SocketAddr>,
+    pub data_dir: Option<PathBuf>,
+    /// Control socket of the daemon, `<data_dir>/control.sock` by default
+    pub control: Option<PathBuf>,
+    /// Admin socket, only served when set
+    pub admin_socket: Option<PathBuf>,
+    pub log_filter: Option<String>,
+    /// Push local keys to other peers before shutting down, on by default
+    pub hand_off_keys: bool,
+    pub dht: DhtConfig,
+}
+
+impl Settings {
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut table = match &cli.config {
+            Some(path) => {
+                let contents = fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Invalid config file {}", path.display()))?
+            }
+            None => Table::new(),
+        };
+        apply_env(&mut table, |name| std::env::var(name).ok())?;
+
+        let file =
+            FileConfig::deserialize(Value::Table(table)).with_context(|| match &cli.config {
+                Some(path) => format!("Invalid settings in {} or DHT_* variables", path.display()),
+                None => "Invalid settings in DHT_* variables".to_string(),
+            })?;
+
+        let peers = match &cli.peers {
+            Some(peers) => peers
+                .split(',')
+                .map(|peer| {
+                    peer.trim()
+                        .parse()
+                        .with_context(|| format!("Invalid peer address {:?}", peer))
+                })
+                .collect::<Result<_>>()?,
+            None => file.peers,
+        };
+
+        let data_dir = cli.data_dir.clone().or(file.data_dir);
+        let control = cli
+            .control
+            .clone()
+            .or(file.control)
+            .or_else(|| data_dir.as_ref().map(|dir| dir.join("control.sock")));
+
+        Ok(Self {
+            addr: cli.addr.or(file.addr),
+            peers,
+            data_dir,
+            control,
+            admin_socket: cli.admin_socket.clone().or(file.admin_socket),
+            log_filter: cli.log_filter.clone().or(file.log_level),
+            hand_off_keys: !cli.no_hand_off && file.hand_off_keys.unwrap_or(true),
+            dht: file.dht,
+        })
+    }
+
+    /// The address to bind a node to.
+    pub fn bind_addr(&self) -> Result<SocketAddr> {
+        self.addr
+            .ok_or_else(|| anyhow!("No bind address: pass --addr or set it in the config file"))
+    }
+}
+
+/// Fills in the keys of `table` that are unset from [`ENV_VARS`], reading
+/// variables through `var`.
+fn apply_env(table: &mut Table, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+    for &(name, key, kind) in ENV_VARS {
+        let Some(raw) = var(name) else { continue };
+
+        let value = match kind {
+            EnvKind::String => Value::String(raw),
+            EnvKind::Integer => Value::Integer(
+                raw.trim()
+                    .parse()
+                    .with_context(|| format!("{} must be an integer, got {:?}", name, raw))?,
+            ),
+            EnvKind::List => Value::Array(
+                raw.split(',')
+                    .map(|item| Value::String(item.trim().to_string()))
+                    .collect(),
+            ),
+        };
+
+        let (path, leaf) = key
+            .rsplit_once('.')
+            .map_or((None, key), |(p, l)| (Some(p), l));
+        let mut section = &mut *table;
+        for part in path.into_iter().flat_map(|path| path.split('.')) {
+            section = match section
+                .entry(part)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                Value::Table(section) => section,
+                _ => return Err(anyhow!("Config key {} is not a table", part)),
+            };
+        }
+        section.entry(leaf).or_insert(value);
+    }
+
+    Ok(())
+}
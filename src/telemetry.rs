@@ -0,0 +1,58 @@
+//! OTLP export of the node's tracing spans.
+//!
+//! The library instruments stores, lookups (hop by hop), replication and
+//! every RPC with [`tracing`] spans. With the `otel` feature enabled,
+//! [`otlp_layer`] ships those spans to an OpenTelemetry collector such as
+//! Jaeger or Tempo. The endpoint is taken from the standard
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` variable and defaults to
+//! `http://localhost:4318`.
+
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{
+    Resource,
+    trace::{SdkTracer, SdkTracerProvider},
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Flushes and shuts the exporter down when dropped.
+pub struct OtlpGuard {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        let _ = self.provider.shutdown();
+    }
+}
+
+/// Builds a subscriber layer that exports spans over OTLP/HTTP.
+///
+/// Keep the returned guard alive for as long as spans should be exported.
+///
+/// # Examples
+///
+/// ```no_run
+/// use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+///
+/// let (layer, _otlp) = rust_p2p_node::telemetry::otlp_layer("dht-node").unwrap();
+/// tracing_subscriber::registry().with(layer).init();
+/// ```
+pub fn otlp_layer<S>(
+    service_name: &'static str,
+) -> Result<(OpenTelemetryLayer<S, SdkTracer>, OtlpGuard)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name));
+    Ok((layer, OtlpGuard { provider }))
+}
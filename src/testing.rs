@@ -0,0 +1,672 @@
+//! In-process clusters for tests.
+//!
+//! A [`TestCluster`] runs several [`DhtNode`]s inside the current tokio
+//! runtime, connected over an in-memory network instead of sockets, so tests
+//! need no free ports. Nodes can be killed and revived, the network can be
+//! partitioned and healed, and links given latency and loss, to exercise
+//! failure handling.
+//!
+//! # Simulations
+//!
+//! Everything in the node runs on tokio's clock, including the Unix time
+//! used for TTLs and versions (see [`now`](crate::helpers::now)). Run a
+//! cluster under a paused clock, with tokio's `test-util` feature, and time
+//! becomes virtual: it only moves on when every task waits, straight to the
+//! next timer. Latencies, timeouts, TTLs and maintenance intervals then cost
+//! no wall time, and a scenario replays the same way on every run:
+//!
+//! ```
+//! use std::time::Duration;
+//! use rust_p2p_node::testing::{LinkConditions, TestCluster};
+//!
+//! #[tokio::main(flavor = "current_thread", start_paused = true)]
+//! async fn main() {
+//!     let cluster = TestCluster::new(3).await;
+//!     cluster.set_conditions(LinkConditions {
+//!         latency: Duration::from_millis(40),
+//!         loss: 0.05,
+//!     });
+//!     for node in cluster.nodes() {
+//!         node.start_maintenance_service().await;
+//!     }
+//!
+//!     cluster.partition(&[2]);
+//!     let _ = cluster.node(0).store(b"key".to_vec(), b"value".to_vec()).await;
+//!     tokio::time::sleep(Duration::from_secs(600)).await;
+//!     cluster.heal();
+//! }
+//! ```
+//!
+//! Loss is drawn from a generator seeded with [`TestCluster::set_seed`], in
+//! the order writes happen.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use dashmap::{DashMap, mapref::entry::Entry};
+use futures::FutureExt;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+    time::Sleep,
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+#[cfg(any(test, feature = "testing"))]
+use crate::dht::faults::FaultInjector;
+use crate::{
+    dht::{
+        DhtNode,
+        config::DhtConfig,
+        connection::{
+            connector::{Connector, PeerStream},
+            transport::{Listener, Transport},
+        },
+        identity::Identity,
+        node::NodeId,
+    },
+    helpers::test_config,
+};
+
+/// Bytes buffered in each direction of a simulated connection.
+const LINK_BUFFER: usize = 64 * 1024;
+
+/// Several nodes that are all peers of each other, talking over a simulated
+/// network.
+///
+/// # Examples
+///
+/// ```
+/// use rust_p2p_node::testing::TestCluster;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let cluster = TestCluster::new(3).await;
+///     cluster.node(0).store(b"key".to_vec(), b"value".to_vec()).await.unwrap();
+///
+///     cluster.kill(0);
+///     assert_eq!(
+///         cluster.node(1).find_value(b"key".to_vec()).await.as_deref(),
+///         Some(&b"value"[..])
+///     );
+/// }
+/// ```
+pub struct TestCluster {
+    nodes: Vec<DhtNode>,
+    servers: Vec<JoinHandle<()>>,
+    network: Arc<Network>,
+    #[cfg(any(test, feature = "testing"))]
+    faults: Arc<FaultInjector>,
+}
+
+impl TestCluster {
+    /// Starts `count` nodes with the configuration of
+    /// [`create_test_node`](crate::helpers::create_test_node), minus the dial
+    /// backoff so that revived and healed nodes are reachable right away.
+    pub async fn new(count: usize) -> Self {
+        let mut config = test_config();
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        Self::with_config(count, config).await
+    }
+
+    /// Starts `count` nodes with `config`.
+    ///
+    /// Nodes listen on `10.0.0.1:7000`, `10.0.0.2:7000` and so on, which
+    /// are never bound on the host. No maintenance service is started.
+    pub async fn with_config(count: usize, config: DhtConfig) -> Self {
+        Self::with_configs(vec![config; count]).await
+    }
+
+    /// Starts one node per configuration of `configs`, in order, for nodes
+    /// that play different parts.
+    pub async fn with_configs(configs: Vec<DhtConfig>) -> Self {
+        let count = configs.len();
+        let network = Arc::new(Network::default());
+        #[cfg(any(test, feature = "testing"))]
+        let faults = Arc::new(FaultInjector::new());
+        let mut nodes = Vec::with_capacity(count);
+        for (index, config) in configs.into_iter().enumerate() {
+            let addr = SocketAddr::from((Ipv4Addr::from(0x0a00_0001 + index as u32), 7000));
+            network.join(addr);
+            let node = DhtNode::new(addr, Some(config))
+                .with_identity(identity(index))
+                .with_network(SimTransport {
+                    local: addr,
+                    network: Arc::clone(&network),
+                });
+            #[cfg(any(test, feature = "testing"))]
+            let node = node.with_fault_injector(Arc::clone(&faults));
+            nodes.push(node);
+        }
+
+        for node in &nodes {
+            for peer in nodes.iter().filter(|peer| peer.addr != node.addr) {
+                node.add_peer(peer.peer_info());
+            }
+        }
+
+        let mut servers = Vec::with_capacity(count);
+        for node in &nodes {
+            servers.push(node.listen().await.expect("simulated addresses are unique"));
+        }
+
+        Self {
+            nodes,
+            servers,
+            network,
+            #[cfg(any(test, feature = "testing"))]
+            faults,
+        }
+    }
+
+    /// Id the node at `index` gets with `config`, known before the cluster
+    /// starts, to name it in the configuration of others.
+    pub fn node_id(index: usize, config: &DhtConfig) -> NodeId {
+        identity(index).node_id(config.id_hash)
+    }
+
+    pub fn node(&self, index: usize) -> &DhtNode {
+        &self.nodes[index]
+    }
+
+    pub fn nodes(&self) -> &[DhtNode] {
+        &self.nodes
+    }
+
+    /// Takes node `index` off the network: it can no longer be reached nor
+    /// reach anyone, and its open connections break.
+    ///
+    /// Its state is kept, and so are its background tasks, which fail to
+    /// reach their peers.
+    pub fn kill(&self, index: usize) {
+        self.network.set_up(self.nodes[index].addr, false);
+    }
+
+    /// Brings a killed node back on the network.
+    pub fn revive(&self, index: usize) {
+        self.network.set_up(self.nodes[index].addr, true);
+    }
+
+    /// Splits the nodes at `indices` from the others: the two sides can no
+    /// longer reach each other and connections between them break.
+    ///
+    /// Replaces any previous partition.
+    pub fn partition(&self, indices: &[usize]) {
+        let side: Vec<_> = indices.iter().map(|&i| self.nodes[i].addr).collect();
+        self.network.partition(&side);
+    }
+
+    /// Lets every live node reach every other again.
+    pub fn heal(&self) {
+        self.network.partition(&[]);
+    }
+
+    /// Applies `conditions` to every link without conditions of its own.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.network.conditions.lock().unwrap() = conditions;
+    }
+
+    /// Applies `conditions` to the link between nodes `a` and `b`, in both
+    /// directions.
+    pub fn set_link_conditions(&self, a: usize, b: usize, conditions: LinkConditions) {
+        let (a, b) = (self.nodes[a].addr, self.nodes[b].addr);
+        self.network
+            .link_conditions
+            .lock()
+            .unwrap()
+            .insert((a.min(b), a.max(b)), conditions);
+    }
+
+    /// Fault injector shared by all nodes, to fail specific RPCs. Built with
+    /// the `testing` feature only.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+
+    /// Restarts the generator deciding which writes are lost. It is seeded
+    /// with 0 to begin with.
+    pub fn set_seed(&self, seed: u64) {
+        *self.network.rng.lock().unwrap() = StdRng::seed_from_u64(seed);
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        for server in &self.servers {
+            server.abort();
+        }
+    }
+}
+
+/// Identity of the node at `index`. Fixed identities keep ids, and so
+/// routing, reproducible.
+fn identity(index: usize) -> Identity {
+    let mut secret = [0u8; 32];
+    secret[..8].copy_from_slice(&(index as u64).to_be_bytes());
+    Identity::from_secret_bytes(&secret)
+}
+
+/// Conditions of simulated links, see [`TestCluster::set_conditions`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay of every connection attempt and every write
+    pub latency: Duration,
+    /// Probability, from 0 to 1, that a connection attempt or a write is
+    /// lost; a lost write breaks its connection
+    pub loss: f64,
+}
+
+/// Reachability of the simulated nodes.
+struct Network {
+    listeners: DashMap<SocketAddr, mpsc::UnboundedSender<SimStream>>,
+    hosts: Mutex<HashMap<SocketAddr, Host>>,
+    conditions: Mutex<LinkConditions>,
+    /// Conditions of single links, by their ends in ascending order
+    link_conditions: Mutex<HashMap<(SocketAddr, SocketAddr), LinkConditions>>,
+    rng: Mutex<StdRng>,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            listeners: DashMap::new(),
+            hosts: Mutex::new(HashMap::new()),
+            conditions: Mutex::new(LinkConditions::default()),
+            link_conditions: Mutex::new(HashMap::new()),
+            rng: Mutex::new(StdRng::seed_from_u64(0)),
+        }
+    }
+}
+
+struct Host {
+    up: bool,
+    /// Side of the current partition
+    side: bool,
+    /// Cancelled, and replaced, whenever the host loses connectivity
+    links: CancellationToken,
+}
+
+impl Network {
+    fn join(&self, addr: SocketAddr) {
+        self.hosts.lock().unwrap().insert(
+            addr,
+            Host {
+                up: true,
+                side: false,
+                links: CancellationToken::new(),
+            },
+        );
+    }
+
+    fn set_up(&self, addr: SocketAddr, up: bool) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.get_mut(&addr).expect("unknown host");
+        if host.up && !up {
+            host.links.cancel();
+            host.links = CancellationToken::new();
+        }
+        host.up = up;
+    }
+
+    fn partition(&self, side: &[SocketAddr]) {
+        for (addr, host) in self.hosts.lock().unwrap().iter_mut() {
+            let moved = side.contains(addr);
+            if host.side != moved {
+                host.side = moved;
+                host.links.cancel();
+                host.links = CancellationToken::new();
+            }
+        }
+    }
+
+    fn conditions(&self, a: SocketAddr, b: SocketAddr) -> LinkConditions {
+        self.link_conditions
+            .lock()
+            .unwrap()
+            .get(&(a.min(b), a.max(b)))
+            .copied()
+            .unwrap_or_else(|| *self.conditions.lock().unwrap())
+    }
+
+    /// Draws whether something sent between `a` and `b` is lost, returning
+    /// the link's latency otherwise.
+    fn transmit(&self, a: SocketAddr, b: SocketAddr) -> Option<Duration> {
+        let conditions = self.conditions(a, b);
+        let lost =
+            conditions.loss > 0.0 && self.rng.lock().unwrap().gen_bool(conditions.loss.min(1.0));
+        (!lost).then_some(conditions.latency)
+    }
+
+    /// Tokens cut when either end loses connectivity, if `from` can reach
+    /// `to` at all.
+    fn link(&self, from: SocketAddr, to: SocketAddr) -> Option<[CancellationToken; 2]> {
+        let hosts = self.hosts.lock().unwrap();
+        let (from, to) = (hosts.get(&from)?, hosts.get(&to)?);
+        (from.up && to.up && from.side == to.side).then(|| [from.links.clone(), to.links.clone()])
+    }
+}
+
+/// Transport of one simulated node.
+#[derive(Clone)]
+struct SimTransport {
+    local: SocketAddr,
+    network: Arc<Network>,
+}
+
+#[async_trait]
+impl Connector for SimTransport {
+    async fn connect(&self, addr: SocketAddr) -> io::Result<Box<dyn PeerStream>> {
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, addr.to_string());
+        let Some(latency) = self.network.transmit(self.local, addr) else {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, addr.to_string()));
+        };
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+
+        let links = self.network.link(self.local, addr).ok_or_else(refused)?;
+        let listener = self.network.listeners.get(&addr).ok_or_else(refused)?;
+
+        let (local, remote) = tokio::io::duplex(LINK_BUFFER);
+        let remote = SimStream::new(remote, [addr, self.local], &self.network, links.clone());
+        listener.send(remote).map_err(|_| refused())?;
+        Ok(Box::new(SimStream::new(
+            local,
+            [self.local, addr],
+            &self.network,
+            links,
+        )))
+    }
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn bind(&self, addr: SocketAddr) -> io::Result<Box<dyn Listener>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        match self.network.listeners.entry(addr) {
+            Entry::Occupied(_) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, addr.to_string()));
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+            }
+        }
+        Ok(Box::new(SimListener(receiver)))
+    }
+}
+
+struct SimListener(mpsc::UnboundedReceiver<SimStream>);
+
+#[async_trait]
+impl Listener for SimListener {
+    async fn accept(&mut self) -> io::Result<(Box<dyn PeerStream>, Option<SocketAddr>)> {
+        match self.0.recv().await {
+            Some(stream) => Ok((Box::new(stream), None)),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Unbound")),
+        }
+    }
+}
+
+/// One end of a simulated connection, which fails once the link is cut or
+/// a write is lost.
+struct SimStream {
+    inner: DuplexStream,
+    /// Local and remote address
+    ends: [SocketAddr; 2],
+    network: Arc<Network>,
+    links: [CancellationToken; 2],
+    cut: [Pin<Box<WaitForCancellationFutureOwned>>; 2],
+    lost: bool,
+    /// Latency of the write under way
+    delay: Option<Pin<Box<Sleep>>>,
+    /// Whether the write under way has waited out its latency
+    delayed: bool,
+}
+
+impl SimStream {
+    fn new(
+        inner: DuplexStream,
+        ends: [SocketAddr; 2],
+        network: &Arc<Network>,
+        links: [CancellationToken; 2],
+    ) -> Self {
+        Self {
+            inner,
+            ends,
+            network: Arc::clone(network),
+            cut: links.clone().map(|token| Box::pin(token.cancelled_owned())),
+            links,
+            lost: false,
+            delay: None,
+            delayed: false,
+        }
+    }
+
+    fn poll_cut(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        if self.lost {
+            return Poll::Ready(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Simulated write was lost",
+            ));
+        }
+        for cut in &mut self.cut {
+            if cut.poll_unpin(cx).is_ready() {
+                return Poll::Ready(io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    "Simulated link was cut",
+                ));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl PeerStream for SimStream {
+    fn is_alive(&self) -> bool {
+        !self.lost && !self.links.iter().any(CancellationToken::is_cancelled)
+    }
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Poll::Ready(e) = self.poll_cut(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(e) = self.poll_cut(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if !self.delayed {
+            if self.delay.is_none() {
+                let [local, remote] = self.ends;
+                match self.network.transmit(local, remote) {
+                    None => {
+                        self.lost = true;
+                        return self.poll_cut(cx).map(Err);
+                    }
+                    Some(latency) if !latency.is_zero() => {
+                        self.delay = Some(Box::pin(tokio::time::sleep(latency)));
+                    }
+                    Some(_) => {}
+                }
+            }
+            if let Some(delay) = &mut self.delay {
+                ready!(delay.poll_unpin(cx));
+                self.delay = None;
+            }
+            self.delayed = true;
+        }
+
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf));
+        self.delayed = false;
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod testing_tests {
+    use std::time::Duration;
+
+    use tokio::time::{Instant, sleep};
+
+    use crate::dht::rpc::DhtRpc;
+
+    use super::{LinkConditions, TestCluster};
+
+    #[tokio::test]
+    async fn test_killed_nodes_are_unreachable_until_revived() {
+        let cluster = TestCluster::new(2).await;
+        let (a, b) = (cluster.node(0), cluster.node(1));
+        assert!(matches!(
+            a.send_rpc(b.addr, DhtRpc::Ping).await,
+            Ok(DhtRpc::Pong)
+        ));
+
+        cluster.kill(1);
+        assert!(a.send_rpc(b.addr, DhtRpc::Ping).await.is_err());
+        assert!(b.send_rpc(a.addr, DhtRpc::Ping).await.is_err());
+
+        cluster.revive(1);
+        assert!(matches!(
+            a.send_rpc(b.addr, DhtRpc::Ping).await,
+            Ok(DhtRpc::Pong)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_partitions_split_and_heal() {
+        let cluster = TestCluster::new(3).await;
+        let [a, b, c] = [0, 1, 2].map(|i| cluster.node(i).addr);
+
+        cluster.partition(&[2]);
+        let ping = |from: usize, to| cluster.node(from).send_rpc(to, DhtRpc::Ping);
+        assert!(ping(0, b).await.is_ok());
+        assert!(ping(0, c).await.is_err());
+        assert!(ping(2, a).await.is_err());
+
+        cluster.heal();
+        assert!(ping(0, c).await.is_ok());
+        assert!(ping(2, a).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_and_ttls_run_on_virtual_time() {
+        let cluster = TestCluster::new(2).await;
+        cluster.set_conditions(LinkConditions {
+            latency: Duration::from_millis(100),
+            loss: 0.0,
+        });
+
+        let started = Instant::now();
+        let ping = cluster.node(0).send_rpc(cluster.node(1).addr, DhtRpc::Ping);
+        assert!(ping.await.is_ok());
+        // Connecting, then the length and body of the request and response
+        assert_eq!(started.elapsed(), Duration::from_millis(500));
+
+        cluster
+            .node(0)
+            .store(b"key".to_vec(), b"value".to_vec())
+            .await
+            .unwrap();
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(cluster.node(1).find_value(b"key".to_vec()).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lossy_links_drop_rpcs() {
+        let cluster = TestCluster::new(3).await;
+        let lossless = LinkConditions::default();
+        cluster.set_conditions(LinkConditions {
+            loss: 1.0,
+            ..lossless
+        });
+        cluster.set_link_conditions(0, 1, lossless);
+
+        let ping = |to: usize| {
+            cluster
+                .node(0)
+                .send_rpc(cluster.node(to).addr, DhtRpc::Ping)
+        };
+        assert!(ping(1).await.is_ok());
+        assert!(ping(2).await.is_err());
+    }
+
+    /// Keys each node holds after a run with churn and lossy links.
+    async fn churn_scenario() -> Vec<Vec<Vec<u8>>> {
+        let mut config = crate::helpers::test_config();
+        config.storage.default_ttl = 3600;
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        let cluster = TestCluster::with_config(4, config).await;
+        cluster.set_seed(7);
+        cluster.set_conditions(LinkConditions {
+            latency: Duration::from_millis(30),
+            loss: 0.05,
+        });
+
+        for round in 0..3u8 {
+            cluster.kill(usize::from(round) + 1);
+            for i in 0..5u8 {
+                let _ = cluster.node(0).store(vec![round, i], vec![i]).await;
+            }
+            sleep(Duration::from_secs(30)).await;
+            cluster.revive(usize::from(round) + 1);
+            cluster.node(0).flush_outbox().await;
+        }
+
+        cluster
+            .nodes()
+            .iter()
+            .map(|node| {
+                let mut keys: Vec<_> = node.storage.iter().map(|(key, _)| key).collect();
+                keys.sort();
+                keys
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_simulations_are_reproducible() {
+        let run = || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .start_paused(true)
+                .build()
+                .unwrap()
+                .block_on(churn_scenario())
+        };
+        assert_eq!(run(), run());
+    }
+}
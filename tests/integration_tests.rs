@@ -3,45 +3,54 @@ mod integration_tests {
     use std::{sync::Arc, time::Duration};
 
     use rust_p2p_node::{
-        dht::{peer::PeerInfo, rpc::DhtRpc},
-        helpers::{create_test_node, now},
+        dht::{
+            DhtNode,
+            peer::PeerInfo,
+            rpc::{DhtRpc, Envelope},
+        },
+        helpers::{create_test_node, now, test_config},
+        testing::TestCluster,
     };
     use tokio::{
         io::{AsyncReadExt, AsyncWriteExt},
         net::TcpListener,
-        task,
+        task::{self, JoinHandle},
     };
 
-    #[tokio::test]
-    async fn test_two_nodes_communication() {
-        let node1 = Arc::new(create_test_node(8091));
-        let node2 = Arc::new(create_test_node(8092));
-
-        let node2_clone = Arc::clone(&node2);
-        let handle = task::spawn(async move {
-            let listener = TcpListener::bind(node2_clone.addr).await.unwrap();
+    fn spawn_rpc_server(node: Arc<DhtNode>) -> JoinHandle<()> {
+        task::spawn(async move {
+            let listener = TcpListener::bind(node.addr).await.unwrap();
             loop {
                 let (mut socket, _) = listener.accept().await.unwrap();
-                let node = Arc::clone(&node2_clone);
+                let node = Arc::clone(&node);
 
+                // Pooled connections carry several requests, so serve until EOF
                 task::spawn(async move {
                     let mut len_buf = [0u8; 4];
-                    socket.read_exact(&mut len_buf).await.unwrap();
+                    while socket.read_exact(&mut len_buf).await.is_ok() {
+                        let len = u32::from_be_bytes(len_buf) as usize;
+                        let mut buf = vec![0u8; len];
+                        socket.read_exact(&mut buf).await.unwrap();
 
-                    let len = u32::from_be_bytes(len_buf) as usize;
-                    let mut buf = vec![0u8; len];
-                    socket.read_exact(&mut buf).await.unwrap();
+                        let request: Envelope = bincode::deserialize(&buf).unwrap();
+                        let response = node.handle_rpc(request.rpc).await;
 
-                    let request: DhtRpc = bincode::deserialize(&buf).unwrap();
-                    let response = node.handle_rpc(request).await;
-
-                    let response_buf = bincode::serialize(&response).unwrap();
-                    let len = (response_buf.len() as u32).to_be_bytes();
-                    socket.write_all(&len).await.unwrap();
-                    socket.write_all(&response_buf).await.unwrap();
+                        let response_buf = bincode::serialize(&response).unwrap();
+                        let len = (response_buf.len() as u32).to_be_bytes();
+                        socket.write_all(&len).await.unwrap();
+                        socket.write_all(&response_buf).await.unwrap();
+                    }
                 });
             }
-        });
+        })
+    }
+
+    #[tokio::test]
+    async fn test_two_nodes_communication() {
+        let node1 = Arc::new(create_test_node(8091));
+        let node2 = Arc::new(create_test_node(8092));
+
+        let handle = spawn_rpc_server(Arc::clone(&node2));
 
         // Даем время на запуск сервера
         tokio::time::sleep(Duration::from_millis(100)).await;
@@ -50,7 +59,11 @@ mod integration_tests {
         let peer_info = PeerInfo {
             id: node2.id.clone(),
             addr: node2.addr,
+            alt_addrs: Vec::new(),
+            mirror: false,
             last_seen: now(),
+            public_key: None,
+            signature: None,
         };
         node1.add_peer(peer_info);
 
@@ -75,4 +88,167 @@ mod integration_tests {
 
         handle.abort();
     }
+
+    /// Two nodes on an in-memory network, keeping values for a minute.
+    async fn long_lived_pair() -> TestCluster {
+        let mut config = test_config();
+        config.storage.default_ttl = 60;
+        TestCluster::with_config(2, config).await
+    }
+
+    #[tokio::test]
+    async fn test_hand_off_keys_before_shutdown() {
+        let cluster = long_lived_pair().await;
+        let (node1, node2) = (cluster.node(0), cluster.node(1));
+
+        let key = b"handoff_key".to_vec();
+        let value = b"handoff_value".to_vec();
+        node1.store(key.clone(), value.clone()).await.unwrap();
+        node2.storage.clear();
+
+        assert_eq!(node1.hand_off_keys().await, 1);
+        assert_eq!(node2.find_value(key).await, Some(value.into()));
+    }
+
+    #[tokio::test]
+    async fn test_replication_checker_repairs_missing_replicas() {
+        let cluster = long_lived_pair().await;
+        let (node1, node2) = (cluster.node(0), cluster.node(1));
+
+        let key = b"repair_key".to_vec();
+        let value = b"repair_value".to_vec();
+        node1.store(key.clone(), value.clone()).await.unwrap();
+        node2.storage.clear();
+
+        assert_eq!(node1.check_replication().await, 1);
+        assert_eq!(node2.find_value(key).await, Some(value.into()));
+    }
+
+    #[tokio::test]
+    async fn test_replication_check_batches_per_peer() {
+        let cluster = long_lived_pair().await;
+        let (node1, node2) = (cluster.node(0), cluster.node(1));
+
+        for i in 0..10u8 {
+            node1.store(vec![i], vec![i]).await.unwrap();
+        }
+        node2.storage.clear();
+        let requests = || node1.peer_stats()[&node2.addr].requests;
+        let before = requests();
+
+        assert_eq!(node1.check_replication().await, 10);
+        // One batch asking which keys are missing, one sending them
+        assert_eq!(requests() - before, 2);
+        assert_eq!(node2.storage.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_writes_during_a_partition_are_replicated_once_healed() {
+        let mut config = test_config();
+        config.storage.default_ttl = 60;
+        config.connection_pool.dial_backoff_base = Duration::ZERO;
+        let cluster = TestCluster::with_config(3, config).await;
+
+        cluster.partition(&[0]);
+        let key = b"partitioned_key".to_vec();
+        cluster
+            .node(0)
+            .store(key.clone(), b"value".to_vec())
+            .await
+            .unwrap();
+        assert!(cluster.node(0).outbox.contains_key(&key));
+        assert_eq!(cluster.node(1).find_value(key.clone()).await, None);
+
+        cluster.heal();
+        assert_eq!(cluster.node(0).flush_outbox().await, 1);
+        for node in cluster.nodes() {
+            assert!(node.local_value(&key).is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_rpcs() {
+        let mut node1 = create_test_node(8097);
+        node1.config.connection_pool.multiplexing = true;
+        let node2 = Arc::new(create_test_node(8098));
+
+        let server = Arc::clone(&node2);
+        let handle = task::spawn(async move {
+            let listener = TcpListener::bind(server.addr).await.unwrap();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let node = Arc::clone(&server);
+                task::spawn(async move { node.serve_multiplexed(socket).await });
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let pings = (0..10).map(|_| node1.send_rpc(node2.addr, DhtRpc::Ping));
+        for response in futures::future::join_all(pings).await {
+            assert!(matches!(response.unwrap(), DhtRpc::Pong));
+        }
+
+        handle.abort();
+    }
+
+    #[cfg(feature = "http")]
+    async fn http_get(addr: &str, path: &str) -> String {
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: probe\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn test_health_endpoint_reports_readiness() {
+        let node1 = create_test_node(8099);
+        let node2 = create_test_node(8100);
+        node2.listen().await.unwrap();
+        node1
+            .serve_health("127.0.0.1:8101".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert!(
+            http_get("127.0.0.1:8101", "/livez")
+                .await
+                .starts_with("HTTP/1.1 503")
+        );
+        assert!(
+            http_get("127.0.0.1:8101", "/readyz")
+                .await
+                .starts_with("HTTP/1.1 503")
+        );
+
+        node1.listen().await.unwrap();
+        node1.add_peer(PeerInfo::new(node2.id.clone(), node2.addr));
+        node1.start_maintenance_service().await;
+
+        assert!(
+            http_get("127.0.0.1:8101", "/livez")
+                .await
+                .starts_with("HTTP/1.1 200")
+        );
+        assert!(
+            http_get("127.0.0.1:8101", "/readyz")
+                .await
+                .starts_with("HTTP/1.1 200")
+        );
+
+        let health = http_get("127.0.0.1:8101", "/health").await;
+        assert!(health.contains(r#""bound":true"#));
+        assert!(health.contains(r#""peers":1"#));
+
+        assert!(
+            http_get("127.0.0.1:8101", "/nope")
+                .await
+                .starts_with("HTTP/1.1 404")
+        );
+    }
 }